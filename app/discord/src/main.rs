@@ -1,5 +1,3 @@
-use std::env;
-
 use anyhow::{Context, Result};
 
 use dfiles::aspects;
@@ -30,8 +28,8 @@ RUN apt-get update && apt-get --fix-broken install -y \
 }
 
 fn main() -> Result<()> {
-    let home = env::var("HOME").expect("HOME must be set");
-    let container_path = format!("{}/.config/discord/", home);
+    let user = aspects::CurrentUser::detect().context("detecting current user")?;
+    let container_path = format!("{}/.config/discord/", user.home());
 
     let version = env!("CARGO_PKG_VERSION");
 
@@ -42,7 +40,7 @@ fn main() -> Result<()> {
         vec![
             Box::new(Discord {}),
             Box::new(aspects::Name("discord".to_string())),
-            Box::new(aspects::CurrentUser::detect().context("detecting current user")?),
+            Box::new(user),
             Box::new(aspects::PulseAudio {}),
             Box::new(aspects::X11 {}),
             Box::new(aspects::Video {}),
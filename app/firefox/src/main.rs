@@ -1,5 +1,3 @@
-use std::env;
-
 use anyhow::{Context, Result};
 
 use dfiles::aspects;
@@ -41,8 +39,8 @@ RUN ln -sf /opt/firefox/firefox-bin /usr/local/bin/firefox"#,
 }
 
 fn main() -> Result<()> {
-    let home = env::var("HOME").expect("HOME must be set");
-    let container_path = format!("{}/.mozilla/firefox/profile", home);
+    let user = aspects::CurrentUser::detect().context("detecting current user")?;
+    let container_path = format!("{}/.mozilla/firefox/profile", user.home());
 
     let version = env!("CARGO_PKG_VERSION");
 
@@ -53,7 +51,7 @@ fn main() -> Result<()> {
         vec![
             Box::new(Firefox {}),
             Box::new(aspects::Name("firefox".to_string())),
-            Box::new(aspects::CurrentUser::detect().context("detecting current user")?),
+            Box::new(user),
             Box::new(aspects::PulseAudio {}),
             Box::new(aspects::X11 {}),
             Box::new(aspects::Video {}),
@@ -1,5 +1,3 @@
-use std::env;
-
 use anyhow::{Context, Result};
 
 use dfiles::aspects;
@@ -39,8 +37,8 @@ impl aspects::ContainerAspect for Signal {
 }
 
 fn main() -> Result<()> {
-    let home = env::var("HOME").expect("HOME must be set");
-    let container_path = format!("{}/.config/Signal/", home);
+    let user = aspects::CurrentUser::detect().context("detecting current user")?;
+    let container_path = format!("{}/.config/Signal/", user.home());
 
     let mut mgr = ContainerManager::default_debian(
         "signal".to_string(),
@@ -50,7 +48,7 @@ fn main() -> Result<()> {
             Box::new(Signal {}),
             Box::new(aspects::Name("signal".to_string())),
             Box::new(aspects::PulseAudio {}),
-            Box::new(aspects::CurrentUser::detect().context("detecting current user")?),
+            Box::new(user),
             Box::new(aspects::X11 {}),
             Box::new(aspects::Video {}),
             Box::new(aspects::DBus {}),
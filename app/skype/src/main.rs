@@ -1,5 +1,3 @@
-use std::env;
-
 use anyhow::{Context, Result};
 
 use dfiles::aspects;
@@ -102,8 +100,8 @@ while ps -C skypeforlinux >/dev/null;do sleep 3;done "#,
 }
 
 fn main() -> Result<()> {
-    let home = env::var("HOME").expect("HOME must be set");
-    let container_path = format!("{}/.config/skypeforlinux", home);
+    let user = aspects::CurrentUser::detect().context("detecting current user")?;
+    let container_path = format!("{}/.config/skypeforlinux", user.home());
 
     let version = env!("CARGO_PKG_VERSION");
 
@@ -114,7 +112,7 @@ fn main() -> Result<()> {
         vec![
             Box::new(Skype {}),
             Box::new(aspects::Name("skype".to_string())),
-            Box::new(aspects::CurrentUser::detect().context("detecting current user")?),
+            Box::new(user),
             Box::new(aspects::PulseAudio {}),
             Box::new(aspects::X11 {}),
             Box::new(aspects::Video {}),
@@ -1,5 +1,3 @@
-use std::env;
-
 use anyhow::{Context, Result};
 
 use dfiles::aspects;
@@ -31,8 +29,8 @@ RUN chmod 4755 /opt/Signal/chrome-sandox"#,
 }
 
 fn main() -> Result<()> {
-    let home = env::var("HOME").expect("HOME must be set");
-    let container_path = format!("{}/.steam/", home);
+    let user = aspects::CurrentUser::detect().context("detecting current user")?;
+    let container_path = format!("{}/.steam/", user.home());
 
     let version = env!("CARGO_PKG_VERSION");
 
@@ -43,7 +41,7 @@ fn main() -> Result<()> {
         vec![
             Box::new(Steam {}),
             Box::new(aspects::Name("steam".to_string())),
-            Box::new(aspects::CurrentUser::detect().context("detecting current user")?),
+            Box::new(user),
             Box::new(aspects::PulseAudio {}),
             Box::new(aspects::Alsa {}),
             Box::new(aspects::X11 {}),
@@ -1,5 +1,3 @@
-use std::env;
-
 use anyhow::{Context, Result};
 
 use dfiles::aspects;
@@ -30,18 +28,22 @@ RUN apt-get update && apt-get --fix-broken install -y \
 }
 
 fn main() -> Result<()> {
-    let home = env::var("HOME").expect("HOME must be set");
+    let user = aspects::CurrentUser::detect().context("detecting current user")?;
+    let home = user.home();
 
     let version = env!("CARGO_PKG_VERSION");
 
     let mut mgr = ContainerManager::default_debian(
         "zoom".to_string(),
         vec![format!("{}:{}", "waynr/zoom", version)],
-        vec![format!("{}/.zoom/", home), format!("{}/.config/zoomus.conf", home), ],
+        vec![
+            format!("{}/.zoom/", home),
+            format!("{}/.config/zoomus.conf", home),
+        ],
         vec![
             Box::new(Zoom {}),
             Box::new(aspects::Name("zoom".to_string())),
-            Box::new(aspects::CurrentUser::detect().context("detecting current user")?),
+            Box::new(user),
             Box::new(aspects::PulseAudio {}),
             Box::new(aspects::X11 {}),
             Box::new(aspects::Video {}),
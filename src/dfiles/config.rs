@@ -1,15 +1,20 @@
 use std::convert::TryFrom;
 use std::fs;
-use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Once;
 
 use clap::{Arg, ArgMatches};
+use directories_next::ProjectDirs;
+use log::warn;
 use serde::{Deserialize, Serialize};
 
 use super::aspects;
 use super::dirs;
 use super::error::{Error, Result};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Config {
     pub mounts: Option<Vec<aspects::Mount>>,
     pub timezone: Option<aspects::Timezone>,
@@ -17,6 +22,36 @@ pub struct Config {
     pub cpu_shares: Option<aspects::CPUShares>,
     pub network: Option<aspects::Network>,
     pub locale: Option<aspects::Locale>,
+    pub apt_packages: Option<Vec<String>>,
+    pub run_commands: Option<Vec<aspects::Run>>,
+    pub workdir: Option<aspects::Workdir>,
+    pub extra_hosts: Option<Vec<(String, String)>>,
+    pub ulimits: Option<Vec<aspects::Ulimit>>,
+    pub init: Option<bool>,
+    pub sysctls: Option<Vec<(String, String)>>,
+    pub env_files: Option<Vec<aspects::EnvFile>>,
+    pub inherit_env: Option<Vec<String>>,
+    pub cursor_theme: Option<aspects::CursorTheme>,
+    pub keyboard_layout: Option<aspects::KeyboardLayout>,
+    pub cpu_set: Option<aspects::CpuSet>,
+    pub pids_limit: Option<aspects::PidsLimit>,
+    pub pip_packages: Option<Vec<String>>,
+    pub npm_packages: Option<Vec<String>>,
+    pub apparmor: Option<aspects::AppArmor>,
+    pub log_driver: Option<aspects::LogDriver>,
+    pub cgroup_parent: Option<aspects::CgroupParent>,
+    pub stop_signal: Option<aspects::StopSignal>,
+    pub stop_timeout: Option<aspects::StopTimeout>,
+    pub mac_address: Option<aspects::MacAddress>,
+    pub ip: Option<aspects::Ip>,
+    pub hosts_file: Option<aspects::HostsFile>,
+    pub resolv_conf: Option<aspects::ResolvConf>,
+    pub command: Option<Vec<String>>,
+    pub hidpi: Option<aspects::HiDpi>,
+    pub memory_swap: Option<aspects::MemorySwap>,
+    pub memory_reservation: Option<aspects::MemoryReservation>,
+    pub user_home: Option<aspects::UserHome>,
+    pub user_shell: Option<aspects::UserShell>,
 }
 
 impl Config {
@@ -28,78 +63,357 @@ impl Config {
             cpu_shares: None,
             network: None,
             locale: None,
+            apt_packages: None,
+            run_commands: None,
+            workdir: None,
+            extra_hosts: None,
+            ulimits: None,
+            init: None,
+            sysctls: None,
+            env_files: None,
+            inherit_env: None,
+            cursor_theme: None,
+            keyboard_layout: None,
+            cpu_set: None,
+            pids_limit: None,
+            pip_packages: None,
+            npm_packages: None,
+            apparmor: None,
+            log_driver: None,
+            cgroup_parent: None,
+            stop_signal: None,
+            stop_timeout: None,
+            mac_address: None,
+            ip: None,
+            hosts_file: None,
+            resolv_conf: None,
+            command: None,
+            hidpi: None,
+            memory_swap: None,
+            memory_reservation: None,
+            user_home: None,
+            user_shell: None,
         }
     }
 
+    /// Merges the config into the config file of the given application and profile like command
+    /// line flags, see `MergePolicy::CliWins`.
     pub fn save(&self, application: Option<&str>, profile: Option<&str>) -> Result<()> {
         let existing_config = Config::load_layer(application, profile)?;
-        let merged = existing_config.merge(self, true);
+        let merged = existing_config.merge(self, MergePolicy::CliWins);
 
-        let config_dir = dirs::get_config_dir(application, profile)?;
-        fs::create_dir_all(&config_dir)?;
+        let path = Config::path(application, profile)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        merged.save_file(&path)
+    }
+
+    /// Resolves the config file for the combination of application and profile options; if both
+    /// are none, resolves the global config file.
+    pub fn path(application: Option<&str>, profile: Option<&str>) -> Result<PathBuf> {
+        Ok(Config::path_in(config_dir()?, application, profile))
+    }
+
+    fn path_in(config_dir: PathBuf, application: Option<&str>, profile: Option<&str>) -> PathBuf {
+        layer_path(&dirs::scoped(config_dir, application, profile))
+    }
+
+    /// Copies the config of an application's `from` profile, or the application config that
+    /// `config` saves without `--profile` if `from` is none, to its `to` profile, refusing to
+    /// replace an existing `to` profile unless `force` is set.
+    pub fn copy_profile(
+        application: &str,
+        from: Option<&str>,
+        to: &str,
+        force: bool,
+    ) -> Result<()> {
+        Config::copy_profile_in(&config_dir()?, application, from, to, force)
+    }
+
+    fn copy_profile_in(
+        config_dir: &Path,
+        application: &str,
+        from: Option<&str>,
+        to: &str,
+        force: bool,
+    ) -> Result<()> {
+        let profile_dir =
+            |p: Option<&str>| dirs::scoped(config_dir.to_path_buf(), Some(application), p);
+        let source = layer_path(&profile_dir(from));
+        if !source.exists() {
+            return Err(match from {
+                Some(from) => Error::MissingProfile(from.to_string()),
+                None => Error::MissingAppConfig(application.to_string()),
+            });
+        }
+        let cfg = Config::load_file(&source)?;
+
+        let dest_dir = profile_dir(Some(to));
+        let existing = layer_path(&dest_dir);
+        if existing.exists() {
+            if !force {
+                return Err(Error::ProfileExists(to.to_string()));
+            }
+            // a leftover config.toml would shadow a copied config.yaml
+            fs::remove_file(&existing)?;
+        }
+        fs::create_dir_all(&dest_dir)?;
+        cfg.save_file(&dest_dir.join(source.file_name().unwrap_or_default()))
+    }
+
+    /// Deletes the config of one of an application's profiles.
+    pub fn delete(application: &str, profile: &str) -> Result<()> {
+        Config::delete_in(&config_dir()?, application, profile)
+    }
+
+    fn delete_in(config_dir: &Path, application: &str, profile: &str) -> Result<()> {
+        let dir = dirs::scoped(config_dir.to_path_buf(), Some(application), Some(profile));
+        let mut deleted = false;
+        for file in &["config.toml", "config.yaml"] {
+            let path = dir.join(file);
+            if path.exists() {
+                fs::remove_file(&path)?;
+                deleted = true;
+            }
+        }
+        if !deleted {
+            return Err(Error::MissingProfile(profile.to_string()));
+        }
+        // leave the directory alone if it still holds anything else, e.g. a host overlay
+        let _ = fs::remove_dir(&dir);
+        Ok(())
+    }
+
+    /// Lists the profiles of an application that have a config file, sorted by name.
+    pub fn profiles(application: &str) -> Result<Vec<Profile>> {
+        Config::profiles_in(&config_dir()?, application)
+    }
+
+    fn profiles_in(config_dir: &Path, application: &str) -> Result<Vec<Profile>> {
+        let dir = dirs::scoped(config_dir.to_path_buf(), Some(application), None).join("profiles");
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+        let mut profiles = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = layer_path(&entry.path());
+            if !path.is_file() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                profiles.push(Profile {
+                    name: name.to_string(),
+                    path,
+                });
+            }
+        }
+        profiles.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(profiles)
+    }
+
+    /// Opens the given config file in an editor, creating it first if necessary, and makes sure
+    /// it still parses once the editor exits.
+    pub fn edit_file(path: &Path, editor: &str) -> Result<()> {
+        if !path.exists() {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            Config::empty().save_file(path)?;
+        }
 
-        let path = config_dir.join("config.yaml");
-        let mut config_file = fs::File::create(path)?;
+        let mut parts = editor.split_whitespace();
+        let program = parts.next().unwrap_or("vi");
+        if !Command::new(program)
+            .args(parts)
+            .arg(path)
+            .status()?
+            .success()
+        {
+            return Err(Error::EditorFailed(editor.to_string()));
+        }
 
-        let s = serde_yaml::to_string(&merged).map_err(|_| Error::FailedToSaveConfig)?;
-        config_file.write_all(&s.into_bytes())?;
+        Config::load_file(path).map(|_| ())
+    }
 
+    /// Writes the config to the given file, as TOML if it has a `.toml` extension and as YAML
+    /// otherwise.
+    pub fn save_file(&self, path: &Path) -> Result<()> {
+        let s = if is_toml(path) {
+            toml::to_string(&TomlConfig::from(self)).map_err(|_| Error::FailedToSaveConfig)?
+        } else {
+            self.to_yaml()?
+        };
+        fs::write(path, s)?;
         Ok(())
     }
 
+    /// Renders the config in the default config file format.
+    pub fn to_yaml(&self) -> Result<String> {
+        serde_yaml::to_string(self).map_err(|_| Error::FailedToSaveConfig)
+    }
+
+    /// Reads the config from the given file, as TOML if it has a `.toml` extension and as YAML
+    /// otherwise.
+    pub fn load_file(path: &Path) -> Result<Config> {
+        let contents = fs::read_to_string(path)?;
+        if is_toml(path) {
+            let t: TomlConfig = toml::from_str(&contents).map_err(|_| Error::FailedToLoadConfig)?;
+            Ok(Config::from(t))
+        } else {
+            serde_yaml::from_str(&contents).map_err(|_| Error::FailedToLoadConfig)
+        }
+    }
+
     /// Loads a single config file specified by the combination of application and profile options;
     /// if both are none, then loads the global config.
     fn load_layer(application: Option<&str>, profile: Option<&str>) -> Result<Config> {
-        let config_dir = dirs::get_config_dir(application, profile)?;
-        let yaml_file = config_dir.join("config.yaml");
+        Config::load_layer_in(&config_dir()?, application, profile)
+    }
+
+    fn load_layer_in(
+        config_dir: &Path,
+        application: Option<&str>,
+        profile: Option<&str>,
+    ) -> Result<Config> {
+        let path = Config::path_in(config_dir.to_path_buf(), application, profile);
 
         let mut cfg = Config::empty();
 
-        if yaml_file.exists() {
-            let yaml = fs::read_to_string(yaml_file)?;
-            cfg = serde_yaml::from_str(&yaml).map_err(|_| Error::FailedToLoadConfig)?;
+        if path.exists() {
+            cfg = Config::load_file(&path)?;
         }
 
         Ok(cfg)
     }
 
+    /// Loads the effective file-based config for the given application; layers are merged with
+    /// increasing precedence:
+    ///
+    /// 1. dfiles global config
+    /// 2. application config
+    /// 3. application profile config
+    /// 4. application host overlay for the current hostname
+    ///
+    /// CLI args take precedence over all of these, see `ContainerManager::load_config`.
     pub fn load(application: &str, profile: Option<&str>) -> Result<Config> {
+        Config::load_in(&config_dir()?, application, profile)
+    }
+
+    /// Like `load` but with the layers stored under `config_dir`.
+    pub fn load_in(config_dir: &Path, application: &str, profile: Option<&str>) -> Result<Config> {
         // load dfiles global config if it exists
-        let global_config = Config::load_layer(None, None)?;
+        let global_config = Config::load_layer_in(config_dir, None, None)?;
         // load application global config if it exists
-        let app_config = Config::load_layer(Some(application), None)?;
+        let app_config = Config::load_layer_in(config_dir, Some(application), None)?;
         // load application profile config if profile is specified and it exists
-        let profile_config = Config::load_layer(Some(application), profile)?;
-        Ok(global_config
-            .merge(&app_config, false)
-            .merge(&profile_config, false))
+        let profile_config = Config::load_layer_in(config_dir, Some(application), profile)?;
+        // load application host overlay if the hostname is known and it exists
+        let host_config = match hostname() {
+            Some(h) => Config::load_host_layer(config_dir, application, &h)?,
+            None => Config::empty(),
+        };
+        Ok(merge_layers(&[
+            global_config,
+            app_config,
+            profile_config,
+            host_config,
+        ]))
     }
 
-    /// Merge aspects from the given Config into a copy of the current, return a new Config.
-    pub fn merge(&self, other: &Config, overwrite: bool) -> Config {
-        let mut cfg = (*self).clone();
+    /// Loads the per-host overlay for an application, which lets synced config directories carry
+    /// machine-specific settings such as mounts.
+    fn load_host_layer(config_dir: &Path, application: &str, hostname: &str) -> Result<Config> {
+        let host_dir = dirs::scoped(config_dir.to_path_buf(), Some(application), None)
+            .join("hosts")
+            .join(hostname);
+        let path = layer_path(&host_dir);
 
-        cfg.mounts = merge(&self.mounts, &other.mounts, overwrite);
+        let mut cfg = Config::empty();
 
-        if let Some(v) = &other.timezone {
-            cfg.timezone = Some(v.clone());
+        if path.exists() {
+            cfg = Config::load_file(&path)?;
         }
 
-        if let Some(v) = &other.memory {
-            cfg.memory = Some(v.clone());
-        }
+        Ok(cfg)
+    }
 
-        if let Some(v) = &other.cpu_shares {
-            cfg.cpu_shares = Some(v.clone());
-        }
+    /// Merge aspects from the given Config into a copy of the current, return a new Config; see
+    /// `MergePolicy` for which side wins.
+    pub fn merge(&self, other: &Config, policy: MergePolicy) -> Config {
+        let mut cfg = (*self).clone();
 
-        if let Some(v) = &other.network {
-            cfg.network = Some(v.clone());
-        }
+        cfg.mounts = merge(&self.mounts, &other.mounts, policy);
+        cfg.apt_packages = merge(&self.apt_packages, &other.apt_packages, policy);
+        cfg.run_commands = merge(&self.run_commands, &other.run_commands, policy);
 
-        if let Some(v) = &other.locale {
-            cfg.locale = Some(v.clone());
-        }
+        cfg.timezone = pick(&self.timezone, &other.timezone, policy);
+
+        cfg.memory = pick(&self.memory, &other.memory, policy);
+
+        cfg.cpu_shares = pick(&self.cpu_shares, &other.cpu_shares, policy);
+
+        cfg.network = pick(&self.network, &other.network, policy);
+
+        cfg.locale = pick(&self.locale, &other.locale, policy);
+
+        cfg.workdir = pick(&self.workdir, &other.workdir, policy);
+
+        cfg.extra_hosts = merge(&self.extra_hosts, &other.extra_hosts, policy);
+
+        cfg.ulimits = merge(&self.ulimits, &other.ulimits, policy);
+
+        cfg.init = pick(&self.init, &other.init, policy);
+
+        cfg.sysctls = merge(&self.sysctls, &other.sysctls, policy);
+
+        cfg.env_files = merge(&self.env_files, &other.env_files, policy);
+
+        cfg.inherit_env = merge(&self.inherit_env, &other.inherit_env, policy);
+
+        cfg.cursor_theme = pick(&self.cursor_theme, &other.cursor_theme, policy);
+
+        cfg.keyboard_layout = pick(&self.keyboard_layout, &other.keyboard_layout, policy);
+
+        cfg.cpu_set = pick(&self.cpu_set, &other.cpu_set, policy);
+
+        cfg.pids_limit = pick(&self.pids_limit, &other.pids_limit, policy);
+
+        cfg.pip_packages = merge(&self.pip_packages, &other.pip_packages, policy);
+
+        cfg.npm_packages = merge(&self.npm_packages, &other.npm_packages, policy);
+
+        cfg.apparmor = pick(&self.apparmor, &other.apparmor, policy);
+
+        cfg.log_driver = pick(&self.log_driver, &other.log_driver, policy);
+
+        cfg.cgroup_parent = pick(&self.cgroup_parent, &other.cgroup_parent, policy);
+
+        cfg.stop_signal = pick(&self.stop_signal, &other.stop_signal, policy);
+
+        cfg.stop_timeout = pick_copy(self.stop_timeout, other.stop_timeout, policy);
+
+        cfg.mac_address = pick(&self.mac_address, &other.mac_address, policy);
+
+        cfg.ip = pick_copy(self.ip, other.ip, policy);
+
+        cfg.hosts_file = pick(&self.hosts_file, &other.hosts_file, policy);
+
+        cfg.resolv_conf = pick(&self.resolv_conf, &other.resolv_conf, policy);
+
+        cfg.command = pick(&self.command, &other.command, policy);
+
+        cfg.hidpi = pick(&self.hidpi, &other.hidpi, policy);
+
+        cfg.memory_swap = pick(&self.memory_swap, &other.memory_swap, policy);
+
+        cfg.memory_reservation = pick(&self.memory_reservation, &other.memory_reservation, policy);
+
+        cfg.user_home = pick(&self.user_home, &other.user_home, policy);
+
+        cfg.user_shell = pick(&self.user_shell, &other.user_shell, policy);
 
         cfg
     }
@@ -117,8 +431,20 @@ impl Config {
             aspects.push(Box::new(timezone.clone()));
         }
 
-        if let Some(memory) = &self.memory {
-            aspects.push(Box::new(memory.clone()));
+        match &self.memory {
+            Some(memory) => aspects.push(Box::new(aspects::MemoryLimits {
+                memory: memory.clone(),
+                swap: self.memory_swap.clone(),
+                reservation: self.memory_reservation.clone(),
+            })),
+            None => {
+                if let Some(swap) = &self.memory_swap {
+                    aspects.push(Box::new(swap.clone()));
+                }
+                if let Some(reservation) = &self.memory_reservation {
+                    aspects.push(Box::new(reservation.clone()));
+                }
+            }
         }
 
         if let Some(cpu_shares) = &self.cpu_shares {
@@ -133,172 +459,2066 @@ impl Config {
             aspects.push(Box::new(locale.clone()));
         }
 
-        aspects
-    }
-}
+        if let Some(packages) = &self.apt_packages {
+            aspects.push(Box::new(aspects::AptPackages(packages.clone())));
+        }
 
-impl TryFrom<&ArgMatches<'_>> for Config {
-    type Error = Error;
-    fn try_from(matches: &ArgMatches) -> Result<Self> {
-        let mut cfg = Config::empty();
+        if let Some(run_commands) = &self.run_commands {
+            for run in run_commands {
+                aspects.push(Box::new(run.clone()));
+            }
+        }
 
-        if let Some(vs) = matches.values_of("mount") {
-            let mut mounts: Vec<aspects::Mount> = Vec::new();
-            for v in vs {
-                mounts.push(aspects::Mount::try_from(v)?);
+        if let Some(workdir) = &self.workdir {
+            aspects.push(Box::new(workdir.clone()));
+        }
+
+        if let Some(extra_hosts) = &self.extra_hosts {
+            aspects.push(Box::new(aspects::ExtraHosts(extra_hosts.clone())));
+        }
+
+        if let Some(ulimits) = &self.ulimits {
+            aspects.push(Box::new(aspects::Ulimits(ulimits.clone())));
+        }
+
+        if let Some(true) = self.init {
+            aspects.push(Box::new(aspects::Init {}));
+        }
+
+        if let Some(sysctls) = &self.sysctls {
+            aspects.push(Box::new(aspects::Sysctl(sysctls.clone())));
+        }
+
+        if let Some(env_files) = &self.env_files {
+            for env_file in env_files {
+                aspects.push(Box::new(env_file.clone()));
             }
-            cfg.mounts = Some(mounts);
         }
 
-        if let Some(tz) = matches.value_of("timezone") {
-            cfg.timezone = Some(aspects::Timezone::try_from(tz)?);
+        if let Some(names) = &self.inherit_env {
+            aspects.push(Box::new(aspects::InheritEnv(names.clone())));
         }
 
-        if let Some(memory) = matches.value_of("memory") {
-            cfg.memory = Some(aspects::Memory::try_from(memory)?);
+        if let Some(cursor_theme) = &self.cursor_theme {
+            aspects.push(Box::new(cursor_theme.clone()));
         }
 
-        if let Some(cpu_shares) = matches.value_of("cpu-shares") {
-            cfg.cpu_shares = Some(aspects::CPUShares::try_from(cpu_shares)?);
+        if let Some(keyboard_layout) = &self.keyboard_layout {
+            aspects.push(Box::new(keyboard_layout.clone()));
         }
 
-        if let Some(network) = matches.value_of("network") {
-            cfg.network = Some(aspects::Network::try_from(network)?);
+        if let Some(cpu_set) = &self.cpu_set {
+            aspects.push(Box::new(cpu_set.clone()));
         }
 
-        if let Some(locale) = matches.value_of("locale") {
-            cfg.locale = Some(aspects::Locale::try_from(locale)?);
+        if let Some(pids_limit) = &self.pids_limit {
+            aspects.push(Box::new(pids_limit.clone()));
         }
 
-        Ok(cfg)
-    }
-}
+        if let Some(packages) = &self.pip_packages {
+            aspects.push(Box::new(aspects::PipPackages {
+                packages: packages.clone(),
+                use_venv: false,
+            }));
+        }
 
-fn merge<T: Clone>(
-    left: &Option<Vec<T>>,
-    right: &Option<Vec<T>>,
-    overwrite: bool,
-) -> Option<Vec<T>> {
-    let mut new = Vec::new();
+        if let Some(packages) = &self.npm_packages {
+            aspects.push(Box::new(aspects::NpmPackages(packages.clone())));
+        }
 
-    if let Some(v) = &left {
-        new = v.clone();
-    }
+        if let Some(apparmor) = &self.apparmor {
+            aspects.push(Box::new(apparmor.clone()));
+        }
 
-    if let Some(v) = &right {
-        if overwrite {
-            new = v.clone();
-        } else {
-            new.append(&mut v.clone());
+        if let Some(log_driver) = &self.log_driver {
+            aspects.push(Box::new(log_driver.clone()));
         }
-    }
 
-    match new.len() {
-        x if x > 0 => Some(new),
-        _ => None,
+        if let Some(cgroup_parent) = &self.cgroup_parent {
+            aspects.push(Box::new(cgroup_parent.clone()));
+        }
+
+        if let Some(stop_signal) = &self.stop_signal {
+            aspects.push(Box::new(stop_signal.clone()));
+        }
+
+        if let Some(stop_timeout) = &self.stop_timeout {
+            aspects.push(Box::new(*stop_timeout));
+        }
+
+        if let Some(mac_address) = &self.mac_address {
+            aspects.push(Box::new(mac_address.clone()));
+        }
+
+        if let Some(ip) = &self.ip {
+            aspects.push(Box::new(*ip));
+        }
+
+        if let Some(hosts_file) = &self.hosts_file {
+            aspects.push(Box::new(hosts_file.clone()));
+        }
+
+        if let Some(resolv_conf) = &self.resolv_conf {
+            aspects.push(Box::new(resolv_conf.clone()));
+        }
+
+        if let Some(hidpi) = &self.hidpi {
+            aspects.push(Box::new(*hidpi));
+        }
+
+        if let Some(home) = &self.user_home {
+            aspects.push(Box::new(home.clone()));
+        }
+
+        if let Some(shell) = &self.user_shell {
+            aspects.push(Box::new(shell.clone()));
+        }
+
+        aspects
     }
 }
 
-pub fn cli_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
-    vec![
-        Arg::with_name("mount")
-            .short("m")
-            .long("mount")
-            .multiple(true)
-            .takes_value(true)
-            .help("specify a local path to be mapped into the container filesystem at runtime"),
-        Arg::with_name("timezone")
-            .short("t")
-            .long("timezone")
-            .takes_value(true)
-            .help("specify the timezone to be built into the container image"),
-        Arg::with_name("memory")
-            .long("memory")
-            .takes_value(true)
-            .help("specify the runtime memory resource limit"),
-        Arg::with_name("cpu-shares")
-            .long("cpu-shares")
-            .takes_value(true)
-            .help("specify the runtime proportion of cpu cycles for the container"),
-        Arg::with_name("network")
-            .long("network")
-            .takes_value(true)
-            .help("specify the runtime network mode for the container (default: bridge)"),
-        Arg::with_name("locale")
-            .long("locale")
-            .takes_value(true)
-            .help("specify the locale in the form <language>_<territory>.<codeset> for the container (default: en_US.UTF8)"),
-    ]
+/// A profile of an application and the config file that holds it.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    pub path: PathBuf,
 }
 
-#[cfg(test)]
-mod merge_should {
-    use super::*;
+/// Merges config layers in order of increasing precedence.
+fn merge_layers(layers: &[Config]) -> Config {
+    layers.iter().fold(Config::empty(), |acc, layer| {
+        acc.merge(layer, MergePolicy::Append)
+    })
+}
 
-    #[test]
-    fn return_some() {
-        let left: Vec<i32> = vec![1, 2, 3, 4];
-        let right: Vec<i32> = vec![5, 6, 7];
-        let empty: Option<Vec<i32>> = Some(Vec::new());
+fn hostname() -> Option<String> {
+    hostname::get().ok().and_then(|h| h.into_string().ok())
+}
 
-        assert_eq!(
-            merge(&Some(left.clone()), &Some(right.clone()), true),
-            Some(vec![5, 6, 7])
-        );
-        assert_eq!(
-            merge(&Some(left.clone()), &Some(right.clone()), false),
-            Some(vec![1, 2, 3, 4, 5, 6, 7])
-        );
+/// Where config layers are stored: `DFILES_CONFIG_DIR` if set, else `$XDG_CONFIG_HOME/dfiles`,
+/// else `~/.config/dfiles`. Until that exists, a config directory left at the platform default
+/// of earlier releases, e.g. `~/Library/Application Support/dfiles` on macOS, is still used.
+pub fn config_dir() -> Result<PathBuf> {
+    let legacy = ProjectDirs::from("", "", "dfiles").map(|d| d.config_dir().to_path_buf());
+    config_dir_from(|name| std::env::var_os(name), legacy)
+}
 
-        assert_eq!(
-            merge(&Some(left.clone()), &None, false),
-            Some(vec![1, 2, 3, 4])
-        );
-        assert_eq!(
-            merge(&Some(left.clone()), &None, true),
-            Some(vec![1, 2, 3, 4])
-        );
+fn config_dir_from<F>(var: F, legacy: Option<PathBuf>) -> Result<PathBuf>
+where
+    F: Fn(&str) -> Option<std::ffi::OsString>,
+{
+    let set = |name: &str| var(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+    if let Some(dir) = set("DFILES_CONFIG_DIR") {
+        return Ok(dir);
+    }
+    // the XDG spec says relative paths are invalid and should be ignored
+    let dir = match set("XDG_CONFIG_HOME").filter(|d| d.is_absolute()) {
+        Some(dir) => dir.join("dfiles"),
+        None => match set("HOME") {
+            Some(home) => home.join(".config").join("dfiles"),
+            None => return Err(Error::MissingDirectory),
+        },
+    };
+    match legacy {
+        Some(old) if old != dir && old.is_dir() && !dir.exists() => {
+            LEGACY_CONFIG_DIR_WARNING.call_once(|| {
+                warn!(
+                    "reading config from {}, move it to {} to keep using it",
+                    old.display(),
+                    dir.display()
+                )
+            });
+            Ok(old)
+        }
+        _ => Ok(dir),
+    }
+}
 
-        assert_eq!(
-            merge(&None, &Some(right.clone()), false),
-            Some(vec![5, 6, 7])
-        );
-        assert_eq!(
-            merge(&None, &Some(right.clone()), true),
-            Some(vec![5, 6, 7])
-        );
+static LEGACY_CONFIG_DIR_WARNING: Once = Once::new();
 
-        assert_eq!(
-            merge(&Some(left.clone()), &empty.clone(), false),
-            Some(vec![1, 2, 3, 4])
-        );
-        assert_eq!(merge(&Some(left.clone()), &empty.clone(), true), None);
+/// Each config layer lives in a single file; a hand-written `config.toml` takes precedence over the
+/// default `config.yaml`.
+fn layer_path(config_dir: &Path) -> PathBuf {
+    let toml_file = config_dir.join("config.toml");
+    if toml_file.exists() {
+        toml_file
+    } else {
+        config_dir.join("config.yaml")
+    }
+}
 
-        assert_eq!(
-            merge(&empty.clone(), &Some(right.clone()), false),
-            Some(vec![5, 6, 7])
-        );
-        assert_eq!(
-            merge(&empty.clone(), &Some(right.clone()), true),
-            Some(vec![5, 6, 7])
-        );
+fn is_toml(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str()) == Some("toml")
+}
+
+/// Splits a command line into words the way a POSIX shell would: whitespace separates words,
+/// single quotes keep everything literally, and a backslash escapes the next character outside
+/// quotes and `"`, `\`, `$` and `` ` `` inside double quotes.
+fn split_command(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(Error::InvalidRunCommand(line.to_string())),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) if "\"\\$`".contains(c) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(Error::InvalidRunCommand(line.to_string())),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(Error::InvalidRunCommand(line.to_string())),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                match chars.next() {
+                    Some(c) => word.push(c),
+                    None => return Err(Error::InvalidRunCommand(line.to_string())),
+                }
+            }
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
     }
 
-    #[test]
-    fn return_none() {
-        let none: Option<Vec<i32>> = None;
-        let empty: Option<Vec<i32>> = Some(Vec::new());
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
 
-        assert_eq!(merge(&none.clone(), &none.clone(), true), None);
-        assert_eq!(merge(&none.clone(), &none.clone(), false), None);
+/// The TOML schema is a flat list of typed aspect entries, eg:
+///
+/// ```toml
+/// [[aspects]]
+/// type = "mount"
+/// host_path = "/home/me/Downloads"
+/// container_path = "/home/me/Downloads"
+///
+/// [[aspects]]
+/// type = "memory"
+/// value = "2g"
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct TomlConfig {
+    #[serde(default)]
+    aspects: Vec<AspectConfig>,
+}
 
-        assert_eq!(merge(&none.clone(), &empty.clone(), true), None);
-        assert_eq!(merge(&none.clone(), &empty.clone(), false), None);
+/// A single config-capable aspect as stored in a config file. Every `Config` field maps to one
+/// or more of these so that any config survives a save and load.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum AspectConfig {
+    Mount(aspects::Mount),
+    Timezone { value: String },
+    Memory { value: String },
+    CpuShares { value: String },
+    Network(aspects::Network),
+    Locale(aspects::Locale),
+    AptPackage { name: String },
+    Run(aspects::Run),
+    Workdir { path: String },
+    ExtraHost { name: String, ip: String },
+    Ulimit(aspects::Ulimit),
+    Init { enabled: bool },
+    Sysctl { key: String, value: String },
+    EnvFile { path: PathBuf },
+    InheritEnv { name: String },
+    CursorTheme(aspects::CursorTheme),
+    KeyboardLayout(aspects::KeyboardLayout),
+    CpuSet(aspects::CpuSet),
+    PidsLimit { value: i64 },
+    PipPackage { name: String },
+    NpmPackage { name: String },
+    AppArmor { profile: String },
+    LogDriver(aspects::LogDriver),
+    CgroupParent { value: String },
+    StopSignal { value: String },
+    StopTimeout { value: u32 },
+    MacAddress { value: String },
+    Ip { address: IpAddr },
+    HostsFile { path: PathBuf },
+    ResolvConf { path: PathBuf },
+    Command { args: Vec<String> },
+    HiDpi { scale: f32 },
+    MemorySwap { value: String },
+    MemoryReservation { value: String },
+    UserHome { path: String },
+    UserShell { path: String },
+}
 
-        assert_eq!(merge(&empty.clone(), &empty.clone(), true), None);
-        assert_eq!(merge(&empty.clone(), &empty.clone(), false), None);
+impl From<&Config> for TomlConfig {
+    fn from(cfg: &Config) -> Self {
+        let mut entries: Vec<AspectConfig> = Vec::new();
 
-        assert_eq!(merge(&empty.clone(), &none.clone(), true), None);
-        assert_eq!(merge(&empty.clone(), &none.clone(), false), None);
+        if let Some(mounts) = &cfg.mounts {
+            for mount in mounts {
+                entries.push(AspectConfig::Mount(mount.clone()));
+            }
+        }
+
+        if let Some(timezone) = &cfg.timezone {
+            entries.push(AspectConfig::Timezone {
+                value: timezone.0.clone(),
+            });
+        }
+
+        if let Some(memory) = &cfg.memory {
+            entries.push(AspectConfig::Memory {
+                value: memory.0.clone(),
+            });
+        }
+
+        if let Some(cpu_shares) = &cfg.cpu_shares {
+            entries.push(AspectConfig::CpuShares {
+                value: cpu_shares.0.clone(),
+            });
+        }
+
+        if let Some(network) = &cfg.network {
+            entries.push(AspectConfig::Network(network.clone()));
+        }
+
+        if let Some(locale) = &cfg.locale {
+            entries.push(AspectConfig::Locale(locale.clone()));
+        }
+
+        if let Some(packages) = &cfg.apt_packages {
+            for package in packages {
+                entries.push(AspectConfig::AptPackage {
+                    name: package.clone(),
+                });
+            }
+        }
+
+        if let Some(run_commands) = &cfg.run_commands {
+            for run in run_commands {
+                entries.push(AspectConfig::Run(run.clone()));
+            }
+        }
+
+        if let Some(workdir) = &cfg.workdir {
+            entries.push(AspectConfig::Workdir {
+                path: workdir.0.clone(),
+            });
+        }
+
+        if let Some(extra_hosts) = &cfg.extra_hosts {
+            for (name, ip) in extra_hosts {
+                entries.push(AspectConfig::ExtraHost {
+                    name: name.clone(),
+                    ip: ip.clone(),
+                });
+            }
+        }
+
+        if let Some(ulimits) = &cfg.ulimits {
+            for ulimit in ulimits {
+                entries.push(AspectConfig::Ulimit(ulimit.clone()));
+            }
+        }
+
+        if let Some(enabled) = cfg.init {
+            entries.push(AspectConfig::Init { enabled });
+        }
+
+        if let Some(sysctls) = &cfg.sysctls {
+            for (key, value) in sysctls {
+                entries.push(AspectConfig::Sysctl {
+                    key: key.clone(),
+                    value: value.clone(),
+                });
+            }
+        }
+
+        if let Some(env_files) = &cfg.env_files {
+            for env_file in env_files {
+                entries.push(AspectConfig::EnvFile {
+                    path: env_file.0.clone(),
+                });
+            }
+        }
+
+        if let Some(names) = &cfg.inherit_env {
+            for name in names {
+                entries.push(AspectConfig::InheritEnv { name: name.clone() });
+            }
+        }
+
+        if let Some(cursor_theme) = &cfg.cursor_theme {
+            entries.push(AspectConfig::CursorTheme(cursor_theme.clone()));
+        }
+
+        if let Some(keyboard_layout) = &cfg.keyboard_layout {
+            entries.push(AspectConfig::KeyboardLayout(keyboard_layout.clone()));
+        }
+
+        if let Some(cpu_set) = &cfg.cpu_set {
+            entries.push(AspectConfig::CpuSet(cpu_set.clone()));
+        }
+
+        if let Some(pids_limit) = &cfg.pids_limit {
+            entries.push(AspectConfig::PidsLimit {
+                value: pids_limit.0,
+            });
+        }
+
+        if let Some(packages) = &cfg.pip_packages {
+            for package in packages {
+                entries.push(AspectConfig::PipPackage {
+                    name: package.clone(),
+                });
+            }
+        }
+
+        if let Some(packages) = &cfg.npm_packages {
+            for package in packages {
+                entries.push(AspectConfig::NpmPackage {
+                    name: package.clone(),
+                });
+            }
+        }
+
+        if let Some(apparmor) = &cfg.apparmor {
+            entries.push(AspectConfig::AppArmor {
+                profile: apparmor.0.clone(),
+            });
+        }
+
+        if let Some(log_driver) = &cfg.log_driver {
+            entries.push(AspectConfig::LogDriver(log_driver.clone()));
+        }
+
+        if let Some(cgroup_parent) = &cfg.cgroup_parent {
+            entries.push(AspectConfig::CgroupParent {
+                value: cgroup_parent.0.clone(),
+            });
+        }
+
+        if let Some(stop_signal) = &cfg.stop_signal {
+            entries.push(AspectConfig::StopSignal {
+                value: stop_signal.0.clone(),
+            });
+        }
+
+        if let Some(stop_timeout) = &cfg.stop_timeout {
+            entries.push(AspectConfig::StopTimeout {
+                value: stop_timeout.0,
+            });
+        }
+
+        if let Some(mac_address) = &cfg.mac_address {
+            entries.push(AspectConfig::MacAddress {
+                value: mac_address.0.clone(),
+            });
+        }
+
+        if let Some(ip) = &cfg.ip {
+            entries.push(AspectConfig::Ip { address: ip.0 });
+        }
+
+        if let Some(hosts_file) = &cfg.hosts_file {
+            entries.push(AspectConfig::HostsFile {
+                path: hosts_file.0.clone(),
+            });
+        }
+
+        if let Some(resolv_conf) = &cfg.resolv_conf {
+            entries.push(AspectConfig::ResolvConf {
+                path: resolv_conf.0.clone(),
+            });
+        }
+
+        if let Some(args) = &cfg.command {
+            entries.push(AspectConfig::Command { args: args.clone() });
+        }
+
+        if let Some(hidpi) = &cfg.hidpi {
+            entries.push(AspectConfig::HiDpi { scale: hidpi.scale });
+        }
+
+        if let Some(swap) = &cfg.memory_swap {
+            entries.push(AspectConfig::MemorySwap {
+                value: swap.0.clone(),
+            });
+        }
+
+        if let Some(reservation) = &cfg.memory_reservation {
+            entries.push(AspectConfig::MemoryReservation {
+                value: reservation.0.clone(),
+            });
+        }
+
+        if let Some(home) = &cfg.user_home {
+            entries.push(AspectConfig::UserHome {
+                path: home.0.clone(),
+            });
+        }
+
+        if let Some(shell) = &cfg.user_shell {
+            entries.push(AspectConfig::UserShell {
+                path: shell.0.clone(),
+            });
+        }
+
+        TomlConfig { aspects: entries }
+    }
+}
+
+impl From<TomlConfig> for Config {
+    fn from(t: TomlConfig) -> Self {
+        let mut cfg = Config::empty();
+
+        for entry in t.aspects {
+            match entry {
+                AspectConfig::Mount(m) => cfg.mounts.get_or_insert_with(Vec::new).push(m),
+                AspectConfig::Timezone { value } => cfg.timezone = Some(aspects::Timezone(value)),
+                AspectConfig::Memory { value } => cfg.memory = Some(aspects::Memory(value)),
+                AspectConfig::CpuShares { value } => {
+                    cfg.cpu_shares = Some(aspects::CPUShares(value))
+                }
+                AspectConfig::Network(n) => cfg.network = Some(n),
+                AspectConfig::Locale(l) => cfg.locale = Some(l),
+                AspectConfig::AptPackage { name } => {
+                    cfg.apt_packages.get_or_insert_with(Vec::new).push(name)
+                }
+                AspectConfig::Run(r) => cfg.run_commands.get_or_insert_with(Vec::new).push(r),
+                AspectConfig::Workdir { path } => cfg.workdir = Some(aspects::Workdir(path)),
+                AspectConfig::ExtraHost { name, ip } => cfg
+                    .extra_hosts
+                    .get_or_insert_with(Vec::new)
+                    .push((name, ip)),
+                AspectConfig::Ulimit(u) => cfg.ulimits.get_or_insert_with(Vec::new).push(u),
+                AspectConfig::Init { enabled } => cfg.init = Some(enabled),
+                AspectConfig::Sysctl { key, value } => {
+                    cfg.sysctls.get_or_insert_with(Vec::new).push((key, value))
+                }
+                AspectConfig::EnvFile { path } => cfg
+                    .env_files
+                    .get_or_insert_with(Vec::new)
+                    .push(aspects::EnvFile(path)),
+                AspectConfig::InheritEnv { name } => {
+                    cfg.inherit_env.get_or_insert_with(Vec::new).push(name)
+                }
+                AspectConfig::CursorTheme(c) => cfg.cursor_theme = Some(c),
+                AspectConfig::KeyboardLayout(k) => cfg.keyboard_layout = Some(k),
+                AspectConfig::CpuSet(c) => cfg.cpu_set = Some(c),
+                AspectConfig::PidsLimit { value } => {
+                    cfg.pids_limit = Some(aspects::PidsLimit(value))
+                }
+                AspectConfig::PipPackage { name } => {
+                    cfg.pip_packages.get_or_insert_with(Vec::new).push(name)
+                }
+                AspectConfig::NpmPackage { name } => {
+                    cfg.npm_packages.get_or_insert_with(Vec::new).push(name)
+                }
+                AspectConfig::AppArmor { profile } => {
+                    cfg.apparmor = Some(aspects::AppArmor(profile))
+                }
+                AspectConfig::LogDriver(l) => cfg.log_driver = Some(l),
+                AspectConfig::CgroupParent { value } => {
+                    cfg.cgroup_parent = Some(aspects::CgroupParent(value))
+                }
+                AspectConfig::StopSignal { value } => {
+                    cfg.stop_signal = Some(aspects::StopSignal(value))
+                }
+                AspectConfig::StopTimeout { value } => {
+                    cfg.stop_timeout = Some(aspects::StopTimeout(value))
+                }
+                AspectConfig::MacAddress { value } => {
+                    cfg.mac_address = Some(aspects::MacAddress(value))
+                }
+                AspectConfig::Ip { address } => cfg.ip = Some(aspects::Ip(address)),
+                AspectConfig::HostsFile { path } => cfg.hosts_file = Some(aspects::HostsFile(path)),
+                AspectConfig::ResolvConf { path } => {
+                    cfg.resolv_conf = Some(aspects::ResolvConf(path))
+                }
+                AspectConfig::Command { args } => cfg.command = Some(args),
+                AspectConfig::HiDpi { scale } => cfg.hidpi = Some(aspects::HiDpi { scale }),
+                AspectConfig::MemorySwap { value } => {
+                    cfg.memory_swap = Some(aspects::MemorySwap(value))
+                }
+                AspectConfig::MemoryReservation { value } => {
+                    cfg.memory_reservation = Some(aspects::MemoryReservation(value))
+                }
+                AspectConfig::UserHome { path } => cfg.user_home = Some(aspects::UserHome(path)),
+                AspectConfig::UserShell { path } => cfg.user_shell = Some(aspects::UserShell(path)),
+            }
+        }
+
+        cfg
+    }
+}
+
+impl TryFrom<&ArgMatches<'_>> for Config {
+    type Error = Error;
+    fn try_from(matches: &ArgMatches) -> Result<Self> {
+        let mut cfg = Config::empty();
+
+        if let Some(vs) = matches.values_of("mount") {
+            let mut mounts: Vec<aspects::Mount> = Vec::new();
+            for v in vs {
+                let mount = aspects::Mount::try_from(v)?;
+                if let Err(e) = mount.validate() {
+                    if matches.is_present("strict") {
+                        return Err(e);
+                    }
+                    warn!("{}: host path should be absolute and exist", e);
+                }
+                mounts.push(mount);
+            }
+            cfg.mounts = Some(mounts);
+        }
+
+        if let Some(tz) = matches.value_of("timezone") {
+            cfg.timezone = Some(aspects::Timezone::try_from(tz)?);
+        }
+
+        if let Some(memory) = matches.value_of("memory") {
+            cfg.memory = Some(aspects::Memory::try_from(memory)?);
+        }
+
+        if let Some(cpu_shares) = matches.value_of("cpu-shares") {
+            cfg.cpu_shares = Some(aspects::CPUShares::try_from(cpu_shares)?);
+        }
+
+        if let Some(network) = matches.value_of("network") {
+            cfg.network = Some(aspects::Network::try_from(network)?);
+        }
+
+        if let Some(locale) = matches.value_of("locale") {
+            cfg.locale = Some(aspects::Locale::try_from(locale)?);
+        }
+
+        if let Some(vs) = matches.values_of("apt-package") {
+            cfg.apt_packages = Some(vs.map(String::from).collect());
+        }
+
+        if let Some(vs) = matches.values_of("run") {
+            let mut run_commands: Vec<aspects::Run> = Vec::new();
+            for v in vs {
+                run_commands.push(aspects::Run::try_from(v)?);
+            }
+            cfg.run_commands = Some(run_commands);
+        }
+
+        if let Some(workdir) = matches.value_of("workdir") {
+            cfg.workdir = Some(aspects::Workdir::try_from(workdir)?);
+        }
+
+        if let Some(vs) = matches.values_of("add-host") {
+            let mut extra_hosts: Vec<(String, String)> = Vec::new();
+            for v in vs {
+                extra_hosts.push(aspects::ExtraHosts::parse(v)?);
+            }
+            cfg.extra_hosts = Some(extra_hosts);
+        }
+
+        if let Some(vs) = matches.values_of("ulimit") {
+            let mut ulimits: Vec<aspects::Ulimit> = Vec::new();
+            for v in vs {
+                ulimits.push(aspects::Ulimit::try_from(v)?);
+            }
+            cfg.ulimits = Some(ulimits);
+        }
+
+        if matches.is_present("init") {
+            cfg.init = Some(true);
+        }
+
+        if let Some(vs) = matches.values_of("sysctl") {
+            let mut sysctls: Vec<(String, String)> = Vec::new();
+            for v in vs {
+                sysctls.push(aspects::Sysctl::parse(v)?);
+            }
+            cfg.sysctls = Some(sysctls);
+        }
+
+        if let Some(vs) = matches.values_of("env-file") {
+            cfg.env_files = Some(vs.map(|v| aspects::EnvFile(PathBuf::from(v))).collect());
+        }
+
+        if let Some(vs) = matches.values_of("inherit-env") {
+            cfg.inherit_env = Some(vs.map(String::from).collect());
+        }
+
+        if let Some(cursor_theme) = matches.value_of("cursor-theme") {
+            cfg.cursor_theme = Some(aspects::CursorTheme::try_from(cursor_theme)?);
+        }
+
+        if let Some(keyboard_layout) = matches.value_of("keyboard-layout") {
+            cfg.keyboard_layout = Some(aspects::KeyboardLayout::try_from(keyboard_layout)?);
+        }
+
+        if let Some(cpus) = matches.value_of("cpuset-cpus") {
+            cfg.cpu_set = Some(aspects::CpuSet::new(cpus, matches.value_of("cpuset-mems"))?);
+        }
+
+        if let Some(pids_limit) = matches.value_of("pids-limit") {
+            cfg.pids_limit = Some(aspects::PidsLimit::try_from(pids_limit)?);
+        }
+
+        if let Some(vs) = matches.values_of("pip-package") {
+            cfg.pip_packages = Some(vs.map(String::from).collect());
+        }
+
+        if let Some(vs) = matches.values_of("npm-package") {
+            cfg.npm_packages = Some(vs.map(String::from).collect());
+        }
+
+        if let Some(profile) = matches.value_of("apparmor") {
+            cfg.apparmor = Some(aspects::AppArmor(profile.to_string()));
+        }
+
+        if let Some(driver) = matches.value_of("log-driver") {
+            let mut options: Vec<(String, String)> = Vec::new();
+            for v in matches.values_of("log-opt").into_iter().flatten() {
+                options.push(aspects::LogDriver::parse_option(v)?);
+            }
+            cfg.log_driver = Some(aspects::LogDriver::new(driver, options)?);
+        }
+
+        if let Some(cgroup_parent) = matches.value_of("cgroup-parent") {
+            cfg.cgroup_parent = Some(aspects::CgroupParent::try_from(cgroup_parent)?);
+        }
+
+        if let Some(stop_signal) = matches.value_of("stop-signal") {
+            cfg.stop_signal = Some(aspects::StopSignal::try_from(stop_signal)?);
+        }
+
+        if let Some(stop_timeout) = matches.value_of("stop-timeout") {
+            cfg.stop_timeout = Some(aspects::StopTimeout::try_from(stop_timeout)?);
+        }
+
+        if let Some(mac_address) = matches.value_of("mac-address") {
+            cfg.mac_address = Some(aspects::MacAddress::try_from(mac_address)?);
+        }
+
+        if let Some(ip) = matches.value_of("ip") {
+            let address = ip
+                .parse()
+                .map_err(|_| Error::InvalidIpAddress(ip.to_string()))?;
+            cfg.ip = Some(aspects::Ip(address));
+        }
+
+        if let Some(path) = matches.value_of("hosts-file") {
+            cfg.hosts_file = Some(aspects::HostsFile(PathBuf::from(path)));
+        }
+
+        if let Some(path) = matches.value_of("resolv-conf") {
+            cfg.resolv_conf = Some(aspects::ResolvConf(PathBuf::from(path)));
+        }
+
+        if let Some(command) = matches.value_of("default-command") {
+            cfg.command = Some(split_command(command)?);
+        }
+
+        if let Some(scale) = matches.value_of("hidpi-scale") {
+            cfg.hidpi = Some(aspects::HiDpi::try_from(scale)?);
+        }
+
+        if let Some(swap) = matches.value_of("memory-swap") {
+            cfg.memory_swap = Some(aspects::MemorySwap::try_from(swap)?);
+        }
+
+        if let Some(reservation) = matches.value_of("memory-reservation") {
+            cfg.memory_reservation = Some(aspects::MemoryReservation::try_from(reservation)?);
+        }
+
+        if let Some(home) = matches.value_of("user-home") {
+            cfg.user_home = Some(aspects::UserHome::try_from(home)?);
+        }
+
+        if let Some(shell) = matches.value_of("user-shell") {
+            cfg.user_shell = Some(aspects::UserShell::try_from(shell)?);
+        }
+
+        Ok(cfg)
+    }
+}
+
+/// How `Config::merge` resolves aspects set in both configs, `self` being the config merged into
+/// (usually from files) and `other` the one merged in (usually from the command line). See
+/// `Config::field_merge` for which fields hold a single aspect and which a list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// Single aspects `other` sets override the ones in `self`. Lists accumulate, except that an
+    /// entry of `other` replaces the entries of `self` for the same thing, so `run --mount` adds
+    /// to the configured mounts but wins over one at the same container path.
+    CliWins,
+    /// Every aspect `self` sets is kept, `other` only fills in the ones `self` leaves unset.
+    FileWins,
+    /// Single aspects `other` sets override the ones in `self` and lists accumulate from both
+    /// configs in full. This is how config file layers combine.
+    Append,
+}
+
+/// How a `Config` field set in both configs is combined by `Config::merge`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldMerge {
+    /// The field holds a single aspect, e.g. memory, which the winning config overrides.
+    Override,
+    /// The field holds a list of aspects, e.g. mounts, which accumulate from both configs.
+    Append,
+}
+
+/// The `Config` fields holding lists of aspects.
+const APPENDING_FIELDS: &[&str] = &[
+    "mounts",
+    "apt_packages",
+    "run_commands",
+    "extra_hosts",
+    "ulimits",
+    "sysctls",
+    "env_files",
+    "inherit_env",
+    "pip_packages",
+    "npm_packages",
+];
+
+impl Config {
+    /// How the field of the given name, as it is spelled in YAML config files, combines when
+    /// configs are merged; `None` for fields the config doesn't have.
+    pub fn field_merge(field: &str) -> Option<FieldMerge> {
+        if APPENDING_FIELDS.contains(&field) {
+            return Some(FieldMerge::Append);
+        }
+        match serde_yaml::to_value(Config::empty()) {
+            Ok(serde_yaml::Value::Mapping(fields))
+                if fields.contains_key(&serde_yaml::Value::from(field)) =>
+            {
+                Some(FieldMerge::Override)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Identifies the entries of list fields that configure the same thing, see
+/// `MergePolicy::CliWins`.
+trait ListEntry {
+    fn same_entry(&self, other: &Self) -> bool;
+}
+
+impl ListEntry for String {
+    fn same_entry(&self, other: &Self) -> bool {
+        self == other
+    }
+}
+
+/// Extra hosts and sysctls, keyed by host name and sysctl key.
+impl ListEntry for (String, String) {
+    fn same_entry(&self, other: &Self) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl ListEntry for aspects::Mount {
+    fn same_entry(&self, other: &Self) -> bool {
+        self.container_path == other.container_path
+    }
+}
+
+impl ListEntry for aspects::Run {
+    fn same_entry(&self, other: &Self) -> bool {
+        self == other
+    }
+}
+
+impl ListEntry for aspects::Ulimit {
+    fn same_entry(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl ListEntry for aspects::EnvFile {
+    fn same_entry(&self, other: &Self) -> bool {
+        self == other
+    }
+}
+
+fn pick<T: Clone>(left: &Option<T>, right: &Option<T>, policy: MergePolicy) -> Option<T> {
+    match policy {
+        MergePolicy::FileWins => left.clone().or_else(|| right.clone()),
+        MergePolicy::CliWins | MergePolicy::Append => right.clone().or_else(|| left.clone()),
+    }
+}
+
+fn pick_copy<T: Copy>(left: Option<T>, right: Option<T>, policy: MergePolicy) -> Option<T> {
+    match policy {
+        MergePolicy::FileWins => left.or(right),
+        MergePolicy::CliWins | MergePolicy::Append => right.or(left),
+    }
+}
+
+fn merge<T: Clone + ListEntry>(
+    left: &Option<Vec<T>>,
+    right: &Option<Vec<T>>,
+    policy: MergePolicy,
+) -> Option<Vec<T>> {
+    let mut new = Vec::new();
+
+    if let Some(v) = &left {
+        new = v.clone();
+    }
+
+    if let Some(v) = &right {
+        match policy {
+            MergePolicy::FileWins => {
+                if new.is_empty() {
+                    new = v.clone();
+                }
+            }
+            MergePolicy::CliWins => {
+                new.retain(|l| !v.iter().any(|r| r.same_entry(l)));
+                new.append(&mut v.clone());
+            }
+            MergePolicy::Append => new.append(&mut v.clone()),
+        }
+    }
+
+    match new.len() {
+        x if x > 0 => Some(new),
+        _ => None,
+    }
+}
+
+pub fn cli_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("strict")
+            .long("strict")
+            .help("treat questionable config values, like missing mount host paths, as errors"),
+        Arg::with_name("mount")
+            .short("m")
+            .long("mount")
+            .multiple(true)
+            .takes_value(true)
+            .help("specify a local path to be mapped into the container filesystem at runtime"),
+        Arg::with_name("timezone")
+            .short("t")
+            .long("timezone")
+            .takes_value(true)
+            .help("specify the timezone to be built into the container image"),
+        Arg::with_name("memory")
+            .long("memory")
+            .takes_value(true)
+            .help("specify the runtime memory resource limit"),
+        Arg::with_name("cpu-shares")
+            .long("cpu-shares")
+            .takes_value(true)
+            .help("specify the runtime proportion of cpu cycles for the container"),
+        Arg::with_name("network")
+            .long("network")
+            .takes_value(true)
+            .help("specify the runtime network mode for the container (default: bridge)"),
+        Arg::with_name("locale")
+            .long("locale")
+            .takes_value(true)
+            .help("specify the locale in the form <language>_<territory>.<codeset> for the container (default: en_US.UTF8)"),
+        Arg::with_name("apt-package")
+            .long("apt-package")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true)
+            .help("specify a debian package to be installed into the container image"),
+        Arg::with_name("run")
+            .long("run")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true)
+            .help("specify a shell command to be run while building the container image"),
+        Arg::with_name("workdir")
+            .long("workdir")
+            .takes_value(true)
+            .help("specify the absolute working directory inside the container"),
+        Arg::with_name("add-host")
+            .long("add-host")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true)
+            .help("specify a custom host-to-IP mapping in the form <name>:<ip|host-gateway>"),
+        Arg::with_name("ulimit")
+            .long("ulimit")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true)
+            .help("specify a runtime resource limit in the form <name>=<soft>[:<hard>]"),
+        Arg::with_name("init")
+            .long("init")
+            .help("run an init process in the container to reap zombie processes"),
+        Arg::with_name("sysctl")
+            .long("sysctl")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true)
+            .help("specify a namespaced kernel parameter in the form <key>=<value>"),
+        Arg::with_name("env-file")
+            .long("env-file")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true)
+            .help("specify a host file of environment variables for the container"),
+        Arg::with_name("inherit-env")
+            .long("inherit-env")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true)
+            .help("specify the name of a host environment variable to pass through to the container"),
+        Arg::with_name("cursor-theme")
+            .long("cursor-theme")
+            .takes_value(true)
+            .help("specify the mouse cursor theme in the form <name>[:<size>] (default size: 24)"),
+        Arg::with_name("keyboard-layout")
+            .long("keyboard-layout")
+            .takes_value(true)
+            .help("specify the XKB keyboard layout in the form <layout>[:<variant>[:<option>,...]]"),
+        Arg::with_name("cpuset-cpus")
+            .long("cpuset-cpus")
+            .takes_value(true)
+            .help("specify the cpus the container may run on, e.g. 0-3,5"),
+        Arg::with_name("cpuset-mems")
+            .long("cpuset-mems")
+            .takes_value(true)
+            .requires("cpuset-cpus")
+            .help("specify the NUMA memory nodes the container may use, e.g. 0"),
+        Arg::with_name("pids-limit")
+            .long("pids-limit")
+            .takes_value(true)
+            .allow_hyphen_values(true)
+            .help("specify the maximum number of processes in the container, -1 for unlimited"),
+        Arg::with_name("pip-package")
+            .long("pip-package")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true)
+            .help("specify a python package, optionally pinned as in <name>==<version>, to be pip installed into the container image"),
+        Arg::with_name("npm-package")
+            .long("npm-package")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true)
+            .help("specify a node package, optionally versioned as in <name>@<version>, to be installed globally into the container image"),
+        Arg::with_name("apparmor")
+            .long("apparmor")
+            .takes_value(true)
+            .help("specify the AppArmor profile to confine the container with, or `unconfined`"),
+        Arg::with_name("log-driver")
+            .long("log-driver")
+            .takes_value(true)
+            .help("specify the docker logging driver for the container's output, e.g. journald"),
+        Arg::with_name("log-opt")
+            .long("log-opt")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true)
+            .requires("log-driver")
+            .help("specify a logging driver option in the form <key>=<value>"),
+        Arg::with_name("cgroup-parent")
+            .long("cgroup-parent")
+            .takes_value(true)
+            .help("specify the parent cgroup or systemd slice to run the container under"),
+        Arg::with_name("stop-signal")
+            .long("stop-signal")
+            .takes_value(true)
+            .help("specify the signal that asks the app to shut down, e.g. SIGINT"),
+        Arg::with_name("stop-timeout")
+            .long("stop-timeout")
+            .takes_value(true)
+            .help("specify the seconds to let the app shut down before docker kills it (default 10)"),
+        Arg::with_name("mac-address")
+            .long("mac-address")
+            .takes_value(true)
+            .help("specify a fixed MAC address for the container"),
+        Arg::with_name("ip")
+            .long("ip")
+            .takes_value(true)
+            .help("specify a static IP address for the container, requires a user-defined --network"),
+        Arg::with_name("hosts-file")
+            .long("hosts-file")
+            .takes_value(true)
+            .help("specify a hosts file to mount read-only over /etc/hosts"),
+        Arg::with_name("resolv-conf")
+            .long("resolv-conf")
+            .takes_value(true)
+            .help("specify a resolv.conf to mount read-only over /etc/resolv.conf"),
+        Arg::with_name("default-command")
+            .long("command")
+            .takes_value(true)
+            .allow_hyphen_values(true)
+            .help("specify the command the container runs by default, quoted like a shell command line; `run -- <cmd>` overrides it"),
+        Arg::with_name("hidpi-scale")
+            .long("hidpi-scale")
+            .takes_value(true)
+            .help("specify the factor by which GTK and Qt apps scale their UI, e.g. 1.5"),
+        Arg::with_name("memory-swap")
+            .long("memory-swap")
+            .takes_value(true)
+            .help("specify the memory plus swap limit, at least --memory or -1 for unlimited swap"),
+        Arg::with_name("memory-reservation")
+            .long("memory-reservation")
+            .takes_value(true)
+            .help("specify the soft memory limit, at most --memory"),
+        Arg::with_name("user-home")
+            .long("user-home")
+            .takes_value(true)
+            .help("specify the home directory of the container user, e.g. /var/lib/me"),
+        Arg::with_name("user-shell")
+            .long("user-shell")
+            .takes_value(true)
+            .help("specify the login shell of the container user, e.g. /bin/zsh"),
+    ]
+}
+
+#[cfg(test)]
+mod toml_should {
+    use super::*;
+
+    pub(super) fn full_config() -> Config {
+        Config {
+            mounts: Some(vec![
+                aspects::Mount {
+                    host_path: "/home/me/Downloads".to_string(),
+                    container_path: "/home/me/Downloads".to_string(),
+                },
+                aspects::Mount {
+                    host_path: "/srv/data".to_string(),
+                    container_path: "/data".to_string(),
+                },
+            ]),
+            timezone: Some(aspects::Timezone("America/Chicago".to_string())),
+            memory: Some(aspects::Memory("2g".to_string())),
+            cpu_shares: Some(aspects::CPUShares("512".to_string())),
+            network: Some(aspects::Network {
+                mode: "bridge".to_string(),
+            }),
+            locale: Some(aspects::Locale {
+                language: "en".to_string(),
+                territory: "US".to_string(),
+                codeset: "UTF-8".to_string(),
+            }),
+            apt_packages: Some(vec!["curl".to_string(), "git".to_string()]),
+            run_commands: Some(vec![aspects::Run {
+                order: 89,
+                command: "pip install foo".to_string(),
+            }]),
+            workdir: Some(aspects::Workdir("/data".to_string())),
+            extra_hosts: Some(vec![(
+                "host.docker.internal".to_string(),
+                "host-gateway".to_string(),
+            )]),
+            ulimits: Some(vec![aspects::Ulimit {
+                name: "nofile".to_string(),
+                soft: 1024,
+                hard: 4096,
+            }]),
+            init: Some(true),
+            sysctls: Some(vec![("net.ipv4.ip_forward".to_string(), "1".to_string())]),
+            env_files: Some(vec![aspects::EnvFile(PathBuf::from("/home/me/app.env"))]),
+            inherit_env: Some(vec!["EDITOR".to_string(), "LANG".to_string()]),
+            cursor_theme: Some(aspects::CursorTheme {
+                name: "Adwaita".to_string(),
+                size: 32,
+            }),
+            keyboard_layout: Some(aspects::KeyboardLayout {
+                layout: "de".to_string(),
+                variant: Some("nodeadkeys".to_string()),
+                options: vec!["ctrl:nocaps".to_string()],
+            }),
+            cpu_set: Some(aspects::CpuSet {
+                cpus: "0-3,5".to_string(),
+                mems: Some("0".to_string()),
+            }),
+            pids_limit: Some(aspects::PidsLimit(512)),
+            pip_packages: Some(vec!["requests==2.24.0".to_string()]),
+            npm_packages: Some(vec!["typescript@4.0.3".to_string()]),
+            apparmor: Some(aspects::AppArmor("dfiles-firefox".to_string())),
+            log_driver: Some(aspects::LogDriver {
+                driver: "journald".to_string(),
+                options: vec![("tag".to_string(), "dfiles".to_string())],
+            }),
+            cgroup_parent: Some(aspects::CgroupParent("dfiles.slice".to_string())),
+            stop_signal: Some(aspects::StopSignal("SIGINT".to_string())),
+            stop_timeout: Some(aspects::StopTimeout(30)),
+            mac_address: Some(aspects::MacAddress("02:42:ac:11:00:02".to_string())),
+            ip: Some(aspects::Ip("172.20.0.5".parse().unwrap())),
+            hosts_file: Some(aspects::HostsFile(PathBuf::from("/etc/dfiles/hosts"))),
+            resolv_conf: Some(aspects::ResolvConf(PathBuf::from(
+                "/etc/dfiles/resolv.conf",
+            ))),
+            command: Some(vec!["firefox".to_string(), "--private-window".to_string()]),
+            hidpi: Some(aspects::HiDpi { scale: 1.5 }),
+            memory_swap: Some(aspects::MemorySwap("4g".to_string())),
+            memory_reservation: Some(aspects::MemoryReservation("1g".to_string())),
+            user_home: Some(aspects::UserHome("/var/lib/me".to_string())),
+            user_shell: Some(aspects::UserShell("/bin/zsh".to_string())),
+        }
+    }
+
+    #[test]
+    fn round_trip_all_aspects() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.toml");
+
+        full_config().save_file(&path)?;
+        assert_eq!(Config::load_file(&path)?, full_config());
+        Ok(())
+    }
+
+    #[test]
+    fn load_hand_written_toml() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            r#"
+[[aspects]]
+type = "mount"
+host_path = "/srv/data"
+container_path = "/data"
+
+[[aspects]]
+type = "memory"
+value = "1g"
+"#,
+        )?;
+
+        let cfg = Config::load_file(&path)?;
+        assert_eq!(
+            cfg.mounts,
+            Some(vec![aspects::Mount {
+                host_path: "/srv/data".to_string(),
+                container_path: "/data".to_string(),
+            }])
+        );
+        assert_eq!(cfg.memory, Some(aspects::Memory("1g".to_string())));
+        assert_eq!(cfg.timezone, None);
+        Ok(())
+    }
+
+    #[test]
+    fn keep_using_yaml_for_other_extensions() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.yaml");
+
+        full_config().save_file(&path)?;
+        assert!(serde_yaml::from_str::<Config>(&fs::read_to_string(&path)?).is_ok());
+        assert_eq!(Config::load_file(&path)?, full_config());
+        Ok(())
+    }
+
+    #[test]
+    fn prefer_toml_layer_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        assert_eq!(layer_path(dir.path()), dir.path().join("config.yaml"));
+
+        fs::write(dir.path().join("config.toml"), "")?;
+        assert_eq!(layer_path(dir.path()), dir.path().join("config.toml"));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod aspect_config_should {
+    use super::*;
+
+    fn config_fields() -> serde_yaml::Mapping {
+        match serde_yaml::to_value(toml_should::full_config()).unwrap() {
+            serde_yaml::Value::Mapping(m) => m,
+            other => panic!("expected a mapping, got {:?}", other),
+        }
+    }
+
+    /// The full config with only the fields picked by `pick` set.
+    fn config_with<F: FnMut(usize) -> bool>(mut pick: F) -> Config {
+        let fields = config_fields()
+            .into_iter()
+            .enumerate()
+            .map(|(i, (k, v))| (k, if pick(i) { v } else { serde_yaml::Value::Null }))
+            .collect();
+        serde_yaml::from_value(serde_yaml::Value::Mapping(fields)).unwrap()
+    }
+
+    fn described_aspects(cfg: &Config) -> Vec<String> {
+        cfg.get_aspects()
+            .iter()
+            .map(|a| format!("{}", a.as_ref()))
+            .collect()
+    }
+
+    #[test]
+    fn give_every_field_an_aspect() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.toml");
+        for (field, (name, _)) in config_fields().iter().enumerate() {
+            let cfg = config_with(|i| i == field);
+            // the command replaces the manager's default args, see `ContainerManager::load_config`
+            assert!(
+                name.as_str() == Some("command") || !cfg.get_aspects().is_empty(),
+                "field {} has no aspect",
+                field
+            );
+
+            cfg.save_file(&path)?;
+            assert_eq!(Config::load_file(&path)?, cfg, "field {} is lost", field);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reproduce_mixed_configs_after_save_and_load() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        // a fixed linear congruential generator keeps the picked field combinations reproducible
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..64 {
+            let cfg = config_with(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1_442_695_040_888_963_407);
+                state >> 63 == 1
+            });
+            for file in &["config.toml", "config.yaml"] {
+                let path = dir.path().join(file);
+                cfg.save_file(&path)?;
+
+                let loaded = Config::load_file(&path)?;
+                assert_eq!(loaded, cfg);
+                assert_eq!(described_aspects(&loaded), described_aspects(&cfg));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod edit_should {
+    use super::*;
+
+    #[test]
+    fn resolve_profile_path() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = Config::path_in(dir.path().to_path_buf(), Some("firefox"), Some("work"));
+        assert_eq!(
+            path,
+            dir.path()
+                .join("applications/firefox/profiles/work/config.yaml")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn create_missing_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("profiles").join("work").join("config.yaml");
+
+        Config::edit_file(&path, "true")?;
+        assert_eq!(Config::load_file(&path)?, Config::empty());
+        Ok(())
+    }
+
+    #[test]
+    fn reject_invalid_edits() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.yaml");
+        let garbage = dir.path().join("garbage.yaml");
+        fs::write(&garbage, "mounts: [")?;
+
+        let editor = format!("cp {}", garbage.to_string_lossy());
+        match Config::edit_file(&path, &editor) {
+            Err(Error::FailedToLoadConfig) => Ok(()),
+            other => panic!("expected FailedToLoadConfig, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn fail_when_editor_fails() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.yaml");
+
+        match Config::edit_file(&path, "false") {
+            Err(Error::EditorFailed(editor)) => assert_eq!(editor, "false"),
+            other => panic!("expected EditorFailed, got {:?}", other),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod copy_profile_should {
+    use super::*;
+
+    fn profile_file(config_dir: &Path, profile: &str, file: &str) -> PathBuf {
+        dirs::scoped(config_dir.to_path_buf(), Some("firefox"), Some(profile)).join(file)
+    }
+
+    fn save_profile(config_dir: &Path, profile: &str, memory: &str) -> Result<()> {
+        let path = profile_file(config_dir, profile, "config.yaml");
+        fs::create_dir_all(path.parent().unwrap())?;
+        let mut cfg = Config::empty();
+        cfg.memory = Some(aspects::Memory(memory.to_string()));
+        cfg.save_file(&path)
+    }
+
+    fn memory_of(config_dir: &Path, profile: &str) -> Result<Option<aspects::Memory>> {
+        let dir = dirs::scoped(config_dir.to_path_buf(), Some("firefox"), Some(profile));
+        Ok(Config::load_file(&layer_path(&dir))?.memory)
+    }
+
+    #[test]
+    fn copy_profile_config() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        save_profile(dir.path(), "work", "2g")?;
+
+        Config::copy_profile_in(dir.path(), "firefox", Some("work"), "work-laptop", false)?;
+        assert_eq!(
+            memory_of(dir.path(), "work-laptop")?,
+            Some(aspects::Memory("2g".to_string()))
+        );
+        assert_eq!(
+            memory_of(dir.path(), "work")?,
+            Some(aspects::Memory("2g".to_string()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn copy_application_config_without_from() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        match Config::copy_profile_in(dir.path(), "firefox", None, "work", false) {
+            Err(Error::MissingAppConfig(app)) => assert_eq!(app, "firefox"),
+            other => panic!("expected MissingAppConfig, got {:?}", other),
+        }
+
+        let mut cfg = Config::empty();
+        cfg.memory = Some(aspects::Memory("3g".to_string()));
+        let path = Config::path_in(dir.path().to_path_buf(), Some("firefox"), None);
+        fs::create_dir_all(path.parent().unwrap())?;
+        cfg.save_file(&path)?;
+
+        Config::copy_profile_in(dir.path(), "firefox", None, "work", false)?;
+        assert_eq!(
+            memory_of(dir.path(), "work")?,
+            Some(aspects::Memory("3g".to_string()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn guard_existing_destination() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        save_profile(dir.path(), "work", "2g")?;
+        save_profile(dir.path(), "work-laptop", "1g")?;
+
+        match Config::copy_profile_in(dir.path(), "firefox", Some("work"), "work-laptop", false) {
+            Err(Error::ProfileExists(p)) => assert_eq!(p, "work-laptop"),
+            other => panic!("expected ProfileExists, got {:?}", other),
+        }
+        assert_eq!(
+            memory_of(dir.path(), "work-laptop")?,
+            Some(aspects::Memory("1g".to_string()))
+        );
+
+        Config::copy_profile_in(dir.path(), "firefox", Some("work"), "work-laptop", true)?;
+        assert_eq!(
+            memory_of(dir.path(), "work-laptop")?,
+            Some(aspects::Memory("2g".to_string()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn replace_destination_in_other_format() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        save_profile(dir.path(), "work", "2g")?;
+        let toml = profile_file(dir.path(), "work-laptop", "config.toml");
+        fs::create_dir_all(toml.parent().unwrap())?;
+        fs::write(&toml, "")?;
+
+        Config::copy_profile_in(dir.path(), "firefox", Some("work"), "work-laptop", true)?;
+        assert!(!toml.exists());
+        assert_eq!(
+            memory_of(dir.path(), "work-laptop")?,
+            Some(aspects::Memory("2g".to_string()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_missing_source() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        match Config::copy_profile_in(dir.path(), "firefox", Some("work"), "work-laptop", false) {
+            Err(Error::MissingProfile(p)) => assert_eq!(p, "work"),
+            other => panic!("expected MissingProfile, got {:?}", other),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod delete_should {
+    use super::*;
+
+    #[test]
+    fn remove_profile_config() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let profile_dir = dirs::scoped(dir.path().to_path_buf(), Some("firefox"), Some("work"));
+        fs::create_dir_all(&profile_dir)?;
+        Config::empty().save_file(&profile_dir.join("config.yaml"))?;
+        fs::write(profile_dir.join("config.toml"), "")?;
+
+        Config::delete_in(dir.path(), "firefox", "work")?;
+        assert!(!profile_dir.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn report_missing_profile() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        match Config::delete_in(dir.path(), "firefox", "work") {
+            Err(Error::MissingProfile(p)) => assert_eq!(p, "work"),
+            other => panic!("expected MissingProfile, got {:?}", other),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod profiles_should {
+    use super::*;
+
+    fn save_profile(config_dir: &Path, profile: &str, file: &str) -> Result<()> {
+        let dir = dirs::scoped(config_dir.to_path_buf(), Some("firefox"), Some(profile));
+        fs::create_dir_all(&dir)?;
+        Config::empty().save_file(&dir.join(file))
+    }
+
+    #[test]
+    fn list_profiles_with_config_by_name() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        save_profile(dir.path(), "work", "config.yaml")?;
+        save_profile(dir.path(), "home", "config.toml")?;
+        // a profile directory without a config file, eg left behind by a host overlay
+        fs::create_dir_all(dirs::scoped(
+            dir.path().to_path_buf(),
+            Some("firefox"),
+            Some("empty"),
+        ))?;
+
+        let profiles = Config::profiles_in(dir.path(), "firefox")?;
+        let names: Vec<&str> = profiles.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["home", "work"]);
+        assert!(profiles[0].path.ends_with("profiles/home/config.toml"));
+        assert!(profiles[1].path.ends_with("profiles/work/config.yaml"));
+        Ok(())
+    }
+
+    #[test]
+    fn list_nothing_for_unconfigured_application() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        assert_eq!(Config::profiles_in(dir.path(), "firefox")?, Vec::new());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod merge_layers_should {
+    use super::*;
+
+    fn layer(memory: Option<&str>, cpu_shares: Option<&str>, mount: &str) -> Config {
+        let mut cfg = Config::empty();
+        cfg.memory = memory.map(|m| aspects::Memory(m.to_string()));
+        cfg.cpu_shares = cpu_shares.map(|c| aspects::CPUShares(c.to_string()));
+        cfg.mounts = Some(vec![aspects::Mount {
+            host_path: mount.to_string(),
+            container_path: mount.to_string(),
+        }]);
+        cfg
+    }
+
+    fn mount_paths(cfg: &Config) -> Vec<String> {
+        cfg.mounts
+            .iter()
+            .flatten()
+            .map(|m| m.host_path.clone())
+            .collect()
+    }
+
+    /// base sets memory and cpu shares, the profile bumps memory, the host overlay bumps it again
+    /// and the CLI has the final word on cpu shares; mounts from every layer accumulate.
+    #[test]
+    fn apply_four_level_precedence() {
+        let base = layer(Some("1g"), Some("256"), "/base");
+        let profile = layer(Some("2g"), None, "/profile");
+        let host = layer(Some("3g"), None, "/host");
+        let cli = layer(None, Some("1024"), "/cli");
+
+        let files = merge_layers(&[base, profile, host]);
+        assert_eq!(mount_paths(&files), vec!["/base", "/profile", "/host"]);
+
+        let cfg = files.merge(&cli, MergePolicy::CliWins);
+        assert_eq!(cfg.memory, Some(aspects::Memory("3g".to_string())));
+        assert_eq!(cfg.cpu_shares, Some(aspects::CPUShares("1024".to_string())));
+        assert_eq!(
+            mount_paths(&cfg),
+            vec!["/base", "/profile", "/host", "/cli"]
+        );
+    }
+
+    #[test]
+    fn resolve_overlapping_aspects_per_policy() {
+        let file = layer(Some("1g"), Some("256"), "/file");
+        let cli = layer(Some("2g"), None, "/cli");
+
+        let cfg = file.merge(&cli, MergePolicy::CliWins);
+        assert_eq!(cfg.memory, Some(aspects::Memory("2g".to_string())));
+        assert_eq!(cfg.cpu_shares, Some(aspects::CPUShares("256".to_string())));
+        assert_eq!(mount_paths(&cfg), vec!["/file", "/cli"]);
+
+        let cfg = file.merge(&cli, MergePolicy::FileWins);
+        assert_eq!(cfg.memory, Some(aspects::Memory("1g".to_string())));
+        assert_eq!(cfg.cpu_shares, Some(aspects::CPUShares("256".to_string())));
+        assert_eq!(mount_paths(&cfg), vec!["/file"]);
+
+        let cfg = file.merge(&cli, MergePolicy::Append);
+        assert_eq!(cfg.memory, Some(aspects::Memory("2g".to_string())));
+        assert_eq!(cfg.cpu_shares, Some(aspects::CPUShares("256".to_string())));
+        assert_eq!(mount_paths(&cfg), vec!["/file", "/cli"]);
+    }
+
+    #[test]
+    fn check_memory_limits_once_merged() {
+        let file = layer(Some("1g"), None, "/file");
+        let mut cli = Config::empty();
+        cli.memory_swap = Some(aspects::MemorySwap("512m".to_string()));
+
+        let cfg = file.merge(&cli, MergePolicy::CliWins);
+        let failures: Vec<Result<Vec<String>>> = cfg
+            .get_aspects()
+            .iter()
+            .map(|a| a.run_args(None))
+            .filter(|args| args.is_err())
+            .collect();
+        match failures.as_slice() {
+            [Err(Error::InvalidMemory(_))] => (),
+            other => panic!("expected InvalidMemory, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn fill_unset_aspects_from_other_when_file_wins() {
+        let file = Config::empty();
+        let cli = layer(Some("2g"), None, "/cli");
+
+        let cfg = file.merge(&cli, MergePolicy::FileWins);
+        assert_eq!(cfg.memory, Some(aspects::Memory("2g".to_string())));
+        assert_eq!(mount_paths(&cfg), vec!["/cli"]);
+    }
+
+    #[test]
+    fn replace_entries_for_the_same_thing_only_when_cli_wins() {
+        let mount = |host: &str, container: &str| aspects::Mount {
+            host_path: host.to_string(),
+            container_path: container.to_string(),
+        };
+        let mut file = Config::empty();
+        file.mounts = Some(vec![mount("/a", "/data"), mount("/b", "/cache")]);
+        let mut cli = Config::empty();
+        cli.mounts = Some(vec![mount("/c", "/data")]);
+
+        assert_eq!(
+            file.merge(&cli, MergePolicy::CliWins).mounts,
+            Some(vec![mount("/b", "/cache"), mount("/c", "/data")])
+        );
+        assert_eq!(
+            file.merge(&cli, MergePolicy::Append).mounts,
+            Some(vec![
+                mount("/a", "/data"),
+                mount("/b", "/cache"),
+                mount("/c", "/data")
+            ])
+        );
+    }
+
+    #[test]
+    fn report_how_each_field_merges() {
+        assert_eq!(Config::field_merge("memory"), Some(FieldMerge::Override));
+        assert_eq!(Config::field_merge("mounts"), Some(FieldMerge::Append));
+        assert_eq!(Config::field_merge("no_such_field"), None);
+        for field in APPENDING_FIELDS {
+            assert_eq!(Config::field_merge(field), Some(FieldMerge::Append));
+        }
+    }
+
+    /// Merging a config with itself doubles exactly the appending fields under `Append`.
+    #[test]
+    fn append_only_list_fields() {
+        let full = toml_should::full_config();
+        let doubled = serde_yaml::to_value(full.merge(&full, MergePolicy::Append)).unwrap();
+        let doubled = doubled.as_mapping().expect("mapping");
+        let single = serde_yaml::to_value(&full).unwrap();
+        for (key, value) in single.as_mapping().expect("mapping") {
+            let field = key.as_str().expect("field name");
+            let merged = doubled.get(key).expect("merged field");
+            match (value.as_sequence(), Config::field_merge(field)) {
+                (Some(list), Some(FieldMerge::Append)) => {
+                    assert_eq!(merged.as_sequence().map(|l| l.len()), Some(list.len() * 2))
+                }
+                (_, field_merge) => {
+                    assert_eq!(
+                        field_merge,
+                        Some(FieldMerge::Override),
+                        "{} should append",
+                        field
+                    );
+                    assert_eq!(merged, value, "{} should override", field)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ignore_missing_layers() {
+        let base = layer(Some("1g"), None, "/base");
+
+        let cfg = merge_layers(&[base.clone(), Config::empty(), Config::empty()]);
+        assert_eq!(cfg, base);
+    }
+}
+
+#[cfg(test)]
+mod init_should {
+    use super::*;
+
+    #[test]
+    fn emit_flag_once_across_layers() -> Result<()> {
+        let mut file_cfg = Config::empty();
+        file_cfg.init = Some(true);
+        let mut cli_cfg = Config::empty();
+        cli_cfg.init = Some(true);
+
+        let mut args: Vec<String> = Vec::new();
+        for aspect in file_cfg.merge(&cli_cfg, MergePolicy::CliWins).get_aspects() {
+            args.extend(aspect.run_args(None)?);
+        }
+        assert_eq!(args.iter().filter(|a| *a == "--init").count(), 1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod cursor_theme_should {
+    use clap::App;
+
+    use super::*;
+
+    fn parse(argv: &[&str]) -> Result<Config> {
+        let matches = App::new("test")
+            .args(&cli_args())
+            .get_matches_from_safe(argv)
+            .expect("valid argv");
+        Config::try_from(&matches)
+    }
+
+    #[test]
+    fn parse_cursor_theme_arg() -> Result<()> {
+        assert_eq!(
+            parse(&["test", "--cursor-theme", "Adwaita:48"])?.cursor_theme,
+            Some(aspects::CursorTheme::new("Adwaita", 48)?)
+        );
+        assert_eq!(
+            parse(&["test", "--cursor-theme", "Adwaita"])?.cursor_theme,
+            Some(aspects::CursorTheme::new("Adwaita", 24)?)
+        );
+        assert_eq!(parse(&["test"])?.cursor_theme, None);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_zero_size() {
+        match parse(&["test", "--cursor-theme", "Adwaita:0"]) {
+            Err(Error::InvalidCursorTheme(s)) => assert_eq!(s, "Adwaita:0"),
+            other => panic!("expected InvalidCursorTheme, got {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod merge_should {
+    use super::*;
+
+    impl ListEntry for i32 {
+        /// Entries with the same tens digit configure the same thing.
+        fn same_entry(&self, other: &Self) -> bool {
+            self / 10 == other / 10
+        }
+    }
+
+    #[test]
+    fn return_some() {
+        let left: Vec<i32> = vec![1, 2, 3, 4];
+        let right: Vec<i32> = vec![5, 6, 7];
+        let empty: Option<Vec<i32>> = Some(Vec::new());
+
+        for policy in &[MergePolicy::Append, MergePolicy::CliWins] {
+            assert_eq!(
+                merge(&Some(vec![1, 12]), &Some(vec![23, 34]), *policy),
+                Some(vec![1, 12, 23, 34])
+            );
+            assert_eq!(
+                merge(&Some(left.clone()), &None, *policy),
+                Some(vec![1, 2, 3, 4])
+            );
+            assert_eq!(
+                merge(&None, &Some(right.clone()), *policy),
+                Some(vec![5, 6, 7])
+            );
+            assert_eq!(
+                merge(&Some(left.clone()), &empty.clone(), *policy),
+                Some(vec![1, 2, 3, 4])
+            );
+            assert_eq!(
+                merge(&empty.clone(), &Some(right.clone()), *policy),
+                Some(vec![5, 6, 7])
+            );
+        }
+    }
+
+    #[test]
+    fn append_all_entries() {
+        assert_eq!(
+            merge(
+                &Some(vec![1, 12, 23]),
+                &Some(vec![15, 4]),
+                MergePolicy::Append
+            ),
+            Some(vec![1, 12, 23, 15, 4])
+        );
+    }
+
+    #[test]
+    fn replace_entries_for_the_same_thing_when_cli_wins() {
+        assert_eq!(
+            merge(
+                &Some(vec![1, 12, 23]),
+                &Some(vec![15, 4]),
+                MergePolicy::CliWins
+            ),
+            Some(vec![23, 15, 4])
+        );
+        assert_eq!(
+            merge(&Some(vec![1, 2]), &Some(vec![3]), MergePolicy::CliWins),
+            Some(vec![3])
+        );
+    }
+
+    #[test]
+    fn keep_left_when_file_wins() {
+        let left: Vec<i32> = vec![1, 2, 3, 4];
+        let right: Vec<i32> = vec![5, 6, 7];
+        let empty: Option<Vec<i32>> = Some(Vec::new());
+
+        assert_eq!(
+            merge(
+                &Some(left.clone()),
+                &Some(right.clone()),
+                MergePolicy::FileWins
+            ),
+            Some(vec![1, 2, 3, 4])
+        );
+        assert_eq!(
+            merge(&None, &Some(right.clone()), MergePolicy::FileWins),
+            Some(vec![5, 6, 7])
+        );
+        assert_eq!(
+            merge(&empty, &Some(right.clone()), MergePolicy::FileWins),
+            Some(vec![5, 6, 7])
+        );
+        assert_eq!(merge(&empty, &empty, MergePolicy::FileWins), None);
+    }
+
+    #[test]
+    fn return_none() {
+        let none: Option<Vec<i32>> = None;
+        let empty: Option<Vec<i32>> = Some(Vec::new());
+
+        for policy in &[MergePolicy::Append, MergePolicy::CliWins] {
+            assert_eq!(merge(&none.clone(), &none.clone(), *policy), None);
+            assert_eq!(merge(&none.clone(), &empty.clone(), *policy), None);
+            assert_eq!(merge(&empty.clone(), &empty.clone(), *policy), None);
+            assert_eq!(merge(&empty.clone(), &none.clone(), *policy), None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod config_dir_should {
+    use std::collections::HashMap;
+    use std::ffi::OsString;
+
+    use super::*;
+
+    fn config_dir_with(vars: &[(&str, &str)]) -> Result<PathBuf> {
+        let vars: HashMap<String, OsString> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), OsString::from(v)))
+            .collect();
+        config_dir_from(|name| vars.get(name).cloned(), None)
+    }
+
+    #[test]
+    fn prefer_dfiles_config_dir() -> Result<()> {
+        assert_eq!(
+            config_dir_with(&[
+                ("DFILES_CONFIG_DIR", "/tmp/dfiles-test"),
+                ("XDG_CONFIG_HOME", "/home/me/xdg"),
+                ("HOME", "/home/me"),
+            ])?,
+            PathBuf::from("/tmp/dfiles-test")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fall_back_to_xdg_config_home() -> Result<()> {
+        assert_eq!(
+            config_dir_with(&[
+                ("DFILES_CONFIG_DIR", ""),
+                ("XDG_CONFIG_HOME", "/home/me/xdg"),
+                ("HOME", "/home/me"),
+            ])?,
+            PathBuf::from("/home/me/xdg/dfiles")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fall_back_to_home() -> Result<()> {
+        assert_eq!(
+            config_dir_with(&[("HOME", "/home/me")])?,
+            PathBuf::from("/home/me/.config/dfiles")
+        );
+        assert_eq!(
+            config_dir_with(&[("XDG_CONFIG_HOME", "relative"), ("HOME", "/home/me")])?,
+            PathBuf::from("/home/me/.config/dfiles")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn keep_reading_legacy_dir_until_moved() -> Result<()> {
+        let home = tempfile::tempdir()?;
+        let legacy = home.path().join("Library/Application Support/dfiles");
+        fs::create_dir_all(&legacy)?;
+        let vars = |name: &str| match name {
+            "HOME" => Some(home.path().as_os_str().to_os_string()),
+            _ => None,
+        };
+
+        assert_eq!(config_dir_from(vars, Some(legacy.clone()))?, legacy);
+
+        let dir = home.path().join(".config/dfiles");
+        fs::create_dir_all(&dir)?;
+        assert_eq!(config_dir_from(vars, Some(legacy))?, dir);
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_missing_legacy_dir() -> Result<()> {
+        let home = tempfile::tempdir()?;
+        let vars = |name: &str| match name {
+            "HOME" => Some(home.path().as_os_str().to_os_string()),
+            _ => None,
+        };
+        assert_eq!(
+            config_dir_from(
+                vars,
+                Some(home.path().join("Library/Application Support/dfiles"))
+            )?,
+            home.path().join(".config/dfiles")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fail_without_any_base() {
+        match config_dir_with(&[]) {
+            Err(Error::MissingDirectory) => (),
+            other => panic!("expected MissingDirectory, got {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod split_command_should {
+    use super::*;
+
+    #[test]
+    fn split_on_whitespace() -> Result<()> {
+        assert_eq!(split_command("  /bin/zsh\t-i ")?, ["/bin/zsh", "-i"]);
+        Ok(())
+    }
+
+    #[test]
+    fn keep_quoted_args_together() -> Result<()> {
+        assert_eq!(
+            split_command(r#"/bin/sh -c 'echo "hi there"' "a b" c\ d"#)?,
+            ["/bin/sh", "-c", r#"echo "hi there""#, "a b", "c d"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unescape_inside_double_quotes() -> Result<()> {
+        assert_eq!(
+            split_command(r#"echo "a \"b\" \$HOME \n" ''"#)?,
+            ["echo", r#"a "b" $HOME \n"#, ""]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fail_on_unterminated_quote() -> Result<()> {
+        match split_command("/bin/sh -c 'echo hi") {
+            Err(Error::InvalidRunCommand(_)) => Ok(()),
+            other => panic!("expected InvalidRunCommand, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn fail_on_trailing_backslash() -> Result<()> {
+        match split_command("/bin/sh \\") {
+            Err(Error::InvalidRunCommand(_)) => Ok(()),
+            other => panic!("expected InvalidRunCommand, got {:?}", other),
+        }
     }
 }
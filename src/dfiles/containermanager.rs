@@ -1,32 +1,50 @@
 use std::collections::BTreeMap;
 use std::convert::TryFrom;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
 
-use clap::{App, ArgMatches, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, ArgSettings, Shell, SubCommand};
+use dockworker::credentials::{Credential, UserPassword};
 use dockworker::{ContainerBuildOptions, Docker};
-use dyn_clone;
-use serde::Deserialize;
+use env_logger;
+use log::{debug, error, info, warn, LevelFilter};
+use serde::{Deserialize, Serialize};
 use serde_json::from_str;
+use sha2::{Digest, Sha256};
 use tar::{Builder, Header};
 use tempfile::NamedTempFile;
+use users;
 
-use super::aspects;
+use super::aspects::{self, ContainerAspect};
 use super::config;
+use super::dirs;
 use super::docker;
 use super::error::{Error, Result};
 
 #[derive(Deserialize, Debug)]
 struct BuildOutput {
-    stream: String,
+    stream: Option<String>,
+    error: Option<String>,
 }
 
+#[derive(Clone)]
 pub struct ContainerManager {
     name: String,
     tags: Vec<String>,
     container_paths: Vec<String>,
     aspects: Vec<Box<dyn aspects::ContainerAspect>>,
     args: Vec<String>,
+    output_prefix: Option<String>,
 }
 
 impl ContainerManager {
@@ -37,61 +55,674 @@ impl ContainerManager {
         mut aspects: Vec<Box<dyn aspects::ContainerAspect>>,
         args: Vec<String>,
     ) -> ContainerManager {
-        aspects.insert(0, Box::new(Debian {}));
+        aspects.insert(0, Box::new(Debian::new()));
+        ContainerManager::new(name, tags, container_paths, aspects, args)
+    }
+
+    /// Creates a manager without any base aspect; the caller is responsible for providing one
+    /// that emits a `FROM` line at order 0, eg `Debian::with_digest` or `aspects::BaseImage`.
+    pub fn new(
+        name: String,
+        tags: Vec<String>,
+        container_paths: Vec<String>,
+        mut aspects: Vec<Box<dyn aspects::ContainerAspect>>,
+        args: Vec<String>,
+    ) -> ContainerManager {
+        aspects.insert(
+            0,
+            Box::new(aspects::Profile {
+                name: name.clone(),
+                container_paths: container_paths.clone(),
+            }),
+        );
         ContainerManager {
             name: name,
             tags: tags,
             container_paths: container_paths,
             aspects: aspects,
             args: args,
+            output_prefix: None,
         }
     }
 
-    fn image(&self) -> String {
-        self.tags[0].clone()
+    /// Also tags the image as `latest` in the repository of each existing tag, so `build` tags
+    /// both e.g. `waynr/discord:0.0.10` and `waynr/discord:latest`.
+    pub fn with_latest_tag(mut self) -> Self {
+        self.tags = with_latest_tags(&self.tags);
+        self
     }
 
-    fn run(&self, matches: &ArgMatches) -> Result<()> {
-        let mut args: Vec<String> = vec!["--rm"].into_iter().map(String::from).collect();
+    fn image(&self) -> Result<String> {
+        let tag = self.tags.first().ok_or(Error::MissingImageTag)?;
+        Ok(tag.parse::<docker::ImageRef>()?.to_string())
+    }
 
+    /// Aspects are identified by their name up to any `:`, so `User` matches `User: wayne`.
+    fn has_aspect(&self, kind: &str) -> bool {
+        if kind == aspects::ENTRYPOINT {
+            return !self.entrypoint_fns().is_empty();
+        }
+        self.aspects
+            .iter()
+            .any(|a| a.name().split(':').next().map(str::trim) == Some(kind))
+    }
+
+    /// Fails on missing dependencies or hard conflicts between aspects and logs soft conflicts.
+    fn check_aspects(&self) -> Result<()> {
+        self.check_dependencies()?;
+        for warning in self.check_conflicts()? {
+            warn!("{}", warning);
+        }
+        if let Some(warning) = self.check_network()? {
+            warn!("{}", warning);
+        }
+        Ok(())
+    }
+
+    /// A static IP is only assigned on a user-defined network, which `Network` has to name.
+    fn check_network(&self) -> Result<Option<String>> {
+        if !self.has_aspect("Ip") {
+            return Ok(None);
+        }
+        for aspect in self.aspects.iter().filter(|a| a.name() == "Network") {
+            if let [flag, mode] = aspect.run_args(None)?.as_slice() {
+                let network = aspects::Network { mode: mode.clone() };
+                if flag == "--net" && network.is_user_defined() {
+                    return Ok(None);
+                }
+            }
+        }
+        Ok(Some(String::from(
+            "aspect `Ip` needs the `Network` aspect to name a user-defined network, docker \
+             rejects static IPs on its built-in networks",
+        )))
+    }
+
+    /// Returns a message for each conflict that is only worth a warning.
+    fn check_conflicts(&self) -> Result<Vec<String>> {
+        let mut warnings = Vec::new();
         for aspect in &self.aspects {
-            println!("{:}", aspect);
-            args.extend(aspect.run_args(Some(&matches))?);
+            for conflict in aspect.conflicts() {
+                if !self.has_aspect(&conflict.aspect) {
+                    continue;
+                }
+                match conflict.severity {
+                    aspects::Severity::Warn => warnings.push(format!(
+                        "aspect `{}` conflicts with the `{}` aspect: {}",
+                        aspect.name(),
+                        conflict.aspect,
+                        conflict.reason
+                    )),
+                    aspects::Severity::Error => {
+                        return Err(Error::ConflictingAspects {
+                            aspect: aspect.name(),
+                            other: conflict.aspect,
+                            reason: conflict.reason,
+                        })
+                    }
+                }
+            }
         }
+        Ok(warnings)
+    }
 
-        args.push(self.image().to_string());
-        args.extend_from_slice(&self.args);
-        docker::run(args);
+    fn check_dependencies(&self) -> Result<()> {
+        for aspect in &self.aspects {
+            for dependency in aspect.dependencies() {
+                if !self.has_aspect(&dependency) {
+                    return Err(Error::MissingDependency {
+                        aspect: aspect.name(),
+                        dependency,
+                    });
+                }
+            }
+        }
         Ok(())
     }
 
-    fn build(&self) -> Result<()> {
+    /// Lets every aspect provide its facts, see `aspects::Context`.
+    pub fn context(&self) -> aspects::Context {
+        let mut context = aspects::Context {
+            app: self.name.clone(),
+            ..aspects::Context::default()
+        };
+        for aspect in &self.aspects {
+            aspect.provide(&mut context);
+        }
+        context
+    }
+
+    fn run_args(&self, matches: &ArgMatches) -> Result<Vec<String>> {
+        self.check_aspects()?;
+        let mut args: Vec<String> = vec!["--rm"].into_iter().map(String::from).collect();
+        args.extend(session_args(matches));
+
+        let context = self.context();
+        for aspect in &self.aspects {
+            debug!("{:}", aspect);
+            args.extend(aspect.run_args_with(Some(&matches), &context)?);
+        }
+
+        // extra docker args must land before the image since everything after it is passed to
+        // the container's command
+        if let Some(vs) = matches.values_of("docker-arg") {
+            let extra = aspects::ExtraArgs(vs.map(String::from).collect());
+            args.extend(extra.run_args(Some(&matches))?);
+        }
+
+        // an explicit entrypoint replaces the injected one and with it every entrypoint fn
+        let inject_entrypoint = match matches.value_of("entrypoint") {
+            Some(entrypoint) => {
+                args.push(String::from("--entrypoint"));
+                args.push(entrypoint.to_string());
+                false
+            }
+            None => !self.entrypoint_fns().is_empty(),
+        };
+        if inject_entrypoint {
+            args.push(String::from("--entrypoint"));
+            args.push(String::from(ENTRYPOINT_PATH));
+        }
+
+        args.push(self.image()?);
+        if inject_entrypoint {
+            args.push(String::from("entrypoint"));
+            args.push(String::from("--"));
+        }
+        match matches.values_of("command") {
+            Some(command) => args.extend(command.map(String::from)),
+            None => args.extend_from_slice(&self.args),
+        }
+        Ok(args)
+    }
+
+    /// The container name the `Name` aspect resolves from `--name` or the profile, if any.
+    fn named_container(&self, matches: &ArgMatches) -> Result<Option<String>> {
+        for aspect in self.aspects.iter().filter(|a| a.name() == "Name") {
+            if let [flag, name] = aspect.run_args(Some(matches))?.as_slice() {
+                if flag == "--name" {
+                    return Ok(Some(name.clone()));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Like `named_container` but falling back to the app name.
+    fn container_name(&self, matches: &ArgMatches) -> Result<String> {
+        Ok(self
+            .named_container(matches)?
+            .unwrap_or_else(|| self.name.clone()))
+    }
+
+    /// The seconds the `StopTimeout` aspect gives the app to shut down, docker's default of 10
+    /// without it.
+    fn stop_timeout(&self, matches: &ArgMatches) -> Result<u32> {
+        for aspect in self.aspects.iter().filter(|a| a.name() == "StopTimeout") {
+            if let [flag, timeout] = aspect.run_args(Some(matches))?.as_slice() {
+                if flag == "--stop-timeout" {
+                    return aspects::StopTimeout::try_from(timeout.as_str()).map(|t| t.0);
+                }
+            }
+        }
+        Ok(10)
+    }
+
+    fn stats(&self, matches: &ArgMatches) -> Result<()> {
+        docker::stats(
+            &self.container_name(matches)?,
+            matches.is_present("follow"),
+            OutputFormat::of(matches) == OutputFormat::Json,
+        )
+    }
+
+    fn run(&self, matches: &ArgMatches) -> Result<()> {
+        let args = self.run_args(matches)?;
+        let detach = matches.is_present("detach");
+        if matches.is_present("replace") {
+            if let Some(name) = self.named_container(matches)? {
+                docker::remove_stale(&name, &docker::DockerContainers)?;
+            }
+        }
+        // only a named container can be stopped, and a detached one outlives dfiles anyway
+        if !detach {
+            if let Some(name) = self.named_container(matches)? {
+                let handler =
+                    docker::stop_handler(name, self.stop_timeout(matches)?, docker::DockerStop);
+                docker::forward_signals(handler)?;
+            }
+        }
+        self.with_hooks(!detach, || {
+            docker::run(args);
+            Ok(())
+        })
+    }
+
+    /// Calls every aspect's `pre_run` in order, then `run`, then the `post_run`s in reverse
+    /// order. A failing `pre_run` skips the container but still unwinds the aspects whose
+    /// `pre_run` succeeded; `post_run`s are skipped for detached containers, which are still
+    /// running when `run` returns.
+    fn with_hooks<F>(&self, post_run: bool, run: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        let context = self.context();
+        let mut started = 0;
+        let mut res = Ok(());
+        for aspect in &self.aspects {
+            res = aspect.pre_run_with(&context);
+            if res.is_err() {
+                break;
+            }
+            started += 1;
+        }
+        if res.is_ok() {
+            res = run();
+        }
+        if !post_run {
+            return res;
+        }
+        for aspect in self.aspects[..started].iter().rev() {
+            if let Err(e) = aspect.post_run_with(&context) {
+                error!("post-run hook of {} failed: {}", aspect.name(), e);
+                res = res.and(Err(e));
+            }
+        }
+        res
+    }
+
+    fn build(&self, matches: &ArgMatches) -> Result<()> {
+        if matches.is_present("lint") {
+            self.lint(matches.is_present("strict"))?;
+        }
+        // wait for the daemon first, probing it for BuildKit support needs it too
+        let daemon = self.connect(connect_retries(matches))?;
+        let buildkit = match self.buildkit_options(matches) {
+            Some(opts) if docker::buildkit_available() => Some(opts),
+            Some(_) => {
+                warn!("BuildKit is unavailable, falling back to the legacy build output");
+                None
+            }
+            None => None,
+        };
+
+        // skip the build when neither the dockerfile nor any container file changed since the
+        // last successful build of an image that still exists; the creation timestamp differs on
+        // every build so it is left out of the hash
+        let opts = ArchiveOptions {
+            offline: matches.is_present("offline"),
+            buildkit: buildkit.is_some(),
+            ..ArchiveOptions::from_env()?
+        };
+        let hashed_opts = ArchiveOptions {
+            metadata: opts.metadata.without_created(),
+            ..opts.clone()
+        };
+        let mut hashed_file = NamedTempFile::new()?;
+        self.generate_archive_with(hashed_file.as_file_mut(), &hashed_opts)?;
+        let hash = archive_hash(hashed_file.path())?;
+        let hash_path = self.hash_path()?;
+        if !matches.is_present("force")
+            && is_up_to_date(&hash, &hash_path)?
+            && docker::image_exists(&self.image()?)
+        {
+            info!(
+                "{} is up to date, skipping build (use --force to rebuild)",
+                self.image()?
+            );
+            return Ok(());
+        }
+
         let mut tar_file = NamedTempFile::new()?;
-        self.generate_archive_impl(&mut tar_file.as_file_mut())?;
+        self.generate_archive_with(tar_file.as_file_mut(), &opts)?;
+        match buildkit {
+            Some(opts) => docker::build_with_buildkit(&opts, tar_file.path())?,
+            None => self.build_legacy(&daemon, tar_file.path(), matches)?,
+        }
+
+        if let Some(dir) = hash_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&hash_path, &hash)?;
+        fs::write(
+            self.last_dockerfile_path()?,
+            self.render_dockerfile_with(&hashed_opts)?,
+        )?;
+        Ok(())
+    }
 
-        let docker = Docker::connect_with_defaults()?;
-        let options = ContainerBuildOptions {
+    /// Logs a warning for every lint finding in the rendered Dockerfile, or fails on the first
+    /// one under `strict`.
+    fn lint(&self, strict: bool) -> Result<()> {
+        for message in lint_dockerfile(&self.render_dockerfile()?) {
+            if strict {
+                return Err(Error::DockerfileLint { message });
+            }
+            warn!("Dockerfile lint: {}", message);
+        }
+        Ok(())
+    }
+
+    /// A file about the last successful build, keyed by the image tag as well as the app name so
+    /// that building a different tag neither counts as up to date nor changes what `diff` shows.
+    fn last_build_path(&self, extension: &str) -> Result<PathBuf> {
+        let tag: String = self
+            .image()?
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' => c,
+                _ => '_',
+            })
+            .collect();
+        Ok(dirs::get_cache_dir(None, None)?.join(format!("{}-{}.{}", self.name, tag, extension)))
+    }
+
+    /// Where the hash of the last successful build is kept.
+    fn hash_path(&self) -> Result<PathBuf> {
+        self.last_build_path("hash")
+    }
+
+    /// Where the Dockerfile of the last successful build is kept, next to its hash.
+    fn last_dockerfile_path(&self) -> Result<PathBuf> {
+        self.last_build_path("Dockerfile")
+    }
+
+    /// Prints how the Dockerfile changed since the last build. Like the stored copy it leaves out
+    /// the creation timestamp, and it renders the plain Dockerfile so a last build with
+    /// `--offline` or BuildKit shows those rewrites as changes too.
+    fn diff(&self) -> Result<()> {
+        let previous = match fs::read_to_string(self.last_dockerfile_path()?) {
+            Ok(previous) => previous,
+            Err(_) => {
+                info!(
+                    "{} has not been built yet, nothing to diff against",
+                    self.image()?
+                );
+                return Ok(());
+            }
+        };
+        let opts = ArchiveOptions::from_env()?;
+        let opts = ArchiveOptions {
+            metadata: opts.metadata.without_created(),
+            ..opts
+        };
+        let current = self.render_dockerfile_with(&opts)?;
+        print!(
+            "{}",
+            unified_diff(&previous, &current, "last build", "current")
+        );
+        Ok(())
+    }
+
+    fn buildkit_options(&self, matches: &ArgMatches) -> Option<docker::BuildKitOptions> {
+        matches
+            .value_of("progress")
+            .map(|progress| docker::BuildKitOptions {
+                progress: progress.to_string(),
+                tags: self.tags.clone(),
+                cache_from: cache_from(matches),
+                prefix: self.output_prefix.clone(),
+            })
+    }
+
+    fn legacy_build_options(&self, matches: &ArgMatches) -> ContainerBuildOptions {
+        ContainerBuildOptions {
             dockerfile: "Dockerfile".into(),
             t: self.tags.clone(),
+            cachefrom: cache_from(matches),
             ..ContainerBuildOptions::default()
-        };
+        }
+    }
+
+    fn default_build_matches(&self) -> Result<ArgMatches> {
+        self.build_app()
+            .get_matches_from_safe(vec![self.name.as_str(), "build"])
+            .map_err(|e| Error::BuildFailed(e.message))
+    }
+
+    /// Builds the image as `build` without any flags would.
+    fn build_default(&self) -> Result<()> {
+        let matches = self.default_build_matches()?;
+        let build = matches
+            .subcommand_matches("build")
+            .ok_or(Error::NoSubcommand)?;
+        self.build(build)
+    }
+
+    /// Like `build_default` but with the configured aspects loaded first, the way `execute`
+    /// loads them before dispatching a subcommand.
+    fn build_configured(&self) -> Result<()> {
+        let matches = self.default_build_matches()?;
+        let build = matches
+            .subcommand_matches("build")
+            .ok_or(Error::NoSubcommand)?;
+        let mut mgr = self.clone();
+        mgr.load_config(build)?;
+        mgr.build(build)
+    }
+
+    /// Builds the image only if it doesn't exist yet, so it's cheap to call before anything that
+    /// needs the image.
+    pub fn ensure_built(&self) -> Result<()> {
+        self.ensure_built_with(docker::image_exists, ContainerManager::build_default)
+    }
+
+    fn ensure_built_with<E, B>(&self, image_exists: E, build: B) -> Result<()>
+    where
+        E: Fn(&str) -> bool,
+        B: Fn(&ContainerManager) -> Result<()>,
+    {
+        let image = self.image()?;
+        if image_exists(&image) {
+            debug!("{} exists, not building it", image);
+            return Ok(());
+        }
+        build(self)
+    }
+
+    /// Credentials for the registry the image is pushed to: `--registry-user` with the password
+    /// read from `input` under `--registry-pass-stdin` wins over `DOCKER_AUTH` and
+    /// `~/.docker/config.json`.
+    fn registry_auth<R: BufRead>(
+        &self,
+        matches: &ArgMatches,
+        input: &mut R,
+    ) -> Result<Option<docker::RegistryAuth>> {
+        let image: docker::ImageRef = self.image()?.parse()?;
+        match matches.value_of("registry-user") {
+            Some(username) if matches.is_present("registry-pass-stdin") => {
+                let mut password = String::new();
+                input.read_line(&mut password)?;
+                Ok(Some(docker::RegistryAuth {
+                    username: username.to_string(),
+                    password: password.trim_end_matches(&['\r', '\n'][..]).to_string(),
+                    server: image
+                        .registry
+                        .unwrap_or_else(|| String::from(docker::DOCKER_HUB)),
+                }))
+            }
+            _ => docker::RegistryAuth::lookup(image.registry.as_deref()),
+        }
+    }
+
+    /// Connects to the docker daemon and pings it, retrying only those two steps so errors of
+    /// whatever is done with the connection afterwards are never retried.
+    fn connect(&self, retries: u32) -> Result<Docker> {
+        retry(retries, CONNECT_BACKOFF, || {
+            let docker = Docker::connect_with_defaults()?;
+            docker.ping()?;
+            Ok(docker)
+        })
+        .map_err(|source| Error::DockerUnavailable {
+            attempts: retries + 1,
+            source,
+        })
+    }
+
+    fn connect_with_auth(&self, matches: &ArgMatches, retries: u32) -> Result<Docker> {
+        let mut docker = self.connect(retries)?;
+        if let Some(auth) = self.registry_auth(matches, &mut io::stdin().lock())? {
+            debug!("using {} credentials for {}", auth.username, auth.server);
+            docker.set_credential(Credential::with_password(UserPassword::new(
+                auth.username,
+                auth.password,
+                String::new(),
+                auth.server,
+            )));
+        }
+        Ok(docker)
+    }
+
+    fn push(&self, matches: &ArgMatches) -> Result<()> {
+        let docker = self.connect_with_auth(matches, connect_retries(matches))?;
+        for tag in &self.tags {
+            let (repository, version) = match tag_repository(tag) {
+                repository if repository.len() < tag.len() => {
+                    (repository, &tag[repository.len() + 1..])
+                }
+                repository => (repository, "latest"),
+            };
+            info!("pushing {}:{}", repository, version);
+            docker
+                .push_image(repository, version)
+                .map_err(|source| Error::PushFailed {
+                    tag: tag.clone(),
+                    source,
+                })?;
+        }
+        Ok(())
+    }
+
+    fn print_build_output(&self, s: &str) {
+        match &self.output_prefix {
+            Some(prefix) => {
+                for line in s.lines() {
+                    println!("{}{}", prefix, line);
+                }
+            }
+            None => print!("{}", s),
+        }
+    }
 
-        let res = docker.build_image(options, tar_file.path())?;
+    fn build_legacy(&self, docker: &Docker, context: &Path, matches: &ArgMatches) -> Result<()> {
+        // failures of the build itself are reported through the output stream below
+        let res = docker
+            .build_image(self.legacy_build_options(matches), context)
+            .map_err(|e| Error::BuildFailed(e.to_string()))?;
+        let mut failure: Option<String> = None;
         BufReader::new(res)
             .lines()
             .filter_map(std::result::Result::ok)
             .map(|l| from_str::<BuildOutput>(&l))
             .filter_map(std::result::Result::ok)
-            .for_each(|bo: BuildOutput| print!("{}", bo.stream));
+            .for_each(|bo: BuildOutput| {
+                if let Some(s) = bo.stream {
+                    self.print_build_output(&s);
+                }
+                if let Some(e) = bo.error {
+                    error!("{}", e);
+                    failure = Some(e);
+                }
+            });
+        if let Some(e) = failure {
+            return Err(Error::BuildFailed(e));
+        }
         Ok(())
     }
 
+    fn entrypoint_fns(&self) -> Vec<aspects::EntrypointFn> {
+        self.aspects
+            .iter()
+            .flat_map(|aspect| aspect.entrypoint_fns())
+            .collect()
+    }
+
+    /// Runs inside the container as its entrypoint. The entrypoint fns that require root run
+    /// through `sudo dfiles-entrypoint entrypoint --root-steps`, the only command sudoers lets the
+    /// container user run as root; the other fns and finally the app command keep running as the
+    /// user the container was started as. A container started as root drops to the `CurrentUser`
+    /// for those instead.
+    ///
+    /// The fns are read from the list baked into the image at build time rather than from the
+    /// aspects, since those inside the container lack the ones loaded from config on the host.
+    fn entrypoint(&self, matches: &ArgMatches) -> Result<()> {
+        if env::var_os(ENTRYPOINT_ENV).is_none() {
+            return Err(Error::NotInEntrypointMode);
+        }
+        let fns = read_entrypoint_fns(Path::new(ENTRYPOINT_FNS_PATH))?;
+        if matches.is_present("root-steps") {
+            for f in fns.iter().filter(|f| f.requires_root) {
+                run_entrypoint_fn(f, &f.command)?;
+            }
+            return Ok(());
+        }
+
+        let command: Vec<String> = matches
+            .values_of("command")
+            .map(|vs| vs.map(String::from).collect())
+            .unwrap_or_default();
+        let uid = users::get_current_uid();
+
+        if let Some(sudo) = root_steps_argv(uid, &fns) {
+            let status = Command::new(&sudo[0]).args(&sudo[1..]).status()?;
+            if !status.success() {
+                return Err(Error::EntrypointFnFailed(String::from("root steps")));
+            }
+        }
+
+        let user = if uid == 0 {
+            // docker run --group-add ids are only known to this process
+            let added = fs::read_to_string("/proc/self/status")
+                .map(|status| status_groups(&status))
+                .unwrap_or_default();
+            EntrypointUser::from_context(&self.context(), &added)
+        } else {
+            None
+        };
+
+        run_entrypoint_fns(&fns, uid, user.as_ref(), run_entrypoint_fn)?;
+
+        let mut app = match &user {
+            Some(u) => {
+                let argv = u.drop_privileges(&command);
+                let mut app = Command::new(&argv[0]);
+                app.args(&argv[1..]);
+                if let Some(home) = &u.home {
+                    app.env("HOME", home);
+                }
+                app
+            }
+            None => {
+                let mut app = Command::new(&command[0]);
+                app.args(&command[1..]);
+                app
+            }
+        };
+        Err(app.exec().into())
+    }
+
     fn generate_archive_impl(&self, f: &mut std::fs::File) -> Result<()> {
-        let mut a = Builder::new(f);
+        self.generate_archive_with(f, &ArchiveOptions::from_env()?)
+    }
+
+    /// Assembles the Dockerfile from every aspect's snippets, ordered by snippet order.
+    fn render_dockerfile(&self) -> Result<String> {
+        self.render_dockerfile_with(&ArchiveOptions::from_env()?)
+    }
+
+    fn render_dockerfile_with(&self, opts: &ArchiveOptions) -> Result<String> {
+        self.check_aspects()?;
 
         let mut contents: BTreeMap<u8, String> = BTreeMap::new();
-        for aspect in &self.aspects {
-            let dockerfile_snippets = aspect.dockerfile_snippets();
+        let context = self.context();
+        let metadata: &dyn ContainerAspect = &opts.metadata;
+        for aspect in self
+            .aspects
+            .iter()
+            .map(|a| a.as_ref())
+            .chain(Some(metadata))
+        {
+            let dockerfile_snippets = aspect.dockerfile_snippets_with(&context);
             for snippet in dockerfile_snippets {
                 contents
                     .entry(snippet.order)
@@ -101,9 +732,16 @@ impl ContainerManager {
                     })
                     .or_insert(snippet.content);
             }
-            for file in aspect.container_files() {
-                add_file_to_archive(&mut a, &file.container_path, &file.contents)?;
-            }
+        }
+
+        if !self.entrypoint_fns().is_empty() {
+            contents
+                .entry(ENTRYPOINT_ORDER)
+                .and_modify(|e| {
+                    e.push('\n');
+                    e.push_str(&entrypoint_snippet());
+                })
+                .or_insert_with(entrypoint_snippet);
         }
 
         let mut dockerfile_contents = String::new();
@@ -113,10 +751,63 @@ impl ContainerManager {
             dockerfile_contents.push('\n');
             dockerfile_contents.push('\n');
         }
+        if opts.offline {
+            dockerfile_contents = without_apt_update(&dockerfile_contents);
+        }
+        if opts.buildkit {
+            dockerfile_contents = with_apt_cache_mounts(&dockerfile_contents, !opts.offline);
+        }
+        Ok(dockerfile_contents)
+    }
 
-        add_file_to_archive(&mut a, "Dockerfile", &dockerfile_contents)?;
+    fn generate_archive_with(&self, f: &mut std::fs::File, opts: &ArchiveOptions) -> Result<()> {
+        let dockerfile_contents = self.render_dockerfile_with(opts)?;
+        // Entries are collected by path and written in sorted order with fixed metadata so the
+        // same configuration always produces the same bytes.
+        let mut entries: BTreeMap<String, (Vec<u8>, u32)> = BTreeMap::new();
 
-        Ok(())
+        let mut dockerignore_patterns: Vec<String> = Vec::new();
+        for aspect in &self.aspects {
+            for file in aspect.container_files() {
+                entries.insert(file.container_path, (file.contents.into_bytes(), 0o644));
+            }
+            for pattern in aspect.dockerignore_patterns() {
+                if !dockerignore_patterns.contains(&pattern) {
+                    dockerignore_patterns.push(pattern);
+                }
+            }
+        }
+
+        let fns = self.entrypoint_fns();
+        if !fns.is_empty() {
+            let binary = fs::read(env::current_exe()?)?;
+            entries.insert(ENTRYPOINT_ARCHIVE_PATH.to_string(), (binary, 0o755));
+            entries.insert(
+                ENTRYPOINT_FNS_ARCHIVE_PATH.to_string(),
+                (to_json(&fns)?.into_bytes(), 0o644),
+            );
+        }
+
+        entries.insert(
+            "Dockerfile".to_string(),
+            (dockerfile_contents.into_bytes(), 0o644),
+        );
+
+        if !dockerignore_patterns.is_empty() {
+            let mut dockerignore_contents = dockerignore_patterns.join("\n");
+            dockerignore_contents.push('\n');
+            entries.insert(
+                ".dockerignore".to_string(),
+                (dockerignore_contents.into_bytes(), 0o644),
+            );
+        }
+
+        let mut a = Builder::new(f);
+        for (path, (contents, mode)) in &entries {
+            add_to_archive(&mut a, path, contents, *mode, opts.mtime)?;
+        }
+        a.finish()
+            .map_err(|e| Error::FailedToAddFileToArchive { source: e })
     }
 
     fn generate_archive(&self) -> Result<()> {
@@ -124,6 +815,15 @@ impl ContainerManager {
         self.generate_archive_impl(&mut tar_file)
     }
 
+    fn dump_dockerfile(&self, matches: &ArgMatches) -> Result<()> {
+        let dockerfile = self.render_dockerfile()?;
+        match matches.value_of("output") {
+            Some(path) => fs::write(path, dockerfile)?,
+            None => print!("{}", dockerfile),
+        }
+        Ok(())
+    }
+
     /// Takes configuration options for the dfiles binary and saves them to be loaded at build or
     /// run time.
     ///
@@ -136,6 +836,22 @@ impl ContainerManager {
     /// $ firefox config --mount <hostpath>:<containerpath>
     /// ```
     fn config(&self, matches: &ArgMatches) -> Result<()> {
+        match matches.subcommand() {
+            ("list", Some(subm)) => return self.config_list(subm),
+            ("show", Some(subm)) => return self.config_show(subm),
+            ("edit", Some(subm)) => return self.config_edit(subm),
+            ("delete", Some(subm)) => return self.config_delete(subm),
+            ("copy", Some(subm)) => {
+                return config::Config::copy_profile(
+                    &self.name,
+                    subm.value_of("from"),
+                    subm.value_of("to").unwrap_or_default(),
+                    subm.is_present("force"),
+                )
+            }
+            _ => (),
+        }
+
         let cfg = config::Config::try_from(matches)?;
 
         let mut profile: Option<&str> = None;
@@ -146,102 +862,1459 @@ impl ContainerManager {
         cfg.save(Some(&self.name), profile)
     }
 
-    fn load_config(&mut self, matches: &ArgMatches) -> Result<()> {
+    /// Prints the effective configuration, ie what `run` and `build` would end up using, without
+    /// saving or running anything.
+    fn config_show(&self, matches: &ArgMatches) -> Result<()> {
+        print!("{}", self.show_config_in(&config::config_dir()?, matches)?);
+        Ok(())
+    }
+
+    /// The effective configuration with the layers stored under `config_dir`, in the format
+    /// requested with `--format`.
+    fn show_config_in(&self, config_dir: &Path, matches: &ArgMatches) -> Result<String> {
+        let cfg = self.effective_config_in(config_dir, matches)?;
+        match OutputFormat::of(matches) {
+            OutputFormat::Human => cfg.to_yaml(),
+            OutputFormat::Json => Ok(format!("{}\n", to_json(&cfg)?)),
+        }
+    }
+
+    /// Prints the profiles that have a config file of their own.
+    fn config_list(&self, matches: &ArgMatches) -> Result<()> {
+        let list = ProfileList {
+            application: self.name.clone(),
+            profiles: config::Config::profiles(&self.name)?,
+        };
+        print!("{}", list.render(OutputFormat::of(matches))?);
+        Ok(())
+    }
+
+    /// Deletes the config of the profile named with `--profile`, which is required so that the
+    /// default profile is never deleted by accident.
+    fn config_delete(&self, matches: &ArgMatches) -> Result<()> {
+        let profile = matches.value_of("profile").unwrap_or_default();
+        if !matches.is_present("yes") {
+            let prompt = format!("delete the `{}` profile of {}?", profile, self.name);
+            if !confirm(&prompt, &mut io::stdin().lock())? {
+                return Ok(());
+            }
+        }
+        match config::Config::delete(&self.name, profile) {
+            Err(Error::MissingProfile(_)) => {
+                println!(
+                    "{} has no `{}` profile, nothing to delete",
+                    self.name, profile
+                );
+                Ok(())
+            }
+            other => other,
+        }
+    }
+
+    /// Opens the selected profile's config file in `$EDITOR`.
+    fn config_edit(&self, matches: &ArgMatches) -> Result<()> {
+        let mut profile: Option<&str> = None;
+        if matches.occurrences_of("profile") > 0 {
+            profile = matches.value_of("profile");
+        }
+        let path = config::Config::path(Some(&self.name), profile)?;
+        let editor = env::var("EDITOR").unwrap_or_else(|_| String::from("vi"));
+        config::Config::edit_file(&path, &editor)
+    }
+
+    /// Merges file-based config for the selected profile with config given on the command line.
+    fn effective_config(&self, matches: &ArgMatches) -> Result<config::Config> {
+        self.effective_config_in(&config::config_dir()?, matches)
+    }
+
+    fn effective_config_in(
+        &self,
+        config_dir: &Path,
+        matches: &ArgMatches,
+    ) -> Result<config::Config> {
         let mut profile: Option<&str> = None;
         if matches.occurrences_of("profile") > 0 {
             profile = matches.value_of("profile");
         }
-        let cfg = config::Config::load(&self.name, profile)?;
+        let cfg = config::Config::load_in(config_dir, &self.name, profile)?;
 
         let cli_cfg = config::Config::try_from(matches)?;
 
-        self.aspects
-            .extend(cfg.merge(&cli_cfg, false).get_aspects());
+        Ok(cfg.merge(&cli_cfg, config::MergePolicy::CliWins))
+    }
+
+    fn load_config(&mut self, matches: &ArgMatches) -> Result<()> {
+        let cfg = self.effective_config(matches)?;
+        self.apply_config(&cfg);
         Ok(())
     }
 
-    pub fn execute(&mut self) -> Result<()> {
+    /// Adds the configured aspects and replaces the default command if one is configured; a
+    /// command given to `run` after `--` still wins over both, see `run_args`.
+    fn apply_config(&mut self, cfg: &config::Config) {
+        if let Some(command) = &cfg.command {
+            self.args = command.clone();
+        }
+        self.aspects.extend(cfg.get_aspects());
+    }
+
+    /// Prints a shell completion script covering the subcommands and args of the configured
+    /// aspects.
+    fn completions(&self, matches: &ArgMatches) -> Result<()> {
+        let shell = match matches.value_of("shell").unwrap_or("bash").parse::<Shell>() {
+            Ok(s) => s,
+            Err(e) => return Err(Error::UnsupportedShell(e)),
+        };
+        self.write_completions(shell, &mut std::io::stdout());
+        Ok(())
+    }
+
+    fn write_completions<W: Write>(&self, shell: Shell, buf: &mut W) {
+        self.build_app()
+            .gen_completions_to(self.name.clone(), shell, buf);
+    }
+
+    /// Builds the clap app with all subcommands and the args contributed by the manager's
+    /// aspects; `execute` parses the process args with it, but it can equally be used to parse
+    /// arbitrary argv or generate completions.
+    pub fn build_app(&self) -> App {
         let mut run = SubCommand::with_name("run").about("run app in container");
-        let mut build = SubCommand::with_name("build").about("build app container");
+        let mut build = SubCommand::with_name("build")
+            .about("build app container")
+            .arg(
+                Arg::with_name("force")
+                    .long("force")
+                    .help("rebuild the image even if nothing changed since the last build"),
+            )
+            .arg(connect_retries_arg())
+            .arg(
+                Arg::with_name("cache-from")
+                    .long("cache-from")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("image to use as a layer cache source, may be repeated"),
+            )
+            .arg(
+                Arg::with_name("lint")
+                    .long("lint")
+                    .help("check the Dockerfile for common mistakes before building"),
+            )
+            .arg(
+                Arg::with_name("strict")
+                    .long("strict")
+                    .requires("lint")
+                    .help("fail the build on lint findings instead of warning"),
+            )
+            .arg(Arg::with_name("offline").long("offline").help(
+                "skip apt-get update, installing only what the base image already has cached",
+            ))
+            .arg(
+                Arg::with_name("progress")
+                    .long("progress")
+                    .takes_value(true)
+                    .possible_values(&["plain", "auto"])
+                    .help("build with BuildKit and render its progress output in this style"),
+            );
+        let push = SubCommand::with_name("push")
+            .about("push app container image to its registry")
+            .arg(
+                Arg::with_name("registry-user")
+                    .long("registry-user")
+                    .takes_value(true)
+                    .requires("registry-pass-stdin")
+                    .help("registry username, overrides DOCKER_AUTH and ~/.docker/config.json"),
+            )
+            .arg(
+                Arg::with_name("registry-pass-stdin")
+                    .long("registry-pass-stdin")
+                    .requires("registry-user")
+                    .help("read the registry password for --registry-user from stdin"),
+            )
+            .arg(connect_retries_arg());
+        let mut stats = SubCommand::with_name("stats")
+            .about("print the running app container's cpu, memory and network usage")
+            .arg(
+                Arg::with_name("follow")
+                    .short("f")
+                    .long("follow")
+                    .help("keep streaming usage instead of printing it once"),
+            );
         let mut config = SubCommand::with_name("config").about("configure app container settings");
+        let mut config_show = SubCommand::with_name("show")
+            .about("print the effective configuration after merging profiles and flags");
         let generate_archive = SubCommand::with_name("generate-archive")
             .about("generate archive used to build container");
+        let lint = SubCommand::with_name("lint")
+            .about("check the Dockerfile for common mistakes")
+            .arg(
+                Arg::with_name("strict")
+                    .long("strict")
+                    .help("exit with an error on lint findings instead of warning"),
+            );
+        let diff = SubCommand::with_name("diff")
+            .about("show how the Dockerfile changed since the last build");
+        let dump_dockerfile = SubCommand::with_name("dump-dockerfile")
+            .about("print the Dockerfile used to build the container")
+            .arg(
+                Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .takes_value(true)
+                    .help("write the Dockerfile to this path instead of stdout"),
+            );
+        let entrypoint = SubCommand::with_name("entrypoint")
+            .about("run entrypoint fns and then the given command, used inside the container")
+            .setting(AppSettings::Hidden)
+            .arg(
+                Arg::with_name("root-steps")
+                    .long("root-steps")
+                    .help("only run the entrypoint fns that require root, used through sudo"),
+            )
+            .arg(
+                Arg::with_name("command")
+                    .multiple(true)
+                    .required_unless("root-steps")
+                    .last(true)
+                    .help("the command to exec once the entrypoint fns are done"),
+            );
+        let completions = SubCommand::with_name("completions")
+            .about("print a shell completion script")
+            .arg(
+                Arg::with_name("shell")
+                    .required(true)
+                    .possible_values(&["bash", "zsh", "fish"])
+                    .help("the shell to generate completions for"),
+            );
 
-        let mut app = App::new(&self.name).version("0.0");
-
-        self.aspects.insert(
-            0,
-            Box::new(aspects::Profile {
-                name: self.name.clone(),
-                container_paths: self.container_paths.clone(),
-            }),
-        );
+        let app = App::new(&self.name)
+            .version("0.0")
+            .arg(
+                Arg::with_name("verbose")
+                    .short("v")
+                    .long("verbose")
+                    .multiple(true)
+                    .global(true)
+                    .help("increase log verbosity, may be repeated (overrides RUST_LOG)"),
+            )
+            .arg(
+                Arg::with_name("quiet")
+                    .short("q")
+                    .long("quiet")
+                    .global(true)
+                    .conflicts_with("verbose")
+                    .help("only log errors (overrides RUST_LOG)"),
+            )
+            .arg(
+                Arg::with_name("format")
+                    .long("format")
+                    .global(true)
+                    .takes_value(true)
+                    .possible_values(&["human", "json"])
+                    .default_value("human")
+                    .help("print reports as human readable text or as JSON for scripts"),
+            );
 
         for arg in &config::cli_args() {
             run = run.arg(arg);
             config = config.arg(arg);
+            config_show = config_show.arg(arg);
         }
 
-        let cloned = dyn_clone::clone_box(&self.aspects);
-        for aspect in cloned.iter() {
+        run = run
+            .arg(
+                Arg::with_name("interactive")
+                    .short("i")
+                    .long("interactive")
+                    .help("keep stdin open, like docker run -i"),
+            )
+            .arg(
+                // -t is taken by --timezone
+                Arg::with_name("tty")
+                    .long("tty")
+                    .help("allocate a pseudo-tty, like docker run -t"),
+            )
+            .arg(
+                Arg::with_name("it")
+                    .long("it")
+                    .help("shorthand for --interactive --tty"),
+            )
+            .arg(
+                Arg::with_name("detach")
+                    .short("d")
+                    .long("detach")
+                    .help("run the container in the background, ignoring --interactive and --tty"),
+            );
+        run = run.arg(
+            Arg::with_name("replace")
+                .long("replace")
+                .help("remove a stopped container left over with the same name before running"),
+        );
+        run = run.arg(
+            Arg::with_name("entrypoint")
+                .long("entrypoint")
+                .takes_value(true)
+                .help("override the container entrypoint, skipping all aspect entrypoint fns"),
+        );
+        run = run.arg(
+            Arg::with_name("command")
+                .multiple(true)
+                .last(true)
+                .help("run this command in the container instead of the app, given after --"),
+        );
+        run = run.arg(
+            Arg::with_name("docker-arg")
+                .long("docker-arg")
+                .multiple(true)
+                .number_of_values(1)
+                .allow_hyphen_values(true)
+                .takes_value(true)
+                .help("specify an extra argument to pass through to docker run"),
+        );
+
+        for aspect in &self.aspects {
             for arg in aspect.config_args() {
                 run = run.arg(arg);
             }
+            for arg in aspect.config_args() {
+                stats = stats.arg(arg);
+            }
             for arg in aspect.cli_build_args() {
                 build = build.arg(arg);
             }
             for arg in aspect.config_args() {
                 config = config.arg(arg);
             }
-        }
+            for arg in aspect.config_args() {
+                // global args already propagate down from `config`
+                if !arg.is_set(ArgSettings::Global) {
+                    config_show = config_show.arg(arg);
+                }
+            }
+        }
+        config = config.subcommand(config_show).subcommand(
+            SubCommand::with_name("list").about("list the profiles that have a config file"),
+        );
+        config = config.subcommand(
+            SubCommand::with_name("edit")
+                .about("open the app or profile config file in $EDITOR")
+                .arg(
+                    Arg::with_name("profile")
+                        .short("p")
+                        .long("profile")
+                        .help("specify the profile to edit")
+                        .takes_value(true),
+                ),
+        );
+        config = config.subcommand(
+            SubCommand::with_name("delete")
+                .about("delete a profile's config")
+                .arg(
+                    Arg::with_name("profile")
+                        .short("p")
+                        .long("profile")
+                        .required(true)
+                        .takes_value(true)
+                        .help("specify the profile to delete"),
+                )
+                .arg(
+                    Arg::with_name("yes")
+                        .short("y")
+                        .long("yes")
+                        .help("delete without asking for confirmation"),
+                ),
+        );
+        config = config.subcommand(
+            SubCommand::with_name("copy")
+                .about("copy a profile's config to another profile")
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .takes_value(true)
+                        .help("specify the profile to copy, the application config by default"),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .required(true)
+                        .takes_value(true)
+                        .help("specify the profile to create"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("replace the destination profile if it exists"),
+                ),
+        );
 
-        app = app
-            .subcommand(run)
+        app.subcommand(run)
             .subcommand(build)
+            .subcommand(push)
+            .subcommand(stats)
             .subcommand(config)
-            .subcommand(generate_archive);
+            .subcommand(generate_archive)
+            .subcommand(dump_dockerfile)
+            .subcommand(diff)
+            .subcommand(lint)
+            .subcommand(completions)
+            .subcommand(entrypoint)
+    }
+
+    pub fn execute(&mut self) -> Result<()> {
+        self.execute_from(env::args_os())
+    }
 
-        let matches = app.get_matches();
+    /// Like `execute` but parses the given argv rather than the process args.
+    pub fn execute_from<I, T>(&mut self, argv: I) -> Result<()>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        // the clap app borrows its arg definitions from the aspects, so parse against a snapshot
+        // to leave self free to pick up configured aspects below
+        let snapshot = self.clone();
+        let argv: Vec<OsString> = argv.into_iter().map(Into::into).collect();
+        reject_combined_tty(&argv)?;
+        let matches = snapshot.build_app().get_matches_from(argv);
         let (subc, subm) = matches.subcommand();
 
-        if let Some(v) = subm {
-            self.load_config(&v)?;
+        // global flags propagate down to the subcommand's matches
+        let m = subm.unwrap_or(&matches);
+        init_logging(log_level(
+            m.occurrences_of("verbose"),
+            m.is_present("quiet"),
+        ));
+
+        // `config edit` must be able to open a config that no longer loads in order to fix it
+        match (subc, subm) {
+            ("config", Some(c)) if c.subcommand_name() == Some("edit") => (),
+            (_, Some(v)) => self.load_config(&v)?,
+            _ => (),
         }
 
         match (subc, subm) {
             ("run", Some(subm)) => self.run(&subm),
-            ("build", _) => self.build(),
+            ("build", Some(subm)) => self.build(&subm),
+            ("push", Some(subm)) => self.push(&subm),
+            ("stats", Some(subm)) => self.stats(&subm),
             ("config", Some(subm)) => self.config(&subm),
             ("generate-archive", _) => self.generate_archive(),
-            (_, _) => Ok(println!("{}", matches.usage())),
+            ("dump-dockerfile", Some(subm)) => self.dump_dockerfile(&subm),
+            ("diff", _) => self.diff(),
+            ("lint", Some(subm)) => self.lint(subm.is_present("strict")),
+            ("completions", Some(subm)) => self.completions(&subm),
+            ("entrypoint", Some(subm)) => self.entrypoint(&subm),
+            (_, _) => {
+                eprintln!("{}", matches.usage());
+                Err(Error::NoSubcommand)
+            }
+        }
+    }
+}
+
+/// The repository part of an image tag, i.e. without any `:tag` suffix; a colon before the last
+/// `/` belongs to a registry port rather than a tag.
+fn tag_repository(tag: &str) -> &str {
+    match tag.rfind(':') {
+        Some(i) if !tag[i..].contains('/') => &tag[..i],
+        _ => tag,
+    }
+}
+
+fn with_latest_tags(tags: &[String]) -> Vec<String> {
+    let mut tags = tags.to_vec();
+    for tag in tags.clone() {
+        let latest = format!("{}:latest", tag_repository(&tag));
+        if !tags.contains(&latest) {
+            tags.push(latest);
+        }
+    }
+    tags
+}
+
+/// Builds the images of all `managers`, at most `concurrency` at a time, as if `build` had been
+/// run for each of them. Results are returned in the order of `managers` and a failed build
+/// doesn't stop the others. Build output is prefixed with the app name to keep interleaved logs
+/// readable.
+pub fn build_all(managers: Vec<ContainerManager>, concurrency: usize) -> Vec<Result<()>> {
+    let managers: Vec<ContainerManager> = managers
+        .into_iter()
+        .map(|mut mgr| {
+            mgr.output_prefix = Some(format!("[{}] ", mgr.name));
+            mgr
+        })
+        .collect();
+    run_bounded(managers, concurrency, ContainerManager::build_configured)
+}
+
+/// Calls `f` on every item from a pool of `concurrency` threads.
+fn run_bounded<T, F>(items: Vec<T>, concurrency: usize, f: F) -> Vec<Result<()>>
+where
+    T: Send + Sync + 'static,
+    F: Fn(&T) -> Result<()> + Send + Sync + 'static,
+{
+    let len = items.len();
+    let items = Arc::new(items);
+    let f = Arc::new(f);
+    let next = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = mpsc::channel();
+
+    let workers: Vec<thread::JoinHandle<()>> = (0..concurrency.max(1).min(len))
+        .map(|_| {
+            let (items, f, next, tx) = (items.clone(), f.clone(), next.clone(), tx.clone());
+            thread::spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= items.len() {
+                    break;
+                }
+                let _ = tx.send((i, f(&items[i])));
+            })
+        })
+        .collect();
+    drop(tx);
+
+    let mut results: Vec<Option<Result<()>>> = (0..len).map(|_| None).collect();
+    for (i, result) in rx {
+        results[i] = Some(result);
+    }
+    for worker in workers {
+        let _ = worker.join();
+    }
+    results
+        .into_iter()
+        .map(|r| r.unwrap_or_else(|| Err(Error::BuildFailed(String::from("build panicked")))))
+        .collect()
+}
+
+/// docker flags for how the container attaches to the terminal; a detached container has no
+/// terminal so `-i`/`-t` are dropped in favor of `-d`.
+/// `-t` is taken by `--timezone`, so clap reads docker's `-it` as `-i` plus a timezone taken from
+/// the next argument, silently swallowing it. Values of the args that accept leading hyphens and
+/// everything after `--` are passed through as given.
+fn reject_combined_tty(argv: &[OsString]) -> Result<()> {
+    let mut args = argv.iter().take_while(|a| *a != "--");
+    while let Some(arg) = args.next() {
+        if arg == "--docker-arg" || arg == "--command" {
+            args.next();
+        } else if arg == "-it" || arg == "-ti" {
+            return Err(Error::CombinedTtyFlag(arg.to_string_lossy().to_string()));
+        }
+    }
+    Ok(())
+}
+
+fn session_args(matches: &ArgMatches) -> Vec<String> {
+    if matches.is_present("detach") {
+        return vec![String::from("-d")];
+    }
+    let mut args = Vec::new();
+    if matches.is_present("interactive") || matches.is_present("it") {
+        args.push(String::from("-i"));
+    }
+    if matches.is_present("tty") || matches.is_present("it") {
+        args.push(String::from("-t"));
+    }
+    args
+}
+
+/// Logs at info level by default and `RUST_LOG` overrides that as usual, unless a level was
+/// explicitly requested with `--verbose` or `--quiet`.
+fn init_logging(level: Option<LevelFilter>) {
+    let mut builder = match level {
+        Some(l) => {
+            let mut b = env_logger::Builder::new();
+            b.filter_level(l);
+            b
+        }
+        None => env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")),
+    };
+    // ignore failure to initialize, eg if the embedding binary already set up a logger
+    let _ = builder.try_init();
+}
+
+/// Maps repeated `-v` above the default info level, `-v` being debug and `-vv` trace, and
+/// `--quiet` to errors only; without either flag the level is left to `RUST_LOG`.
+fn log_level(verbose: u64, quiet: bool) -> Option<LevelFilter> {
+    if quiet {
+        return Some(LevelFilter::Error);
+    }
+    match verbose {
+        0 => None,
+        1 => Some(LevelFilter::Debug),
+        _ => Some(LevelFilter::Trace),
+    }
+}
+
+/// Initial delay between attempts to reach the docker daemon, doubled after every failure.
+const CONNECT_BACKOFF: Duration = Duration::from_millis(500);
+
+fn connect_retries_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("connect-retries")
+        .long("connect-retries")
+        .takes_value(true)
+        .default_value("3")
+        .validator(|v| {
+            v.parse::<u32>()
+                .map(|_| ())
+                .map_err(|_| format!("`{}` is not a number of retries", v))
+        })
+        .help("how many times to retry reaching the docker daemon before giving up")
+}
+
+fn connect_retries(matches: &ArgMatches) -> u32 {
+    matches
+        .value_of("connect-retries")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(3)
+}
+
+/// Asks a yes/no question on stderr, reading the answer from `input`; anything but yes is no.
+fn confirm<R: BufRead>(prompt: &str, input: &mut R) -> Result<bool> {
+    eprint!("{} [y/N] ", prompt);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+#[cfg(test)]
+mod test_support {
+    use super::*;
+
+    /// A manager for the `test` app, tagged `dfiles/test:0.0` and running `test-command`.
+    pub fn manager(aspects: Vec<Box<dyn ContainerAspect>>) -> ContainerManager {
+        ContainerManager::default_debian(
+            "test".to_string(),
+            vec!["dfiles/test:0.0".to_string()],
+            Vec::new(),
+            aspects,
+            vec!["test-command".to_string()],
+        )
+    }
+
+    /// Parses `argv` with the manager's own CLI, panicking if it doesn't accept them.
+    pub fn matches<'a>(mgr: &'a ContainerManager, argv: &[&str]) -> ArgMatches<'a> {
+        mgr.build_app()
+            .get_matches_from_safe(argv)
+            .expect("valid argv")
+    }
+}
+
+#[cfg(test)]
+mod confirm_should {
+    use super::*;
+
+    #[test]
+    fn accept_only_yes() -> Result<()> {
+        for (answer, expected) in &[
+            ("y\n", true),
+            ("YES\n", true),
+            ("n\n", false),
+            ("\n", false),
+            ("", false),
+            ("yep\n", false),
+        ] {
+            assert_eq!(confirm("delete?", &mut answer.as_bytes())?, *expected);
+        }
+        Ok(())
+    }
+}
+
+/// How subcommands print what they report, selected with the global `--format` flag.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Human,
+    Json,
+}
+
+impl OutputFormat {
+    fn of(matches: &ArgMatches) -> OutputFormat {
+        match matches.value_of("format") {
+            Some("json") => OutputFormat::Json,
+            _ => OutputFormat::Human,
+        }
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String> {
+    serde_json::to_string_pretty(value).map_err(|e| Error::FailedToRenderJson(e.to_string()))
+}
+
+/// What `config list` reports.
+#[derive(Serialize, Debug)]
+struct ProfileList {
+    application: String,
+    profiles: Vec<config::Profile>,
+}
+
+impl ProfileList {
+    fn render(&self, format: OutputFormat) -> Result<String> {
+        match format {
+            OutputFormat::Json => Ok(format!("{}\n", to_json(self)?)),
+            OutputFormat::Human => Ok(self
+                .profiles
+                .iter()
+                .map(|p| format!("{}\t{}\n", p.name, p.path.display()))
+                .collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod output_format_should {
+    use super::test_support::{manager, matches};
+    use super::*;
+
+    fn list() -> ProfileList {
+        ProfileList {
+            application: "firefox".to_string(),
+            profiles: vec![
+                config::Profile {
+                    name: "home".to_string(),
+                    path: PathBuf::from("/cfg/profiles/home/config.toml"),
+                },
+                config::Profile {
+                    name: "work".to_string(),
+                    path: PathBuf::from("/cfg/profiles/work/config.yaml"),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn render_config_list_as_valid_json() -> Result<()> {
+        let json: serde_json::Value = from_str(&list().render(OutputFormat::Json)?)
+            .map_err(|e| Error::FailedToRenderJson(e.to_string()))?;
+        assert_eq!(json["application"], "firefox");
+        assert_eq!(json["profiles"][0]["name"], "home");
+        assert_eq!(
+            json["profiles"][1]["path"],
+            "/cfg/profiles/work/config.yaml"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn render_empty_config_list_as_valid_json() -> Result<()> {
+        let empty = ProfileList {
+            application: "firefox".to_string(),
+            profiles: Vec::new(),
+        };
+        let json: serde_json::Value = from_str(&empty.render(OutputFormat::Json)?)
+            .map_err(|e| Error::FailedToRenderJson(e.to_string()))?;
+        assert_eq!(json["profiles"], serde_json::json!([]));
+        Ok(())
+    }
+
+    #[test]
+    fn render_config_list_as_text_by_default() -> Result<()> {
+        assert_eq!(
+            list().render(OutputFormat::Human)?,
+            "home\t/cfg/profiles/home/config.toml\nwork\t/cfg/profiles/work/config.yaml\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn read_format_from_global_flag() {
+        let mgr = manager(Vec::new());
+        let format = |argv: Vec<&str>| {
+            let matches = matches(&mgr, &argv);
+            let config = matches.subcommand_matches("config").expect("config");
+            OutputFormat::of(config.subcommand_matches("list").expect("list"))
+        };
+        assert_eq!(format(vec!["test", "config", "list"]), OutputFormat::Human);
+        assert_eq!(
+            format(vec!["test", "--format", "json", "config", "list"]),
+            OutputFormat::Json
+        );
+        assert_eq!(
+            format(vec!["test", "config", "list", "--format", "json"]),
+            OutputFormat::Json
+        );
+    }
+}
+
+#[cfg(test)]
+mod config_show_should {
+    use super::test_support::{manager, matches};
+    use super::*;
+
+    /// A config dir where the app config sets memory and cpu shares and the `work` profile bumps
+    /// memory, each adding a mount.
+    fn layers() -> Result<tempfile::TempDir> {
+        let dir = tempfile::tempdir()?;
+        let layer = |profile: Option<&str>, memory: &str, mount: &str| -> Result<()> {
+            let mut cfg = config::Config::empty();
+            cfg.memory = Some(aspects::Memory(memory.to_string()));
+            if profile.is_none() {
+                cfg.cpu_shares = Some(aspects::CPUShares("256".to_string()));
+            }
+            cfg.mounts = Some(vec![aspects::Mount {
+                host_path: mount.to_string(),
+                container_path: mount.to_string(),
+            }]);
+            let scoped = dirs::scoped(dir.path().to_path_buf(), Some("test"), profile);
+            fs::create_dir_all(&scoped)?;
+            cfg.save_file(&scoped.join("config.yaml"))
+        };
+        layer(None, "1g", "/base")?;
+        layer(Some("work"), "2g", "/profile")?;
+        Ok(dir)
+    }
+
+    fn show(config_dir: &Path, argv: &[&str]) -> Result<String> {
+        let mgr = manager(Vec::new());
+        let matches = matches(&mgr, argv);
+        let show = matches
+            .subcommand_matches("config")
+            .and_then(|m| m.subcommand_matches("show"))
+            .expect("show subcommand matches");
+        mgr.show_config_in(config_dir, show)
+    }
+
+    #[test]
+    fn render_merged_layers_in_config_format() -> Result<()> {
+        let dir = layers()?;
+        let argv = [
+            "test",
+            "config",
+            "show",
+            "-p",
+            "work",
+            "--mount",
+            "/cli:/cli",
+        ];
+
+        let rendered = show(dir.path(), &argv)?;
+        let parsed: config::Config =
+            serde_yaml::from_str(&rendered).map_err(|_| Error::FailedToLoadConfig)?;
+
+        assert!(rendered.contains("memory: 2g"));
+        assert!(rendered.contains("cpu_shares: \"256\""));
+        assert_eq!(parsed.mounts.map(|m| m.len()), Some(3));
+        Ok(())
+    }
+
+    #[test]
+    fn render_json_when_asked() -> Result<()> {
+        let dir = layers()?;
+        let rendered = show(dir.path(), &["test", "config", "show", "--format", "json"])?;
+        let json: serde_json::Value =
+            from_str(&rendered).map_err(|e| Error::FailedToRenderJson(e.to_string()))?;
+
+        assert_eq!(json["memory"], "1g");
+        assert_eq!(json["mounts"][0]["container_path"], "/base");
+        Ok(())
+    }
+}
+
+fn cache_from(matches: &ArgMatches) -> Vec<String> {
+    matches
+        .values_of("cache-from")
+        .map(|vs| vs.map(String::from).collect())
+        .unwrap_or_default()
+}
+
+/// Calls `f` until it succeeds or has been retried `retries` times, sleeping `backoff` before the
+/// first retry and doubling it before each one after that.
+fn retry<T, E, F>(retries: u32, backoff: Duration, mut f: F) -> std::result::Result<T, E>
+where
+    E: fmt::Display,
+    F: FnMut() -> std::result::Result<T, E>,
+{
+    let mut delay = backoff;
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                warn!(
+                    "could not reach docker daemon ({}), retrying in {:?} ({}/{})",
+                    e, delay, attempt, retries
+                );
+                thread::sleep(delay);
+                delay *= 2;
+            }
+            r => return r,
+        }
+    }
+}
+
+/// Settings that shape the generated build context beyond what the aspects contribute.
+#[derive(Clone, Debug, Default)]
+struct ArchiveOptions {
+    metadata: aspects::BuildMetadata,
+    offline: bool,
+    /// Whether the context is built with BuildKit and may use its Dockerfile extensions.
+    buildkit: bool,
+    /// Modification time stamped on every archive entry so the context bytes don't depend on
+    /// when it was generated.
+    mtime: u64,
+}
+
+impl ArchiveOptions {
+    fn from_env() -> Result<Self> {
+        Ok(ArchiveOptions {
+            metadata: aspects::BuildMetadata::from_env()?,
+            offline: false,
+            buildkit: false,
+            mtime: aspects::source_date_epoch()?.unwrap_or(0),
+        })
+    }
+}
+
+/// Drops `apt-get update` and the `rm -rf /var/lib/apt/lists/*` cleanup from the chains the
+/// aspects emit, so offline builds install from the package lists and archives already in the base
+/// image and keep those lists for the next install.
+fn without_apt_update(dockerfile: &str) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    for line in dockerfile.lines() {
+        let step = line.trim().trim_end_matches('\\').trim_end();
+        if step == "RUN apt-get update"
+            || step == "&& apt-get update"
+            || step == "&& rm -rf /var/lib/apt/lists/*"
+        {
+            // dropping the last step of a chain leaves the previous one ending the instruction
+            if !line.trim_end().ends_with('\\') && step != "RUN apt-get update" {
+                if let Some(last) = lines.last_mut() {
+                    *last = last
+                        .trim_end()
+                        .trim_end_matches('\\')
+                        .trim_end()
+                        .to_string();
+                }
+            }
+            continue;
+        }
+        lines.push(
+            line.replace("apt-get update && ", "")
+                .replace(" && apt-get update", "")
+                .replace(" && rm -rf /var/lib/apt/lists/*", ""),
+        );
+    }
+    lines.join("\n") + "\n"
+}
+
+const APT_CACHE_MOUNTS: &str = "--mount=type=cache,target=/var/cache/apt,sharing=locked \
+    --mount=type=cache,target=/var/lib/apt,sharing=locked";
+
+/// Keeps apt's package lists and downloaded archives in BuildKit cache mounts so rebuilds don't
+/// download them again. Debian images delete archives after every install and the aspects delete
+/// the lists, so both are disabled; neither directory ends up in the image layers either way.
+/// `RUN --mount` needs the dockerfile 1.2 frontend which the `syntax` directive pulls, unless
+/// `pull_frontend` is false and the daemon's builtin frontend has to do.
+fn with_apt_cache_mounts(dockerfile: &str, pull_frontend: bool) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    if pull_frontend {
+        lines.push(String::from("# syntax=docker/dockerfile:1"));
+    }
+    let mut apt_configured = false;
+    let all: Vec<&str> = dockerfile.lines().collect();
+    let mut i = 0;
+    while i < all.len() {
+        if !all[i].starts_with("RUN ") {
+            lines.push(all[i].to_string());
+            i += 1;
+            continue;
+        }
+        let mut end = i;
+        while end + 1 < all.len() && all[end].trim_end().ends_with('\\') {
+            end += 1;
+        }
+        let instruction = &all[i..=end];
+        if instruction.iter().any(|l| l.contains("apt-get")) {
+            if !apt_configured {
+                lines.push(String::from(
+                    r#"RUN rm -f /etc/apt/apt.conf.d/docker-clean \
+  && echo 'Binary::apt::APT::Keep-Downloaded-Packages "true";' > /etc/apt/apt.conf.d/keep-cache"#,
+                ));
+                apt_configured = true;
+            }
+            lines.push(format!("RUN {} \\\n    {}", APT_CACHE_MOUNTS, &all[i][4..]));
+            lines.extend(
+                instruction[1..]
+                    .iter()
+                    .filter(|l| l.trim() != "&& rm -rf /var/lib/apt/lists/* \\")
+                    .map(|l| l.to_string()),
+            );
+        } else {
+            lines.extend(instruction.iter().map(|l| l.to_string()));
+        }
+        i = end + 1;
+    }
+    lines.join("\n") + "\n"
+}
+
+/// The Dockerfile's instructions with continuation lines joined, skipping comments and blank
+/// lines.
+fn dockerfile_instructions(dockerfile: &str) -> Vec<String> {
+    let mut instructions = Vec::new();
+    let mut current = String::new();
+    for line in dockerfile.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        match trimmed.strip_suffix('\\') {
+            Some(rest) => {
+                current.push_str(rest);
+                current.push(' ');
+            }
+            None => {
+                current.push_str(trimmed);
+                instructions.push(current);
+                current = String::new();
+            }
+        }
+    }
+    if !current.is_empty() {
+        instructions.push(current);
+    }
+    instructions
+}
+
+/// Finds mistakes that would otherwise only show up as failed or bloated builds.
+fn lint_dockerfile(dockerfile: &str) -> Vec<String> {
+    let instructions = dockerfile_instructions(dockerfile);
+    let mut findings = Vec::new();
+    match instructions.first() {
+        Some(first) if first.starts_with("FROM ") => (),
+        _ => findings.push(String::from(
+            "the Dockerfile must start with FROM, is the base image aspect at order 0 missing?",
+        )),
+    }
+    for instruction in &instructions {
+        if instruction.starts_with("RUN ")
+            && instruction.contains("apt-get install")
+            && !instruction.contains("rm -rf /var/lib/apt/lists/*")
+        {
+            findings.push(format!(
+                "apt-get install without `rm -rf /var/lib/apt/lists/*` bloats the layer: {}",
+                instruction
+            ));
+        }
+        if instruction.starts_with("ADD ")
+            && instruction
+                .split_whitespace()
+                .skip(1)
+                .any(|arg| arg.starts_with("http://") || arg.starts_with("https://"))
+        {
+            findings.push(format!(
+                "ADD of a remote url isn't cached or verified, download with RUN curl instead: {}",
+                instruction
+            ));
+        }
+    }
+    findings
+}
+
+/// A line-based diff of `old` and `new` in unified format with three lines of context, empty if
+/// they are equal.
+fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    const CONTEXT: usize = 3;
+    let a: Vec<&str> = old.lines().collect();
+    let b: Vec<&str> = new.lines().collect();
+
+    // lcs[i][j] is the length of the longest common subsequence of a[i..] and b[j..]
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut ops: Vec<(char, &str)> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            ops.push((' ', a[i]));
+            i += 1;
+            j += 1;
+        } else if j < b.len() && (i == a.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            ops.push(('+', b[j]));
+            j += 1;
+        } else {
+            ops.push(('-', a[i]));
+            i += 1;
+        }
+    }
+
+    // group changes closer than twice the context into one hunk
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (k, _) in ops.iter().enumerate().filter(|(_, (op, _))| *op != ' ') {
+        let start = k.saturating_sub(CONTEXT);
+        let end = (k + CONTEXT + 1).min(ops.len());
+        match hunks.last_mut() {
+            Some((_, last_end)) if start <= *last_end => *last_end = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    if hunks.is_empty() {
+        return String::new();
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", old_name, new_name);
+    let count = |ops: &[(char, &str)], side: char| {
+        ops.iter()
+            .filter(|(op, _)| *op == ' ' || *op == side)
+            .count()
+    };
+    for (start, end) in hunks {
+        let range = |side: char| {
+            let len = count(&ops[start..end], side);
+            let first = count(&ops[..start], side) + if len == 0 { 0 } else { 1 };
+            format!("{},{}", first, len)
+        };
+        out.push_str(&format!("@@ -{} +{} @@\n", range('-'), range('+')));
+        for (op, line) in &ops[start..end] {
+            out.push_str(&format!("{}{}\n", op, line));
+        }
+    }
+    out
+}
+
+/// Hashes the build context archive, which covers both the assembled Dockerfile and the contents
+/// of every container file. It is a sha256 so that it stays the same across Rust releases.
+fn archive_hash(path: &Path) -> Result<String> {
+    Ok(format!("{:x}", Sha256::digest(&fs::read(path)?)))
+}
+
+fn is_up_to_date(hash: &str, hash_path: &Path) -> Result<bool> {
+    if !hash_path.exists() {
+        return Ok(false);
+    }
+    Ok(fs::read_to_string(hash_path)?.trim() == hash)
+}
+
+/// Where the app binary is stored in the build context and installed in the image when it is
+/// needed as the container entrypoint.
+const ENTRYPOINT_ARCHIVE_PATH: &str = "dfiles-entrypoint";
+const ENTRYPOINT_PATH: &str = "/usr/local/bin/dfiles-entrypoint";
+/// Where the entrypoint fns of every aspect known at build time are stored next to the binary,
+/// root-owned so that the container user can't change what the root steps run.
+const ENTRYPOINT_FNS_ARCHIVE_PATH: &str = "dfiles-entrypoint-fns.json";
+const ENTRYPOINT_FNS_PATH: &str = "/usr/local/share/dfiles/entrypoint-fns.json";
+/// Set in images built with the entrypoint so the `entrypoint` subcommand refuses to run on the
+/// host.
+const ENTRYPOINT_ENV: &str = "DFILES_ENTRYPOINT";
+/// Right before the `USER` switch at 98 so installing sudo still runs as root.
+const ENTRYPOINT_ORDER: u8 = 97;
+
+/// Installs the app binary for use as the entrypoint and lets any user run its root steps through
+/// sudo, which some entrypoint fns need for their setup. `run` injects it with `--entrypoint`
+/// rather than baking an `ENTRYPOINT` into the image so that it can be overridden.
+fn entrypoint_snippet() -> String {
+    format!(
+        r#"COPY {archive_path} {path}
+COPY {fns_archive_path} {fns_path}
+RUN apt-get update && apt-get install -y \
+    --no-install-recommends \
+    sudo \
+  && apt-get purge --autoremove \
+  && rm -rf /var/lib/apt/lists/* \
+  && echo 'Defaults!{path} env_keep += "{env}"' > /etc/sudoers.d/dfiles-entrypoint \
+  && echo 'ALL ALL=(root) NOPASSWD: {path} entrypoint --root-steps' >> /etc/sudoers.d/dfiles-entrypoint \
+  && chmod 0440 /etc/sudoers.d/dfiles-entrypoint
+ENV {env}=1"#,
+        archive_path = ENTRYPOINT_ARCHIVE_PATH,
+        path = ENTRYPOINT_PATH,
+        fns_archive_path = ENTRYPOINT_FNS_ARCHIVE_PATH,
+        fns_path = ENTRYPOINT_FNS_PATH,
+        env = ENTRYPOINT_ENV,
+    )
+}
+
+fn read_entrypoint_fns(path: &Path) -> Result<Vec<aspects::EntrypointFn>> {
+    serde_json::from_slice(&fs::read(path)?).map_err(|e| Error::InvalidEntrypointFns(e.to_string()))
+}
+
+/// Runs the fns an unprivileged entrypoint may run itself, i.e. all but the root steps unless it
+/// runs as root; a root entrypoint runs the others as `user` if it has one.
+fn run_entrypoint_fns<F>(
+    fns: &[aspects::EntrypointFn],
+    uid: u32,
+    user: Option<&EntrypointUser>,
+    mut run: F,
+) -> Result<()>
+where
+    F: FnMut(&aspects::EntrypointFn, &[String]) -> Result<()>,
+{
+    for f in fns {
+        match user {
+            _ if f.requires_root && uid != 0 => continue,
+            Some(u) if !f.requires_root => run(f, &u.drop_privileges(&f.command))?,
+            _ => run(f, &f.command)?,
+        }
+    }
+    Ok(())
+}
+
+fn run_entrypoint_fn(f: &aspects::EntrypointFn, argv: &[String]) -> Result<()> {
+    info!("entrypoint: {}", f.description);
+    let status = Command::new(&argv[0]).args(&argv[1..]).status()?;
+    if !status.success() {
+        return Err(Error::EntrypointFnFailed(f.description.clone()));
+    }
+    Ok(())
+}
+
+/// The sudo argv running the entrypoint fns that require root, if an unprivileged entrypoint has
+/// any; it must match the command the sudoers rule in `entrypoint_snippet` allows.
+fn root_steps_argv(uid: u32, fns: &[aspects::EntrypointFn]) -> Option<Vec<String>> {
+    if uid == 0 || !fns.iter().any(|f| f.requires_root) {
+        return None;
+    }
+    Some(
+        vec!["sudo", ENTRYPOINT_PATH, "entrypoint", "--root-steps"]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+    )
+}
+
+/// The container user a root entrypoint drops to, as the `CurrentUser` aspect created it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct EntrypointUser {
+    uid: u32,
+    gid: u32,
+    groups: Vec<u32>,
+    home: Option<String>,
+}
+
+impl EntrypointUser {
+    /// Groups are the user's memberships in the container's group database plus `added`, the
+    /// non-root supplementary groups docker gave the entrypoint process.
+    fn from_context(context: &aspects::Context, added: &[u32]) -> Option<EntrypointUser> {
+        let id = |v: &Option<String>| v.as_ref().and_then(|v| v.parse::<u32>().ok());
+        let uid = id(&context.uid)?;
+        if uid == 0 {
+            return None;
+        }
+        let gid = id(&context.gid)?;
+        let mut groups: Vec<u32> = context
+            .user
+            .as_ref()
+            .and_then(|name| users::get_user_groups(name, gid))
+            .map(|gs| gs.iter().map(|g| g.gid()).collect())
+            .unwrap_or_default();
+        groups.extend(added.iter().filter(|g| **g != 0));
+        groups.sort_unstable();
+        groups.dedup();
+        Some(EntrypointUser {
+            uid,
+            gid,
+            groups,
+            home: context.home.clone(),
+        })
+    }
+
+    /// The argv running `command` as this user through `setpriv`, which util-linux ships in every
+    /// Debian image.
+    fn drop_privileges(&self, command: &[String]) -> Vec<String> {
+        let groups = if self.groups.is_empty() {
+            String::from("--clear-groups")
+        } else {
+            format!("--groups={}", join_ids(&self.groups))
+        };
+        let mut argv = vec![
+            String::from("setpriv"),
+            format!("--reuid={}", self.uid),
+            format!("--regid={}", self.gid),
+            groups,
+            String::from("--"),
+        ];
+        argv.extend_from_slice(command);
+        argv
+    }
+}
+
+/// The supplementary group ids listed in the `Groups:` line of a `/proc/<pid>/status` file.
+fn status_groups(status: &str) -> Vec<u32> {
+    status
+        .lines()
+        .find(|l| l.starts_with("Groups:"))
+        .map(|l| {
+            l["Groups:".len()..]
+                .split_whitespace()
+                .filter_map(|g| g.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn join_ids(ids: &[u32]) -> String {
+    ids.iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+#[cfg(test)]
+mod drop_privileges_should {
+    use super::*;
+
+    fn context(uid: &str) -> aspects::Context {
+        aspects::Context {
+            user: Some("dfiles-no-such-user".to_string()),
+            uid: Some(uid.to_string()),
+            gid: Some("1000".to_string()),
+            home: Some("/home/me".to_string()),
+            ..aspects::Context::default()
+        }
+    }
+
+    fn command() -> Vec<String> {
+        vec!["firefox".to_string(), "--new-instance".to_string()]
+    }
+
+    fn step(requires_root: bool) -> aspects::EntrypointFn {
+        aspects::EntrypointFn {
+            description: "step".to_string(),
+            command: vec!["true".to_string()],
+            requires_root,
         }
     }
+
+    #[test]
+    fn run_command_as_container_user_with_added_groups() {
+        let user = EntrypointUser::from_context(&context("1000"), &[44, 0, 29, 44])
+            .expect("container user");
+        assert_eq!(user.home, Some("/home/me".to_string()));
+        assert_eq!(
+            user.drop_privileges(&command()),
+            vec![
+                "setpriv",
+                "--reuid=1000",
+                "--regid=1000",
+                "--groups=29,44",
+                "--",
+                "firefox",
+                "--new-instance"
+            ]
+        );
+    }
+
+    #[test]
+    fn clear_groups_without_any() {
+        let user = EntrypointUser::from_context(&context("1000"), &[]).expect("container user");
+        assert_eq!(
+            user.drop_privileges(&command())[..5],
+            [
+                "setpriv",
+                "--reuid=1000",
+                "--regid=1000",
+                "--clear-groups",
+                "--"
+            ]
+        );
+    }
+
+    #[test]
+    fn stay_root_without_container_user() {
+        assert_eq!(
+            EntrypointUser::from_context(&aspects::Context::default(), &[44]),
+            None
+        );
+        assert_eq!(EntrypointUser::from_context(&context("0"), &[44]), None);
+    }
+
+    #[test]
+    fn skip_sudo_when_no_fn_requires_root() {
+        assert_eq!(root_steps_argv(1000, &[]), None);
+        assert_eq!(root_steps_argv(1000, &[step(false), step(false)]), None);
+        assert_eq!(root_steps_argv(0, &[step(true)]), None);
+    }
+
+    #[test]
+    fn sudo_only_the_root_steps() {
+        assert_eq!(
+            root_steps_argv(1000, &[step(false), step(true)]),
+            Some(
+                vec![
+                    "sudo",
+                    "/usr/local/bin/dfiles-entrypoint",
+                    "entrypoint",
+                    "--root-steps"
+                ]
+                .into_iter()
+                .map(String::from)
+                .collect()
+            )
+        );
+        assert!(entrypoint_snippet()
+            .contains("NOPASSWD: /usr/local/bin/dfiles-entrypoint entrypoint --root-steps'"));
+    }
+
+    #[test]
+    fn read_supplementary_groups_from_proc_status() {
+        let status =
+            "Name:\tdfiles\nUid:\t1000\t1000\t1000\t1000\nGroups:\t29 44 1000 \nNSpid:\t1\n";
+        assert_eq!(status_groups(status), vec![29, 44, 1000]);
+        assert_eq!(join_ids(&status_groups(status)), "29,44,1000");
+        assert!(status_groups("Groups:\n").is_empty());
+    }
 }
 
-fn add_file_to_archive<W: Write>(b: &mut Builder<W>, name: &str, contents: &str) -> Result<()> {
+fn add_to_archive<W: Write>(
+    b: &mut Builder<W>,
+    name: &str,
+    contents: &[u8],
+    mode: u32,
+    mtime: u64,
+) -> Result<()> {
     let mut header = Header::new_gnu();
     header
         .set_path(name)
         .map_err(|e| Error::FailedToAddFileToArchive { source: e })?;
     header.set_size(contents.len() as u64);
+    header.set_mode(mode);
+    header.set_mtime(mtime);
+    header.set_uid(0);
+    header.set_gid(0);
     header.set_cksum();
-    b.append(&header, contents.as_bytes())
+    b.append(&header, contents)
         .map_err(|e| Error::FailedToAddFileToArchive { source: e })
 }
 
 #[derive(Clone)]
-struct Debian {}
+pub struct Debian {
+    base: aspects::BaseImage,
+}
+
+impl Debian {
+    pub fn new() -> Debian {
+        Debian {
+            base: aspects::BaseImage {
+                reference: String::from("debian:buster"),
+            },
+        }
+    }
+
+    /// Pins the debian base image by digest rather than the mutable `buster` tag.
+    pub fn with_digest(digest: &str) -> Result<Debian> {
+        Ok(Debian {
+            base: aspects::BaseImage::with_digest("debian", digest)?,
+        })
+    }
+}
+
+impl Default for Debian {
+    fn default() -> Self {
+        Debian::new()
+    }
+}
 
 impl aspects::ContainerAspect for Debian {
     fn name(&self) -> String {
         String::from("Debian")
     }
     fn dockerfile_snippets(&self) -> Vec<aspects::DockerfileSnippet> {
-        vec![
-            aspects::DockerfileSnippet {
-                order: 00,
-                content: String::from("FROM debian:buster"),
-            },
+        let mut snippets = self.base.dockerfile_snippets();
+        snippets.extend(vec![
             aspects::DockerfileSnippet {
                 order: 3,
                 content: String::from(
@@ -280,6 +2353,1631 @@ RUN apt-get update && apt-get install -y --no-install-recommends \
   && rm -rf /src/*.deb "#,
                 ),
             },
-        ]
+        ]);
+        snippets
+    }
+}
+
+#[cfg(test)]
+mod debian_should {
+    use super::*;
+
+    #[test]
+    fn pin_base_image_by_digest() -> Result<()> {
+        let digest = format!("sha256:{}", "0123456789abcdef".repeat(4));
+        let snippets = Debian::with_digest(&digest)?.dockerfile_snippets();
+        let from: Vec<&aspects::DockerfileSnippet> =
+            snippets.iter().filter(|s| s.order == 0).collect();
+        assert_eq!(from.len(), 1);
+        assert_eq!(from[0].content, format!("FROM debian@{}", digest));
+        Ok(())
+    }
+
+    #[test]
+    fn default_to_buster_tag() {
+        let snippets = Debian::new().dockerfile_snippets();
+        assert!(snippets
+            .iter()
+            .any(|s| s.order == 0 && s.content == "FROM debian:buster"));
+    }
+}
+
+#[cfg(test)]
+mod run_args_should {
+    use super::test_support::{manager, matches};
+    use super::*;
+
+    #[test]
+    fn place_extra_docker_args_before_image() -> Result<()> {
+        let mgr = manager(vec![Box::new(aspects::Shm {})]);
+        let matches = matches(
+            &mgr,
+            &[
+                "test",
+                "run",
+                "--docker-arg",
+                "--cap-add=NET_ADMIN",
+                "--docker-arg",
+                "--read-only",
+            ],
+        );
+
+        assert_eq!(
+            mgr.run_args(matches.subcommand_matches("run").expect("run"))?,
+            vec![
+                "--rm",
+                "-v",
+                "/dev/shm:/dev/shm",
+                "--cap-add=NET_ADMIN",
+                "--read-only",
+                "dfiles/test:0.0",
+                "test-command",
+            ]
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod context_should {
+    use super::test_support::matches;
+    use super::*;
+
+    /// Provides a home like `CurrentUser` does.
+    #[derive(Clone)]
+    struct Home(&'static str);
+    impl ContainerAspect for Home {
+        fn name(&self) -> String {
+            String::from("Home")
+        }
+        fn provide(&self, context: &mut aspects::Context) {
+            context.home = Some(self.0.to_string());
+        }
+    }
+
+    /// Emits what it finds in the context.
+    #[derive(Clone)]
+    struct Probe {}
+    impl ContainerAspect for Probe {
+        fn name(&self) -> String {
+            String::from("Probe")
+        }
+        fn run_args_with(
+            &self,
+            _: Option<&ArgMatches>,
+            context: &aspects::Context,
+        ) -> Result<Vec<String>> {
+            Ok(vec![
+                String::from("-e"),
+                format!("PROBE_HOME={}", context.home()?),
+            ])
+        }
+        fn dockerfile_snippets_with(
+            &self,
+            context: &aspects::Context,
+        ) -> Vec<aspects::DockerfileSnippet> {
+            vec![aspects::DockerfileSnippet {
+                order: 91,
+                content: format!("ENV PROBE_APP={}", context.app),
+            }]
+        }
+    }
+
+    fn manager() -> ContainerManager {
+        // the probe comes first so it only sees the home if every aspect provides before any emits
+        ContainerManager::default_debian(
+            "test".to_string(),
+            vec!["dfiles/test:0.0".to_string()],
+            vec!["/data".to_string()],
+            vec![Box::new(Probe {}), Box::new(Home("/var/lib/me"))],
+            Vec::new(),
+        )
+    }
+
+    #[test]
+    fn resolve_facts_from_all_aspects() {
+        let context = manager().context();
+        assert_eq!(context.app, "test");
+        assert_eq!(context.container_paths, vec!["/data"]);
+        assert_eq!(context.home, Some("/var/lib/me".to_string()));
+    }
+
+    #[test]
+    fn thread_context_into_run_args() -> Result<()> {
+        let mgr = manager();
+        let matches = matches(&mgr, &["test", "run"]);
+        let args = mgr.run_args(matches.subcommand_matches("run").expect("run"))?;
+        assert!(args.contains(&"PROBE_HOME=/var/lib/me".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn thread_context_into_dockerfile() -> Result<()> {
+        let dockerfile = manager().render_dockerfile_with(&ArchiveOptions::default())?;
+        assert!(dockerfile.contains("ENV PROBE_APP=test"));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod entrypoint_override_should {
+    use super::test_support::{manager, matches};
+    use super::*;
+
+    fn run_args(argv: &[&str]) -> Result<Vec<String>> {
+        let mgr = manager(vec![Box::new(aspects::Fonts {
+            mount_user_fonts: false,
+            extra: Vec::new(),
+        })]);
+        let matches = matches(&mgr, argv);
+        mgr.run_args(matches.subcommand_matches("run").expect("run"))
+    }
+
+    #[test]
+    fn inject_entrypoint_for_entrypoint_fns() -> Result<()> {
+        let args = run_args(&["test", "run"])?;
+        assert_eq!(
+            args[args.len() - 6..],
+            [
+                "--entrypoint",
+                ENTRYPOINT_PATH,
+                "dfiles/test:0.0",
+                "entrypoint",
+                "--",
+                "test-command"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn skip_injection_for_explicit_entrypoint() -> Result<()> {
+        let args = run_args(&["test", "run", "--entrypoint", "/bin/sh"])?;
+        assert_eq!(
+            args[args.len() - 4..],
+            ["--entrypoint", "/bin/sh", "dfiles/test:0.0", "test-command"]
+        );
+        assert!(!args.contains(&ENTRYPOINT_PATH.to_string()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod command_override_should {
+    use super::test_support::{self, matches};
+    use super::*;
+
+    fn manager() -> ContainerManager {
+        let mut mgr = test_support::manager(Vec::new());
+        mgr.args.push("--flag".to_string());
+        mgr
+    }
+
+    fn run_args(argv: &[&str]) -> Result<Vec<String>> {
+        let mgr = manager();
+        let matches = matches(&mgr, argv);
+        mgr.run_args(matches.subcommand_matches("run").expect("run"))
+    }
+
+    #[test]
+    fn run_default_command() -> Result<()> {
+        let args = run_args(&["test", "run"])?;
+        assert_eq!(
+            args[args.len() - 3..],
+            ["dfiles/test:0.0", "test-command", "--flag"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn replace_command_after_separator() -> Result<()> {
+        let args = run_args(&["test", "run", "--", "/bin/bash", "-l"])?;
+        assert_eq!(
+            args[args.len() - 3..],
+            ["dfiles/test:0.0", "/bin/bash", "-l"]
+        );
+        Ok(())
+    }
+
+    /// Run args after applying a file config with the given command and the config from `argv`,
+    /// merged the way `effective_config` does.
+    fn configured_run_args(file_command: Option<&[&str]>, argv: &[&str]) -> Result<Vec<String>> {
+        let mut mgr = manager();
+        let snapshot = mgr.clone();
+        let matches = matches(&snapshot, argv);
+        let subm = matches.subcommand_matches("run").expect("run");
+
+        let mut file_cfg = config::Config::empty();
+        file_cfg.command = file_command.map(|c| c.iter().map(|s| s.to_string()).collect());
+        let cfg = file_cfg.merge(
+            &config::Config::try_from(subm)?,
+            config::MergePolicy::CliWins,
+        );
+        mgr.apply_config(&cfg);
+        mgr.run_args(subm)
+    }
+
+    #[test]
+    fn run_configured_command_instead_of_default() -> Result<()> {
+        let args = configured_run_args(Some(&["/bin/sh", "-l"]), &["test", "run"])?;
+        assert_eq!(args[args.len() - 3..], ["dfiles/test:0.0", "/bin/sh", "-l"]);
+        Ok(())
+    }
+
+    #[test]
+    fn prefer_cli_command_over_file_config() -> Result<()> {
+        let args = configured_run_args(
+            Some(&["/bin/sh", "-l"]),
+            &["test", "run", "--command", "/bin/zsh -i"],
+        )?;
+        assert_eq!(
+            args[args.len() - 3..],
+            ["dfiles/test:0.0", "/bin/zsh", "-i"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn prefer_command_after_separator_over_config() -> Result<()> {
+        let args = configured_run_args(
+            Some(&["/bin/sh", "-l"]),
+            &["test", "run", "--command", "/bin/zsh -i", "--", "/bin/bash"],
+        )?;
+        assert_eq!(args[args.len() - 2..], ["dfiles/test:0.0", "/bin/bash"]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod stats_should {
+    use super::test_support::{manager, matches};
+    use super::*;
+
+    fn container_name(mgr: &ContainerManager, argv: &[&str]) -> Result<String> {
+        let matches = matches(mgr, argv);
+        mgr.container_name(matches.subcommand_matches("stats").expect("stats"))
+    }
+
+    #[test]
+    fn resolve_container_name_like_run() -> Result<()> {
+        let mgr = manager(vec![Box::new(aspects::Name("test".to_string()))]);
+        assert_eq!(container_name(&mgr, &["test", "stats"])?, "test-default");
+        assert_eq!(
+            container_name(&mgr, &["test", "stats", "-p", "work", "--follow"])?,
+            "test-work"
+        );
+        assert_eq!(
+            container_name(&mgr, &["test", "stats", "--name", "other"])?,
+            "other"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn default_to_app_name_without_name_aspect() -> Result<()> {
+        let mgr = manager(Vec::new());
+        assert_eq!(container_name(&mgr, &["test", "stats"])?, "test");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod hooks_should {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Clone)]
+    struct Recorder {
+        name: &'static str,
+        fail_pre: bool,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl aspects::ContainerAspect for Recorder {
+        fn name(&self) -> String {
+            self.name.to_string()
+        }
+        fn pre_run(&self) -> Result<()> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("pre {}", self.name));
+            if self.fail_pre {
+                return Err(Error::HookFailed(self.name.to_string()));
+            }
+            Ok(())
+        }
+        fn post_run(&self) -> Result<()> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("post {}", self.name));
+            Ok(())
+        }
+    }
+
+    fn manager(calls: &Arc<Mutex<Vec<String>>>, failing: &str) -> ContainerManager {
+        let recorder = |name: &'static str| -> Box<dyn aspects::ContainerAspect> {
+            Box::new(Recorder {
+                name,
+                fail_pre: name == failing,
+                calls: calls.clone(),
+            })
+        };
+        ContainerManager::new(
+            "test".to_string(),
+            vec!["dfiles/test:0.0".to_string()],
+            Vec::new(),
+            vec![recorder("a"), recorder("b"), recorder("c")],
+            Vec::new(),
+        )
+    }
+
+    #[test]
+    fn wrap_run_in_pre_and_post_hooks() -> Result<()> {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        manager(&calls, "").with_hooks(true, || {
+            calls.lock().unwrap().push("run".to_string());
+            Ok(())
+        })?;
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec!["pre a", "pre b", "pre c", "run", "post c", "post b", "post a"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn skip_run_when_a_pre_hook_fails() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let res = manager(&calls, "b").with_hooks(true, || {
+            calls.lock().unwrap().push("run".to_string());
+            Ok(())
+        });
+        match res {
+            Err(Error::HookFailed(name)) => assert_eq!(name, "b"),
+            other => panic!("expected HookFailed, got {:?}", other),
+        }
+        assert_eq!(*calls.lock().unwrap(), vec!["pre a", "pre b", "post a"]);
+    }
+
+    #[test]
+    fn skip_post_hooks_for_detached_runs() -> Result<()> {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        manager(&calls, "").with_hooks(false, || Ok(()))?;
+        assert_eq!(*calls.lock().unwrap(), vec!["pre a", "pre b", "pre c"]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod lint_should {
+    use super::test_support::manager;
+    use super::*;
+
+    #[test]
+    fn accept_the_default_dockerfile() -> Result<()> {
+        let mgr = manager(Vec::new());
+        let findings = lint_dockerfile(&mgr.render_dockerfile_with(&ArchiveOptions::default())?);
+        assert!(findings.is_empty(), "{:?}", findings);
+        Ok(())
+    }
+
+    #[test]
+    fn require_from_first() {
+        let findings = lint_dockerfile("# comment\nRUN echo hi\nFROM debian:buster\n");
+        assert_eq!(findings.len(), 1);
+        assert!(findings[0].starts_with("the Dockerfile must start with FROM"));
+        assert_eq!(lint_dockerfile("").len(), 1);
+    }
+
+    #[test]
+    fn flag_apt_install_without_list_cleanup() {
+        let findings = lint_dockerfile(
+            "FROM debian:buster\nRUN apt-get update && apt-get install -y \\\n    curl\n",
+        );
+        assert_eq!(findings.len(), 1);
+        assert!(findings[0].starts_with("apt-get install without"));
+        assert!(findings[0].ends_with("RUN apt-get update && apt-get install -y  curl"));
+
+        let cleaned = r#"FROM debian:buster
+RUN apt-get install -y curl \
+  && rm -rf /var/lib/apt/lists/*
+"#;
+        assert!(lint_dockerfile(cleaned).is_empty());
+    }
+
+    #[test]
+    fn flag_remote_adds() {
+        let findings = lint_dockerfile(
+            "FROM debian:buster\nADD https://example.com/app.deb /src/\nADD a /b\n",
+        );
+        assert_eq!(findings.len(), 1);
+        assert!(findings[0].starts_with("ADD of a remote url"));
+    }
+
+    #[test]
+    fn fail_under_strict() {
+        // without a base image aspect there is no FROM
+        let mgr = ContainerManager::new(
+            "test".to_string(),
+            vec!["dfiles/test:0.0".to_string()],
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+        );
+        match mgr.lint(true) {
+            Err(Error::DockerfileLint { message }) => {
+                assert!(message.starts_with("the Dockerfile must start with FROM"))
+            }
+            other => panic!("expected DockerfileLint, got {:?}", other),
+        }
+        assert!(mgr.lint(false).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod diff_should {
+    use super::*;
+
+    #[test]
+    fn print_changed_lines_with_context() {
+        let before = "FROM debian:buster\nRUN a\nRUN b\nRUN c\nRUN d\nRUN e\nRUN f\nUSER me\n";
+        let after = "FROM debian:buster\nRUN a\nRUN b\nRUN c\nRUN d\nRUN e\nRUN g\nUSER me\nWORKDIR /data\n";
+        assert_eq!(
+            unified_diff(before, after, "last build", "current"),
+            "--- last build
++++ current
+@@ -4,5 +4,6 @@
+ RUN c
+ RUN d
+ RUN e
+-RUN f
++RUN g
+ USER me
++WORKDIR /data
+"
+        );
+    }
+
+    #[test]
+    fn split_distant_changes_into_hunks() {
+        let before = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n";
+        let after = "0\n1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+        assert_eq!(
+            unified_diff(before, after, "a", "b"),
+            "--- a
++++ b
+@@ -1,3 +1,4 @@
++0
+ 1
+ 2
+ 3
+@@ -7,4 +8,3 @@
+ 7
+ 8
+ 9
+-10
+"
+        );
+    }
+
+    #[test]
+    fn print_nothing_for_identical_dockerfiles() {
+        assert_eq!(unified_diff("FROM x\n", "FROM x\n", "a", "b"), "");
+    }
+}
+
+#[cfg(test)]
+mod stop_timeout_should {
+    use super::test_support::{manager, matches};
+    use super::*;
+
+    fn stop_timeout_of(aspects: Vec<Box<dyn aspects::ContainerAspect>>) -> Result<u32> {
+        let mgr = manager(aspects);
+        let matches = matches(&mgr, &["test", "run"]);
+        mgr.stop_timeout(matches.subcommand_matches("run").expect("run"))
+    }
+
+    #[test]
+    fn default_to_ten_seconds() -> Result<()> {
+        assert_eq!(stop_timeout_of(Vec::new())?, 10);
+        assert_eq!(
+            stop_timeout_of(vec![Box::new(aspects::StopTimeout(30))])?,
+            30
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod session_args_should {
+    use super::test_support::{manager, matches};
+    use super::*;
+
+    fn session(argv: &[&str]) -> Vec<String> {
+        let mgr = manager(Vec::new());
+        let matches = matches(&mgr, argv);
+        session_args(matches.subcommand_matches("run").expect("run"))
+    }
+
+    #[test]
+    fn map_terminal_flags() {
+        assert!(session(&["test", "run"]).is_empty());
+        assert_eq!(session(&["test", "run", "-i"]), vec!["-i"]);
+        assert_eq!(session(&["test", "run", "--tty"]), vec!["-t"]);
+        assert_eq!(
+            session(&["test", "run", "--interactive", "--tty"]),
+            vec!["-i", "-t"]
+        );
+        assert_eq!(session(&["test", "run", "--it"]), vec!["-i", "-t"]);
+    }
+
+    #[test]
+    fn reject_docker_style_combined_tty_flag() {
+        let argv = |args: &[&str]| -> Vec<OsString> { args.iter().map(OsString::from).collect() };
+        for flag in &["-it", "-ti"] {
+            match reject_combined_tty(&argv(&["test", "run", flag, "firefox"])) {
+                Err(Error::CombinedTtyFlag(f)) => assert_eq!(&f, flag),
+                other => panic!("expected CombinedTtyFlag, got {:?}", other),
+            }
+        }
+        assert!(reject_combined_tty(&argv(&["test", "run", "--it"])).is_ok());
+        assert!(reject_combined_tty(&argv(&["test", "run", "--docker-arg", "-it"])).is_ok());
+        assert!(reject_combined_tty(&argv(&["test", "run", "--", "docker", "run", "-it"])).is_ok());
+    }
+
+    #[test]
+    fn suppress_terminal_flags_when_detached() {
+        assert_eq!(session(&["test", "run", "--it", "--detach"]), vec!["-d"]);
+        assert_eq!(session(&["test", "run", "-d", "-i"]), vec!["-d"]);
+    }
+}
+
+#[cfg(test)]
+mod check_dependencies_should {
+    use super::test_support::manager;
+    use super::*;
+
+    #[test]
+    fn accept_satisfied_dependencies() -> Result<()> {
+        manager(vec![
+            Box::new(aspects::Notifications {}),
+            Box::new(aspects::DBus {}),
+        ])
+        .check_dependencies()
+    }
+
+    #[test]
+    fn reject_missing_dependencies() {
+        match manager(vec![Box::new(aspects::Notifications {})]).check_dependencies() {
+            Err(Error::MissingDependency { aspect, dependency }) => {
+                assert_eq!(aspect, "Notifications");
+                assert_eq!(dependency, "DBus");
+            }
+            other => panic!("expected MissingDependency, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn require_dbus_for_accessibility() -> Result<()> {
+        match manager(vec![Box::new(aspects::Accessibility {})]).check_dependencies() {
+            Err(Error::MissingDependency { aspect, dependency }) => {
+                assert_eq!(aspect, "Accessibility");
+                assert_eq!(dependency, "DBus");
+            }
+            other => panic!("expected MissingDependency, got {:?}", other),
+        }
+        manager(vec![
+            Box::new(aspects::DBus {}),
+            Box::new(aspects::Accessibility {}),
+        ])
+        .check_dependencies()
+    }
+}
+
+#[cfg(test)]
+mod check_conflicts_should {
+    use super::test_support::manager;
+    use super::*;
+
+    #[test]
+    fn warn_about_localtime_with_timezone() -> Result<()> {
+        let warnings = manager(vec![
+            Box::new(aspects::Localtime {}),
+            Box::new(aspects::Timezone("Europe/Berlin".to_string())),
+        ])
+        .check_conflicts()?;
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("aspect `Localtime` conflicts with the `Timezone` aspect"));
+        Ok(())
+    }
+
+    #[derive(Clone)]
+    struct User {}
+
+    impl aspects::ContainerAspect for User {
+        fn name(&self) -> String {
+            String::from("User: me")
+        }
+    }
+
+    #[test]
+    fn warn_about_userns_remap_with_current_user() -> Result<()> {
+        let warnings = manager(vec![
+            Box::new(User {}),
+            Box::new(aspects::UserNsRemap("default".to_string())),
+        ])
+        .check_conflicts()?;
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("aspect `UserNsRemap` conflicts with the `User` aspect"));
+        Ok(())
+    }
+
+    #[test]
+    fn reject_xvfb_with_x11() -> Result<()> {
+        match manager(vec![
+            Box::new(aspects::X11 {}),
+            Box::new(aspects::Xvfb::new(1280, 720, 24)?),
+        ])
+        .check_conflicts()
+        {
+            Err(Error::ConflictingAspects { aspect, other, .. }) => {
+                assert_eq!((aspect.as_str(), other.as_str()), ("Xvfb", "X11"))
+            }
+            other => panic!("expected ConflictingAspects, got {:?}", other),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn warn_about_no_new_privileges_with_entrypoint() -> Result<()> {
+        assert!(manager(vec![Box::new(aspects::NoNewPrivileges {})])
+            .check_conflicts()?
+            .is_empty());
+
+        let warnings = manager(vec![
+            Box::new(aspects::NoNewPrivileges {}),
+            Box::new(aspects::Clipboard {}),
+        ])
+        .check_conflicts()?;
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0]
+            .starts_with("aspect `NoNewPrivileges` conflicts with the `Entrypoint` aspect"));
+        Ok(())
+    }
+
+    #[test]
+    fn warn_about_static_ip_without_user_defined_network() -> Result<()> {
+        let ip = || -> Box<dyn aspects::ContainerAspect> {
+            Box::new(aspects::Ip("172.20.0.5".parse().unwrap()))
+        };
+        let network = |mode: &str| -> Box<dyn aspects::ContainerAspect> {
+            Box::new(aspects::Network {
+                mode: mode.to_string(),
+            })
+        };
+
+        assert!(manager(vec![ip()]).check_network()?.is_some());
+        assert!(manager(vec![ip(), network("bridge")])
+            .check_network()?
+            .is_some());
+        assert_eq!(
+            manager(vec![ip(), network("dfiles")]).check_network()?,
+            None
+        );
+        assert_eq!(manager(vec![network("bridge")]).check_network()?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_absent_conflicting_aspects() -> Result<()> {
+        assert!(manager(vec![Box::new(aspects::Localtime {})])
+            .check_conflicts()?
+            .is_empty());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod generate_archive_should {
+    use std::io::Read;
+
+    use tar::Archive;
+
+    use super::test_support::manager;
+    use super::*;
+
+    fn archive_entries(mgr: &ContainerManager) -> Result<BTreeMap<String, String>> {
+        let mut tar_file = NamedTempFile::new()?;
+        mgr.generate_archive_impl(tar_file.as_file_mut())?;
+
+        let mut entries = BTreeMap::new();
+        let mut archive = Archive::new(File::open(tar_file.path())?);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().to_string();
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents)?;
+            entries.insert(path, String::from_utf8_lossy(&contents).to_string());
+        }
+        Ok(entries)
+    }
+
+    #[test]
+    fn include_copied_files_and_copy_instruction() -> Result<()> {
+        let copy_file = aspects::CopyFile {
+            host_contents: "some contents".to_string(),
+            container_path: "/etc/dfiles/some.conf".to_string(),
+            order: 76,
+        };
+        let mgr = manager(vec![Box::new(copy_file.clone())]);
+
+        let entries = archive_entries(&mgr)?;
+        assert_eq!(
+            entries.get(&copy_file.archive_path()),
+            Some(&"some contents".to_string())
+        );
+
+        let dockerfile = entries.get("Dockerfile").expect("Dockerfile in archive");
+        assert!(dockerfile
+            .lines()
+            .any(|l| l == "COPY copy-file/etc/dfiles/some.conf /etc/dfiles/some.conf"));
+        Ok(())
+    }
+
+    #[test]
+    fn produce_identical_bytes_across_generations() -> Result<()> {
+        let copy_file = aspects::CopyFile {
+            host_contents: "some contents".to_string(),
+            container_path: "/etc/dfiles/some.conf".to_string(),
+            order: 76,
+        };
+        let mgr = manager(vec![Box::new(copy_file), Box::new(aspects::PulseAudio {})]);
+        let opts = ArchiveOptions {
+            mtime: 1_600_000_000,
+            ..ArchiveOptions::default()
+        };
+
+        let mut generations = Vec::new();
+        for _ in 0..2 {
+            let mut tar_file = NamedTempFile::new()?;
+            mgr.generate_archive_with(tar_file.as_file_mut(), &opts)?;
+            generations.push(fs::read(tar_file.path())?);
+        }
+        assert_eq!(generations[0], generations[1]);
+
+        let mut archive = Archive::new(&generations[0][..]);
+        let mut paths = Vec::new();
+        for entry in archive.entries()? {
+            let entry = entry?;
+            assert_eq!(entry.header().mtime()?, 1_600_000_000);
+            assert_eq!(entry.header().uid()?, 0);
+            assert_eq!(entry.header().gid()?, 0);
+            assert_eq!(entry.header().mode()?, 0o644);
+            paths.push(entry.path()?.to_string_lossy().to_string());
+        }
+        let mut sorted = paths.clone();
+        sorted.sort();
+        assert_eq!(paths, sorted);
+        Ok(())
+    }
+
+    fn dockerfile_with(opts: &ArchiveOptions) -> Result<String> {
+        let mgr = manager(Vec::new());
+        let mut tar_file = NamedTempFile::new()?;
+        mgr.generate_archive_with(tar_file.as_file_mut(), opts)?;
+
+        let mut archive = Archive::new(File::open(tar_file.path())?);
+        let mut dockerfile = String::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if entry.path()?.to_string_lossy() == "Dockerfile" {
+                entry.read_to_string(&mut dockerfile)?;
+            }
+        }
+        Ok(dockerfile)
+    }
+
+    #[test]
+    fn stamp_oci_labels() -> Result<()> {
+        let opts = ArchiveOptions {
+            metadata: aspects::BuildMetadata {
+                version: "0.1.0".to_string(),
+                revision: Some("abc123".to_string()),
+                created: Some("2020-01-01T00:00:00Z".to_string()),
+            },
+            ..ArchiveOptions::default()
+        };
+        let dockerfile = dockerfile_with(&opts)?;
+        for label in &[
+            r#"org.opencontainers.image.version="0.1.0""#,
+            r#"org.opencontainers.image.revision="abc123""#,
+            r#"org.opencontainers.image.created="2020-01-01T00:00:00Z""#,
+        ] {
+            assert!(
+                dockerfile.contains(label),
+                "{} missing from {}",
+                label,
+                dockerfile
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn render_snippets_in_order() -> Result<()> {
+        let mgr = manager(vec![
+            Box::new(aspects::Workdir("/data".to_string())),
+            Box::new(aspects::Run {
+                order: 89,
+                command: "echo hi".to_string(),
+            }),
+        ]);
+        let dockerfile = mgr.render_dockerfile_with(&ArchiveOptions::default())?;
+        let position = |needle: &str| {
+            dockerfile
+                .find(needle)
+                .unwrap_or_else(|| panic!("{} missing from {}", needle, dockerfile))
+        };
+        assert_eq!(position("FROM debian:buster"), 0);
+        assert!(position("FROM debian:buster") < position("# Useful language packs"));
+        assert!(position("# Useful language packs") < position("RUN echo hi"));
+        assert!(position("RUN echo hi") < position("LABEL org.opencontainers.image.version"));
+        assert!(position("LABEL org.opencontainers.image.version") < position("WORKDIR /data"));
+        Ok(())
+    }
+
+    #[test]
+    fn drop_apt_update_when_offline() -> Result<()> {
+        let online = dockerfile_with(&ArchiveOptions::default())?;
+        assert!(online.contains("RUN apt-get update && apt-get install"));
+
+        let offline = dockerfile_with(&ArchiveOptions {
+            offline: true,
+            ..ArchiveOptions::default()
+        })?;
+        assert!(!offline.contains("apt-get update"), "{}", offline);
+        assert!(!offline.contains("/var/lib/apt/lists"), "{}", offline);
+        assert!(offline.contains("RUN apt-get install -y --no-install-recommends"));
+        Ok(())
+    }
+
+    #[test]
+    fn mount_apt_caches_only_under_buildkit() -> Result<()> {
+        let legacy = dockerfile_with(&ArchiveOptions::default())?;
+        assert!(!legacy.contains("--mount=type=cache"));
+        assert!(legacy.contains("rm -rf /var/lib/apt/lists/*"));
+
+        let buildkit = dockerfile_with(&ArchiveOptions {
+            buildkit: true,
+            ..ArchiveOptions::default()
+        })?;
+        assert!(buildkit.starts_with("# syntax=docker/dockerfile:1\n"));
+        assert!(buildkit.contains("rm -f /etc/apt/apt.conf.d/docker-clean"));
+        assert!(buildkit.contains(
+            "RUN --mount=type=cache,target=/var/cache/apt,sharing=locked \
+             --mount=type=cache,target=/var/lib/apt,sharing=locked \\\n    \
+             apt-get update && apt-get install -y"
+        ));
+        assert!(!buildkit.contains("rm -rf /var/lib/apt/lists/*"));
+        Ok(())
+    }
+
+    #[test]
+    fn leave_runs_without_apt_alone() {
+        let dockerfile = "FROM debian:buster\nRUN useradd -m me \\\n  && echo hi\n";
+        assert_eq!(
+            with_apt_cache_mounts(dockerfile, false),
+            dockerfile.to_string()
+        );
+    }
+
+    #[test]
+    fn strip_chained_apt_updates() {
+        let dockerfile = r#"RUN apt-get update
+RUN curl x | apt-key add - \
+    && apt-get update && apt-get install -y foo
+"#;
+        assert_eq!(
+            without_apt_update(dockerfile),
+            "RUN curl x | apt-key add - \\\n    && apt-get install -y foo\n"
+        );
+    }
+
+    #[test]
+    fn keep_package_lists_offline() {
+        let dockerfile = r#"RUN apt-get update && apt-get install -y \
+    foo \
+  && rm -rf /var/lib/apt/lists/* \
+  && rm -rf /src/*.deb
+RUN mkdir -p /etc/apt/keyrings \
+  && echo "deb http://deb.example.com/ ./" > /etc/apt/sources.list.d/deb.example.com.list \
+  && apt-get update \
+  && rm -rf /var/lib/apt/lists/*
+RUN echo done
+"#;
+        assert_eq!(
+            without_apt_update(dockerfile),
+            r#"RUN apt-get install -y \
+    foo \
+  && rm -rf /src/*.deb
+RUN mkdir -p /etc/apt/keyrings \
+  && echo "deb http://deb.example.com/ ./" > /etc/apt/sources.list.d/deb.example.com.list
+RUN echo done
+"#
+        );
+    }
+
+    #[derive(Clone)]
+    struct Ignores(Vec<&'static str>);
+
+    impl aspects::ContainerAspect for Ignores {
+        fn name(&self) -> String {
+            String::from("Ignores")
+        }
+        fn dockerignore_patterns(&self) -> Vec<String> {
+            self.0.iter().map(|p| p.to_string()).collect()
+        }
+    }
+
+    #[test]
+    fn include_deduplicated_dockerignore() -> Result<()> {
+        let mgr = manager(vec![
+            Box::new(Ignores(vec!["*.log", "cache/"])),
+            Box::new(Ignores(vec!["cache/", "*.tar"])),
+        ]);
+
+        let entries = archive_entries(&mgr)?;
+        assert_eq!(
+            entries.get(".dockerignore"),
+            Some(&"*.log\ncache/\n*.tar\n".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn omit_dockerignore_without_patterns() -> Result<()> {
+        let mgr = manager(Vec::new());
+
+        assert!(!archive_entries(&mgr)?.contains_key(".dockerignore"));
+        Ok(())
+    }
+
+    #[test]
+    fn install_entrypoint_for_registered_fns() -> Result<()> {
+        let mgr = manager(vec![Box::new(aspects::Clipboard {})]);
+        assert_eq!(mgr.entrypoint_fns(), aspects::Clipboard {}.entrypoint_fns());
+
+        let entries = archive_entries(&mgr)?;
+        assert!(entries.contains_key(ENTRYPOINT_ARCHIVE_PATH));
+        let dockerfile = entries.get("Dockerfile").expect("Dockerfile in archive");
+        assert!(dockerfile
+            .lines()
+            .any(|l| l == "COPY dfiles-entrypoint /usr/local/bin/dfiles-entrypoint"));
+        // callers must not be able to pass e.g. LD_PRELOAD through sudo
+        assert!(!dockerfile.contains("SETENV"));
+        assert!(dockerfile.contains(
+            r#"Defaults!/usr/local/bin/dfiles-entrypoint env_keep += "DFILES_ENTRYPOINT""#
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn run_fns_of_aspects_the_container_binary_lacks() -> Result<()> {
+        let built_in = manager(Vec::new());
+        let mut configured = built_in.clone();
+        // the aspects `apply_config` loads from the config only exist on the host
+        configured.aspects.push(Box::new(aspects::Clipboard {}));
+        assert!(built_in.entrypoint_fns().is_empty());
+
+        let entries = archive_entries(&configured)?;
+        assert!(entries["Dockerfile"]
+            .lines()
+            .any(|l| l
+                == "COPY dfiles-entrypoint-fns.json /usr/local/share/dfiles/entrypoint-fns.json"));
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("entrypoint-fns.json");
+        fs::write(&path, &entries[ENTRYPOINT_FNS_ARCHIVE_PATH])?;
+
+        let mut ran = Vec::new();
+        run_entrypoint_fns(&read_entrypoint_fns(&path)?, 1000, None, |f, argv| {
+            ran.push((f.description.clone(), argv.to_vec()));
+            Ok(())
+        })?;
+        assert_eq!(
+            ran,
+            vec![(
+                "start clipboard sync".to_string(),
+                aspects::Clipboard {}.entrypoint_fns()[0].command.clone()
+            )]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn fail_on_unreadable_entrypoint_fns() -> Result<()> {
+        let file = NamedTempFile::new()?;
+        fs::write(file.path(), "not json")?;
+        match read_entrypoint_fns(file.path()) {
+            Err(Error::InvalidEntrypointFns(_)) => Ok(()),
+            other => panic!("expected InvalidEntrypointFns, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn omit_entrypoint_without_fns() -> Result<()> {
+        let mgr = manager(Vec::new());
+
+        let entries = archive_entries(&mgr)?;
+        assert!(!entries.contains_key(ENTRYPOINT_ARCHIVE_PATH));
+        assert!(!entries["Dockerfile"].contains("dfiles-entrypoint"));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod build_should {
+    use super::test_support::{manager, matches};
+    use super::*;
+
+    fn hash(mgr: &ContainerManager) -> Result<String> {
+        let mut tar_file = NamedTempFile::new()?;
+        mgr.generate_archive_with(tar_file.as_file_mut(), &ArchiveOptions::default())?;
+        archive_hash(tar_file.path())
+    }
+
+    #[test]
+    fn hash_identical_contexts_identically() -> Result<()> {
+        assert_eq!(hash(&manager(Vec::new()))?, hash(&manager(Vec::new()))?);
+        Ok(())
+    }
+
+    #[test]
+    fn hash_changed_contexts_differently() -> Result<()> {
+        let copy_file = |contents: &str| -> Box<dyn aspects::ContainerAspect> {
+            Box::new(aspects::CopyFile {
+                host_contents: contents.to_string(),
+                container_path: "/etc/some.conf".to_string(),
+                order: 76,
+            })
+        };
+        assert_ne!(
+            hash(&manager(vec![copy_file("a")]))?,
+            hash(&manager(vec![copy_file("b")]))?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn map_progress_flag_to_buildkit() {
+        let mgr = manager(Vec::new());
+        let argv = |args: &[&str]| -> Option<docker::BuildKitOptions> {
+            let matches = matches(&mgr, args);
+            mgr.buildkit_options(matches.subcommand_matches("build").expect("build"))
+        };
+
+        assert_eq!(argv(&["test", "build"]), None);
+        let opts = argv(&["test", "build", "--progress=plain"]).expect("buildkit options");
+        assert_eq!(opts.progress, "plain");
+        assert_eq!(opts.tags, vec!["dfiles/test:0.0".to_string()]);
+        assert_eq!(
+            opts.env(),
+            vec![("DOCKER_BUILDKIT".to_string(), "1".to_string())]
+        );
+        assert!(mgr
+            .build_app()
+            .get_matches_from_safe(&["test", "build", "--progress=fancy"])
+            .is_err());
+    }
+
+    #[test]
+    fn map_cache_from_to_build_options() {
+        let mgr = manager(Vec::new());
+        let matches = matches(
+            &mgr,
+            &[
+                "test",
+                "build",
+                "--cache-from",
+                "dfiles/test:0.0",
+                "--cache-from=registry.example.com/dfiles/test:ci",
+                "--progress=plain",
+            ],
+        );
+        let build = matches.subcommand_matches("build").expect("build");
+        let expected = vec![
+            "dfiles/test:0.0".to_string(),
+            "registry.example.com/dfiles/test:ci".to_string(),
+        ];
+
+        assert_eq!(mgr.legacy_build_options(build).cachefrom, expected);
+        let opts = mgr.buildkit_options(build).expect("buildkit options");
+        assert_eq!(opts.cache_from, expected);
+        assert_eq!(
+            opts.args(),
+            vec![
+                "build",
+                "--progress=plain",
+                "-t",
+                "dfiles/test:0.0",
+                "--cache-from",
+                "dfiles/test:0.0",
+                "--cache-from",
+                "registry.example.com/dfiles/test:ci",
+                "-f",
+                "Dockerfile",
+                "-"
+            ]
+        );
+    }
+
+    #[test]
+    fn hash_with_sha256() -> Result<()> {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(b"abc")?;
+        assert_eq!(
+            archive_hash(file.path())?,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn key_last_build_by_image_tag() -> Result<()> {
+        let mut mgr = manager(Vec::new());
+        let first = (mgr.hash_path()?, mgr.last_dockerfile_path()?);
+        mgr.tags = vec!["dfiles/test:1.0".to_string()];
+        let second = (mgr.hash_path()?, mgr.last_dockerfile_path()?);
+        assert_ne!(first.0, second.0);
+        assert_ne!(first.1, second.1);
+        let name = |p: &PathBuf| p.file_name().and_then(|n| n.to_str()).map(String::from);
+        assert_eq!(
+            name(&second.0),
+            Some("test-dfiles_test_1.0.hash".to_string())
+        );
+        assert_eq!(
+            name(&second.1),
+            Some("test-dfiles_test_1.0.Dockerfile".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rebuild_only_when_stored_hash_differs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let hash_path = dir.path().join("test.hash");
+        assert!(!is_up_to_date("abc", &hash_path)?);
+
+        fs::write(&hash_path, "abc")?;
+        assert!(is_up_to_date("abc", &hash_path)?);
+        assert!(!is_up_to_date("def", &hash_path)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod retry_should {
+    use super::test_support::{manager, matches};
+    use super::*;
+
+    #[test]
+    fn succeed_after_transient_failures() {
+        let mut calls = 0;
+        let res = retry(3, Duration::from_millis(0), || {
+            calls += 1;
+            if calls < 3 {
+                Err("connection refused")
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(res, Ok(3));
+    }
+
+    #[test]
+    fn give_up_when_retries_are_exhausted() {
+        let mut calls = 0;
+        let res: std::result::Result<(), _> = retry(2, Duration::from_millis(0), || {
+            calls += 1;
+            Err("connection refused")
+        });
+        assert_eq!(res, Err("connection refused"));
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn parse_connect_retries_flag() {
+        let mgr = manager(Vec::new());
+        let retries = |args: &[&str]| {
+            let matches = matches(&mgr, args);
+            connect_retries(matches.subcommand().1.expect("subcommand"))
+        };
+        assert_eq!(retries(&["test", "build"]), 3);
+        assert_eq!(retries(&["test", "build", "--connect-retries", "0"]), 0);
+        assert_eq!(retries(&["test", "push"]), 3);
+        assert_eq!(retries(&["test", "push", "--connect-retries", "5"]), 5);
+        assert!(mgr
+            .build_app()
+            .get_matches_from_safe(&["test", "build", "--connect-retries", "many"])
+            .is_err());
+    }
+}
+
+#[cfg(test)]
+mod registry_auth_should {
+    use super::test_support::{manager, matches};
+    use super::*;
+
+    #[test]
+    fn read_password_from_stdin() -> Result<()> {
+        let mgr = ContainerManager::default_debian(
+            "test".to_string(),
+            vec!["registry.example.com/dfiles/test:0.0".to_string()],
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+        );
+        let matches = matches(
+            &mgr,
+            &[
+                "test",
+                "push",
+                "--registry-user",
+                "me",
+                "--registry-pass-stdin",
+            ],
+        );
+        let push = matches.subcommand_matches("push").expect("push");
+        let auth = mgr.registry_auth(push, &mut "s3cret\n".as_bytes())?;
+        assert_eq!(
+            auth,
+            Some(docker::RegistryAuth {
+                username: "me".to_string(),
+                password: "s3cret".to_string(),
+                server: "registry.example.com".to_string(),
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn take_no_password_in_argv() {
+        let mgr = manager(Vec::new());
+        assert!(mgr
+            .build_app()
+            .get_matches_from_safe(&["test", "push", "--registry-user", "me"])
+            .is_err());
+        assert!(mgr
+            .build_app()
+            .get_matches_from_safe(&["test", "push", "--registry-pass", "s3cret"])
+            .is_err());
+    }
+}
+
+#[cfg(test)]
+mod image_should {
+    use super::*;
+
+    fn manager(tags: Vec<&str>) -> ContainerManager {
+        ContainerManager::default_debian(
+            "test".to_string(),
+            tags.into_iter().map(String::from).collect(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+        )
+    }
+
+    #[test]
+    fn use_the_first_tag() -> Result<()> {
+        assert_eq!(
+            manager(vec!["dfiles/test:0.0", "dfiles/test:latest"]).image()?,
+            "dfiles/test:0.0"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_missing_or_malformed_tags() {
+        match manager(Vec::new()).image() {
+            Err(Error::MissingImageTag) => (),
+            other => panic!("expected MissingImageTag, got {:?}", other),
+        }
+        match manager(vec!["Not A Tag"]).image() {
+            Err(Error::InvalidImageRef(_)) => (),
+            other => panic!("expected InvalidImageRef, got {:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod latest_tag_should {
+    use super::*;
+
+    #[test]
+    fn parse_tag_repository() {
+        assert_eq!(tag_repository("waynr/discord:0.0.10"), "waynr/discord");
+        assert_eq!(tag_repository("waynr/discord"), "waynr/discord");
+        assert_eq!(
+            tag_repository("localhost:5000/waynr/discord:0.0.10"),
+            "localhost:5000/waynr/discord"
+        );
+        assert_eq!(
+            tag_repository("localhost:5000/waynr/discord"),
+            "localhost:5000/waynr/discord"
+        );
+    }
+
+    #[test]
+    fn derive_latest_tags() {
+        let tags = |ts: &[&str]| ts.iter().map(|t| t.to_string()).collect::<Vec<String>>();
+        assert_eq!(
+            with_latest_tags(&tags(&["waynr/discord:0.0.10"])),
+            tags(&["waynr/discord:0.0.10", "waynr/discord:latest"])
+        );
+        assert_eq!(
+            with_latest_tags(&tags(&["waynr/discord:latest", "waynr/discord:0.0.10"])),
+            tags(&["waynr/discord:latest", "waynr/discord:0.0.10"])
+        );
+    }
+
+    #[test]
+    fn keep_the_version_tag_first() -> Result<()> {
+        let mgr = ContainerManager::default_debian(
+            "discord".to_string(),
+            vec!["waynr/discord:0.0.10".to_string()],
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+        )
+        .with_latest_tag();
+        assert_eq!(mgr.image()?, "waynr/discord:0.0.10");
+        assert_eq!(mgr.tags.len(), 2);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod ensure_built_should {
+    use std::cell::Cell;
+
+    use super::test_support::manager;
+    use super::*;
+
+    #[test]
+    fn skip_build_when_image_is_present() -> Result<()> {
+        let builds = Cell::new(0);
+        let mgr = manager(Vec::new());
+        for _ in 0..2 {
+            mgr.ensure_built_with(
+                |image| image == "dfiles/test:0.0",
+                |_| {
+                    builds.set(builds.get() + 1);
+                    Ok(())
+                },
+            )?;
+        }
+        assert_eq!(builds.get(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn build_when_image_is_absent() -> Result<()> {
+        let builds = Cell::new(0);
+        manager(Vec::new()).ensure_built_with(
+            |_| false,
+            |_| {
+                builds.set(builds.get() + 1);
+                Ok(())
+            },
+        )?;
+        assert_eq!(builds.get(), 1);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod build_all_should {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn collect_every_result_in_order() {
+        let results = run_bounded(vec![1, 2, 3, 4, 5], 2, |n: &i32| {
+            if n % 2 == 0 {
+                Err(Error::BuildFailed(format!("image {}", n)))
+            } else {
+                Ok(())
+            }
+        });
+        let failed: Vec<String> = results
+            .iter()
+            .map(|r| match r {
+                Ok(()) => String::from("ok"),
+                Err(e) => e.to_string(),
+            })
+            .collect();
+        assert_eq!(
+            failed,
+            vec![
+                "ok",
+                "failed to build image: image 2",
+                "ok",
+                "failed to build image: image 4",
+                "ok"
+            ]
+        );
+    }
+
+    #[test]
+    fn bound_concurrent_builds() {
+        let running = Arc::new(Mutex::new((0, 0)));
+        let observed = running.clone();
+        let results = run_bounded((0..8).collect(), 3, move |_: &i32| {
+            {
+                let mut r = running.lock().expect("lock");
+                r.0 += 1;
+                r.1 = r.1.max(r.0);
+            }
+            thread::sleep(Duration::from_millis(10));
+            running.lock().expect("lock").0 -= 1;
+            Ok(())
+        });
+        assert_eq!(results.len(), 8);
+        assert!(results.iter().all(|r| r.is_ok()));
+        let max = observed.lock().expect("lock").1;
+        assert!(max <= 3, "{} builds ran at once", max);
+    }
+
+    #[test]
+    fn handle_empty_input() {
+        assert!(run_bounded(Vec::<i32>::new(), 4, |_: &i32| Ok(())).is_empty());
+    }
+}
+
+#[cfg(test)]
+mod log_level_should {
+    use super::*;
+
+    #[test]
+    fn map_verbosity_flags() {
+        assert_eq!(log_level(0, false), None);
+        assert_eq!(log_level(1, false), Some(LevelFilter::Debug));
+        assert_eq!(log_level(2, false), Some(LevelFilter::Trace));
+        assert_eq!(log_level(3, false), Some(LevelFilter::Trace));
+        assert_eq!(log_level(5, false), Some(LevelFilter::Trace));
+    }
+
+    #[test]
+    fn prefer_quiet() {
+        assert_eq!(log_level(0, true), Some(LevelFilter::Error));
+        assert_eq!(log_level(2, true), Some(LevelFilter::Error));
+    }
+}
+
+#[cfg(test)]
+mod completions_should {
+    use super::test_support::manager;
+    use super::*;
+
+    #[test]
+    fn generate_for_each_shell() {
+        let mgr = manager(vec![Box::new(aspects::Name("test".to_string()))]);
+
+        // each shell spells long options differently
+        for (shell, name_flag) in [
+            (Shell::Bash, "--name"),
+            (Shell::Zsh, "'--name=["),
+            (Shell::Fish, "-l name"),
+        ]
+        .iter()
+        {
+            let mut buf: Vec<u8> = Vec::new();
+            mgr.write_completions(*shell, &mut buf);
+            let script = String::from_utf8(buf).expect("utf8 completion script");
+            assert!(script.contains("generate-archive"), "{:?}", shell);
+            assert!(script.contains(name_flag), "{:?}: {}", shell, script);
+        }
+    }
+}
+
+#[cfg(test)]
+mod build_app_should {
+    use super::test_support::{manager, matches};
+    use super::*;
+
+    #[test]
+    fn parse_representative_run_argv() {
+        let mgr = manager(vec![Box::new(aspects::Name("test".to_string()))]);
+        let matches = matches(
+            &mgr,
+            &[
+                "test",
+                "-v",
+                "run",
+                "-p",
+                "work",
+                "--name",
+                "test-work",
+                "--memory",
+                "1g",
+                "--mount",
+                "/a:/b",
+                "--docker-arg",
+                "--read-only",
+            ],
+        );
+        assert_eq!(matches.occurrences_of("verbose"), 1);
+
+        let (subc, subm) = matches.subcommand();
+        assert_eq!(subc, "run");
+        let subm = subm.expect("run subcommand matches");
+        assert_eq!(subm.value_of("profile"), Some("work"));
+        assert_eq!(subm.value_of("container_name"), Some("test-work"));
+        assert_eq!(subm.value_of("memory"), Some("1g"));
+        assert_eq!(
+            subm.values_of("mount").map(|v| v.collect::<Vec<&str>>()),
+            Some(vec!["/a:/b"])
+        );
+        assert_eq!(
+            subm.values_of("docker-arg")
+                .map(|v| v.collect::<Vec<&str>>()),
+            Some(vec!["--read-only"])
+        );
+    }
+
+    #[test]
+    fn parse_nested_config_show_argv() {
+        let mgr = manager(vec![Box::new(aspects::Name("test".to_string()))]);
+        let matches = matches(&mgr, &["test", "config", "show", "--profile", "work"]);
+
+        let (subc, subm) = matches.subcommand();
+        assert_eq!(subc, "config");
+        let (subc, subm) = subm.expect("config subcommand matches").subcommand();
+        assert_eq!(subc, "show");
+        assert_eq!(
+            subm.expect("show subcommand matches").value_of("profile"),
+            Some("work")
+        );
+    }
+
+    #[test]
+    fn copy_application_config_without_from() {
+        let mgr = manager(vec![Box::new(aspects::Name("test".to_string()))]);
+        let matches = matches(&mgr, &["test", "config", "copy", "--to", "work"]);
+
+        let copy = matches
+            .subcommand_matches("config")
+            .and_then(|m| m.subcommand_matches("copy"))
+            .expect("copy subcommand matches");
+        assert_eq!(copy.value_of("from"), None);
+        assert_eq!(copy.value_of("to"), Some("work"));
+    }
+
+    #[test]
+    fn reject_unknown_args() {
+        let mgr = manager(vec![Box::new(aspects::Name("test".to_string()))]);
+        assert!(mgr
+            .build_app()
+            .get_matches_from_safe(vec!["test", "run", "--no-such-flag"])
+            .is_err());
+    }
+}
+
+#[cfg(test)]
+mod execute_should {
+    use super::test_support::manager;
+    use super::*;
+
+    #[test]
+    fn fail_without_subcommand() {
+        let mut mgr = manager(Vec::new());
+        match mgr.execute_from(vec!["test"]) {
+            Err(Error::NoSubcommand) => (),
+            other => panic!("expected NoSubcommand, got {:?}", other),
+        }
     }
 }
@@ -5,12 +5,17 @@ use directories_next::ProjectDirs;
 use super::error::{Error, Result};
 
 enum DirType {
-    Config,
+    Cache,
     Data,
 }
 
+pub fn get_cache_dir(application: Option<&str>, profile: Option<&str>) -> Result<PathBuf> {
+    get_dir(DirType::Cache, application, profile)
+}
+
+/// Resolved from `config::config_dir` so the config location can be overridden.
 pub fn get_config_dir(application: Option<&str>, profile: Option<&str>) -> Result<PathBuf> {
-    get_dir(DirType::Config, application, profile)
+    Ok(scoped(super::config::config_dir()?, application, profile))
 }
 
 pub fn get_data_dir(application: Option<&str>, profile: Option<&str>) -> Result<PathBuf> {
@@ -19,21 +24,26 @@ pub fn get_data_dir(application: Option<&str>, profile: Option<&str>) -> Result<
 
 fn get_dir(dir_type: DirType, application: Option<&str>, profile: Option<&str>) -> Result<PathBuf> {
     if let Some(proj_dirs) = ProjectDirs::from("", "", "dfiles") {
-        let mut dir = match dir_type {
-            DirType::Config => proj_dirs.config_dir().to_path_buf(),
+        let dir = match dir_type {
+            DirType::Cache => proj_dirs.cache_dir().to_path_buf(),
             DirType::Data => proj_dirs.data_dir().to_path_buf(),
         };
 
-        if let Some(s) = application {
-            dir = dir.join("applications").join(s);
-        }
-
-        if let Some(s) = profile {
-            dir = dir.join("profiles").join(s);
-        }
-
-        Ok(dir)
+        Ok(scoped(dir, application, profile))
     } else {
         Err(Error::MissingDirectory)
     }
 }
+
+/// The directory of the application or of one of its profiles under a dfiles base directory.
+pub fn scoped(mut dir: PathBuf, application: Option<&str>, profile: Option<&str>) -> PathBuf {
+    if let Some(s) = application {
+        dir = dir.join("applications").join(s);
+    }
+
+    if let Some(s) = profile {
+        dir = dir.join("profiles").join(s);
+    }
+
+    dir
+}
@@ -1,39 +1,21 @@
-use std::process::Command;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::thread;
 
+use log::{debug, error, info};
 use serde::Deserialize;
-use serde_json::from_value;
-use shiplift::BuildOptions;
-use shiplift::Docker;
-use tokio::prelude::Future;
-use tokio::prelude::Stream;
 
-#[derive(Deserialize, Debug)]
-struct BuildOutput {
-    stream: String,
-}
-
-pub fn build(opts: &BuildOptions) {
-    let docker = Docker::new();
-
-    let fut = docker
-        .images()
-        .build(opts)
-        .for_each(|output| {
-            let u: Result<BuildOutput, _> = from_value(output);
-            match u {
-                Ok(a) => print!("{}", a.stream),
-                Err(_) => (),
-            }
-            Ok(())
-        })
-        .map_err(|e| eprintln!("Error: {}", e));
-
-    tokio::run(fut);
-}
+use super::error::{Error, Result};
 
 pub fn run(args: Vec<String>) {
     let cmdstr: String = args.join(" ");
-    println!("docker run {}", cmdstr);
+    info!("docker run {}", cmdstr);
 
     let mut child = Command::new("docker")
         .arg("run")
@@ -43,3 +25,770 @@ pub fn run(args: Vec<String>) {
 
     let _ = child.wait().expect("failed waiting for child process");
 }
+
+/// How a foreground container is stopped when dfiles itself is asked to shut down.
+pub trait StopContainer: Send + 'static {
+    fn stop(&self, name: &str, timeout: u32) -> Result<()>;
+}
+
+pub struct DockerStop;
+
+impl StopContainer for DockerStop {
+    fn stop(&self, name: &str, timeout: u32) -> Result<()> {
+        let status = Command::new("docker")
+            .args(vec!["stop", "-t", &timeout.to_string(), name])
+            .stdout(Stdio::null())
+            .status()?;
+        if !status.success() {
+            error!("docker stop {} exited with {}", name, status);
+        }
+        Ok(())
+    }
+}
+
+/// A SIGINT/SIGTERM handler that stops the container `name`, giving it `timeout` seconds to exit
+/// before docker kills it. Repeated signals while the container is stopping are ignored.
+pub fn stop_handler<S: StopContainer>(
+    name: String,
+    timeout: u32,
+    stop: S,
+) -> impl FnMut() + Send + 'static {
+    let mut stopping = false;
+    move || {
+        if stopping {
+            return;
+        }
+        stopping = true;
+        info!("stopping {}, waiting up to {}s", name, timeout);
+        if let Err(e) = stop.stop(&name, timeout) {
+            error!("failed to stop {}: {}", name, e);
+        }
+    }
+}
+
+/// Runs `handler` on SIGINT and SIGTERM instead of exiting, so a foreground `run` keeps waiting
+/// for docker while the container shuts down.
+pub fn forward_signals<F: FnMut() + Send + 'static>(handler: F) -> Result<()> {
+    ctrlc::set_handler(handler).map_err(|e| Error::SignalHandlerFailed(e.to_string()))
+}
+
+#[cfg(test)]
+mod stop_handler_should {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<(String, u32)>>>);
+
+    impl StopContainer for Recorder {
+        fn stop(&self, name: &str, timeout: u32) -> Result<()> {
+            self.0.lock().unwrap().push((name.to_string(), timeout));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stop_container_once_per_run() {
+        let recorder = Recorder::default();
+        let mut handler = stop_handler("firefox-default".to_string(), 30, recorder.clone());
+        assert!(recorder.0.lock().unwrap().is_empty());
+
+        handler();
+        handler();
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![("firefox-default".to_string(), 30)]
+        );
+    }
+}
+
+pub fn image_exists(image: &str) -> bool {
+    Command::new("docker")
+        .args(vec!["image", "inspect", image])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false)
+}
+
+pub fn container_running(name: &str) -> bool {
+    Command::new("docker")
+        .args(vec![
+            "container",
+            "inspect",
+            "-f",
+            "{{.State.Running}}",
+            name,
+        ])
+        .stderr(Stdio::null())
+        .output()
+        .map(|out| out.status.success() && String::from_utf8_lossy(&out.stdout).trim() == "true")
+        .unwrap_or(false)
+}
+
+/// Looks up and removes containers by name.
+pub trait ContainerLister {
+    /// The state docker reports for the container `name`, e.g. `running` or `exited`, or `None`
+    /// if there is no such container.
+    fn state(&self, name: &str) -> Result<Option<String>>;
+    fn remove(&self, name: &str) -> Result<()>;
+}
+
+pub struct DockerContainers;
+
+impl ContainerLister for DockerContainers {
+    fn state(&self, name: &str) -> Result<Option<String>> {
+        let out = Command::new("docker")
+            .args(vec![
+                "container",
+                "inspect",
+                "-f",
+                "{{.State.Status}}",
+                name,
+            ])
+            .stderr(Stdio::null())
+            .output()?;
+        if !out.status.success() {
+            return Ok(None);
+        }
+        Ok(Some(
+            String::from_utf8_lossy(&out.stdout).trim().to_string(),
+        ))
+    }
+
+    fn remove(&self, name: &str) -> Result<()> {
+        let out = Command::new("docker")
+            .args(vec!["rm", name])
+            .stdout(Stdio::null())
+            .output()?;
+        if !out.status.success() {
+            error!("{}", String::from_utf8_lossy(&out.stderr).trim());
+            return Err(Error::ContainerRemoveFailed(name.to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Removes the container `name` if it exists but isn't running, e.g. left behind by a crash, so
+/// a new container can take the name. Returns whether a container was removed.
+pub fn remove_stale<L: ContainerLister>(name: &str, lister: &L) -> Result<bool> {
+    match lister.state(name)?.as_deref() {
+        Some("created") | Some("exited") | Some("dead") => {
+            info!("removing stale container {}", name);
+            lister.remove(name)?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod remove_stale_should {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    struct FakeContainers {
+        states: BTreeMap<String, String>,
+        removed: RefCell<Vec<String>>,
+        fail_removal: bool,
+    }
+
+    impl FakeContainers {
+        fn new(states: &[(&str, &str)]) -> Self {
+            FakeContainers {
+                states: states
+                    .iter()
+                    .map(|(n, s)| (n.to_string(), s.to_string()))
+                    .collect(),
+                removed: RefCell::new(Vec::new()),
+                fail_removal: false,
+            }
+        }
+    }
+
+    impl ContainerLister for FakeContainers {
+        fn state(&self, name: &str) -> Result<Option<String>> {
+            Ok(self.states.get(name).cloned())
+        }
+
+        fn remove(&self, name: &str) -> Result<()> {
+            if self.fail_removal {
+                return Err(Error::ContainerRemoveFailed(name.to_string()));
+            }
+            self.removed.borrow_mut().push(name.to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn remove_only_stopped_containers() -> Result<()> {
+        let containers = FakeContainers::new(&[
+            ("firefox-default", "exited"),
+            ("firefox-work", "running"),
+            ("firefox-dead", "dead"),
+        ]);
+        assert!(remove_stale("firefox-default", &containers)?);
+        assert!(!remove_stale("firefox-work", &containers)?);
+        assert!(remove_stale("firefox-dead", &containers)?);
+        assert!(!remove_stale("firefox-missing", &containers)?);
+        assert_eq!(
+            *containers.removed.borrow(),
+            vec!["firefox-default", "firefox-dead"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn surface_removal_failures() {
+        let mut containers = FakeContainers::new(&[("firefox-default", "exited")]);
+        containers.fail_removal = true;
+        match remove_stale("firefox-default", &containers) {
+            Err(Error::ContainerRemoveFailed(name)) => assert_eq!(name, "firefox-default"),
+            other => panic!("expected ContainerRemoveFailed, got {:?}", other),
+        }
+    }
+}
+
+/// `docker stats` args reporting the usage figures relevant to tuning resource limits, once or
+/// continuously with `follow`.
+/// With `json` set each report is a line holding one JSON object, as docker's `{{json .}}`
+/// template renders it.
+pub fn stats_args(name: &str, follow: bool, json: bool) -> Vec<String> {
+    let mut args = vec!["stats".to_string()];
+    if !follow {
+        args.push("--no-stream".to_string());
+    }
+    args.push("--format".to_string());
+    if json {
+        args.push("{{json .}}".to_string());
+    } else {
+        args.push("table {{.Name}}\t{{.CPUPerc}}\t{{.MemUsage}}\t{{.NetIO}}".to_string());
+    }
+    args.push(name.to_string());
+    args
+}
+
+pub fn stats(name: &str, follow: bool, json: bool) -> Result<()> {
+    if !container_running(name) {
+        return Err(Error::ContainerNotRunning(name.to_string()));
+    }
+    let args = stats_args(name, follow, json);
+    info!("docker {}", args.join(" "));
+    let status = Command::new("docker").args(args).status()?;
+    if !status.success() {
+        return Err(Error::StatsFailed(format!(
+            "docker stats exited with {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod stats_should {
+    use super::*;
+
+    #[test]
+    fn report_once_by_default() {
+        assert_eq!(
+            stats_args("firefox-default", false, false),
+            vec![
+                "stats",
+                "--no-stream",
+                "--format",
+                "table {{.Name}}\t{{.CPUPerc}}\t{{.MemUsage}}\t{{.NetIO}}",
+                "firefox-default"
+            ]
+        );
+    }
+
+    #[test]
+    fn stream_when_following() {
+        let args = stats_args("firefox-default", true, false);
+        assert!(!args.contains(&"--no-stream".to_string()));
+        assert_eq!(args.last().map(String::as_str), Some("firefox-default"));
+    }
+
+    #[test]
+    fn report_json_lines_when_asked() {
+        let args = stats_args("firefox-default", false, true);
+        assert_eq!(
+            args,
+            vec![
+                "stats",
+                "--no-stream",
+                "--format",
+                "{{json .}}",
+                "firefox-default"
+            ]
+        );
+    }
+}
+
+/// A docker image reference such as `registry.example.com:5000/waynr/discord:0.0.10` or
+/// `debian@sha256:<64 hex chars>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageRef {
+    pub registry: Option<String>,
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl FromStr for ImageRef {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidImageRef(s.to_string());
+
+        let (name, digest) = match s.find('@') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        if let Some(d) = digest {
+            if !is_valid_digest(d) {
+                return Err(invalid());
+            }
+        }
+        let (name, tag) = match name.rfind(':') {
+            Some(i) if !name[i..].contains('/') => (&name[..i], Some(&name[i + 1..])),
+            _ => (name, None),
+        };
+        if let Some(t) = tag {
+            if !is_valid_tag(t) {
+                return Err(invalid());
+            }
+        }
+
+        // the first component is a registry if it looks like a host name rather than a path
+        let (registry, repository) = match name.find('/') {
+            Some(i)
+                if name[..i].contains('.')
+                    || name[..i].contains(':')
+                    || &name[..i] == "localhost" =>
+            {
+                (Some(&name[..i]), &name[i + 1..])
+            }
+            _ => (None, name),
+        };
+        if let Some(r) = registry {
+            if !is_valid_registry(r) {
+                return Err(invalid());
+            }
+        }
+        if !repository.split('/').all(is_valid_path_component) {
+            return Err(invalid());
+        }
+
+        Ok(ImageRef {
+            registry: registry.map(String::from),
+            repository: repository.to_string(),
+            tag: tag.map(String::from),
+            digest: digest.map(String::from),
+        })
+    }
+}
+
+impl fmt::Display for ImageRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(r) = &self.registry {
+            write!(f, "{}/", r)?;
+        }
+        write!(f, "{}", self.repository)?;
+        if let Some(t) = &self.tag {
+            write!(f, ":{}", t)?;
+        }
+        if let Some(d) = &self.digest {
+            write!(f, "@{}", d)?;
+        }
+        Ok(())
+    }
+}
+
+/// Lowercase alphanumerics separated by single `.`, `_` or `-` runs, or `__`.
+fn is_valid_path_component(c: &str) -> bool {
+    let alnum = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+    let starts_and_ends_alnum =
+        c.chars().next().map_or(false, alnum) && c.chars().last().map_or(false, alnum);
+    starts_and_ends_alnum
+        && c.chars()
+            .all(|ch| alnum(ch) || ch == '.' || ch == '_' || ch == '-')
+        && !c.contains("..")
+        && !c.contains("___")
+}
+
+fn is_valid_registry(r: &str) -> bool {
+    let (host, port) = match r.rfind(':') {
+        Some(i) => (&r[..i], Some(&r[i + 1..])),
+        None => (r, None),
+    };
+    !host.is_empty()
+        && host
+            .split('.')
+            .all(|l| !l.is_empty() && l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+        && port.map_or(true, |p| {
+            !p.is_empty() && p.chars().all(|c| c.is_ascii_digit())
+        })
+}
+
+fn is_valid_tag(t: &str) -> bool {
+    let word = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    t.len() <= 128
+        && t.chars().next().map_or(false, word)
+        && t.chars().all(|c| word(c) || c == '.' || c == '-')
+}
+
+/// Whether `d` is an immutable image digest of the form `sha256:<64 hex chars>`.
+pub fn is_valid_digest(d: &str) -> bool {
+    match d.strip_prefix("sha256:") {
+        Some(hex) => hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod image_ref_should {
+    use super::*;
+
+    #[test]
+    fn parse_plain_references() -> Result<()> {
+        assert_eq!(
+            "waynr/discord:0.0.10".parse::<ImageRef>()?,
+            ImageRef {
+                registry: None,
+                repository: "waynr/discord".to_string(),
+                tag: Some("0.0.10".to_string()),
+                digest: None,
+            }
+        );
+        assert_eq!("debian".parse::<ImageRef>()?.tag, None);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_registry_qualified_references() -> Result<()> {
+        let image: ImageRef = "registry.example.com:5000/waynr/discord:latest".parse()?;
+        assert_eq!(
+            image.registry,
+            Some("registry.example.com:5000".to_string())
+        );
+        assert_eq!(image.repository, "waynr/discord");
+        assert_eq!(image.tag, Some("latest".to_string()));
+        assert_eq!(
+            "localhost/discord".parse::<ImageRef>()?.registry,
+            Some("localhost".to_string())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_digest_references() -> Result<()> {
+        let digest = format!("sha256:{}", "a".repeat(64));
+        let reference = format!("debian@{}", digest);
+        let image: ImageRef = reference.parse()?;
+        assert_eq!(image.repository, "debian");
+        assert_eq!(image.digest, Some(digest));
+        assert_eq!(image.to_string(), reference);
+        Ok(())
+    }
+
+    #[test]
+    fn reject_malformed_references() {
+        for reference in &[
+            "",
+            "Waynr/discord",
+            "waynr//discord",
+            "waynr/discord:",
+            "waynr/discord:-bad",
+            "debian@sha256:abc",
+            "registry.example.com:port/discord",
+            "-discord",
+        ] {
+            match reference.parse::<ImageRef>() {
+                Err(Error::InvalidImageRef(r)) => assert_eq!(&r, reference),
+                other => panic!(
+                    "expected InvalidImageRef for {:?}, got {:?}",
+                    reference, other
+                ),
+            }
+        }
+    }
+}
+
+/// Docker Hub's key in `~/.docker/config.json`, used for images without a registry.
+pub const DOCKER_HUB: &str = "https://index.docker.io/v1/";
+
+/// Credentials for pushing to or pulling from a registry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegistryAuth {
+    pub username: String,
+    pub password: String,
+    pub server: String,
+}
+
+impl RegistryAuth {
+    /// Decodes the base64 `<username>:<password>` form used by docker's config.json and the
+    /// `DOCKER_AUTH` environment variable.
+    fn decode(auth: &str, server: &str) -> Result<Self> {
+        let failed = || Error::RegistryAuthFailed(format!("malformed credentials for {}", server));
+        let decoded = base64::decode(auth.trim()).map_err(|_| failed())?;
+        let decoded = String::from_utf8(decoded).map_err(|_| failed())?;
+        let mut parts = decoded.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(username), Some(password)) if !username.is_empty() => Ok(RegistryAuth {
+                username: username.to_string(),
+                password: password.to_string(),
+                server: server.to_string(),
+            }),
+            _ => Err(failed()),
+        }
+    }
+
+    /// Looks up credentials for `registry` (Docker Hub when `None`), preferring `DOCKER_AUTH` over
+    /// the `auths` in `~/.docker/config.json`.
+    pub fn lookup(registry: Option<&str>) -> Result<Option<Self>> {
+        let server = registry.unwrap_or(DOCKER_HUB);
+        if let Ok(auth) = env::var("DOCKER_AUTH") {
+            return RegistryAuth::decode(&auth, server).map(Some);
+        }
+        let path = match env::var_os("HOME") {
+            Some(home) => Path::new(&home).join(".docker/config.json"),
+            None => return Ok(None),
+        };
+        match fs::read_to_string(&path) {
+            Ok(json) => RegistryAuth::from_docker_config(&json, server),
+            Err(_) => Ok(None),
+        }
+    }
+
+    fn from_docker_config(json: &str, server: &str) -> Result<Option<Self>> {
+        let config: DockerConfig = serde_json::from_str(json).map_err(|e| {
+            Error::RegistryAuthFailed(format!("could not parse docker config: {}", e))
+        })?;
+        let host = registry_host(server);
+        for (key, entry) in &config.auths {
+            if registry_host(key) != host {
+                continue;
+            }
+            return match &entry.auth {
+                Some(auth) => RegistryAuth::decode(auth, server).map(Some),
+                None => Ok(None),
+            };
+        }
+        Ok(None)
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct DockerConfig {
+    #[serde(default)]
+    auths: BTreeMap<String, DockerConfigAuth>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DockerConfigAuth {
+    auth: Option<String>,
+}
+
+/// config.json keys may be bare hosts or URLs like `https://index.docker.io/v1/`.
+fn registry_host(key: &str) -> &str {
+    let key = key
+        .strip_prefix("https://")
+        .or_else(|| key.strip_prefix("http://"))
+        .unwrap_or(key);
+    key.split('/').next().unwrap_or(key)
+}
+
+#[cfg(test)]
+mod registry_auth_should {
+    use super::*;
+
+    const CONFIG: &str = r#"{
+        "auths": {
+            "https://index.docker.io/v1/": { "auth": "d2F5bnI6aHVudGVyMg==" },
+            "registry.example.com:5000": { "auth": "Y2k6czNjcjN0OndpdGg6Y29sb25z" },
+            "broken.example.com": { "auth": "bm9jb2xvbg==" },
+            "helper.example.com": {}
+        },
+        "credsStore": "desktop"
+    }"#;
+
+    #[test]
+    fn decode_base64_auth_entries() -> Result<()> {
+        assert_eq!(
+            RegistryAuth::from_docker_config(CONFIG, DOCKER_HUB)?,
+            Some(RegistryAuth {
+                username: "waynr".to_string(),
+                password: "hunter2".to_string(),
+                server: DOCKER_HUB.to_string(),
+            })
+        );
+        let auth = RegistryAuth::from_docker_config(CONFIG, "registry.example.com:5000")?
+            .expect("auth for registry.example.com");
+        assert_eq!(auth.username, "ci");
+        assert_eq!(auth.password, "s3cr3t:with:colons");
+        Ok(())
+    }
+
+    #[test]
+    fn skip_unknown_or_helper_managed_registries() -> Result<()> {
+        assert_eq!(
+            RegistryAuth::from_docker_config(CONFIG, "other.example.com")?,
+            None
+        );
+        assert_eq!(
+            RegistryAuth::from_docker_config(CONFIG, "helper.example.com")?,
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn reject_malformed_entries() {
+        match RegistryAuth::from_docker_config(CONFIG, "broken.example.com") {
+            Err(Error::RegistryAuthFailed(_)) => (),
+            other => panic!("expected RegistryAuthFailed, got {:?}", other),
+        }
+    }
+}
+
+/// Options for building an image by shelling out to `docker build` with BuildKit enabled, used
+/// instead of the daemon API when structured progress output is requested.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildKitOptions {
+    pub progress: String,
+    pub tags: Vec<String>,
+    /// Images whose layers may be reused, e.g. one pushed by a previous CI run.
+    pub cache_from: Vec<String>,
+    /// Prepended to every line of build output, e.g. to tell concurrent builds apart.
+    pub prefix: Option<String>,
+}
+
+impl BuildKitOptions {
+    pub fn env(&self) -> Vec<(String, String)> {
+        vec![("DOCKER_BUILDKIT".to_string(), "1".to_string())]
+    }
+
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec!["build".to_string(), format!("--progress={}", self.progress)];
+        for tag in &self.tags {
+            args.push("-t".to_string());
+            args.push(tag.to_string());
+        }
+        for image in &self.cache_from {
+            args.push("--cache-from".to_string());
+            args.push(image.to_string());
+        }
+        args.extend(vec!["-f", "Dockerfile", "-"].into_iter().map(String::from));
+        args
+    }
+}
+
+/// Builds the image from the tar build context at `context`, streaming it to `docker build` on
+/// stdin.
+pub fn build_with_buildkit(opts: &BuildKitOptions, context: &Path) -> Result<()> {
+    let args = opts.args();
+    info!("docker {}", args.join(" "));
+
+    let mut cmd = Command::new("docker");
+    cmd.args(args)
+        .envs(opts.env())
+        .stdin(Stdio::from(File::open(context)?));
+    let status = match &opts.prefix {
+        None => cmd.status()?,
+        Some(prefix) => {
+            let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+            // BuildKit renders its progress on stderr; drain stdout on the side so neither pipe
+            // fills up and blocks docker
+            let stdout = child.stdout.take().map(|out| {
+                let prefix = prefix.clone();
+                thread::spawn(move || {
+                    for line in BufReader::new(out).lines().filter_map(|l| l.ok()) {
+                        println!("{}{}", prefix, line);
+                    }
+                })
+            });
+            if let Some(err) = child.stderr.take() {
+                for line in BufReader::new(err).lines().filter_map(|l| l.ok()) {
+                    eprintln!("{}{}", prefix, line);
+                }
+            }
+            if let Some(handle) = stdout {
+                let _ = handle.join();
+            }
+            child.wait()?
+        }
+    };
+    if !status.success() {
+        return Err(Error::BuildFailed(format!(
+            "docker build exited with {}",
+            status
+        )));
+    }
+    Ok(())
+}
+
+/// BuildKit ships with docker 18.09 and later.
+pub fn buildkit_available() -> bool {
+    let output = match Command::new("docker")
+        .args(vec!["version", "--format", "{{.Server.Version}}"])
+        .stderr(Stdio::null())
+        .output()
+    {
+        Ok(o) if o.status.success() => o,
+        _ => return false,
+    };
+    let version = String::from_utf8_lossy(&output.stdout);
+    debug!("docker server version {}", version.trim());
+    supports_buildkit(version.trim())
+}
+
+fn supports_buildkit(version: &str) -> bool {
+    let mut parts = version.split('.').map(|p| p.parse::<u32>());
+    match (parts.next(), parts.next()) {
+        (Some(Ok(major)), Some(Ok(minor))) => (major, minor) >= (18, 9),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod buildkit_should {
+    use super::*;
+
+    #[test]
+    fn enable_buildkit_in_env() {
+        let opts = BuildKitOptions {
+            progress: "plain".to_string(),
+            tags: vec!["dfiles/test:0.0".to_string()],
+            cache_from: Vec::new(),
+            prefix: None,
+        };
+        assert_eq!(
+            opts.env(),
+            vec![("DOCKER_BUILDKIT".to_string(), "1".to_string())]
+        );
+        assert_eq!(
+            opts.args(),
+            vec![
+                "build",
+                "--progress=plain",
+                "-t",
+                "dfiles/test:0.0",
+                "-f",
+                "Dockerfile",
+                "-"
+            ]
+        );
+    }
+
+    #[test]
+    fn detect_buildkit_capable_versions() {
+        assert!(supports_buildkit("19.03.12"));
+        assert!(supports_buildkit("18.09.0"));
+        assert!(!supports_buildkit("18.06.1-ce"));
+        assert!(!supports_buildkit(""));
+    }
+}
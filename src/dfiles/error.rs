@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use dockworker;
 use thiserror;
 
@@ -8,6 +10,18 @@ pub enum Error {
     #[error("not in entrypoint mode")]
     NotInEntrypointMode,
 
+    #[error("entrypoint step `{0}` failed")]
+    EntrypointFnFailed(String),
+
+    #[error("could not read the entrypoint steps baked into the image: {0}")]
+    InvalidEntrypointFns(String),
+
+    #[error("Dockerfile lint failed: {message}")]
+    DockerfileLint { message: String },
+
+    #[error("hook `{0}` failed")]
+    HookFailed(String),
+
     #[error("could not find current binary")]
     CouldNotFindCurrentBinary(#[from] std::io::Error),
 
@@ -26,15 +40,218 @@ pub enum Error {
     #[error("invalide locale `{0:?}`")]
     InvalidLocale(String),
 
+    #[error("invalid run command `{0:?}`")]
+    InvalidRunCommand(String),
+
+    #[error("invalid workdir `{0:?}`, expected an absolute path")]
+    InvalidWorkdir(String),
+
+    #[error("invalid proxy url `{0:?}`")]
+    InvalidProxy(String),
+
+    #[error("invalid apt repository `{0:?}`, expected an http(s) uri and a suite")]
+    InvalidAptRepository(String),
+
+    #[error("invalid image reference `{0:?}`")]
+    InvalidImageRef(String),
+
+    #[error("no image tag configured")]
+    MissingImageTag,
+
+    #[error("invalid image digest `{0:?}`")]
+    InvalidDigest(String),
+
+    #[error("invalid extra host `{0:?}`, expected <name>:<ip|host-gateway>")]
+    InvalidExtraHost(String),
+
+    #[error("invalid ulimit `{0:?}`, expected <name>=<soft>[:<hard>] with hard >= soft")]
+    InvalidUlimit(String),
+
+    #[error("invalid device `{0:?}`, permissions must be a combination of r, w and m")]
+    InvalidDevice(String),
+
+    #[error("invalid sysctl `{0:?}`, expected <key>=<value> under the net. or kernel. namespace")]
+    InvalidSysctl(String),
+
+    #[error("unknown log driver `{0:?}`")]
+    InvalidLogDriver(String),
+
+    #[error("invalid log option `{0:?}`, expected <key>=<value>")]
+    InvalidLogOpt(String),
+
+    #[error("invalid cursor theme `{0:?}`, expected <name>[:<size>] with size > 0")]
+    InvalidCursorTheme(String),
+
+    #[error("invalid keyboard layout `{0:?}`, expected an alphabetic XKB layout code")]
+    InvalidKeyboardLayout(String),
+
+    #[error("font path `{0:?}` does not exist")]
+    MissingFontPath(PathBuf),
+
+    #[error("ca certificate `{0:?}` does not exist")]
+    MissingCaCertificate(PathBuf),
+
+    #[error("invalid ca certificate `{0:?}`, expected a file ending in .crt")]
+    InvalidCaCertificate(PathBuf),
+
+    #[error("hosts file `{0:?}` does not exist")]
+    MissingHostsFile(PathBuf),
+
+    #[error("resolv.conf `{0:?}` does not exist")]
+    MissingResolvConf(PathBuf),
+
+    #[error("git config file `{0:?}` does not exist")]
+    MissingGitConfig(PathBuf),
+
+    #[error("invalid group `{0:?}`, expected a group name or gid")]
+    InvalidGroup(String),
+
+    #[error("invalid user namespace mode `{0:?}`")]
+    InvalidUserNs(String),
+
+    #[error("invalid cgroup parent `{0:?}`, expected a systemd slice such as dfiles.slice or a cgroup path")]
+    InvalidCgroupParent(String),
+
+    #[error("unknown stop signal `{0:?}`, expected a signal name such as SIGINT")]
+    InvalidStopSignal(String),
+
+    #[error("invalid stop timeout `{0:?}`, expected a number of seconds")]
+    InvalidStopTimeout(String),
+
+    #[error("invalid HiDPI scale `{0:?}`, expected a number greater than 0")]
+    InvalidHiDpiScale(String),
+
+    #[error("invalid IP address `{0:?}`")]
+    InvalidIpAddress(String),
+
+    #[error("invalid MAC address `{0:?}`, expected six hex octets such as 02:42:ac:11:00:02")]
+    InvalidMacAddress(String),
+
+    #[error(
+        "invalid home or shell `{0:?}`, expected an absolute path of letters, digits and /._-+@"
+    )]
+    InvalidUserPath(String),
+
+    #[error("invalid memory `{0:?}`")]
+    InvalidMemory(String),
+
+    #[error("invalid pids limit `{0:?}`, expected a positive number or -1 for unlimited")]
+    InvalidPidsLimit(String),
+
+    #[error("invalid SOURCE_DATE_EPOCH `{0:?}`, expected seconds since the unix epoch")]
+    InvalidSourceDateEpoch(String),
+
+    #[error("invalid Xvfb screen `{0}`, expected a resolution up to 16384x16384 and a depth of 8, 15, 16 or 24")]
+    InvalidXvfbScreen(String),
+
+    #[error("invalid cpuset list `{0:?}`, expected e.g. 0-3,5")]
+    InvalidCpuSet(String),
+
+    #[error("env file `{0:?}` does not exist")]
+    MissingEnvFile(PathBuf),
+
+    #[error("input method socket `{0:?}` does not exist, is the input method running?")]
+    MissingImeSocket(PathBuf),
+
+    #[error("at-spi bus directory `{0:?}` does not exist, is at-spi2-core running on the host?")]
+    MissingAtSpiBus(PathBuf),
+
+    #[error("no bluetooth controller found under /sys/class/bluetooth")]
+    MissingBluetoothController,
+
+    #[error("usb device {vid}:{pid} not found")]
+    UsbDeviceNotFound { vid: String, pid: String },
+
+    #[error("cups socket `{0:?}` does not exist, is cupsd running?")]
+    MissingCupsSocket(PathBuf),
+
+    #[error("docker socket `{0:?}` does not exist, is the docker daemon running?")]
+    MissingDockerSocket(PathBuf),
+
+    #[error("environment variable `{0}` must be set")]
+    MissingEnvironmentVariable(String),
+
+    #[error("aspect `{aspect}` conflicts with the `{other}` aspect: {reason}")]
+    ConflictingAspects {
+        aspect: String,
+        other: String,
+        reason: String,
+    },
+
+    #[error("aspect `{aspect}` requires the `{dependency}` aspect")]
+    MissingDependency { aspect: String, dependency: String },
+
+    #[error("no app name to key host directories by")]
+    MissingAppName,
+
     #[error("could not identify directory")]
     MissingDirectory,
 
     #[error("directory")]
     DockerError(#[from] dockworker::errors::Error),
 
+    #[error("could not reach the docker daemon after {attempts} attempts, is it running? (e.g. `systemctl start docker`)")]
+    DockerUnavailable {
+        attempts: u32,
+        source: dockworker::errors::Error,
+    },
+
+    #[error("could not install signal handler: {0}")]
+    SignalHandlerFailed(String),
+
+    #[error("failed to remove stale container `{0}`")]
+    ContainerRemoveFailed(String),
+
+    #[error("container `{0}` is not running")]
+    ContainerNotRunning(String),
+
+    #[error("docker stats failed: {0}")]
+    StatsFailed(String),
+
+    #[error("registry authentication failed: {0}")]
+    RegistryAuthFailed(String),
+
+    #[error("failed to push `{tag}`")]
+    PushFailed {
+        tag: String,
+        source: dockworker::errors::Error,
+    },
+
+    #[error("failed to build image: {0}")]
+    BuildFailed(String),
+
+    #[error("no subcommand given")]
+    NoSubcommand,
+
+    #[error(
+        "`{0}` would read the next argument as a timezone since -t is --timezone, use --it instead"
+    )]
+    CombinedTtyFlag(String),
+
+    #[error("unsupported shell `{0}`")]
+    UnsupportedShell(String),
+
+    #[error("profile `{0}` has no config")]
+    MissingProfile(String),
+
+    #[error("application `{0}` has no config")]
+    MissingAppConfig(String),
+
+    #[error("profile `{0}` already exists, use --force to replace it")]
+    ProfileExists(String),
+
+    #[error("no Intel GPU render node found under /dev/dri")]
+    MissingIntelRenderNode,
+
     #[error("failed to save config to file")]
     FailedToSaveConfig,
 
+    #[error("failed to render JSON output: {0}")]
+    FailedToRenderJson(String),
+
     #[error("failed to load config from file")]
     FailedToLoadConfig,
+
+    #[error("editor `{0}` exited unsuccessfully")]
+    EditorFailed(String),
 }